@@ -0,0 +1,92 @@
+//! Packs the `initrd/` directory into a USTAR archive that the kernel links
+//! in and unpacks into ramfs at boot.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+const BLOCK: usize = 512;
+
+fn main() {
+    let dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("initrd");
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("initrd.tar");
+    println!("cargo:rerun-if-changed=initrd");
+
+    let mut archive = Vec::new();
+    if dir.is_dir() {
+        add_dir(&mut archive, &dir, "").expect("packing initrd failed");
+    }
+    // The archive ends with two zero blocks.
+    archive.resize(archive.len() + 2 * BLOCK, 0);
+    fs::write(out, archive).expect("writing initrd.tar failed");
+}
+
+fn add_dir(archive: &mut Vec<u8>, dir: &Path, prefix: &str) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        let mtime = metadata.mtime().max(0) as u64;
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            add_entry(archive, &name, mode, mtime, b'2', &target.to_string_lossy(), &[]);
+        } else if metadata.is_dir() {
+            let name = format!("{}/", name);
+            add_entry(archive, &name, mode, mtime, b'5', "", &[]);
+            add_dir(archive, &entry.path(), &name)?;
+        } else {
+            let data = fs::read(entry.path())?;
+            add_entry(archive, &name, mode, mtime, b'0', "", &data);
+        }
+    }
+    Ok(())
+}
+
+fn add_entry(archive: &mut Vec<u8>, path: &str, mode: u32, mtime: u64, kind: u8, link: &str, data: &[u8]) {
+    let mut header = [0u8; BLOCK];
+    // Names that do not fit are split at a slash into prefix and name.
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        let split = path[..path.len() - 1]
+            .char_indices()
+            .filter(|&(i, c)| c == '/' && i <= 155 && path.len() - i - 1 <= 100)
+            .map(|(i, _)| i)
+            .next()
+            .unwrap_or_else(|| panic!("initrd path is too long: {}", path));
+        (&path[..split], &path[split + 1..])
+    };
+    assert!(link.len() <= 100, "initrd link target is too long: {}", link);
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], mode as u64);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], data.len() as u64);
+    octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[265..269].copy_from_slice(b"root");
+    header[297..301].copy_from_slice(b"root");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is taken with its own field filled with spaces.
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().div_ceil(BLOCK) * BLOCK, 0);
+}
+
+/// Writes `value` as zero-padded octal ending with a NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
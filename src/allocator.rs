@@ -7,10 +7,10 @@ use x86_64::{
 use fixed_size_block::FixedSizeBlockAllocator;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024;
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024;
 pub mod fixed_size_block;
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(
     FixedSizeBlockAllocator::new());
 
@@ -42,6 +42,11 @@ pub fn init_heap(
     Ok(())
 }
 
+/// Returns `(size, used)` of the kernel heap in bytes.
+pub fn heap_stats() -> (usize, usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| (HEAP_SIZE, ALLOCATOR.lock().used()))
+}
+
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
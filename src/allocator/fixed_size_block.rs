@@ -34,6 +34,19 @@ impl FixedSizeBlockAllocator {
             self.fallback_allocator.init(heap_start, heap_size);
         }
     }
+    /// Bytes handed out and not given back; blocks waiting in the lists
+    /// count as free.
+    pub fn used(&self) -> usize {
+        let mut cached = 0;
+        for (head, &size) in self.list_heads.iter().zip(BLOCK_SIZES) {
+            let mut node = head.as_deref();
+            while let Some(current) = node {
+                cached += size;
+                node = current.next.as_deref();
+            }
+        }
+        self.fallback_allocator.used() - cached
+    }
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
@@ -0,0 +1,376 @@
+//! ATA PIO driver for the four drives of the two legacy IDE channels. QEMU
+//! attaches the boot image as `hda`, the primary master; further `-drive`
+//! options become `hdb`, `hdc` and `hdd`. Transfers are polled with the
+//! channel interrupts switched off.
+
+use alloc::{format, rc::Rc, string::String};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::block::{self, BlockDevice};
+use crate::serial_println;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_COMMAND: u16 = 7;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+const ERROR_ABRT: u8 = 0x04;
+const ERROR_IDNF: u8 = 0x10;
+const ERROR_UNC: u8 = 0x40;
+const ERROR_BBK: u8 = 0x80;
+
+/// Disables the interrupt line of the channel.
+const CONTROL_NIEN: u8 = 0x02;
+/// Resets both drives of the channel.
+const CONTROL_SRST: u8 = 0x04;
+
+/// Status polls before a command is given up.
+const TIMEOUT: usize = 1_000_000;
+
+/// The largest LBA28 address plus one.
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// Sectors moved by one command.
+const MAX_SECTORS: usize = 128;
+
+#[derive(Clone, Copy)]
+struct Channel {
+    io_base: u16,
+    control_base: u16,
+}
+
+const CHANNELS: [Channel; 2] = [
+    Channel { io_base: 0x1F0, control_base: 0x3F6 },
+    Channel { io_base: 0x170, control_base: 0x376 },
+];
+
+/// Both drives of a channel share its registers.
+static CHANNEL_LOCKS: [Mutex<()>; 2] = [Mutex::new(()), Mutex::new(())];
+
+/// What IDENTIFY tells about a drive.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    /// Number of addressable sectors.
+    pub sectors: u64,
+    /// Whether the drive takes 48-bit addresses.
+    pub lba48: bool,
+}
+
+impl Identity {
+    fn parse(words: &[u16; SECTOR_SIZE / 2]) -> Option<Identity> {
+        // Drives without LBA only speak cylinders, heads and sectors.
+        if words[49] & (1 << 9) == 0 {
+            return None;
+        }
+        let lba48 = words[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (0..4).map(|i| (words[100 + i] as u64) << (16 * i)).sum()
+        } else {
+            words[60] as u64 | (words[61] as u64) << 16
+        };
+        Some(Identity {
+            model: ata_string(&words[27..47]),
+            serial: ata_string(&words[10..20]),
+            firmware: ata_string(&words[23..27]),
+            sectors,
+            lba48,
+        })
+    }
+}
+
+/// IDENTIFY strings keep two characters per word, the first in the high
+/// byte, padded with spaces.
+fn ata_string(words: &[u16]) -> String {
+    let bytes: alloc::vec::Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+    String::from_utf8_lossy(&bytes).trim().into()
+}
+
+pub struct AtaDrive {
+    name: String,
+    channel: usize,
+    slave: bool,
+    identity: Identity,
+}
+
+impl Channel {
+    fn read_register(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn write_register(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+
+    fn write_control(&self, value: u8) {
+        unsafe { Port::new(self.control_base).write(value) }
+    }
+
+    fn status(&self) -> u8 {
+        self.read_register(REG_COMMAND)
+    }
+
+    /// Gives the drive the 400ns it needs after a select or a command by
+    /// reading the alternate status four times.
+    fn delay(&self) {
+        let mut alternate = Port::<u8>::new(self.control_base);
+        for _ in 0..4 {
+            unsafe { alternate.read() };
+        }
+    }
+
+    /// Selects a drive in LBA mode; `lba_high` is only used by 28-bit
+    /// commands.
+    fn select(&self, slave: bool, lba_high: u8) {
+        let drive = 0xE0 | if slave { 0x10 } else { 0 } | lba_high;
+        self.write_register(REG_DRIVE, drive);
+        self.delay();
+    }
+
+    fn command(&self, command: u8) {
+        self.write_register(REG_COMMAND, command);
+        self.delay();
+    }
+
+    /// Turns a failed status into a message, from the error register.
+    fn error(&self, status: u8) -> &'static str {
+        if status & STATUS_DF != 0 {
+            return "Drive fault";
+        }
+        let error = self.read_register(REG_ERROR);
+        if error & (ERROR_UNC | ERROR_BBK) != 0 {
+            "Bad sector"
+        } else if error & ERROR_IDNF != 0 {
+            "Sector not found"
+        } else if error & ERROR_ABRT != 0 {
+            "Command aborted"
+        } else {
+            "I/O error"
+        }
+    }
+
+    /// Resets the channel after a drive stopped answering.
+    fn reset(&self) {
+        self.write_control(CONTROL_NIEN | CONTROL_SRST);
+        self.delay();
+        self.write_control(CONTROL_NIEN);
+        for _ in 0..TIMEOUT {
+            if self.status() & STATUS_BSY == 0 {
+                break;
+            }
+        }
+    }
+
+    /// Waits until the drive is not busy and reports an error if it failed.
+    fn wait_ready(&self) -> Result<(), &'static str> {
+        for _ in 0..TIMEOUT {
+            let status = self.status();
+            if status & STATUS_BSY == 0 {
+                if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    return Err(self.error(status));
+                }
+                return Ok(());
+            }
+        }
+        self.reset();
+        Err("Device timeout")
+    }
+
+    /// Waits until the drive is ready to transfer a sector.
+    fn wait_data(&self) -> Result<(), &'static str> {
+        for _ in 0..TIMEOUT {
+            let status = self.status();
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(self.error(status));
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        self.reset();
+        Err("Device timeout")
+    }
+
+    fn read_sector(&self, buf: &mut [u8]) {
+        let mut data = Port::<u16>::new(self.io_base + REG_DATA);
+        for chunk in buf.chunks_exact_mut(2) {
+            chunk.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_sector(&self, buf: &[u8]) {
+        let mut data = Port::<u16>::new(self.io_base + REG_DATA);
+        for chunk in buf.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([chunk[0], chunk[1]])) };
+        }
+    }
+
+    fn identify(&self, slave: bool) -> Option<Identity> {
+        self.select(slave, 0);
+        for register in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+            self.write_register(register, 0);
+        }
+        self.command(CMD_IDENTIFY);
+        if self.status() == 0 {
+            return None;
+        }
+        for _ in 0..TIMEOUT {
+            if self.status() & STATUS_BSY == 0 {
+                break;
+            }
+        }
+        // ATAPI and SATA devices set the signature and abort IDENTIFY.
+        if self.read_register(REG_LBA_MID) != 0 || self.read_register(REG_LBA_HIGH) != 0 {
+            return None;
+        }
+        self.wait_data().ok()?;
+        let mut bytes = [0u8; SECTOR_SIZE];
+        self.read_sector(&mut bytes);
+        let mut words = [0u16; SECTOR_SIZE / 2];
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(2)) {
+            *word = u16::from_le_bytes([chunk[0], chunk[1]]);
+        }
+        Identity::parse(&words)
+    }
+}
+
+impl AtaDrive {
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    fn channel(&self) -> Channel {
+        CHANNELS[self.channel]
+    }
+
+    /// Issues a read or write of `count` sectors at `lba`, with a 48-bit
+    /// address when the 28-bit one does not reach.
+    fn start(&self, lba: u64, count: usize, write: bool) -> Result<(), &'static str> {
+        let channel = self.channel();
+        channel.wait_ready()?;
+        if lba + count as u64 <= LBA28_LIMIT {
+            channel.select(self.slave, (lba >> 24) as u8 & 0x0F);
+            channel.write_register(REG_SECTOR_COUNT, count as u8);
+            channel.write_register(REG_LBA_LOW, lba as u8);
+            channel.write_register(REG_LBA_MID, (lba >> 8) as u8);
+            channel.write_register(REG_LBA_HIGH, (lba >> 16) as u8);
+            channel.command(if write { CMD_WRITE_SECTORS } else { CMD_READ_SECTORS });
+        } else if self.identity.lba48 {
+            channel.select(self.slave, 0);
+            // The high bytes go first, each register holding two.
+            channel.write_register(REG_SECTOR_COUNT, (count >> 8) as u8);
+            channel.write_register(REG_LBA_LOW, (lba >> 24) as u8);
+            channel.write_register(REG_LBA_MID, (lba >> 32) as u8);
+            channel.write_register(REG_LBA_HIGH, (lba >> 40) as u8);
+            channel.write_register(REG_SECTOR_COUNT, count as u8);
+            channel.write_register(REG_LBA_LOW, lba as u8);
+            channel.write_register(REG_LBA_MID, (lba >> 8) as u8);
+            channel.write_register(REG_LBA_HIGH, (lba >> 16) as u8);
+            channel.command(if write { CMD_WRITE_SECTORS_EXT } else { CMD_READ_SECTORS_EXT });
+        } else {
+            return Err("Sector out of range");
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sectors(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn read_sectors(&self, mut lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        let _lock = CHANNEL_LOCKS[self.channel].lock();
+        let channel = self.channel();
+        for chunk in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE) {
+            let count = chunk.len() / SECTOR_SIZE;
+            self.start(lba, count, false)?;
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                channel.wait_data()?;
+                channel.read_sector(sector);
+            }
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, mut lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        let _lock = CHANNEL_LOCKS[self.channel].lock();
+        let channel = self.channel();
+        for chunk in buf.chunks(MAX_SECTORS * SECTOR_SIZE) {
+            let count = chunk.len() / SECTOR_SIZE;
+            self.start(lba, count, true)?;
+            for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                channel.wait_data()?;
+                channel.write_sector(sector);
+            }
+            channel.wait_ready()?;
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        let _lock = CHANNEL_LOCKS[self.channel].lock();
+        let channel = self.channel();
+        channel.wait_ready()?;
+        channel.select(self.slave, 0);
+        channel.command(if self.identity.lba48 { CMD_CACHE_FLUSH_EXT } else { CMD_CACHE_FLUSH });
+        channel.wait_ready()
+    }
+}
+
+/// Probes both channels and registers every ATA drive found.
+pub fn init() {
+    for (index, channel) in CHANNELS.iter().enumerate() {
+        channel.write_control(CONTROL_NIEN);
+        // A floating bus reads as all ones: there is no drive on the channel.
+        if channel.status() == 0xFF {
+            continue;
+        }
+        let _lock = CHANNEL_LOCKS[index].lock();
+        for slave in [false, true] {
+            if let Some(identity) = channel.identify(slave) {
+                let letter = (b'a' + (index * 2 + slave as usize) as u8) as char;
+                let name = format!("hd{}", letter);
+                serial_println!("{}: {}, {} sectors", name, identity.model, identity.sectors);
+                block::register(Rc::new(AtaDrive { name, channel: index, slave, identity }));
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Block devices: disks and anything else addressed in fixed-size sectors.
+//! Drivers register their devices here under names like `hda`, and file
+//! systems find them by name.
+
+use alloc::{rc::Rc, vec::Vec};
+
+use crate::global::Global;
+
+pub type DeviceRef = Rc<dyn BlockDevice>;
+
+pub trait BlockDevice {
+    fn name(&self) -> &str;
+
+    /// Bytes in one sector.
+    fn sector_size(&self) -> usize;
+
+    /// Number of sectors on the device.
+    fn sectors(&self) -> u64;
+
+    /// Reads whole sectors starting at `lba`; `buf` must hold a multiple of
+    /// the sector size.
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str>;
+
+    /// Makes sure everything written has reached the medium.
+    fn flush(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.sectors() * self.sector_size() as u64
+    }
+}
+
+/// Checks that `len` bytes starting at `lba` are whole sectors inside
+/// `device`, giving the number of sectors.
+pub fn check_range(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, &'static str> {
+    if !len.is_multiple_of(device.sector_size()) {
+        return Err("Invalid argument");
+    }
+    let count = (len / device.sector_size()) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.sectors() => Ok(count),
+        _ => Err("Sector out of range"),
+    }
+}
+
+static DEVICES: Global<Vec<DeviceRef>> = Global::new(Vec::new());
+
+pub fn register(device: DeviceRef) {
+    DEVICES.borrow_mut().push(device);
+}
+
+pub fn devices() -> Vec<DeviceRef> {
+    DEVICES.borrow().clone()
+}
+
+pub fn find(name: &str) -> Option<DeviceRef> {
+    DEVICES.borrow().iter().find(|device| device.name() == name).cloned()
+}
+
+/// A disk in memory, for tests.
+#[cfg(test)]
+pub(crate) struct RamDisk {
+    pub(crate) data: core::cell::RefCell<Vec<u8>>,
+    sector_size: usize,
+}
+
+#[cfg(test)]
+impl RamDisk {
+    pub(crate) fn new(sectors: u64, sector_size: usize) -> Rc<RamDisk> {
+        let data = alloc::vec![0; sectors as usize * sector_size];
+        Rc::new(RamDisk { data: core::cell::RefCell::new(data), sector_size })
+    }
+
+    /// Puts `bytes` at byte `offset`, for building images.
+    pub(crate) fn put(&self, offset: usize, bytes: &[u8]) {
+        self.data.borrow_mut()[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        "ram"
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sectors(&self) -> u64 {
+        (self.data.borrow().len() / self.sector_size) as u64
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(self, lba, buf.len())?;
+        let start = lba as usize * self.sector_size;
+        buf.copy_from_slice(&self.data.borrow()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_range(self, lba, buf.len())?;
+        self.put(lba as usize * self.sector_size, buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+//! Buffer cache between file systems and block devices. Devices are read
+//! and written in blocks of `BLOCK_SIZE` bytes kept in a bounded LRU list;
+//! writes only mark a block dirty, and dirty blocks reach the device when
+//! they are evicted or on `sync`. A miss also reads the next few blocks,
+//! since file systems mostly read forward.
+//!
+//! Blocks are keyed by device, so a disk and its partitions are cached
+//! apart: file systems should not be mixed with raw access to the same
+//! sectors.
+//!
+//! Device I/O may yield to other processes, so no reference into the cache
+//! is held across it. A block stays cached while it is being written, so
+//! that nobody reads the older copy on the device meanwhile.
+
+use alloc::{boxed::Box, collections::BTreeMap, rc::Rc, vec, vec::Vec};
+
+use crate::block::DeviceRef;
+use crate::global::Global;
+use crate::{process, serial_println};
+
+pub const BLOCK_SIZE: usize = 4096;
+
+/// Blocks kept in memory at most.
+pub const CAPACITY: usize = 128;
+
+/// Blocks read in one request on a miss, the missing one included.
+const READ_AHEAD: u64 = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks brought in by read-ahead.
+    pub read_ahead: u64,
+    /// Dirty blocks written to their device.
+    pub writebacks: u64,
+    pub evictions: u64,
+    pub cached: usize,
+    pub dirty: usize,
+}
+
+/// Device address and block number.
+type Key = (usize, u64);
+
+struct Buffer {
+    device: DeviceRef,
+    /// Shorter than `BLOCK_SIZE` for the last block of a device whose size
+    /// is not a multiple of it.
+    data: Box<[u8]>,
+    dirty: bool,
+    /// Set while a copy of the block is on its way to the device. Such a
+    /// block is not evicted, and nobody else writes it at the same time.
+    writing: bool,
+    /// Position in `Cache::lru`.
+    used: u64,
+}
+
+struct Cache {
+    buffers: BTreeMap<Key, Buffer>,
+    /// Keys by time of last use, oldest first.
+    lru: BTreeMap<u64, Key>,
+    clock: u64,
+    stats: Stats,
+}
+
+static CACHE: Global<Cache> = Global::new(Cache {
+    buffers: BTreeMap::new(),
+    lru: BTreeMap::new(),
+    clock: 0,
+    stats: Stats { hits: 0, misses: 0, read_ahead: 0, writebacks: 0, evictions: 0, cached: 0, dirty: 0 },
+});
+
+fn id(device: &DeviceRef) -> usize {
+    Rc::as_ptr(device) as *const () as usize
+}
+
+fn sectors_per_block(device: &DeviceRef) -> Result<u64, &'static str> {
+    let sector_size = device.sector_size();
+    if sector_size == 0 || !BLOCK_SIZE.is_multiple_of(sector_size) {
+        return Err("Unsupported sector size");
+    }
+    Ok((BLOCK_SIZE / sector_size) as u64)
+}
+
+fn check_range(device: &DeviceRef, position: u64, len: usize) -> Result<(), &'static str> {
+    match position.checked_add(len as u64) {
+        Some(end) if end <= device.capacity() => Ok(()),
+        _ => Err("Sector out of range"),
+    }
+}
+
+fn touch(cache: &mut Cache, key: Key) {
+    if let Some(buffer) = cache.buffers.get_mut(&key) {
+        cache.clock += 1;
+        cache.lru.remove(&buffer.used);
+        buffer.used = cache.clock;
+        cache.lru.insert(cache.clock, key);
+    }
+}
+
+/// Adds a block read from the device or written whole. A block already
+/// present wins over one just read, which may be older.
+fn insert(device: &DeviceRef, block: u64, data: Box<[u8]>, dirty: bool) {
+    let cache = &mut *CACHE.borrow_mut();
+    let key = (id(device), block);
+    if let Some(buffer) = cache.buffers.get_mut(&key) {
+        if dirty {
+            buffer.data = data;
+            buffer.dirty = true;
+        }
+    } else {
+        cache.buffers.insert(key, Buffer { device: device.clone(), data, dirty, writing: false, used: 0 });
+    }
+    touch(cache, key);
+}
+
+/// Writes `key` out if it is dirty, after any write of it already under
+/// way. The block stays in the cache throughout, and is dirty again if the
+/// write fails.
+fn write_back(key: Key) -> Result<(), &'static str> {
+    let (device, data) = loop {
+        let mut cache = CACHE.borrow_mut();
+        match cache.buffers.get_mut(&key) {
+            Some(buffer) if buffer.writing => {
+                drop(cache);
+                process::yield_now();
+            }
+            Some(buffer) if buffer.dirty => {
+                buffer.dirty = false;
+                buffer.writing = true;
+                break (buffer.device.clone(), buffer.data.clone());
+            }
+            _ => return Ok(()),
+        }
+    };
+    let written = sectors_per_block(&device).and_then(|count| device.write_sectors(key.1 * count, &data));
+    let mut cache = CACHE.borrow_mut();
+    if let Some(buffer) = cache.buffers.get_mut(&key) {
+        buffer.writing = false;
+        buffer.dirty |= written.is_err();
+    }
+    match written {
+        Ok(()) => cache.stats.writebacks += 1,
+        Err(e) => serial_println!("{}: write-back of block {} failed: {}", device.name(), key.1, e),
+    }
+    written
+}
+
+/// Drops the least recently used blocks beyond `CAPACITY`. Dirty ones are
+/// written first; one that fails moves to the back of the list.
+fn evict() -> Result<(), &'static str> {
+    loop {
+        let mut guard = CACHE.borrow_mut();
+        let cache = &mut *guard;
+        if cache.buffers.len() <= CAPACITY {
+            return Ok(());
+        }
+        let victim = cache.lru.iter().find(|(_, key)| !cache.buffers[*key].writing);
+        let Some((&used, &key)) = victim else { return Ok(()) };
+        if cache.buffers[&key].dirty {
+            drop(guard);
+            if let Err(e) = write_back(key) {
+                touch(&mut CACHE.borrow_mut(), key);
+                return Err(e);
+            }
+            // Looked at again: it may have been used or written to meanwhile.
+            continue;
+        }
+        cache.lru.remove(&used);
+        cache.buffers.remove(&key);
+        cache.stats.evictions += 1;
+    }
+}
+
+/// Reads `block` and the ones after it that are not cached yet.
+fn load(device: &DeviceRef, block: u64) -> Result<(), &'static str> {
+    let per_block = sectors_per_block(device)?;
+    let blocks = device.sectors().div_ceil(per_block);
+    if block >= blocks {
+        return Err("Sector out of range");
+    }
+    let cached = |block| CACHE.borrow().buffers.contains_key(&(id(device), block));
+    let mut count = 1;
+    while count < READ_AHEAD && block + count < blocks && !cached(block + count) {
+        count += 1;
+    }
+    let sectors = core::cmp::min(count * per_block, device.sectors() - block * per_block);
+    let mut data = vec![0; sectors as usize * device.sector_size()];
+    device.read_sectors(block * per_block, &mut data)?;
+
+    for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+        insert(device, block + i as u64, chunk.into(), false);
+    }
+    CACHE.borrow_mut().stats.read_ahead += count - 1;
+    Ok(())
+}
+
+/// Runs `f` on the cached copy of `block`, reading it in first if needed.
+fn with_block<R>(device: &DeviceRef, block: u64, f: impl FnOnce(&mut Buffer) -> R) -> Result<R, &'static str> {
+    let key = (id(device), block);
+    let cached = CACHE.borrow().buffers.contains_key(&key);
+    if cached {
+        CACHE.borrow_mut().stats.hits += 1;
+    } else {
+        CACHE.borrow_mut().stats.misses += 1;
+        load(device, block)?;
+    }
+    let result = {
+        let mut cache = CACHE.borrow_mut();
+        touch(&mut cache, key);
+        cache.buffers.get_mut(&key).map(f).ok_or("I/O error")
+    };
+    evict()?;
+    result
+}
+
+/// Reads bytes at any position of `device`.
+pub fn read(device: &DeviceRef, position: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+    check_range(device, position, buf.len())?;
+    let mut done = 0;
+    while done < buf.len() {
+        let at = position + done as u64;
+        let skip = (at % BLOCK_SIZE as u64) as usize;
+        let count = core::cmp::min(BLOCK_SIZE - skip, buf.len() - done);
+        with_block(device, at / BLOCK_SIZE as u64, |buffer| {
+            buf[done..done + count].copy_from_slice(&buffer.data[skip..skip + count]);
+        })?;
+        done += count;
+    }
+    Ok(())
+}
+
+/// Writes bytes at any position of `device`. Blocks written in part are
+/// read first; nothing reaches the device before eviction or `sync`.
+pub fn write(device: &DeviceRef, position: u64, data: &[u8]) -> Result<(), &'static str> {
+    check_range(device, position, data.len())?;
+    let mut done = 0;
+    while done < data.len() {
+        let at = position + done as u64;
+        let block = at / BLOCK_SIZE as u64;
+        let skip = (at % BLOCK_SIZE as u64) as usize;
+        let count = core::cmp::min(BLOCK_SIZE - skip, data.len() - done);
+        let cached = CACHE.borrow().buffers.contains_key(&(id(device), block));
+        if skip == 0 && count == BLOCK_SIZE && !cached {
+            insert(device, block, data[done..done + count].into(), true);
+            evict()?;
+        } else {
+            with_block(device, block, |buffer| {
+                buffer.data[skip..skip + count].copy_from_slice(&data[done..done + count]);
+                buffer.dirty = true;
+            })?;
+        }
+        done += count;
+    }
+    Ok(())
+}
+
+/// Writes out the dirty blocks of `device`, or of every device, giving
+/// the devices written to. Blocks already being written are waited for.
+fn write_dirty(device: Option<&DeviceRef>) -> Result<Vec<DeviceRef>, &'static str> {
+    let mut keys = Vec::new();
+    let mut written: Vec<DeviceRef> = Vec::new();
+    for (&key, buffer) in &CACHE.borrow().buffers {
+        if (buffer.dirty || buffer.writing) && device.is_none_or(|device| id(device) == key.0) {
+            keys.push(key);
+            if !written.iter().any(|other| Rc::ptr_eq(other, &buffer.device)) {
+                written.push(buffer.device.clone());
+            }
+        }
+    }
+    let mut result = Ok(());
+    for key in keys {
+        if let Err(e) = write_back(key) {
+            result = Err(e);
+        }
+    }
+    result.map(|_| written)
+}
+
+/// Writes out the dirty blocks of `device` and flushes it.
+pub fn sync_device(device: &DeviceRef) -> Result<(), &'static str> {
+    write_dirty(Some(device))?;
+    device.flush()
+}
+
+/// Writes out every dirty block and flushes the devices they belong to.
+pub fn sync() -> Result<(), &'static str> {
+    for device in write_dirty(None)? {
+        device.flush()?;
+    }
+    Ok(())
+}
+
+pub fn stats() -> Stats {
+    let cache = CACHE.borrow();
+    Stats {
+        cached: cache.buffers.len(),
+        dirty: cache.buffers.values().filter(|buffer| buffer.dirty).count(),
+        ..cache.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+    use crate::global::TEST_LOCK;
+
+    fn block(disk: &RamDisk, block: usize) -> Vec<u8> {
+        disk.data.borrow()[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE].to_vec()
+    }
+
+    #[test]
+    fn writes_reach_the_device_on_sync() {
+        let _lock = TEST_LOCK.lock();
+        let disk = RamDisk::new(32, 512);
+        let device: DeviceRef = disk.clone();
+        write(&device, 100, b"hello").unwrap();
+        assert!(block(&disk, 0).iter().all(|&b| b == 0));
+        let mut buf = [0; 5];
+        read(&device, 100, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        sync_device(&device).unwrap();
+        assert_eq!(&block(&disk, 0)[100..105], b"hello");
+    }
+
+    #[test]
+    fn misses_read_ahead() {
+        let _lock = TEST_LOCK.lock();
+        let disk = RamDisk::new(4 * READ_AHEAD * 8, 512);
+        disk.put(3 * BLOCK_SIZE, b"three");
+        let device: DeviceRef = disk.clone();
+        let before = stats();
+        let mut buf = [0; 5];
+        read(&device, 0, &mut buf).unwrap();
+        read(&device, 3 * BLOCK_SIZE as u64, &mut buf).unwrap();
+        assert_eq!(&buf, b"three");
+        let after = stats();
+        assert_eq!(after.misses - before.misses, 1);
+        assert_eq!(after.read_ahead - before.read_ahead, READ_AHEAD - 1);
+        assert_eq!(after.hits - before.hits, 1);
+
+        // The cached copy is what is read now.
+        disk.put(3 * BLOCK_SIZE, b"THREE");
+        read(&device, 3 * BLOCK_SIZE as u64, &mut buf).unwrap();
+        assert_eq!(&buf, b"three");
+    }
+
+    #[test]
+    fn least_recently_used_blocks_are_evicted() {
+        let _lock = TEST_LOCK.lock();
+        let disk = RamDisk::new((CAPACITY as u64 + 1) * 8, 512);
+        let device: DeviceRef = disk.clone();
+        write(&device, 0, &[0xA; BLOCK_SIZE]).unwrap();
+        write(&device, BLOCK_SIZE as u64, &[0xB; BLOCK_SIZE]).unwrap();
+        read(&device, 0, &mut [0; 1]).unwrap();
+        let before = stats();
+        for i in 2..=CAPACITY {
+            write(&device, (i * BLOCK_SIZE) as u64, &[i as u8; BLOCK_SIZE]).unwrap();
+        }
+        let after = stats();
+        assert_eq!(after.cached, CAPACITY);
+        assert!(after.evictions > before.evictions && after.writebacks > before.writebacks);
+
+        // Block 1 went out first, and was written on the way; block 0 was
+        // used since and is only in memory.
+        assert_eq!(block(&disk, 1), [0xB; BLOCK_SIZE]);
+        assert_eq!(block(&disk, 0), [0; BLOCK_SIZE]);
+        let mut buf = [0; 1];
+        read(&device, 0, &mut buf).unwrap();
+        assert_eq!(buf, [0xA]);
+
+        sync_device(&device).unwrap();
+        assert_eq!(stats().dirty, 0);
+        assert_eq!(block(&disk, 0), [0xA; BLOCK_SIZE]);
+        assert_eq!(block(&disk, CAPACITY), [CAPACITY as u8; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn devices_need_not_end_on_a_block() {
+        let _lock = TEST_LOCK.lock();
+        let disk = RamDisk::new(9, 512);
+        let device: DeviceRef = disk.clone();
+        write(&device, 4000, &[7; 608]).unwrap();
+        assert_eq!(write(&device, 4000, &[7; 609]), Err("Sector out of range"));
+        sync_device(&device).unwrap();
+        assert_eq!(disk.data.borrow()[4000..], [7; 608]);
+    }
+}
@@ -0,0 +1,380 @@
+//! Device file system, mounted on `/dev`. Character devices (the console,
+//! the serial port, `null` and friends) register here; block devices are
+//! the ones in the block registry and are read and written through the
+//! buffer cache, like the file systems on them.
+
+use alloc::{collections::BTreeMap, rc::Rc, string::{String, ToString}, vec::Vec};
+use core::any::Any;
+use core::cell::{Cell, RefCell};
+use x86_64::instructions::{interrupts, port::Port, random::RdRand};
+
+use crate::block::{self, DeviceRef};
+use crate::global::Global;
+use crate::serial::SERIAL1;
+use crate::vfs::{self, FileSystem, FileType, Inode, InodeRef, Metadata};
+use crate::{cache, file, println, process, rtc, signal};
+
+pub trait CharDevice {
+    fn name(&self) -> &str;
+
+    /// Reads what is available, blocking until there is something if the
+    /// device works that way.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str>;
+
+    fn write(&self, data: &[u8]) -> Result<usize, &'static str>;
+}
+
+pub type CharDeviceRef = Rc<dyn CharDevice>;
+
+static CHAR_DEVICES: Global<Vec<CharDeviceRef>> = Global::new(Vec::new());
+
+pub fn register(device: CharDeviceRef) {
+    CHAR_DEVICES.borrow_mut().push(device);
+}
+
+pub fn char_devices() -> Vec<CharDeviceRef> {
+    CHAR_DEVICES.borrow().clone()
+}
+
+pub fn find(name: &str) -> Option<CharDeviceRef> {
+    CHAR_DEVICES.borrow().iter().find(|device| device.name() == name).cloned()
+}
+
+/// Discards writes, reads as end of file.
+struct Null;
+
+impl CharDevice for Null {
+    fn name(&self) -> &str {
+        "null"
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, &'static str> {
+        Ok(0)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, &'static str> {
+        Ok(data.len())
+    }
+}
+
+/// Discards writes, reads as endless zeros.
+struct Zero;
+
+impl CharDevice for Zero {
+    fn name(&self) -> &str {
+        "zero"
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, &'static str> {
+        Ok(data.len())
+    }
+}
+
+/// RDRAND where the CPU has it, xorshift seeded from the time stamp
+/// counter otherwise. Writes are mixed into the xorshift state.
+struct Random {
+    rdrand: Option<RdRand>,
+    state: Cell<u64>,
+}
+
+impl Random {
+    fn new() -> Self {
+        let seed = unsafe { core::arch::x86_64::_rdtsc() };
+        Random { rdrand: RdRand::new(), state: Cell::new(seed | 1) }
+    }
+
+    fn next(&self) -> u64 {
+        if let Some(value) = self.rdrand.and_then(RdRand::get_u64) {
+            return value;
+        }
+        let mut x = self.state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+impl CharDevice for Random {
+    fn name(&self) -> &str {
+        "random"
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        for chunk in buf.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next().to_le_bytes()[..chunk.len()]);
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, &'static str> {
+        for chunk in data.chunks(8) {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            let mixed = self.state.get().rotate_left(7) ^ u64::from_le_bytes(bytes);
+            self.state.set(if mixed == 0 { 1 } else { mixed });
+        }
+        Ok(data.len())
+    }
+}
+
+/// The screen and keyboard, as used by descriptors 0, 1 and 2.
+struct Console {
+    name: &'static str,
+}
+
+impl CharDevice for Console {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        file::console_read(buf)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, &'static str> {
+        Ok(file::console_write(data))
+    }
+}
+
+/// Line status register of COM1; bit 0 is set while a byte waits.
+const SERIAL_LINE_STATUS: u16 = 0x3F8 + 5;
+
+/// COM1, the port the kernel log goes to.
+struct Serial;
+
+impl Serial {
+    fn has_data() -> bool {
+        unsafe { Port::<u8>::new(SERIAL_LINE_STATUS).read() & 1 != 0 }
+    }
+}
+
+impl CharDevice for Serial {
+    fn name(&self) -> &str {
+        "ttyS0"
+    }
+
+    /// Blocks until at least one byte has arrived.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while !Serial::has_data() {
+            if signal::has_pending() {
+                return Err("Interrupted");
+            }
+            process::yield_now();
+        }
+        let mut len = 0;
+        interrupts::without_interrupts(|| {
+            let mut port = SERIAL1.lock();
+            while len < buf.len() && Serial::has_data() {
+                buf[len] = port.receive();
+                len += 1;
+            }
+        });
+        Ok(len)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, &'static str> {
+        interrupts::without_interrupts(|| {
+            let mut port = SERIAL1.lock();
+            for &byte in data {
+                port.send_raw(byte);
+            }
+        });
+        Ok(data.len())
+    }
+}
+
+enum Device {
+    Char(CharDeviceRef),
+    Block(DeviceRef),
+}
+
+/// A device file. Device files cannot be created or removed; they come and
+/// go with the registries.
+pub struct DevNode {
+    device: Device,
+    ino: u64,
+    created: u64,
+}
+
+impl Inode for DevNode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, size, mode) = match &self.device {
+            Device::Char(_) => (FileType::CharDevice, 0, 0o666),
+            Device::Block(device) => (FileType::BlockDevice, device.capacity(), 0o660),
+        };
+        Metadata {
+            ino: self.ino,
+            nlink: 1,
+            file_type,
+            size,
+            mode,
+            uid: 0,
+            created: self.created,
+            modified: self.created,
+            accessed: self.created,
+        }
+    }
+
+    /// Character devices have no position, so `offset` only matters for
+    /// block devices.
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        match &self.device {
+            Device::Char(device) => device.read(buf),
+            Device::Block(device) => {
+                let capacity = device.capacity() as usize;
+                if offset >= capacity {
+                    return Ok(0);
+                }
+                let len = core::cmp::min(buf.len(), capacity - offset);
+                cache::read(device, offset as u64, &mut buf[..len])?;
+                Ok(len)
+            }
+        }
+    }
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        match &self.device {
+            Device::Char(device) => device.write(data),
+            Device::Block(device) => {
+                let capacity = device.capacity() as usize;
+                if offset >= capacity {
+                    return if data.is_empty() { Ok(0) } else { Err("No space left on device") };
+                }
+                let len = core::cmp::min(data.len(), capacity - offset);
+                cache::write(device, offset as u64, &data[..len])?;
+                Ok(len)
+            }
+        }
+    }
+
+    /// Ignored, so that opening a device with `O_TRUNC` works.
+    fn truncate(&self, _size: usize) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+/// The `/dev` directory. Nodes are made on first lookup and kept, so a
+/// device has the same inode every time.
+pub struct DevDir {
+    nodes: RefCell<BTreeMap<String, Rc<DevNode>>>,
+    created: u64,
+}
+
+impl Inode for DevDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: 1,
+            nlink: 2,
+            file_type: FileType::Directory,
+            size: self.size() as u64,
+            mode: 0o755,
+            uid: 0,
+            created: self.created,
+            modified: self.created,
+            accessed: self.created,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.entries().map_or(0, |entries| entries.len())
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, &'static str> {
+        if let Some(node) = self.nodes.borrow().get(name) {
+            return Ok(node.clone());
+        }
+        let device = match (find(name), block::find(name)) {
+            (Some(device), _) => Device::Char(device),
+            (None, Some(device)) => Device::Block(device),
+            (None, None) => return Err("Entry not found"),
+        };
+        let mut nodes = self.nodes.borrow_mut();
+        let node = Rc::new(DevNode { device, ino: nodes.len() as u64 + 2, created: self.created });
+        nodes.insert(name.to_string(), node.clone());
+        Ok(node)
+    }
+
+    fn create(self: Rc<Self>, _name: &str, _file_type: FileType) -> Result<InodeRef, &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn link(self: Rc<Self>, _name: &str, _target: InodeRef) -> Result<(), &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn symlink(self: Rc<Self>, _name: &str, _target: &str) -> Result<(), &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn rename(self: Rc<Self>, _name: &str, _new_dir: InodeRef, _new_name: &str) -> Result<(), &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn entries(&self) -> Result<Vec<String>, &'static str> {
+        let chars = char_devices().into_iter().map(|device| device.name().to_string());
+        let blocks = block::devices().into_iter().map(|device| device.name().to_string());
+        Ok(chars.chain(blocks).collect())
+    }
+}
+
+pub struct DevFs {
+    root: Rc<DevDir>,
+}
+
+impl DevFs {
+    fn new() -> Self {
+        DevFs { root: Rc::new(DevDir { nodes: RefCell::new(BTreeMap::new()), created: rtc::now() }) }
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+/// The character device behind `inode`, if it is one of ours.
+pub fn char_device(inode: &InodeRef) -> Option<CharDeviceRef> {
+    let node: &dyn Any = &**inode;
+    match &node.downcast_ref::<DevNode>()?.device {
+        Device::Char(device) => Some(device.clone()),
+        Device::Block(_) => None,
+    }
+}
+
+/// Registers the standard character devices and mounts devfs on `/dev`,
+/// which is made if the root file system lacks it.
+pub fn init() {
+    register(Rc::new(Null));
+    register(Rc::new(Zero));
+    register(Rc::new(Random::new()));
+    register(Rc::new(Console { name: "console" }));
+    register(Rc::new(Console { name: "tty" }));
+    register(Rc::new(Serial));
+
+    let result = match vfs::create("/dev", FileType::Directory) {
+        Ok(_) | Err("Entry already exists") => vfs::mount("/dev", Rc::new(DevFs::new())),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        println!("Could not mount /dev: {}", e);
+    }
+}
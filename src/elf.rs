@@ -0,0 +1,118 @@
+use x86_64::VirtAddr;
+
+use crate::memory::AddressSpace;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+const PT_LOAD: u32 = 1;
+const PF_W: u32 = 2;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+pub struct LoadedImage {
+    pub entry: VirtAddr,
+    /// First address past the highest loaded segment.
+    pub end: VirtAddr,
+}
+
+/// Loads the `PT_LOAD` segments of a static x86_64 executable into `space`.
+pub fn load(data: &[u8], space: &mut AddressSpace) -> Result<LoadedImage, &'static str> {
+    if data.len() < HEADER_SIZE || data[..4] != ELF_MAGIC {
+        return Err("Not an ELF file");
+    }
+    if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+        return Err("Not a 64-bit little-endian ELF file");
+    }
+    if read_u16(data, 16) != ET_EXEC || read_u16(data, 18) != EM_X86_64 {
+        return Err("Not an x86_64 executable");
+    }
+
+    let entry = read_u64(data, 24);
+    let phoff = read_u64(data, 32) as usize;
+    let phentsize = read_u16(data, 54) as usize;
+    let phnum = read_u16(data, 56) as usize;
+
+    if phentsize < PROGRAM_HEADER_SIZE {
+        return Err("Bad program header size");
+    }
+    let table_end = phnum
+        .checked_mul(phentsize)
+        .and_then(|size| size.checked_add(phoff))
+        .ok_or("Bad program header table")?;
+    if table_end > data.len() {
+        return Err("Truncated program header table");
+    }
+
+    let mut entry_mapped = false;
+    let mut end = 0;
+    for i in 0..phnum {
+        let base = phoff + i * phentsize;
+        let header = ProgramHeader {
+            kind: read_u32(data, base),
+            flags: read_u32(data, base + 4),
+            offset: read_u64(data, base + 8),
+            vaddr: read_u64(data, base + 16),
+            filesz: read_u64(data, base + 32),
+            memsz: read_u64(data, base + 40),
+        };
+        if header.kind != PT_LOAD {
+            continue;
+        }
+        load_segment(data, &header, space)?;
+        if entry >= header.vaddr && entry < header.vaddr + header.memsz {
+            entry_mapped = true;
+        }
+        end = core::cmp::max(end, header.vaddr + header.memsz);
+    }
+
+    if !entry_mapped {
+        return Err("Entry point is not inside a loaded segment");
+    }
+    Ok(LoadedImage { entry: VirtAddr::new(entry), end: VirtAddr::new(end) })
+}
+
+fn load_segment(data: &[u8], header: &ProgramHeader, space: &mut AddressSpace) -> Result<(), &'static str> {
+    if header.filesz > header.memsz {
+        return Err("Segment file size exceeds memory size");
+    }
+    let file_end = header.offset.checked_add(header.filesz).ok_or("Bad segment offset")?;
+    if file_end > data.len() as u64 {
+        return Err("Truncated segment");
+    }
+    if !crate::memory::is_user_range(header.vaddr, header.memsz) {
+        return Err("Segment outside of user space");
+    }
+
+    let start = VirtAddr::new(header.vaddr);
+    space.map_user(start, header.memsz, header.flags & PF_W != 0)?;
+    space.write(start, &data[header.offset as usize..file_end as usize])
+}
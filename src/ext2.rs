@@ -0,0 +1,587 @@
+//! Read-only ext2. Inodes are read from the inode tables as they are looked
+//! up and kept by number, so every path to a file gives the same node; file
+//! blocks are found through the direct and indirect block pointers.
+
+use alloc::{collections::BTreeMap, rc::{Rc, Weak}, string::String, vec, vec::Vec};
+use core::cell::RefCell;
+use core::convert::TryInto;
+
+use crate::block::DeviceRef;
+use crate::cache;
+use crate::vfs::{FileSystem, FileType, Inode, InodeRef, Metadata};
+
+const SUPERBLOCK: u64 = 1024;
+const MAGIC: u16 = 0xEF53;
+const ROOT_INO: u32 = 2;
+
+/// Directory entries carry a file type byte.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Group metadata may be packed together; the descriptors still say where.
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+const MODE_TYPE: u16 = 0xF000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_SYMLINK: u16 = 0xA000;
+const MODE_CHAR_DEVICE: u16 = 0x2000;
+const MODE_BLOCK_DEVICE: u16 = 0x6000;
+
+const DIRECT_BLOCKS: usize = 12;
+/// Symbolic links shorter than this keep the target in the block pointers.
+const FAST_SYMLINK_MAX: u64 = 60;
+
+const CORRUPT: &str = "Corrupt file system";
+const READ_ONLY: &str = "Read-only file system";
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+struct Volume {
+    device: DeviceRef,
+    block_size: usize,
+    blocks: u32,
+    inodes: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    /// Inode table block of every group.
+    inode_tables: Vec<u32>,
+    filetype: bool,
+    nodes: RefCell<BTreeMap<u32, Weak<Ext2Node>>>,
+}
+
+impl Volume {
+    fn open(device: DeviceRef) -> Result<Self, &'static str> {
+        let mut sb = [0; 1024];
+        cache::read(&device, SUPERBLOCK, &mut sb)?;
+        if u16_at(&sb, 56) != MAGIC {
+            return Err("Not an ext2 file system");
+        }
+        let inodes = u32_at(&sb, 0);
+        let blocks = u32_at(&sb, 4);
+        let first_data_block = u32_at(&sb, 20);
+        let log_block_size = u32_at(&sb, 24);
+        let blocks_per_group = u32_at(&sb, 32);
+        let inodes_per_group = u32_at(&sb, 40);
+        let revision = u32_at(&sb, 76);
+        let (inode_size, incompat) = if revision >= 1 {
+            (u16_at(&sb, 88) as usize, u32_at(&sb, 96))
+        } else {
+            (128, 0)
+        };
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err("Unsupported ext2 features");
+        }
+        if log_block_size > 6
+            || blocks_per_group == 0
+            || inodes_per_group == 0
+            || first_data_block >= blocks
+            || !(128..=4096).contains(&inode_size)
+        {
+            return Err(CORRUPT);
+        }
+        let block_size = 1024usize << log_block_size;
+        if blocks as u64 * block_size as u64 > device.capacity() {
+            return Err("File system is larger than the device");
+        }
+
+        let groups = (blocks - first_data_block).div_ceil(blocks_per_group) as usize;
+        let mut descriptors = vec![0; groups * 32];
+        cache::read(&device, (first_data_block as u64 + 1) * block_size as u64, &mut descriptors)?;
+        let inode_tables: Vec<u32> = descriptors.chunks_exact(32).map(|desc| u32_at(desc, 8)).collect();
+        if inode_tables.iter().any(|&table| table == 0 || table >= blocks) {
+            return Err(CORRUPT);
+        }
+        Ok(Volume {
+            device,
+            block_size,
+            blocks,
+            inodes,
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            nodes: RefCell::new(BTreeMap::new()),
+        })
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<(), &'static str> {
+        if block >= self.blocks {
+            return Err(CORRUPT);
+        }
+        cache::read(&self.device, block as u64 * self.block_size as u64, buf)
+    }
+
+    /// The node for inode `ino`, the same one every time.
+    fn node(self: &Rc<Self>, ino: u32) -> Result<Rc<Ext2Node>, &'static str> {
+        if let Some(node) = self.nodes.borrow().get(&ino).and_then(Weak::upgrade) {
+            return Ok(node);
+        }
+        if ino == 0 || ino > self.inodes {
+            return Err(CORRUPT);
+        }
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        let table = *self.inode_tables.get(group).ok_or(CORRUPT)?;
+        let mut raw = [0; 128];
+        let position = table as u64 * self.block_size as u64 + index * self.inode_size as u64;
+        cache::read(&self.device, position, &mut raw)?;
+
+        let mode = u16_at(&raw, 0);
+        let mut size = u32_at(&raw, 4) as u64;
+        // For regular files the old directory ACL field holds the high half.
+        if mode & MODE_TYPE != MODE_DIRECTORY {
+            size |= (u32_at(&raw, 108) as u64) << 32;
+        }
+        // Sectors taken by data, leaving out an extended attribute block.
+        let sectors = match u32_at(&raw, 104) {
+            0 => u32_at(&raw, 28),
+            _ => u32_at(&raw, 28).saturating_sub((self.block_size / 512) as u32),
+        };
+        let mut pointers = [0; 15];
+        for (i, pointer) in pointers.iter_mut().enumerate() {
+            *pointer = u32_at(&raw, 40 + i * 4);
+        }
+        let node = Rc::new(Ext2Node {
+            volume: self.clone(),
+            ino,
+            mode,
+            uid: u16_at(&raw, 2) as u32 | (u16_at(&raw, 120) as u32) << 16,
+            size,
+            accessed: u32_at(&raw, 8) as u64,
+            changed: u32_at(&raw, 12) as u64,
+            modified: u32_at(&raw, 16) as u64,
+            links: u16_at(&raw, 26) as u32,
+            sectors,
+            pointers,
+            blocks: RefCell::new(None),
+        });
+        let mut nodes = self.nodes.borrow_mut();
+        nodes.retain(|_, node| node.strong_count() > 0);
+        nodes.insert(ino, Rc::downgrade(&node));
+        Ok(node)
+    }
+}
+
+/// A mounted ext2 volume.
+pub struct Ext2Fs {
+    root: Rc<Ext2Node>,
+}
+
+impl Ext2Fs {
+    pub fn new(device: DeviceRef) -> Result<Self, &'static str> {
+        let volume = Rc::new(Volume::open(device)?);
+        let root = volume.node(ROOT_INO)?;
+        if root.file_type() != FileType::Directory {
+            return Err(CORRUPT);
+        }
+        Ok(Ext2Fs { root })
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+
+    fn device(&self) -> Option<DeviceRef> {
+        Some(self.root.volume.device.clone())
+    }
+}
+
+pub struct Ext2Node {
+    volume: Rc<Volume>,
+    ino: u32,
+    mode: u16,
+    uid: u32,
+    size: u64,
+    accessed: u64,
+    /// ext2 has no creation time; the inode change time stands in for it.
+    changed: u64,
+    modified: u64,
+    links: u32,
+    /// 512-byte sectors of data and indirect blocks.
+    sectors: u32,
+    /// Twelve direct pointers, then the single, double and triple indirect.
+    pointers: [u32; 15],
+    /// Block of every block-sized piece of the content, 0 for a hole; read
+    /// on first use.
+    blocks: RefCell<Option<Vec<u32>>>,
+}
+
+struct DirEntry {
+    ino: u32,
+    name: String,
+}
+
+impl Ext2Node {
+    /// Appends the data blocks under `pointer`, which is `depth` levels of
+    /// indirection above them, until `out` has `count` blocks.
+    fn collect_blocks(&self, pointer: u32, depth: u32, count: usize, out: &mut Vec<u32>) -> Result<(), &'static str> {
+        let per_block = self.volume.block_size / 4;
+        if depth == 0 {
+            out.push(pointer);
+            return Ok(());
+        }
+        if pointer == 0 {
+            let hole = core::cmp::min(count - out.len(), per_block.pow(depth));
+            out.resize(out.len() + hole, 0);
+            return Ok(());
+        }
+        let mut table = vec![0; self.volume.block_size];
+        self.volume.read_block(pointer, &mut table)?;
+        for entry in table.chunks_exact(4) {
+            if out.len() >= count {
+                break;
+            }
+            self.collect_blocks(u32_at(entry, 0), depth - 1, count, out)?;
+        }
+        Ok(())
+    }
+
+    fn load_blocks(&self) -> Result<(), &'static str> {
+        if self.blocks.borrow().is_some() {
+            return Ok(());
+        }
+        let count = self.size.div_ceil(self.volume.block_size as u64) as usize;
+        let mut blocks = Vec::new();
+        blocks.try_reserve_exact(count).map_err(|_| "Out of memory")?;
+        for (i, &pointer) in self.pointers.iter().enumerate() {
+            if blocks.len() >= count {
+                break;
+            }
+            let depth = i.saturating_sub(DIRECT_BLOCKS - 1) as u32;
+            self.collect_blocks(pointer, depth, count, &mut blocks)?;
+        }
+        if blocks.len() < count {
+            return Err(CORRUPT);
+        }
+        *self.blocks.borrow_mut() = Some(blocks);
+        Ok(())
+    }
+
+    /// Reads the content, whatever the type of the inode.
+    fn read_content(&self, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if offset as u64 >= self.size {
+            return Ok(0);
+        }
+        let len = core::cmp::min(buf.len() as u64, self.size - offset as u64) as usize;
+        self.load_blocks()?;
+        let blocks = self.blocks.borrow();
+        let blocks = blocks.as_ref().ok_or(CORRUPT)?;
+        let block_size = self.volume.block_size;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let skip = position % block_size;
+            let count = core::cmp::min(block_size - skip, len - done);
+            let dst = &mut buf[done..done + count];
+            match blocks[position / block_size] {
+                0 => dst.fill(0),
+                block => cache::read(
+                    &self.volume.device,
+                    block as u64 * block_size as u64 + skip as u64,
+                    dst,
+                )?,
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    /// Entries of a directory, `.` and `..` included.
+    fn dir_entries(&self) -> Result<Vec<DirEntry>, &'static str> {
+        if self.mode & MODE_TYPE != MODE_DIRECTORY {
+            return Err("Not a directory");
+        }
+        let size = self.size as usize;
+        let mut data = Vec::new();
+        data.try_reserve_exact(size).map_err(|_| "Out of memory")?;
+        data.resize(size, 0);
+        self.read_content(0, &mut data)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let ino = u32_at(&data, offset);
+            let record = u16_at(&data, offset + 4) as usize;
+            let name_len = if self.volume.filetype {
+                data[offset + 6] as usize
+            } else {
+                u16_at(&data, offset + 6) as usize
+            };
+            if record < 8 || offset + record > data.len() || 8 + name_len > record {
+                return Err(CORRUPT);
+            }
+            if ino != 0 {
+                let name = String::from_utf8_lossy(&data[offset + 8..offset + 8 + name_len]).into_owned();
+                entries.push(DirEntry { ino, name });
+            }
+            offset += record;
+        }
+        Ok(entries)
+    }
+}
+
+impl Inode for Ext2Node {
+    fn metadata(&self) -> Metadata {
+        let size = match self.file_type() {
+            FileType::Directory => self.entries().map_or(0, |entries| entries.len() as u64),
+            _ => self.size,
+        };
+        Metadata {
+            ino: self.ino as u64,
+            nlink: self.links,
+            file_type: self.file_type(),
+            size,
+            mode: self.mode & 0o7777,
+            uid: self.uid,
+            created: self.changed,
+            modified: self.modified,
+            accessed: self.accessed,
+        }
+    }
+
+    /// Pipes and sockets show up as empty files.
+    fn file_type(&self) -> FileType {
+        match self.mode & MODE_TYPE {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            MODE_CHAR_DEVICE => FileType::CharDevice,
+            MODE_BLOCK_DEVICE => FileType::BlockDevice,
+            _ => FileType::File,
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        match self.file_type() {
+            FileType::File => self.read_content(offset, buf),
+            FileType::Directory => Err("Is a directory"),
+            // Device numbers of the disk mean nothing here.
+            FileType::Symlink | FileType::CharDevice | FileType::BlockDevice => Err("Invalid argument"),
+        }
+    }
+
+    fn write_at(&self, _offset: usize, _data: &[u8]) -> Result<usize, &'static str> {
+        Err(READ_ONLY)
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), &'static str> {
+        Err(READ_ONLY)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, &'static str> {
+        let entry = self
+            .dir_entries()?
+            .into_iter()
+            .find(|entry| entry.name == name && entry.name != "." && entry.name != "..")
+            .ok_or("Entry not found")?;
+        let node: InodeRef = self.volume.node(entry.ino)?;
+        Ok(node)
+    }
+
+    fn parent(&self) -> Option<InodeRef> {
+        if self.ino == ROOT_INO || self.file_type() != FileType::Directory {
+            return None;
+        }
+        let entry = self.dir_entries().ok()?.into_iter().find(|entry| entry.name == "..")?;
+        let parent: InodeRef = self.volume.node(entry.ino).ok()?;
+        Some(parent)
+    }
+
+    fn create(self: Rc<Self>, _name: &str, _file_type: FileType) -> Result<InodeRef, &'static str> {
+        Err(READ_ONLY)
+    }
+
+    fn link(self: Rc<Self>, _name: &str, _target: InodeRef) -> Result<(), &'static str> {
+        Err(READ_ONLY)
+    }
+
+    fn symlink(self: Rc<Self>, _name: &str, _target: &str) -> Result<(), &'static str> {
+        Err(READ_ONLY)
+    }
+
+    fn read_link(&self) -> Result<String, &'static str> {
+        if self.file_type() != FileType::Symlink {
+            return Err("Invalid argument");
+        }
+        let target = if self.size < FAST_SYMLINK_MAX && self.sectors == 0 {
+            let bytes: Vec<u8> = self.pointers.iter().flat_map(|pointer| pointer.to_le_bytes()).collect();
+            bytes[..self.size as usize].to_vec()
+        } else if self.size <= self.volume.block_size as u64 {
+            let mut data = vec![0; self.size as usize];
+            self.read_content(0, &mut data)?;
+            data
+        } else {
+            return Err(CORRUPT);
+        };
+        String::from_utf8(target).map_err(|_| CORRUPT)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), &'static str> {
+        Err(READ_ONLY)
+    }
+
+    fn rename(self: Rc<Self>, _name: &str, _new_dir: InodeRef, _new_name: &str) -> Result<(), &'static str> {
+        Err(READ_ONLY)
+    }
+
+    fn entries(&self) -> Result<Vec<String>, &'static str> {
+        Ok(self
+            .dir_entries()?
+            .into_iter()
+            .map(|entry| entry.name)
+            .filter(|name| name != "." && name != "..")
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+    use crate::global::TEST_LOCK;
+    use crate::vfs;
+
+    const BLOCK: usize = 1024;
+    const INODE_TABLE: usize = 5;
+
+    fn put_u16(disk: &RamDisk, at: usize, value: u16) {
+        disk.put(at, &value.to_le_bytes());
+    }
+
+    fn put_u32(disk: &RamDisk, at: usize, value: u32) {
+        disk.put(at, &value.to_le_bytes());
+    }
+
+    fn inode(disk: &RamDisk, ino: usize, mode: u16, size: u32, pointers: &[u32]) {
+        let at = INODE_TABLE * BLOCK + (ino - 1) * 128;
+        put_u16(disk, at, mode);
+        put_u32(disk, at + 4, size);
+        put_u16(disk, at + 26, 1);
+        let blocks = pointers.iter().filter(|&&pointer| pointer != 0).count();
+        put_u32(disk, at + 28, (blocks * BLOCK / 512) as u32);
+        for (i, &pointer) in pointers.iter().enumerate() {
+            put_u32(disk, at + 40 + i * 4, pointer);
+        }
+    }
+
+    /// Writes directory entries filling `block`, without the file type byte.
+    fn directory(disk: &RamDisk, block: usize, entries: &[(u32, &str)]) {
+        let mut at = block * BLOCK;
+        for (i, &(ino, name)) in entries.iter().enumerate() {
+            let record = if i + 1 == entries.len() {
+                (block + 1) * BLOCK - at
+            } else {
+                8 + name.len().next_multiple_of(4)
+            };
+            put_u32(disk, at, ino);
+            put_u16(disk, at + 4, record as u16);
+            put_u16(disk, at + 6, name.len() as u16);
+            disk.put(at + 8, name.as_bytes());
+            at += record;
+        }
+    }
+
+    /// A revision 0 volume of 64 1 KiB blocks and 16 inodes in one group.
+    fn image() -> Rc<RamDisk> {
+        let disk = RamDisk::new(128, 512);
+        let sb = SUPERBLOCK as usize;
+        put_u32(&disk, sb, 16);
+        put_u32(&disk, sb + 4, 64);
+        put_u32(&disk, sb + 20, 1);
+        put_u32(&disk, sb + 32, 8192);
+        put_u32(&disk, sb + 40, 16);
+        put_u16(&disk, sb + 56, MAGIC);
+        put_u32(&disk, 2 * BLOCK + 8, INODE_TABLE as u32);
+
+        inode(&disk, 2, 0x41ED, BLOCK as u32, &[10]);
+        directory(&disk, 10, &[(2, "."), (2, ".."), (12, "hello.txt"), (13, "sub"), (14, "link"), (15, "sparse")]);
+        inode(&disk, 12, 0x81A4, 6, &[11]);
+        disk.put(11 * BLOCK, b"hello\n");
+        inode(&disk, 13, 0x41ED, BLOCK as u32, &[12]);
+        directory(&disk, 12, &[(13, "."), (2, "..")]);
+        // A fast symlink keeps the target in the block pointers.
+        inode(&disk, 14, 0xA1FF, 9, &[]);
+        disk.put(INODE_TABLE * BLOCK + 13 * 128 + 40, b"hello.txt");
+        // Block 0 direct, 1 to 11 holes, 12 and 13 through the indirect block.
+        let mut pointers = [0; 13];
+        pointers[0] = 20;
+        pointers[12] = 21;
+        inode(&disk, 15, 0x81A4, 14 * BLOCK as u32, &pointers);
+        put_u32(&disk, 21 * BLOCK, 22);
+        put_u32(&disk, 21 * BLOCK + 4, 23);
+        disk.put(20 * BLOCK, &[b'a'; BLOCK]);
+        disk.put(22 * BLOCK, &[b'b'; BLOCK]);
+        disk.put(23 * BLOCK, &[b'c'; BLOCK]);
+        disk
+    }
+
+    #[test]
+    fn reads_files_directories_and_links() {
+        let _lock = TEST_LOCK.lock();
+        let fs = Ext2Fs::new(image()).unwrap();
+        let root = fs.root();
+        assert_eq!(root.entries().unwrap(), ["hello.txt", "sub", "link", "sparse"]);
+        let hello = root.lookup("hello.txt").unwrap();
+        assert!(Rc::ptr_eq(&hello, &root.lookup("hello.txt").unwrap()));
+        assert_eq!(vfs::read_to_end(&hello).unwrap(), b"hello\n");
+        assert_eq!(root.lookup("HELLO.TXT").err(), Some("Entry not found"));
+
+        let sub = root.lookup("sub").unwrap();
+        assert!(sub.entries().unwrap().is_empty());
+        assert!(Rc::ptr_eq(&sub.parent().unwrap(), &root));
+
+        let link = root.lookup("link").unwrap();
+        assert_eq!(link.file_type(), FileType::Symlink);
+        assert_eq!(link.read_link().unwrap(), "hello.txt");
+
+        let sparse = vfs::read_to_end(&root.lookup("sparse").unwrap()).unwrap();
+        assert_eq!(sparse.len(), 14 * BLOCK);
+        assert!(sparse[..BLOCK].iter().all(|&b| b == b'a'));
+        assert!(sparse[BLOCK..12 * BLOCK].iter().all(|&b| b == 0));
+        assert!(sparse[12 * BLOCK..13 * BLOCK].iter().all(|&b| b == b'b'));
+        assert!(sparse[13 * BLOCK..].iter().all(|&b| b == b'c'));
+    }
+
+    #[test]
+    fn corrupt_directories_and_links_are_rejected() {
+        let _lock = TEST_LOCK.lock();
+        let disk = image();
+        // A record running past the end of the block.
+        put_u16(&disk, 12 * BLOCK + 4, 2000);
+        // A slow symlink longer than a block.
+        inode(&disk, 14, 0xA1FF, 2000, &[30, 31]);
+        let fs = Ext2Fs::new(disk).unwrap();
+        let root = fs.root();
+        assert_eq!(root.lookup("sub").unwrap().entries().err(), Some(CORRUPT));
+        assert_eq!(root.lookup("link").unwrap().read_link().err(), Some(CORRUPT));
+    }
+
+    #[test]
+    fn bad_superblocks_are_rejected() {
+        let _lock = TEST_LOCK.lock();
+        assert_eq!(Ext2Fs::new(RamDisk::new(128, 512)).err(), Some("Not an ext2 file system"));
+
+        let disk = image();
+        put_u32(&disk, SUPERBLOCK as usize + 20, 64);
+        assert_eq!(Ext2Fs::new(disk).err(), Some(CORRUPT));
+
+        let disk = image();
+        put_u32(&disk, SUPERBLOCK as usize + 4, 65);
+        assert_eq!(Ext2Fs::new(disk).err(), Some("File system is larger than the device"));
+
+        let disk = image();
+        put_u32(&disk, SUPERBLOCK as usize + 96, 0x0040);
+        put_u32(&disk, SUPERBLOCK as usize + 76, 1);
+        put_u16(&disk, SUPERBLOCK as usize + 88, 128);
+        assert_eq!(Ext2Fs::new(disk).err(), Some("Unsupported ext2 features"));
+    }
+}
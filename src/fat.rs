@@ -0,0 +1,1380 @@
+//! FAT12, FAT16 and FAT32 on a block device, with long file names.
+//!
+//! FAT has no inode numbers: a file is known by its short directory entry,
+//! so a node is keyed by the first cluster of the directory holding that
+//! entry and the entry's offset in it. Nodes are kept in a table by that
+//! key, so every path to a file gives the same inode as the VFS expects.
+//!
+//! There are no owners, permissions or links; files are 0o644 (0o444 when
+//! marked read-only) and directories 0o755.
+
+use alloc::{collections::BTreeMap, format, rc::{Rc, Weak}, string::String, vec, vec::Vec};
+use core::any::Any;
+use core::cell::RefCell;
+use core::convert::TryInto;
+
+use crate::block::DeviceRef;
+use crate::cache;
+use crate::rtc::{self, DateTime};
+use crate::vfs::{FileSystem, FileType, Inode, InodeRef, Metadata};
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// Bits of byte 12 of a short entry asking for the base name or the
+/// extension to be shown in lower case.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_FREE: u8 = 0xE5;
+const ENTRY_END: u8 = 0x00;
+
+const LFN_LAST: u8 = 0x40;
+/// Byte offsets of the 13 UTF-16 units held by one long name entry.
+const LFN_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Longest name, in UTF-16 units.
+const MAX_NAME: usize = 255;
+/// Directories are limited to 65536 entries.
+const MAX_DIR_SIZE: usize = 65536 * ENTRY_SIZE;
+
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+const CORRUPT: &str = "Corrupt file system";
+const NO_SPACE: &str = "No space left on device";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Seconds since 1970 of a FAT date and time; a zero date means unset.
+fn decode_time(date: u16, time: u16) -> u64 {
+    if date == 0 {
+        return 0;
+    }
+    DateTime {
+        year: 1980 + (date >> 9),
+        month: (date >> 5 & 0xF) as u8,
+        day: (date & 0x1F) as u8,
+        hour: (time >> 11) as u8,
+        minute: (time >> 5 & 0x3F) as u8,
+        second: ((time & 0x1F) * 2) as u8,
+    }
+    .timestamp()
+}
+
+/// FAT (date, time) of a timestamp; FAT cannot go before 1980.
+fn encode_time(timestamp: u64) -> (u16, u16) {
+    let t = DateTime::from_timestamp(timestamp);
+    if t.year < 1980 {
+        return (1 << 5 | 1, 0);
+    }
+    let date = (t.year - 1980) << 9 | (t.month as u16) << 5 | t.day as u16;
+    let time = (t.hour as u16) << 11 | (t.minute as u16) << 5 | (t.second / 2) as u16;
+    (date, time)
+}
+
+fn is_short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// `name` as a short entry name, if it is one already: upper case, at most
+/// eight characters and a three-character extension.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || name.ends_with('.') {
+        return None;
+    }
+    if !base.bytes().chain(ext.bytes()).all(is_short_char) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+/// A short alias for a long name: up to six characters of the name, `~`
+/// and a number, then up to three characters of the extension.
+fn short_alias(name: &str, taken: &[[u8; 11]]) -> Result<[u8; 11], &'static str> {
+    let convert = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match c.to_ascii_uppercase() {
+                c if c.is_ascii() && is_short_char(c as u8) => c as u8,
+                _ => b'_',
+            })
+            .collect()
+    };
+    let name = name.trim_start_matches('.');
+    let (base, ext) = match name.rfind('.') {
+        Some(i) => (convert(&name[..i]), convert(&name[i + 1..])),
+        None => (convert(name), Vec::new()),
+    };
+    for n in 1..1_000_000u32 {
+        let tail = format!("~{}", n);
+        let keep = core::cmp::min(base.len(), 8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        let ext_len = core::cmp::min(ext.len(), 3);
+        short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(NO_SPACE)
+}
+
+/// How a short entry name is shown: `NAME.EXT`, lowered as byte 12 asks.
+fn short_name_string(short: &[u8], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let mut text: String = bytes.iter().map(|&b| b as char).collect();
+        text.truncate(text.trim_end_matches(' ').len());
+        if lower {
+            text.make_ascii_lowercase();
+        }
+        text
+    };
+    let mut base = [0; 8];
+    base.copy_from_slice(&short[..8]);
+    // 0xE5 marks a free entry, so a name starting with it is stored as 0x05.
+    if base[0] == 0x05 {
+        base[0] = ENTRY_FREE;
+    }
+    let base = part(&base, case & CASE_LOWER_BASE != 0);
+    let ext = part(&short[8..11], case & CASE_LOWER_EXT != 0);
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+fn check_name(name: &str) -> Result<(), &'static str> {
+    if name.encode_utf16().count() > MAX_NAME {
+        return Err("File name too long");
+    }
+    if name.ends_with(['.', ' ']) || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) {
+        return Err("Invalid argument");
+    }
+    Ok(())
+}
+
+/// Checksum of a short name stored in its long name entries.
+fn lfn_checksum(short: &[u8]) -> u8 {
+    short[..11].iter().fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// Long name entries for `name`, in the order they are stored: the last
+/// part first.
+fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if !units.len().is_multiple_of(LFN_OFFSETS.len()) {
+        units.push(0);
+        units.resize(units.len().div_ceil(LFN_OFFSETS.len()) * LFN_OFFSETS.len(), 0xFFFF);
+    }
+    let count = units.len() / LFN_OFFSETS.len();
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut entry = [0; ENTRY_SIZE];
+            entry[0] = (i + 1) as u8 | if i + 1 == count { LFN_LAST } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            for (&offset, unit) in LFN_OFFSETS.iter().zip(&units[i * LFN_OFFSETS.len()..]) {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+fn set_cluster(entry: &mut [u8], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+fn set_modified(entry: &mut [u8], timestamp: u64) {
+    let (date, time) = encode_time(timestamp);
+    entry[22..24].copy_from_slice(&time.to_le_bytes());
+    entry[24..26].copy_from_slice(&date.to_le_bytes());
+    entry[18..20].copy_from_slice(&date.to_le_bytes());
+}
+
+/// A short entry with everything but the name filled in.
+fn short_entry(attr: u8, cluster: u32, timestamp: u64) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[..11].fill(b' ');
+    entry[11] = attr;
+    let (date, time) = encode_time(timestamp);
+    entry[14..16].copy_from_slice(&time.to_le_bytes());
+    entry[16..18].copy_from_slice(&date.to_le_bytes());
+    set_modified(&mut entry, timestamp);
+    set_cluster(&mut entry, cluster);
+    entry
+}
+
+/// One file or directory as its directory lists it.
+struct DirEntry {
+    name: String,
+    short: [u8; 11],
+    attr: u8,
+    cluster: u32,
+    size: u32,
+    created: u64,
+    modified: u64,
+    accessed: u64,
+    /// Byte offset of the first long name entry, or of the short entry if
+    /// there are none.
+    start: u32,
+    /// Byte offset of the short entry.
+    offset: u32,
+}
+
+impl DirEntry {
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || short_name_string(&self.short, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+/// Long name entries collected so far for the short entry that follows.
+struct LongName {
+    units: Vec<u16>,
+    /// Ordinal of the part expected next; parts come last to first.
+    next: u8,
+    checksum: u8,
+    start: u32,
+}
+
+/// Entries of a directory, without `.`, `..` and the volume label.
+fn parse_dir(data: &[u8], fat32: bool) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+    let mut long: Option<LongName> = None;
+    for (i, slot) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        let offset = (i * ENTRY_SIZE) as u32;
+        match slot[0] {
+            ENTRY_END => break,
+            ENTRY_FREE => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+        if slot[11] & 0x3F == ATTR_LONG_NAME {
+            let ordinal = slot[0] & 0x1F;
+            if slot[0] & LFN_LAST != 0 && ordinal > 0 {
+                long = Some(LongName {
+                    units: vec![0; ordinal as usize * LFN_OFFSETS.len()],
+                    next: ordinal,
+                    checksum: slot[13],
+                    start: offset,
+                });
+            }
+            long = long.filter(|long| long.next == ordinal && long.checksum == slot[13] && ordinal > 0);
+            if let Some(long) = &mut long {
+                let base = (ordinal as usize - 1) * LFN_OFFSETS.len();
+                for (j, &at) in LFN_OFFSETS.iter().enumerate() {
+                    long.units[base + j] = u16_at(slot, at);
+                }
+                long.next -= 1;
+            }
+            continue;
+        }
+        let long = long.take();
+        if slot[11] & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+        let mut short = [0; 11];
+        short.copy_from_slice(&slot[..11]);
+        let (name, start) = match long {
+            Some(long) if long.next == 0 && long.checksum == lfn_checksum(&short) => {
+                let end = long.units.iter().position(|&unit| unit == 0).unwrap_or(long.units.len());
+                (String::from_utf16_lossy(&long.units[..end]), long.start)
+            }
+            _ => (short_name_string(&short, slot[12]), offset),
+        };
+        if name == "." || name == ".." {
+            continue;
+        }
+        let high = if fat32 { (u16_at(slot, 20) as u32) << 16 } else { 0 };
+        entries.push(DirEntry {
+            name,
+            short,
+            attr: slot[11],
+            cluster: high | u16_at(slot, 26) as u32,
+            size: u32_at(slot, 28),
+            created: decode_time(u16_at(slot, 16), u16_at(slot, 14)),
+            modified: decode_time(u16_at(slot, 24), u16_at(slot, 22)),
+            accessed: decode_time(u16_at(slot, 18), 0),
+            start,
+            offset,
+        });
+    }
+    entries
+}
+
+/// Layout of the volume, read from the boot sector, and the allocation
+/// state kept in memory.
+struct Volume {
+    device: DeviceRef,
+    fat_type: FatType,
+    cluster_size: usize,
+    /// Byte offset of the first FAT; the copies follow it.
+    fat_start: u64,
+    /// Bytes in one FAT.
+    fat_size: u64,
+    fats: u8,
+    /// The fixed root directory of FAT12 and FAT16.
+    root_start: u64,
+    root_size: usize,
+    /// First cluster of the FAT32 root directory.
+    root_cluster: u32,
+    data_start: u64,
+    /// Number of data clusters; they are numbered from 2.
+    clusters: u32,
+    /// Byte offset of the FAT32 FSInfo sector.
+    fsinfo: Option<u64>,
+    state: RefCell<VolumeState>,
+}
+
+struct VolumeState {
+    /// Where the search for a free cluster starts.
+    next_free: u32,
+    /// Number of free clusters, if known.
+    free: Option<u32>,
+    /// Whether FSInfo lags behind `next_free` and `free`.
+    fsinfo_dirty: bool,
+    nodes: BTreeMap<(u32, u32), Weak<FatNode>>,
+}
+
+impl Volume {
+    fn open(device: DeviceRef) -> Result<Self, &'static str> {
+        let mut boot = [0; 512];
+        cache::read(&device, 0, &mut boot)?;
+        if u16_at(&boot, 510) != 0xAA55 {
+            return Err("Not a FAT file system");
+        }
+        let sector_size = u16_at(&boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = u16_at(&boot, 14) as u64;
+        let fats = boot[16];
+        let root_entries = u16_at(&boot, 17) as u64;
+        let total = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            total => total as u64,
+        };
+        let fat_sectors = match u16_at(&boot, 22) {
+            0 => u32_at(&boot, 36) as u64,
+            size => size as u64,
+        };
+        if !matches!(sector_size, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || fat_sectors == 0
+        {
+            return Err("Not a FAT file system");
+        }
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(sector_size);
+        let data_sector = reserved + fats as u64 * fat_sectors + root_sectors;
+        if total <= data_sector {
+            return Err(CORRUPT);
+        }
+        if total * sector_size > device.capacity() {
+            return Err("File system is larger than the device");
+        }
+        let clusters = ((total - data_sector) / sectors_per_cluster) as u32;
+        let fat_type = match clusters {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let (root_cluster, fsinfo) = if fat_type == FatType::Fat32 {
+            let fsinfo = match u16_at(&boot, 48) {
+                0 | 0xFFFF => None,
+                sector => Some(sector as u64 * sector_size),
+            };
+            (u32_at(&boot, 44), fsinfo)
+        } else {
+            (0, None)
+        };
+
+        let mut volume = Volume {
+            device,
+            fat_type,
+            cluster_size: (sectors_per_cluster * sector_size) as usize,
+            fat_start: reserved * sector_size,
+            fat_size: fat_sectors * sector_size,
+            fats,
+            root_start: (reserved + fats as u64 * fat_sectors) * sector_size,
+            root_size: (root_entries * ENTRY_SIZE as u64) as usize,
+            root_cluster,
+            data_start: data_sector * sector_size,
+            clusters,
+            fsinfo: None,
+            state: RefCell::new(VolumeState { next_free: 2, free: None, fsinfo_dirty: false, nodes: BTreeMap::new() }),
+        };
+        if fat_type == FatType::Fat32 && !volume.is_valid(root_cluster) {
+            return Err(CORRUPT);
+        }
+        if let Some(position) = fsinfo {
+            let mut sector = [0; 512];
+            volume.read_bytes(position, &mut sector)?;
+            if u32_at(&sector, 0) == FSINFO_LEAD && u32_at(&sector, 484) == FSINFO_STRUCT {
+                let free = u32_at(&sector, 488);
+                let next = u32_at(&sector, 492);
+                let next_valid = volume.is_valid(next);
+                let state = volume.state.get_mut();
+                if free <= clusters {
+                    state.free = Some(free);
+                }
+                if next_valid {
+                    state.next_free = next;
+                }
+                volume.fsinfo = Some(position);
+            }
+        }
+        Ok(volume)
+    }
+
+    fn read_bytes(&self, position: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        cache::read(&self.device, position, buf)
+    }
+
+    fn write_bytes(&self, position: u64, data: &[u8]) -> Result<(), &'static str> {
+        cache::write(&self.device, position, data)
+    }
+
+    fn cluster_start(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.cluster_size as u64
+    }
+
+    fn is_valid(&self, cluster: u32) -> bool {
+        (2..self.clusters + 2).contains(&cluster)
+    }
+
+    /// Whether a FAT entry ends a chain.
+    fn is_end(&self, value: u32) -> bool {
+        value >= match self.fat_type {
+            FatType::Fat12 => 0xFF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, &'static str> {
+        let mut bytes = [0; 4];
+        match self.fat_type {
+            FatType::Fat12 => {
+                self.read_bytes(self.fat_start + cluster as u64 * 3 / 2, &mut bytes[..2])?;
+                let pair = u16_at(&bytes, 0);
+                Ok(if cluster & 1 == 1 { pair >> 4 } else { pair & 0xFFF } as u32)
+            }
+            FatType::Fat16 => {
+                self.read_bytes(self.fat_start + cluster as u64 * 2, &mut bytes[..2])?;
+                Ok(u16_at(&bytes, 0) as u32)
+            }
+            FatType::Fat32 => {
+                self.read_bytes(self.fat_start + cluster as u64 * 4, &mut bytes)?;
+                Ok(u32_at(&bytes, 0) & 0x0FFF_FFFF)
+            }
+        }
+    }
+
+    /// Sets an entry in every copy of the FAT.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), &'static str> {
+        for copy in 0..self.fats as u64 {
+            let fat = self.fat_start + copy * self.fat_size;
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let position = fat + cluster as u64 * 3 / 2;
+                    let mut bytes = [0; 2];
+                    self.read_bytes(position, &mut bytes)?;
+                    let pair = u16::from_le_bytes(bytes);
+                    let pair = if cluster & 1 == 1 {
+                        (pair & 0x000F) | (value as u16) << 4
+                    } else {
+                        (pair & 0xF000) | (value as u16 & 0x0FFF)
+                    };
+                    self.write_bytes(position, &pair.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.write_bytes(fat + cluster as u64 * 2, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // The top four bits are reserved and kept.
+                    let position = fat + cluster as u64 * 4;
+                    let mut bytes = [0; 4];
+                    self.read_bytes(position, &mut bytes)?;
+                    let value = (u32::from_le_bytes(bytes) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                    self.write_bytes(position, &value.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Clusters of the chain starting at `first`; none for cluster 0.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, &'static str> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        if cluster == 0 {
+            return Ok(chain);
+        }
+        loop {
+            if !self.is_valid(cluster) || chain.len() >= self.clusters as usize {
+                return Err(CORRUPT);
+            }
+            chain.push(cluster);
+            let next = self.fat_entry(cluster)?;
+            if self.is_end(next) {
+                return Ok(chain);
+            }
+            cluster = next;
+        }
+    }
+
+    /// Takes `count` free clusters, links them into a chain and appends it
+    /// to the chain ending at `last`, if any.
+    fn allocate(&self, count: usize, last: Option<u32>, zero: bool) -> Result<Vec<u32>, &'static str> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut found = Vec::new();
+        let mut cluster = self.state.borrow().next_free;
+        for _ in 0..self.clusters {
+            if !self.is_valid(cluster) {
+                cluster = 2;
+            }
+            if self.fat_entry(cluster)? == 0 {
+                found.push(cluster);
+                if found.len() == count {
+                    break;
+                }
+            }
+            cluster += 1;
+        }
+        if found.len() < count {
+            return Err(NO_SPACE);
+        }
+        for (i, &cluster) in found.iter().enumerate() {
+            let next = found.get(i + 1).copied().unwrap_or(self.end_of_chain());
+            self.set_fat_entry(cluster, next)?;
+            if zero {
+                self.write_bytes(self.cluster_start(cluster), &vec![0; self.cluster_size])?;
+            }
+        }
+        if let Some(last) = last {
+            self.set_fat_entry(last, found[0])?;
+        }
+        let mut state = self.state.borrow_mut();
+        state.next_free = found[count - 1] + 1;
+        state.free = state.free.map(|free| free.saturating_sub(count as u32));
+        state.fsinfo_dirty = true;
+        Ok(found)
+    }
+
+    /// Frees the chain starting at `first`.
+    fn free_chain(&self, first: u32) -> Result<(), &'static str> {
+        let chain = self.chain(first)?;
+        for &cluster in &chain {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        let mut state = self.state.borrow_mut();
+        state.free = state.free.map(|free| free + chain.len() as u32);
+        state.fsinfo_dirty = true;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), &'static str> {
+        let (free, next_free, dirty) = {
+            let state = self.state.borrow();
+            (state.free, state.next_free, state.fsinfo_dirty)
+        };
+        if let (Some(position), true) = (self.fsinfo, dirty) {
+            let mut fields = [0; 8];
+            fields[..4].copy_from_slice(&free.unwrap_or(FSINFO_UNKNOWN).to_le_bytes());
+            fields[4..].copy_from_slice(&next_free.to_le_bytes());
+            self.write_bytes(position + 488, &fields)?;
+            self.state.borrow_mut().fsinfo_dirty = false;
+        }
+        cache::sync_device(&self.device)
+    }
+}
+
+/// A mounted FAT volume.
+pub struct FatFs {
+    volume: Rc<Volume>,
+    root: Rc<FatNode>,
+}
+
+impl FatFs {
+    pub fn new(device: DeviceRef) -> Result<Self, &'static str> {
+        let volume = Rc::new(Volume::open(device)?);
+        let root = Rc::new_cyclic(|me| FatNode {
+            volume: volume.clone(),
+            me: me.clone(),
+            state: RefCell::new(NodeState {
+                directory: true,
+                parent: None,
+                start: 0,
+                offset: 0,
+                first_cluster: volume.root_cluster,
+                chain: None,
+                size: 0,
+                attr: ATTR_DIRECTORY,
+                created: 0,
+                modified: 0,
+                accessed: 0,
+                deleted: false,
+            }),
+        });
+        Ok(FatFs { volume, root })
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<(), &'static str> {
+        self.volume.sync()
+    }
+
+    fn device(&self) -> Option<DeviceRef> {
+        Some(self.volume.device.clone())
+    }
+}
+
+pub struct FatNode {
+    volume: Rc<Volume>,
+    /// The node itself, to hand to the nodes it holds as their parent.
+    me: Weak<FatNode>,
+    state: RefCell<NodeState>,
+}
+
+struct NodeState {
+    directory: bool,
+    /// Directory holding the entry; `None` for the root.
+    parent: Option<Rc<FatNode>>,
+    /// Where the entry is in the parent, as in [`DirEntry`].
+    start: u32,
+    offset: u32,
+    /// 0 for an empty file and for the FAT12/16 root directory.
+    first_cluster: u32,
+    /// Clusters of the content, read from the FAT on first use.
+    chain: Option<Vec<u32>>,
+    size: u32,
+    attr: u8,
+    created: u64,
+    modified: u64,
+    accessed: u64,
+    /// Unlinked; the clusters are freed when the last reference goes.
+    deleted: bool,
+}
+
+impl Drop for FatNode {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+        if state.deleted && state.first_cluster != 0 {
+            let _ = self.volume.free_chain(state.first_cluster);
+        }
+    }
+}
+
+impl FatNode {
+    /// Whether this is the root directory of FAT12 or FAT16, which lives
+    /// outside the data clusters and cannot grow.
+    fn is_fixed_root(&self) -> bool {
+        let state = self.state.borrow();
+        state.parent.is_none() && state.first_cluster == 0
+    }
+
+    /// First half of the key of the nodes this directory holds.
+    fn id(&self) -> u32 {
+        self.state.borrow().first_cluster
+    }
+
+    fn is_root(&self) -> bool {
+        self.state.borrow().parent.is_none()
+    }
+
+    fn load_chain(&self) -> Result<(), &'static str> {
+        let first = {
+            let state = self.state.borrow();
+            if state.chain.is_some() {
+                return Ok(());
+            }
+            state.first_cluster
+        };
+        let chain = if self.is_fixed_root() { Vec::new() } else { self.volume.chain(first)? };
+        self.state.borrow_mut().chain = Some(chain);
+        Ok(())
+    }
+
+    /// Bytes of allocated space.
+    fn capacity(&self) -> Result<usize, &'static str> {
+        if self.is_fixed_root() {
+            return Ok(self.volume.root_size);
+        }
+        self.load_chain()?;
+        Ok(self.state.borrow().chain.as_ref().map_or(0, Vec::len) * self.volume.cluster_size)
+    }
+
+    /// Disk position of the byte at `offset` of the content and how many
+    /// bytes follow it in the same cluster; `None` past the allocated space.
+    fn locate(&self, offset: usize) -> Option<(u64, usize)> {
+        if self.is_fixed_root() {
+            let left = self.volume.root_size.checked_sub(offset).filter(|&left| left > 0)?;
+            return Some((self.volume.root_start + offset as u64, left));
+        }
+        let cluster_size = self.volume.cluster_size;
+        let state = self.state.borrow();
+        let cluster = *state.chain.as_ref()?.get(offset / cluster_size)?;
+        let skip = offset % cluster_size;
+        Some((self.volume.cluster_start(cluster) + skip as u64, cluster_size - skip))
+    }
+
+    /// Reads allocated space, whatever the recorded size.
+    fn read_raw(&self, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        self.load_chain()?;
+        let mut done = 0;
+        while done < buf.len() {
+            let Some((position, run)) = self.locate(offset + done) else { break };
+            let count = core::cmp::min(run, buf.len() - done);
+            self.volume.read_bytes(position, &mut buf[done..done + count])?;
+            done += count;
+        }
+        Ok(done)
+    }
+
+    /// Writes anywhere, allocating clusters up to the end of `data`.
+    fn write_raw(&self, offset: usize, data: &[u8]) -> Result<(), &'static str> {
+        self.reserve(offset + data.len())?;
+        let mut done = 0;
+        while done < data.len() {
+            let (position, run) = self.locate(offset + done).ok_or(CORRUPT)?;
+            let count = core::cmp::min(run, data.len() - done);
+            self.volume.write_bytes(position, &data[done..done + count])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Makes at least `len` bytes of space; new directory clusters are
+    /// zeroed so they read as the end of the directory.
+    fn reserve(&self, len: usize) -> Result<(), &'static str> {
+        if self.capacity()? >= len {
+            return Ok(());
+        }
+        if self.is_fixed_root() {
+            return Err(NO_SPACE);
+        }
+        let (needed, last, directory) = {
+            let state = self.state.borrow();
+            let chain = state.chain.as_ref().ok_or(CORRUPT)?;
+            (len.div_ceil(self.volume.cluster_size) - chain.len(), chain.last().copied(), state.directory)
+        };
+        let new = self.volume.allocate(needed, last, directory)?;
+        let mut state = self.state.borrow_mut();
+        if state.first_cluster == 0 {
+            state.first_cluster = new[0];
+        }
+        state.chain.as_mut().ok_or(CORRUPT)?.extend(new);
+        Ok(())
+    }
+
+    /// Writes zeros over `from..to` of a file.
+    fn fill_zeros(&self, from: usize, to: usize) -> Result<(), &'static str> {
+        let zeros = vec![0; self.volume.cluster_size];
+        let mut at = from;
+        while at < to {
+            let count = core::cmp::min(zeros.len(), to - at);
+            self.write_raw(at, &zeros[..count])?;
+            at += count;
+        }
+        Ok(())
+    }
+
+    /// Writes the first cluster, size, attributes and modification time
+    /// back to the entry in the parent.
+    fn store(&self) -> Result<(), &'static str> {
+        let (parent, offset) = {
+            let state = self.state.borrow();
+            match &state.parent {
+                Some(parent) if !state.deleted => (parent.clone(), state.offset as usize),
+                _ => return Ok(()),
+            }
+        };
+        let mut entry = [0; ENTRY_SIZE];
+        parent.read_raw(offset, &mut entry)?;
+        let state = self.state.borrow();
+        entry[11] = state.attr;
+        set_cluster(&mut entry, state.first_cluster);
+        set_modified(&mut entry, state.modified);
+        let size = if state.directory { 0 } else { state.size };
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        drop(state);
+        parent.write_raw(offset, &entry)
+    }
+
+    fn dir_entries(&self) -> Result<Vec<DirEntry>, &'static str> {
+        if !self.state.borrow().directory {
+            return Err("Not a directory");
+        }
+        let mut data = vec![0; self.capacity()?];
+        self.read_raw(0, &mut data)?;
+        Ok(parse_dir(&data, self.volume.fat_type == FatType::Fat32))
+    }
+
+    fn find(&self, name: &str) -> Result<DirEntry, &'static str> {
+        self.dir_entries()?.into_iter().find(|entry| entry.matches(name)).ok_or("Entry not found")
+    }
+
+    /// The node of an entry of this directory, the same one every time.
+    fn child(&self, entry: &DirEntry) -> Result<Rc<FatNode>, &'static str> {
+        let key = (self.id(), entry.offset);
+        if let Some(node) = self.volume.state.borrow().nodes.get(&key).and_then(Weak::upgrade) {
+            return Ok(node);
+        }
+        let parent = self.me.upgrade().ok_or(CORRUPT)?;
+        let node = Rc::new_cyclic(|me| FatNode {
+            volume: self.volume.clone(),
+            me: me.clone(),
+            state: RefCell::new(NodeState {
+                directory: entry.attr & ATTR_DIRECTORY != 0,
+                parent: Some(parent),
+                start: entry.start,
+                offset: entry.offset,
+                first_cluster: entry.cluster,
+                chain: None,
+                size: entry.size,
+                attr: entry.attr,
+                created: entry.created,
+                modified: entry.modified,
+                accessed: entry.accessed,
+                deleted: false,
+            }),
+        });
+        let mut state = self.volume.state.borrow_mut();
+        state.nodes.retain(|_, node| node.strong_count() > 0);
+        state.nodes.insert(key, Rc::downgrade(&node));
+        Ok(node)
+    }
+
+    /// First free run of `count` entries, possibly past the allocated end.
+    fn free_slots(&self, count: usize) -> Result<usize, &'static str> {
+        let capacity = self.capacity()?;
+        let mut data = vec![0; capacity];
+        self.read_raw(0, &mut data)?;
+        let mut run = 0;
+        for (i, slot) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+            match slot[0] {
+                // Everything from here on is free.
+                ENTRY_END => return self.check_slots((i - run) * ENTRY_SIZE, count),
+                ENTRY_FREE => {
+                    run += 1;
+                    if run == count {
+                        return Ok((i + 1 - count) * ENTRY_SIZE);
+                    }
+                }
+                _ => run = 0,
+            }
+        }
+        self.check_slots(capacity - run * ENTRY_SIZE, count)
+    }
+
+    fn check_slots(&self, start: usize, count: usize) -> Result<usize, &'static str> {
+        let end = start + count * ENTRY_SIZE;
+        if end > MAX_DIR_SIZE || (self.is_fixed_root() && end > self.volume.root_size) {
+            return Err(NO_SPACE);
+        }
+        Ok(start)
+    }
+
+    /// Adds `name` with the short entry `entry`, whose name is filled in
+    /// here, giving the (start, offset) it was written at.
+    fn insert(&self, name: &str, mut entry: [u8; ENTRY_SIZE]) -> Result<(u32, u32), &'static str> {
+        let taken: Vec<[u8; 11]> = self.dir_entries()?.iter().map(|entry| entry.short).collect();
+        let (short, exact) = match exact_short_name(name) {
+            Some(short) if !taken.contains(&short) => (short, true),
+            _ => match exact_short_name(&name.to_ascii_uppercase()) {
+                Some(short) if !taken.contains(&short) => (short, false),
+                _ => (short_alias(name, &taken)?, false),
+            },
+        };
+        entry[..11].copy_from_slice(&short);
+        entry[12] = 0;
+        let mut slots = if exact { Vec::new() } else { long_name_entries(name, lfn_checksum(&short)) };
+        slots.push(entry);
+        let start = self.free_slots(slots.len())?;
+        self.write_raw(start, &slots.concat())?;
+        Ok((start as u32, (start + (slots.len() - 1) * ENTRY_SIZE) as u32))
+    }
+
+    /// Marks the entries from `start` to the short entry at `offset` free.
+    fn remove_entries(&self, start: u32, offset: u32) -> Result<(), &'static str> {
+        for at in (start..=offset).step_by(ENTRY_SIZE) {
+            self.write_raw(at as usize, &[ENTRY_FREE])?;
+        }
+        Ok(())
+    }
+
+    /// Points the `..` entry of this directory at `parent`. It is normally
+    /// the second entry, but some tools put long names before `.` and `..`.
+    fn set_dotdot(&self, parent: &FatNode) -> Result<(), &'static str> {
+        let mut head = [0; 8 * ENTRY_SIZE];
+        let len = self.read_raw(0, &mut head)?;
+        let offset = head[..len]
+            .chunks_exact(ENTRY_SIZE)
+            .position(|slot| &slot[..11] == b"..         " && slot[11] & ATTR_DIRECTORY != 0)
+            .ok_or(CORRUPT)?
+            * ENTRY_SIZE;
+        let entry = &mut head[offset..offset + ENTRY_SIZE];
+        // The root is always cluster 0 here, even on FAT32.
+        set_cluster(entry, if parent.is_root() { 0 } else { parent.id() });
+        self.write_raw(offset, entry)
+    }
+}
+
+impl Inode for FatNode {
+    fn metadata(&self) -> Metadata {
+        let size = self.size();
+        let state = self.state.borrow();
+        let ino = match &state.parent {
+            Some(parent) => ((parent.id() as u64) << 16 | (state.offset as usize / ENTRY_SIZE) as u64) + 2,
+            None => 1,
+        };
+        let (file_type, mode) = if state.directory {
+            (FileType::Directory, 0o755)
+        } else if state.attr & ATTR_READ_ONLY != 0 {
+            (FileType::File, 0o444)
+        } else {
+            (FileType::File, 0o644)
+        };
+        Metadata {
+            ino,
+            nlink: 1,
+            file_type,
+            size: size as u64,
+            mode,
+            uid: 0,
+            created: state.created,
+            modified: state.modified,
+            accessed: state.accessed,
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        if self.state.borrow().directory {
+            FileType::Directory
+        } else {
+            FileType::File
+        }
+    }
+
+    fn size(&self) -> usize {
+        if self.state.borrow().directory {
+            self.entries().map_or(0, |entries| entries.len())
+        } else {
+            self.state.borrow().size as usize
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let size = {
+            let state = self.state.borrow();
+            if state.directory {
+                return Err("Is a directory");
+            }
+            state.size as usize
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = core::cmp::min(buf.len(), size - offset);
+        self.read_raw(offset, &mut buf[..len])
+    }
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        let size = {
+            let state = self.state.borrow();
+            if state.directory {
+                return Err("Is a directory");
+            }
+            state.size as usize
+        };
+        let end = offset
+            .checked_add(data.len())
+            .filter(|&end| end <= u32::MAX as usize)
+            .ok_or("File too large")?;
+        // A failed write may still have allocated clusters; the entry is
+        // stored either way so they stay reachable.
+        let result = if offset > size { self.fill_zeros(size, offset) } else { Ok(()) }
+            .and_then(|_| self.write_raw(offset, data));
+        {
+            let mut state = self.state.borrow_mut();
+            if result.is_ok() {
+                state.size = core::cmp::max(size, end) as u32;
+            }
+            state.modified = rtc::now();
+            state.attr |= ATTR_ARCHIVE;
+        }
+        self.store()?;
+        result.map(|_| data.len())
+    }
+
+    fn truncate(&self, size: usize) -> Result<(), &'static str> {
+        let old = {
+            let state = self.state.borrow();
+            if state.directory {
+                return Err("Is a directory");
+            }
+            state.size as usize
+        };
+        if size > u32::MAX as usize {
+            return Err("File too large");
+        }
+        if size > old {
+            self.fill_zeros(old, size)?;
+        } else {
+            self.load_chain()?;
+            let chain = self.state.borrow().chain.clone().ok_or(CORRUPT)?;
+            let keep = size.div_ceil(self.volume.cluster_size);
+            if keep < chain.len() {
+                if keep > 0 {
+                    self.volume.set_fat_entry(chain[keep - 1], self.volume.end_of_chain())?;
+                }
+                self.volume.free_chain(chain[keep])?;
+                let mut state = self.state.borrow_mut();
+                state.chain.as_mut().ok_or(CORRUPT)?.truncate(keep);
+                if keep == 0 {
+                    state.first_cluster = 0;
+                }
+            }
+        }
+        {
+            let mut state = self.state.borrow_mut();
+            state.size = size as u32;
+            state.modified = rtc::now();
+            state.attr |= ATTR_ARCHIVE;
+        }
+        self.store()
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, &'static str> {
+        let node: InodeRef = self.child(&self.find(name)?)?;
+        Ok(node)
+    }
+
+    fn parent(&self) -> Option<InodeRef> {
+        let state = self.state.borrow();
+        if !state.directory {
+            return None;
+        }
+        let parent: InodeRef = state.parent.clone()?;
+        Some(parent)
+    }
+
+    fn create(self: Rc<Self>, name: &str, file_type: FileType) -> Result<InodeRef, &'static str> {
+        check_name(name)?;
+        match self.find(name) {
+            Ok(_) => return Err("Entry already exists"),
+            Err("Entry not found") => {}
+            Err(e) => return Err(e),
+        }
+        let now = rtc::now();
+        let (attr, cluster) = match file_type {
+            FileType::File => (ATTR_ARCHIVE, 0),
+            FileType::Directory => {
+                let cluster = self.volume.allocate(1, None, true)?[0];
+                let mut dot = short_entry(ATTR_DIRECTORY, cluster, now);
+                dot[..11].copy_from_slice(b".          ");
+                let mut dotdot = short_entry(ATTR_DIRECTORY, if self.is_root() { 0 } else { self.id() }, now);
+                dotdot[..11].copy_from_slice(b"..         ");
+                let result = self.volume.write_bytes(self.volume.cluster_start(cluster), &[dot, dotdot].concat());
+                if let Err(e) = result {
+                    let _ = self.volume.free_chain(cluster);
+                    return Err(e);
+                }
+                (ATTR_DIRECTORY, cluster)
+            }
+            FileType::Symlink | FileType::CharDevice | FileType::BlockDevice => return Err("Operation not permitted"),
+        };
+        let (start, offset) = match self.insert(name, short_entry(attr, cluster, now)) {
+            Ok(place) => place,
+            Err(e) => {
+                if cluster != 0 {
+                    let _ = self.volume.free_chain(cluster);
+                }
+                return Err(e);
+            }
+        };
+        let entry = DirEntry {
+            name: String::from(name),
+            short: [0; 11],
+            attr,
+            cluster,
+            size: 0,
+            created: now,
+            modified: now,
+            accessed: now,
+            start,
+            offset,
+        };
+        let node: InodeRef = self.child(&entry)?;
+        Ok(node)
+    }
+
+    fn link(self: Rc<Self>, _name: &str, _target: InodeRef) -> Result<(), &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn symlink(self: Rc<Self>, _name: &str, _target: &str) -> Result<(), &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), &'static str> {
+        let entry = self.find(name)?;
+        let node = self.child(&entry)?;
+        if node.file_type() == FileType::Directory && node.size() > 0 {
+            return Err("Directory not empty");
+        }
+        self.remove_entries(entry.start, entry.offset)?;
+        node.state.borrow_mut().deleted = true;
+        self.volume.state.borrow_mut().nodes.remove(&(self.id(), entry.offset));
+        Ok(())
+    }
+
+    fn rename(self: Rc<Self>, name: &str, new_dir: InodeRef, new_name: &str) -> Result<(), &'static str> {
+        let new_dir: Rc<FatNode> = (new_dir as Rc<dyn Any>).downcast().map_err(|_| "Cross-device link")?;
+        if !Rc::ptr_eq(&self.volume, &new_dir.volume) {
+            return Err("Cross-device link");
+        }
+        check_name(new_name)?;
+        let entry = self.find(name)?;
+        let node = self.child(&entry)?;
+        match new_dir.find(new_name) {
+            // Only the case of the name changes.
+            Ok(existing) if Rc::ptr_eq(&self, &new_dir) && existing.offset == entry.offset => {}
+            Ok(_) => new_dir.unlink(new_name)?,
+            Err("Entry not found") => {}
+            Err(e) => return Err(e),
+        }
+        let mut raw = [0; ENTRY_SIZE];
+        self.read_raw(entry.offset as usize, &mut raw)?;
+        let (start, offset) = new_dir.insert(new_name, raw)?;
+        self.remove_entries(entry.start, entry.offset)?;
+        {
+            let mut nodes = self.volume.state.borrow_mut();
+            nodes.nodes.remove(&(self.id(), entry.offset));
+            nodes.nodes.insert((new_dir.id(), offset), Rc::downgrade(&node));
+        }
+        {
+            let mut state = node.state.borrow_mut();
+            state.parent = Some(new_dir.clone());
+            state.start = start;
+            state.offset = offset;
+        }
+        if node.state.borrow().directory && !Rc::ptr_eq(&self, &new_dir) {
+            node.set_dotdot(&new_dir)?;
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<String>, &'static str> {
+        Ok(self.dir_entries()?.into_iter().map(|entry| entry.name).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+    use crate::global::TEST_LOCK;
+    use crate::vfs;
+
+    fn short(name: &[u8; 11], attr: u8, cluster: u32, size: u32) -> [u8; ENTRY_SIZE] {
+        let mut entry = [0; ENTRY_SIZE];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attr;
+        set_cluster(&mut entry, cluster);
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    fn dir(entries: &[[u8; ENTRY_SIZE]]) -> Vec<u8> {
+        let mut data = entries.concat();
+        // Past the end marker.
+        data.extend_from_slice(&[0; ENTRY_SIZE]);
+        data.extend_from_slice(&short(b"HIDDEN     ", 0, 0, 0));
+        data
+    }
+
+    fn names(entries: &[DirEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn short_names() {
+        let mut lower = short(b"README  MD ", ATTR_ARCHIVE, 5, 10);
+        lower[12] = CASE_LOWER_BASE | CASE_LOWER_EXT;
+        let mut deleted = short(b"GONE    TXT", 0, 0, 0);
+        deleted[0] = ENTRY_FREE;
+        let mut kanji = short(b"xNAME      ", 0, 0, 0);
+        kanji[0] = 0x05;
+        let data = dir(&[
+            short(b"LABEL      ", ATTR_VOLUME_ID, 0, 0),
+            short(b".          ", ATTR_DIRECTORY, 2, 0),
+            short(b"..         ", ATTR_DIRECTORY, 0, 0),
+            short(b"KERNEL  BIN", 0, 3, 1000),
+            deleted,
+            lower,
+            kanji,
+        ]);
+        let entries = parse_dir(&data, false);
+        assert_eq!(names(&entries), ["KERNEL.BIN", "readme.md", "\u{e5}NAME"]);
+        assert_eq!((entries[0].cluster, entries[0].size, entries[0].offset), (3, 1000, 3 * 32));
+        assert!(entries[1].matches("README.MD"));
+    }
+
+    #[test]
+    fn long_names() {
+        let name = "A rather long file name.text";
+        let alias = *b"ARATHE~1TEX";
+        let mut entries = long_name_entries(name, lfn_checksum(&alias));
+        assert_eq!(entries.len(), 3);
+        entries.push(short(&alias, 0, 4, 1));
+        entries.insert(0, short(b"FIRST      ", 0, 0, 0));
+        let parsed = parse_dir(&dir(&entries), false);
+        assert_eq!(names(&parsed), ["FIRST", name]);
+        assert_eq!((parsed[1].start, parsed[1].offset), (32, 4 * 32));
+        assert!(parsed[1].matches("ARATHE~1.TEX") && parsed[1].matches("a RATHER long FILE name.TEXT"));
+    }
+
+    #[test]
+    fn broken_long_names_fall_back_to_the_short_name() {
+        let alias = *b"LONGNA~1   ";
+        // Checksum of another short name.
+        let mut stale = long_name_entries("long name", lfn_checksum(b"OTHER      "));
+        stale.push(short(&alias, 0, 0, 0));
+        // A part missing from the middle.
+        let mut gap = long_name_entries("a name of more than thirteen units", lfn_checksum(&alias));
+        gap.remove(1);
+        gap.push(short(&alias, 0, 0, 0));
+        let entries = parse_dir(&dir(&[stale, gap].concat()), false);
+        assert_eq!(names(&entries), ["LONGNA~1", "LONGNA~1"]);
+        assert_eq!(entries[1].start, entries[1].offset);
+    }
+
+    #[test]
+    fn high_cluster_bits_only_count_on_fat32() {
+        let data = dir(&[short(b"BIG        ", 0, 0x0012_0034, 0)]);
+        assert_eq!(parse_dir(&data, false)[0].cluster, 0x34);
+        assert_eq!(parse_dir(&data, true)[0].cluster, 0x0012_0034);
+    }
+
+    fn set_fat12(disk: &RamDisk, cluster: usize, value: u16) {
+        let at = 512 + cluster * 3 / 2;
+        let mut pair = u16::from_le_bytes([disk.data.borrow()[at], disk.data.borrow()[at + 1]]);
+        pair = if cluster & 1 == 1 { pair & 0x000F | value << 4 } else { pair & 0xF000 | value };
+        disk.put(at, &pair.to_le_bytes());
+    }
+
+    /// FAT12 with one-sector clusters: the boot sector, one FAT, a root of
+    /// 16 entries and clusters from sector 3. The root holds a file with a
+    /// long name in clusters 3 and 4 and a directory in cluster 2.
+    fn image() -> Rc<RamDisk> {
+        let disk = RamDisk::new(64, 512);
+        let mut boot = [0; 512];
+        boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+        boot[13] = 1;
+        boot[14..16].copy_from_slice(&1u16.to_le_bytes());
+        boot[16] = 1;
+        boot[17..19].copy_from_slice(&16u16.to_le_bytes());
+        boot[19..21].copy_from_slice(&64u16.to_le_bytes());
+        boot[22..24].copy_from_slice(&1u16.to_le_bytes());
+        boot[510..].copy_from_slice(&0xAA55u16.to_le_bytes());
+        disk.put(0, &boot);
+
+        set_fat12(&disk, 2, 0xFFF);
+        set_fat12(&disk, 3, 4);
+        set_fat12(&disk, 4, 0xFFF);
+
+        let alias = *b"NOTESF~1TXT";
+        let mut root = long_name_entries("Notes for later.txt", lfn_checksum(&alias));
+        root.push(short(&alias, ATTR_ARCHIVE, 3, 600));
+        root.push(short(b"SUB        ", ATTR_DIRECTORY, 2, 0));
+        disk.put(2 * 512, &root.concat());
+        let sub = [
+            short(b".          ", ATTR_DIRECTORY, 2, 0),
+            short(b"..         ", ATTR_DIRECTORY, 0, 0),
+            short(b"EMPTY      ", 0, 0, 0),
+        ];
+        disk.put(3 * 512, &sub.concat());
+        let mut data = vec![b'a'; 512];
+        data.extend_from_slice(&[b'b'; 88]);
+        disk.put(4 * 512, &data);
+        disk
+    }
+
+    #[test]
+    fn reads_a_volume() {
+        let _lock = TEST_LOCK.lock();
+        let fs = FatFs::new(image()).unwrap();
+        let root = fs.root();
+        assert_eq!(root.entries().unwrap(), ["Notes for later.txt", "SUB"]);
+
+        let file = root.lookup("notes FOR later.TXT").unwrap();
+        assert!(Rc::ptr_eq(&file, &root.lookup("NOTESF~1.TXT").unwrap()));
+        let data = vfs::read_to_end(&file).unwrap();
+        assert_eq!(data.len(), 600);
+        assert!(data[..512].iter().all(|&b| b == b'a') && data[512..].iter().all(|&b| b == b'b'));
+
+        let sub = root.lookup("sub").unwrap();
+        assert_eq!(sub.file_type(), FileType::Directory);
+        assert_eq!(sub.entries().unwrap(), ["EMPTY"]);
+        assert_eq!(vfs::read_to_end(&sub.lookup("empty").unwrap()).unwrap(), b"");
+    }
+
+    #[test]
+    fn rejects_what_is_not_fat() {
+        let _lock = TEST_LOCK.lock();
+        assert_eq!(FatFs::new(RamDisk::new(64, 512)).err(), Some("Not a FAT file system"));
+        let disk = image();
+        // More sectors than the device has.
+        disk.put(19, &128u16.to_le_bytes());
+        assert_eq!(FatFs::new(disk).err(), Some("File system is larger than the device"));
+    }
+}
@@ -0,0 +1,261 @@
+use alloc::{boxed::Box, rc::Rc, vec::Vec};
+use core::cell::RefCell;
+use spin::Mutex;
+
+use crate::devfs::CharDeviceRef;
+use crate::interrupts::ENTER_PRESSED;
+use crate::{process, signal};
+use crate::ipc::{QueueRef, ShmRef};
+use crate::pipe::{self, PipeReader, PipeRef, PipeWriter};
+use crate::vfs;
+use crate::vga_buffer::{WRITER, buffer_clear, buffer_copy};
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+const MAX_FILES: usize = 64;
+
+/// Lines typed on the keyboard that were not consumed by the last `read`.
+static CONSOLE_PENDING: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+pub enum FileKind {
+    Console,
+    File(Box<dyn vfs::File>),
+    /// A character device opened through `/dev`.
+    Device(CharDeviceRef),
+    PipeRead(PipeReader),
+    PipeWrite(PipeWriter),
+    Queue(QueueRef),
+    SharedMemory(ShmRef),
+}
+
+/// What a read or write operates on, taken out of the description so that
+/// it is not borrowed while the caller blocks.
+enum Target {
+    Console,
+    Device(CharDeviceRef),
+    Pipe(PipeRef),
+    /// Regular files and directories never block, so they are used in place.
+    File,
+    /// Objects used through their own syscalls rather than read and write.
+    Other,
+}
+
+/// An open file description, shared by every descriptor duplicated from it.
+pub struct OpenFile {
+    pub kind: FileKind,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+pub type FileRef = Rc<RefCell<OpenFile>>;
+
+impl OpenFile {
+    pub fn console() -> FileRef {
+        Rc::new(RefCell::new(OpenFile {
+            kind: FileKind::Console,
+            readable: true,
+            writable: true,
+        }))
+    }
+
+    pub fn file(file: Box<dyn vfs::File>, readable: bool, writable: bool) -> FileRef {
+        Rc::new(RefCell::new(OpenFile {
+            kind: FileKind::File(file),
+            readable,
+            writable,
+        }))
+    }
+
+    pub fn device(device: CharDeviceRef, readable: bool, writable: bool) -> FileRef {
+        Rc::new(RefCell::new(OpenFile {
+            kind: FileKind::Device(device),
+            readable,
+            writable,
+        }))
+    }
+
+    /// Both ends of a new pipe.
+    pub fn pipe() -> (FileRef, FileRef) {
+        let (reader, writer) = pipe::new();
+        let open = |kind, readable, writable| Rc::new(RefCell::new(OpenFile {
+            kind,
+            readable,
+            writable,
+        }));
+        (open(FileKind::PipeRead(reader), true, false), open(FileKind::PipeWrite(writer), false, true))
+    }
+
+    /// A description for an IPC object, usable with its own syscalls.
+    pub fn object(kind: FileKind) -> FileRef {
+        Rc::new(RefCell::new(OpenFile {
+            kind,
+            readable: true,
+            writable: true,
+        }))
+    }
+
+    fn target(&self) -> Target {
+        match &self.kind {
+            FileKind::Console => Target::Console,
+            FileKind::File(_) => Target::File,
+            FileKind::Device(device) => Target::Device(device.clone()),
+            FileKind::PipeRead(reader) => Target::Pipe(reader.pipe()),
+            FileKind::PipeWrite(writer) => Target::Pipe(writer.pipe()),
+            FileKind::Queue(_) | FileKind::SharedMemory(_) => Target::Other,
+        }
+    }
+
+    /// Files with a position that `lseek` can move.
+    pub fn is_seekable(&self) -> bool {
+        matches!(self.kind, FileKind::File(_))
+    }
+
+    /// Reads at the current offset. The description is not borrowed while
+    /// blocking on the console, a device or a pipe, so other holders can keep
+    /// using it.
+    pub fn read(file: &FileRef, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let target = {
+            let open = file.borrow();
+            if !open.readable {
+                return Err("File not open for reading");
+            }
+            open.target()
+        };
+        match target {
+            Target::Console => console_read(buf),
+            Target::Device(device) => device.read(buf),
+            Target::Pipe(pipe) => pipe::read(&pipe, buf),
+            Target::Other => Err("Bad file descriptor"),
+            Target::File => match &mut file.borrow_mut().kind {
+                FileKind::File(file) => file.read(buf),
+                _ => Err("Bad file descriptor"),
+            },
+        }
+    }
+
+    pub fn write(file: &FileRef, data: &[u8]) -> Result<usize, &'static str> {
+        let target = {
+            let open = file.borrow();
+            if !open.writable {
+                return Err("File not open for writing");
+            }
+            open.target()
+        };
+        match target {
+            Target::Console => Ok(console_write(data)),
+            Target::Device(device) => device.write(data),
+            Target::Pipe(pipe) => pipe::write(&pipe, data),
+            Target::Other => Err("Bad file descriptor"),
+            Target::File => match &mut file.borrow_mut().kind {
+                FileKind::File(file) => file.write(data),
+                _ => Err("Bad file descriptor"),
+            },
+        }
+    }
+}
+
+/// Per-process table mapping descriptor numbers to open files.
+#[derive(Clone)]
+pub struct FileTable {
+    files: Vec<Option<FileRef>>,
+}
+
+impl FileTable {
+    pub fn empty() -> Self {
+        FileTable { files: Vec::new() }
+    }
+
+    /// A table with stdin, stdout and stderr attached to the console.
+    pub fn with_console() -> Self {
+        let console = OpenFile::console();
+        FileTable {
+            files: alloc::vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    /// Number of open descriptors.
+    pub fn count(&self) -> usize {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+
+    pub fn get(&self, fd: usize) -> Option<FileRef> {
+        self.files.get(fd).cloned().flatten()
+    }
+
+    /// Installs `file` at the lowest free descriptor.
+    pub fn insert(&mut self, file: FileRef) -> Result<usize, &'static str> {
+        if let Some(fd) = self.files.iter().position(|f| f.is_none()) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() >= MAX_FILES {
+            return Err("Too many open files");
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    /// Makes `fd` refer to `file`, closing whatever it referred to before.
+    pub fn set(&mut self, fd: usize, file: FileRef) -> Result<(), &'static str> {
+        if fd >= MAX_FILES {
+            return Err("Bad file descriptor");
+        }
+        if self.files.len() <= fd {
+            self.files.resize(fd + 1, None);
+        }
+        self.files[fd] = Some(file);
+        Ok(())
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<(), &'static str> {
+        match self.files.get_mut(fd) {
+            Some(slot @ Some(_)) => {
+                *slot = None;
+                Ok(())
+            }
+            _ => Err("Bad file descriptor"),
+        }
+    }
+}
+
+pub fn console_write(data: &[u8]) -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for &byte in data {
+            match byte {
+                b'\n' => writer.new_line(),
+                0x20..=0x7e => writer.write_byte(byte),
+                _ => writer.write_byte(0xfe),
+            }
+        }
+    });
+    data.len()
+}
+
+/// Blocks until a line has been typed and returns as much of it as fits.
+pub fn console_read(buf: &mut [u8]) -> Result<usize, &'static str> {
+    if CONSOLE_PENDING.lock().is_empty() {
+        buffer_clear();
+        unsafe {
+            ENTER_PRESSED = false;
+            while !ENTER_PRESSED {
+                if signal::has_pending() {
+                    return Err("Interrupted");
+                }
+                process::yield_now();
+            }
+            ENTER_PRESSED = false;
+        }
+        let mut line = [0u8; 256];
+        let len = buffer_copy(&mut line);
+        buffer_clear();
+        CONSOLE_PENDING.lock().extend_from_slice(&line[..len]);
+    }
+    let mut pending = CONSOLE_PENDING.lock();
+    let len = core::cmp::min(buf.len(), pending.len());
+    buf[..len].copy_from_slice(&pending[..len]);
+    pending.drain(..len);
+    Ok(len)
+}
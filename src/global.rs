@@ -0,0 +1,40 @@
+//! Kernel state that cannot go in a `spin::Mutex` because it is not `Send`,
+//! such as tables holding `Rc`s.
+//!
+//! There is one CPU, and the kernel is only left at points it chooses, so a
+//! `RefCell` is enough. A borrow must not be held across anything that may
+//! yield to another process, and state that interrupt handlers reach is only
+//! borrowed with interrupts disabled; doing otherwise panics instead of
+//! corrupting memory.
+
+use core::cell::{Ref, RefCell, RefMut};
+
+pub(crate) struct Global<T> {
+    inner: RefCell<T>,
+}
+
+// SAFETY: `Sync` is only needed to put a `Global` in a `static`. The kernel
+// runs on a single CPU and never touches a `Global` from two threads, so
+// the value, `Rc`s included, is only ever used from one context at a time,
+// and the `RefCell` catches a borrow that overlaps another through yielding
+// or an interrupt handler. Host unit tests do run on several threads; those
+// that reach a `Global` hold `TEST_LOCK` throughout.
+unsafe impl<T: 'static> Sync for Global<T> {}
+
+impl<T> Global<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Global { inner: RefCell::new(value) }
+    }
+
+    pub(crate) fn borrow(&self) -> Ref<'_, T> {
+        self.inner.borrow()
+    }
+
+    pub(crate) fn borrow_mut(&self) -> RefMut<'_, T> {
+        self.inner.borrow_mut()
+    }
+}
+
+/// Serializes unit tests that use kernel globals.
+#[cfg(test)]
+pub(crate) static TEST_LOCK: spin::Mutex<()> = spin::Mutex::new(());
@@ -0,0 +1,294 @@
+//! Initial ramdisk: a USTAR archive of the `initrd/` directory, packed by
+//! `build.rs` and linked into the kernel, unpacked into ramfs at boot so
+//! configuration files and programs ship with the image.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::ramfs::{Node, NodeRef};
+use crate::vfs::{FileType, Inode};
+
+static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+const BLOCK: usize = 512;
+
+const BAD_ARCHIVE: &str = "Bad initrd archive";
+
+/// Parses a NUL- or space-terminated octal field.
+fn octal(field: &[u8]) -> Result<u64, &'static str> {
+    let mut value: u64 = 0;
+    for &byte in field.iter().skip_while(|&&byte| byte == b' ') {
+        match byte {
+            b'0'..=b'7' => {
+                value = value.checked_mul(8).ok_or(BAD_ARCHIVE)? + (byte - b'0') as u64;
+            }
+            0 | b' ' => break,
+            _ => return Err(BAD_ARCHIVE),
+        }
+    }
+    Ok(value)
+}
+
+/// Text of a NUL-padded field.
+fn text(field: &[u8]) -> Result<&str, &'static str> {
+    let len = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| BAD_ARCHIVE)
+}
+
+fn check(header: &[u8]) -> Result<(), &'static str> {
+    if &header[257..262] != b"ustar" {
+        return Err(BAD_ARCHIVE);
+    }
+    // The checksum is taken with its own field counted as spaces.
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| {
+            let byte = if (148..156).contains(&i) { b' ' } else { byte };
+            byte as u64
+        })
+        .sum();
+    if sum != octal(&header[148..156])? {
+        return Err(BAD_ARCHIVE);
+    }
+    Ok(())
+}
+
+/// Splits an archive path into components, without `.` and empty ones.
+fn components(path: &str) -> Result<Vec<&str>, &'static str> {
+    let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty() && *part != ".").collect();
+    if parts.contains(&"..") {
+        return Err(BAD_ARCHIVE);
+    }
+    Ok(parts)
+}
+
+/// Walks to the directory holding `parts`' last component, creating the
+/// directories on the way.
+fn parent_dir(root: &NodeRef, parts: &[&str]) -> Result<NodeRef, &'static str> {
+    let mut dir = root.clone();
+    for &part in &parts[..parts.len() - 1] {
+        dir = match Node::get_entry(&dir, part) {
+            Some(node) if node.file_type() == FileType::Directory => node,
+            Some(_) => return Err("Not a directory"),
+            None => {
+                let node = Node::new_dir();
+                Node::add_entry(&dir, String::from(part), node.clone())?;
+                node
+            }
+        };
+    }
+    Ok(dir)
+}
+
+/// Links `node` under `name`, replacing what was there.
+fn replace_entry(dir: &NodeRef, name: &str, node: NodeRef) -> Result<(), &'static str> {
+    if Node::get_entry(dir, name).is_some() {
+        Node::remove_entry(dir, name)?;
+    }
+    Node::add_entry(dir, String::from(name), node)
+}
+
+/// Unpacks the archive into the tree under `root`, returning how many
+/// entries it had.
+pub fn unpack(root: &NodeRef) -> Result<usize, &'static str> {
+    unpack_archive(ARCHIVE, root)
+}
+
+fn unpack_archive(archive: &[u8], root: &NodeRef) -> Result<usize, &'static str> {
+    let mut offset = 0;
+    let mut count = 0;
+    // Directory times are set last, since adding entries touches them.
+    let mut directories = Vec::new();
+    while offset + BLOCK <= archive.len() {
+        let header = &archive[offset..offset + BLOCK];
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        check(header)?;
+        let size = octal(&header[124..136])? as usize;
+        let start = offset + BLOCK;
+        let end = start.checked_add(size).ok_or(BAD_ARCHIVE)?;
+        let data = archive.get(start..end).ok_or(BAD_ARCHIVE)?;
+        offset = start + size.div_ceil(BLOCK) * BLOCK;
+
+        let prefix = text(&header[345..500])?;
+        let name = text(&header[..100])?;
+        let path = if prefix.is_empty() { String::from(name) } else { alloc::format!("{}/{}", prefix, name) };
+        let parts = components(&path)?;
+        let Some(&last) = parts.last() else {
+            continue;
+        };
+        let dir = parent_dir(root, &parts)?;
+        let node = match header[156] {
+            b'0' | 0 => {
+                let node = Node::new_file();
+                node.write_at(0, data)?;
+                replace_entry(&dir, last, node.clone())?;
+                node
+            }
+            b'5' => match Node::get_entry(&dir, last) {
+                Some(node) if node.file_type() == FileType::Directory => node,
+                _ => {
+                    let node = Node::new_dir();
+                    replace_entry(&dir, last, node.clone())?;
+                    node
+                }
+            },
+            b'2' => {
+                let node = Node::new_symlink(text(&header[157..257])?);
+                replace_entry(&dir, last, node.clone())?;
+                node
+            }
+            b'1' => {
+                // A hard link names an earlier entry of the archive.
+                let mut node = root.clone();
+                for part in components(text(&header[157..257])?)? {
+                    node = Node::get_entry(&node, part).ok_or(BAD_ARCHIVE)?;
+                }
+                if node.file_type() == FileType::Directory {
+                    return Err(BAD_ARCHIVE);
+                }
+                replace_entry(&dir, last, node)?;
+                count += 1;
+                continue;
+            }
+            // Extended headers, devices and FIFOs have nothing to unpack.
+            _ => continue,
+        };
+        let mode = (octal(&header[100..108])? & 0o7777) as u16;
+        let uid = octal(&header[108..116])? as u32;
+        let mtime = octal(&header[136..148])?;
+        if node.file_type() == FileType::Directory {
+            directories.push((node, mode, uid, mtime));
+        } else {
+            set_attributes(&node, mode, uid, mtime);
+        }
+        count += 1;
+    }
+    for (node, mode, uid, mtime) in directories {
+        set_attributes(&node, mode, uid, mtime);
+    }
+    Ok(count)
+}
+
+fn set_attributes(node: &NodeRef, mode: u16, uid: u32, mtime: u64) {
+    let mut node = node.borrow_mut();
+    let attrs = node.attrs_mut();
+    attrs.mode = mode;
+    attrs.uid = uid;
+    attrs.modified = mtime;
+    attrs.accessed = mtime;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::rc::Rc;
+    use crate::vfs::{self, InodeRef};
+
+    /// Appends an entry the way `build.rs` packs it.
+    fn add(archive: &mut Vec<u8>, prefix: &str, name: &str, kind: u8, link: &str, data: &[u8]) {
+        let mut header = [0u8; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[108..116].copy_from_slice(b"0000012\0");
+        header[116..124].copy_from_slice(b"0000000\0");
+        header[124..136].copy_from_slice(alloc::format!("{:011o}\0", data.len()).as_bytes());
+        header[136..148].copy_from_slice(b"00000001750\0");
+        header[156] = kind;
+        header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+        header[148..155].copy_from_slice(alloc::format!("{:06o}\0", checksum).as_bytes());
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().div_ceil(BLOCK) * BLOCK, 0);
+    }
+
+    fn end(archive: &mut Vec<u8>) {
+        archive.resize(archive.len() + 2 * BLOCK, 0);
+    }
+
+    fn get(root: &NodeRef, path: &str) -> NodeRef {
+        path.split('/').fold(root.clone(), |dir, name| Node::get_entry(&dir, name).unwrap())
+    }
+
+    fn contents(root: &NodeRef, path: &str) -> Vec<u8> {
+        let inode: InodeRef = get(root, path);
+        vfs::read_to_end(&inode).unwrap()
+    }
+
+    #[test]
+    fn unpacks_files_directories_and_links() {
+        let mut archive = Vec::new();
+        add(&mut archive, "", "etc/", b'5', "", &[]);
+        add(&mut archive, "", "etc/motd", b'0', "", b"hello\n");
+        add(&mut archive, "", "etc/big", b'0', "", &[7; 3 * BLOCK + 1]);
+        add(&mut archive, "", "etc/greeting", b'2', "motd", &[]);
+        add(&mut archive, "", "etc/copy", b'1', "etc/motd", &[]);
+        end(&mut archive);
+
+        let root = Node::new_dir();
+        assert_eq!(unpack_archive(&archive, &root), Ok(5));
+        let motd = get(&root, "etc/motd");
+        assert_eq!(contents(&root, "etc/motd"), b"hello\n");
+        assert_eq!(contents(&root, "etc/big"), [7; 3 * BLOCK + 1]);
+        assert_eq!(get(&root, "etc/greeting").read_link().unwrap(), "motd");
+        assert!(Rc::ptr_eq(&get(&root, "etc/copy"), &motd));
+        let attrs = *motd.borrow().attrs();
+        assert_eq!(attrs.nlink, 2);
+        assert_eq!((attrs.mode, attrs.uid, attrs.modified), (0o644, 0o12, 0o1750));
+        assert_eq!(get(&root, "etc").borrow().attrs().modified, 0o1750);
+    }
+
+    #[test]
+    fn long_names_use_the_prefix_field() {
+        let mut archive = Vec::new();
+        let dir = "d".repeat(120);
+        add(&mut archive, &dir, "file", b'0', "", b"x");
+        end(&mut archive);
+
+        let root = Node::new_dir();
+        assert_eq!(unpack_archive(&archive, &root), Ok(1));
+        assert_eq!(contents(&root, &alloc::format!("{}/file", dir)), b"x");
+    }
+
+    #[test]
+    fn later_entries_replace_earlier_ones() {
+        let mut archive = Vec::new();
+        add(&mut archive, "", "file", b'0', "", b"old");
+        add(&mut archive, "", "./file", b'0', "", b"new");
+        end(&mut archive);
+
+        let root = Node::new_dir();
+        unpack_archive(&archive, &root).unwrap();
+        assert_eq!(contents(&root, "file"), b"new");
+    }
+
+    #[test]
+    fn bad_archives_are_rejected() {
+        let mut archive = Vec::new();
+        add(&mut archive, "", "file", b'0', "", b"data");
+        end(&mut archive);
+        archive[0] = b'g';
+        assert_eq!(unpack_archive(&archive, &Node::new_dir()), Err(BAD_ARCHIVE));
+
+        let mut archive = Vec::new();
+        add(&mut archive, "", "../escape", b'0', "", b"data");
+        end(&mut archive);
+        assert_eq!(unpack_archive(&archive, &Node::new_dir()), Err(BAD_ARCHIVE));
+
+        let mut archive = Vec::new();
+        add(&mut archive, "", "file", b'0', "", &[1; 2 * BLOCK]);
+        archive.truncate(2 * BLOCK);
+        assert_eq!(unpack_archive(&archive, &Node::new_dir()), Err(BAD_ARCHIVE));
+
+        let mut archive = Vec::new();
+        add(&mut archive, "", "link", b'1', "missing", &[]);
+        end(&mut archive);
+        assert_eq!(unpack_archive(&archive, &Node::new_dir()), Err(BAD_ARCHIVE));
+    }
+}
@@ -1,12 +1,17 @@
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use x86_64::{
+    VirtAddr,
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
     instructions::port::Port,
 };
 use spin::Mutex;
+use core::arch::naked_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::vec::Vec;
 
-use crate::{gdt, hlt_loop, println};
+use crate::{gdt, hlt_loop, println, process, signal, usercopy};
+use crate::syscalls::TrapFrame;
 use crate::vga_buffer::WRITER;
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -18,6 +23,39 @@ pub static mut DOWN_PRESSED: bool = false;
 pub static mut LEFT_PRESSED: bool = false;
 pub static mut RIGHT_PRESSED: bool = false;
 
+/// Timer interrupts since boot.
+pub static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Interrupts taken on each PIC line since boot.
+static IRQ_COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+fn count_irq(irq: u8) {
+    IRQ_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns `(line, count, device)` for every PIC line with a handler.
+pub fn irq_counts() -> Vec<(u8, u64, &'static str)> {
+    let pci_lines: Vec<u8> = x86_64::instructions::interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock().iter().map(|&(line, _)| line).collect()
+    });
+    (0..16u8)
+        .filter_map(|irq| {
+            let device = match irq {
+                0 => "timer",
+                1 => "keyboard",
+                12 => "mouse",
+                irq if pci_lines.contains(&irq) => "pci",
+                _ => return None,
+            };
+            Some((irq, IRQ_COUNTS[irq as usize].load(Ordering::Relaxed), device))
+        })
+        .collect()
+}
+
+/// Rate the PIT is programmed to in `init_timer`.
+pub const TIMER_HZ: u64 = 100;
+const PIT_FREQUENCY: u64 = 1_193_182;
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -26,13 +64,21 @@ lazy_static! {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
             idt[InterruptIndex::Timer.as_usize()]
-                .set_handler_fn(timer_interrupt_handler);
+                .set_handler_addr(VirtAddr::new(timer_entry as *const () as u64));
             idt[InterruptIndex::Keyboard.as_usize()]
                 .set_handler_fn(keyboard_interrupt_handler);
             idt.page_fault
                 .set_handler_fn(page_fault_handler);
+            idt.general_protection_fault
+                .set_handler_fn(general_protection_fault_handler);
+            idt.invalid_opcode
+                .set_handler_fn(invalid_opcode_handler);
             idt[InterruptIndex::Mouse.as_usize()]
                 .set_handler_fn(mouse_interrupt_handler);
+            idt[irq_vector(5)].set_handler_fn(irq5_handler);
+            idt[irq_vector(9)].set_handler_fn(irq9_handler);
+            idt[irq_vector(10)].set_handler_fn(irq10_handler);
+            idt[irq_vector(11)].set_handler_fn(irq11_handler);
         }
         idt
     };
@@ -75,15 +121,128 @@ impl InterruptIndex {
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
-{
+/// PIC lines that QEMU's i440FX routes the PCI interrupt pins to.
+pub const PCI_IRQS: [u8; 4] = [5, 9, 10, 11];
+
+/// A PCI line and a handler to call on its interrupts.
+type IrqHandler = (u8, fn());
+
+/// Handlers of the devices on the PCI lines; devices may share a line.
+static IRQ_HANDLERS: Mutex<Vec<IrqHandler>> = Mutex::new(Vec::new());
+
+fn irq_vector(irq: u8) -> usize {
+    (PIC_1_OFFSET + irq) as usize
+}
+
+macro_rules! pci_irq_handler {
+    ($name:ident, $irq:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+            dispatch_irq($irq);
+        }
+    };
+}
+
+pci_irq_handler!(irq5_handler, 5);
+pci_irq_handler!(irq9_handler, 9);
+pci_irq_handler!(irq10_handler, 10);
+pci_irq_handler!(irq11_handler, 11);
+
+fn dispatch_irq(irq: u8) {
+    count_irq(irq);
+    for (_, handler) in IRQ_HANDLERS.lock().iter().filter(|(line, _)| *line == irq) {
+        handler();
+    }
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+    }
+}
+
+/// Calls `handler` on every interrupt of PCI line `irq` and unmasks the line.
+pub fn add_irq_handler(irq: u8, handler: fn()) -> Result<(), &'static str> {
+    if !PCI_IRQS.contains(&irq) {
+        return Err("Unsupported interrupt line");
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock().push((irq, handler));
+        let mut pics = PICS.lock();
+        unsafe {
+            let [mut master, mut slave] = pics.read_masks();
+            if irq < 8 {
+                master &= !(1 << irq);
+            } else {
+                slave &= !(1 << (irq - 8));
+                // The slave PIC reaches the CPU through line 2.
+                master &= !(1 << 2);
+            }
+            pics.write_masks(master, slave);
+        }
+    });
+    Ok(())
+}
+
+fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// The timer saves every general-purpose register in a `TrapFrame`, unlike
+/// the `x86-interrupt` handlers, so that a signal handler can be entered on
+/// the way back to user mode and `sigreturn` can restore all of them.
+#[unsafe(naked)]
+extern "C" fn timer_entry() -> ! {
+    naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "cld",
+        "mov rdi, rsp",
+        "call {handler}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+        handler = sym timer_interrupt_handler,
+    );
+}
+
+extern "C" fn timer_interrupt_handler(frame: &mut TrapFrame) {
     //print!(".");
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    count_irq(0);
 
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+
+    // Only user code is preempted, the kernel switches at well-defined points.
+    if frame.from_user_mode() {
+        process::schedule();
+        signal::deliver(frame);
+    }
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(
@@ -92,10 +251,12 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use x86_64::instructions::port::Port;
 
+    count_irq(1);
+
     lazy_static! {
         static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
             Mutex::new(Keyboard::new(ScancodeSet1::new(),
-                layouts::Us104Key, HandleControl::Ignore)
+                layouts::Us104Key, HandleControl::MapLettersToUnicode)
             );
     }
 
@@ -114,6 +275,12 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
                             writer.write_byte(b'\n');
                             unsafe{ENTER_PRESSED = true;}
                         }
+                        // Ctrl+C
+                        '\x03' => {
+                            writer.write_string("^C");
+                            signal::interrupt_foreground();
+                        }
+                        c if c.is_control() && c != '\t' => {}
                         c => {
                             writer.write_byte(c as u8);
                         }
@@ -140,11 +307,22 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
 }
 
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
+    if from_user_mode(&stack_frame) {
+        println!("Segmentation fault at {:?} (pid {})", Cr2::read(), process::current_pid());
+        process::exit(process::EXIT_FAULT);
+    }
+    if let Some(fixup) = usercopy::fixup(stack_frame.instruction_pointer) {
+        unsafe {
+            stack_frame.as_mut().update(|frame| frame.instruction_pointer = fixup);
+        }
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
@@ -152,12 +330,34 @@ extern "x86-interrupt" fn page_fault_handler(
     hlt_loop();
 }
 
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    if from_user_mode(&stack_frame) {
+        println!("General protection fault (pid {})", process::current_pid());
+        process::exit(process::EXIT_FAULT);
+    }
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}", error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(
+    stack_frame: InterruptStackFrame)
+{
+    if from_user_mode(&stack_frame) {
+        println!("Illegal instruction (pid {})", process::current_pid());
+        process::exit(signal::exit_status(signal::SIGILL));
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
 lazy_static! {
     static ref MOUSE_PACKET: Mutex<[u8; 4]> = Mutex::new([0; 4]);
     static ref MOUSE_PACKET_INDEX: Mutex<usize> = Mutex::new(0);
 }
 
 extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count_irq(12);
     let mut port = Port::new(0x60);
     let data: u8 = unsafe { port.read() };
 
@@ -206,6 +406,24 @@ extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFr
     }
 }
 
+/// Sets channel 0 of the PIT to fire `TIMER_HZ` times a second.
+pub fn init_timer() {
+    let divisor = (PIT_FREQUENCY / TIMER_HZ) as u16;
+    let mut command_port = Port::new(0x43);
+    let mut data_port = Port::new(0x40);
+    unsafe {
+        // Channel 0, low byte then high byte, rate generator.
+        command_port.write(0x34u8);
+        data_port.write(divisor as u8);
+        data_port.write((divisor >> 8) as u8);
+    }
+}
+
+/// Timer ticks covering at least `ms` milliseconds.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(TIMER_HZ).div_ceil(1000)
+}
+
 pub fn init_mouse() {
     let mut command_port = Port::new(0x64);
     let mut data_port = Port::new(0x60);
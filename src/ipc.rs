@@ -0,0 +1,224 @@
+use alloc::{collections::{BTreeMap, VecDeque}, rc::Rc, string::String, vec::Vec};
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
+use x86_64::structures::paging::PhysFrame;
+
+use crate::{memory, process, signal, usercopy};
+use crate::global::Global;
+use crate::file::{FileKind, FileRef, OpenFile};
+use crate::interrupts::{self, TICKS};
+use crate::syscalls::*;
+
+/// Messages a queue holds before senders block.
+pub const MQ_MAX_MESSAGES: usize = 16;
+/// Largest single message.
+pub const MQ_MAX_SIZE: usize = 4096;
+/// Largest shared memory object.
+pub const SHM_MAX_SIZE: u64 = 4 * 1024 * 1024;
+const MAX_NAME: usize = 64;
+
+pub struct MessageQueue {
+    messages: VecDeque<Vec<u8>>,
+}
+
+pub type QueueRef = Rc<RefCell<MessageQueue>>;
+
+impl MessageQueue {
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+/// Zeroed frames that can be mapped into several address spaces at once.
+/// They are freed when the last descriptor and mapping are gone.
+pub struct SharedMemory {
+    frames: Vec<PhysFrame>,
+}
+
+pub type ShmRef = Rc<SharedMemory>;
+
+impl SharedMemory {
+    fn new(size: u64) -> Result<Self, &'static str> {
+        let pages = size.div_ceil(4096);
+        let mut object = SharedMemory { frames: Vec::with_capacity(pages as usize) };
+        for _ in 0..pages {
+            object.frames.push(memory::allocate_zeroed_frame().ok_or("Out of memory")?);
+        }
+        Ok(object)
+    }
+
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.frames
+    }
+
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * 4096
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for frame in self.frames.drain(..) {
+            memory::deallocate_frame(frame);
+        }
+    }
+}
+
+/// Named objects stay here until they are unlinked, even with nobody using them.
+static QUEUES: Global<BTreeMap<String, QueueRef>> = Global::new(BTreeMap::new());
+static SHARED: Global<BTreeMap<String, ShmRef>> = Global::new(BTreeMap::new());
+
+/// Returns `(name, messages)` for every named queue.
+pub fn queues() -> Vec<(String, usize)> {
+    QUEUES.borrow().iter().map(|(name, queue)| (name.clone(), queue.borrow().len())).collect()
+}
+
+/// Returns `(name, size, users)` for every named shared memory object.
+pub fn shared_objects() -> Vec<(String, u64, usize)> {
+    SHARED
+        .borrow()
+        .iter()
+        .map(|(name, object)| (name.clone(), object.size(), Rc::strong_count(object) - 1))
+        .collect()
+}
+
+fn read_name(ptr: u64, len: usize) -> Result<String, u64> {
+    if len == 0 || len > MAX_NAME {
+        return Err(EINVAL);
+    }
+    usercopy::read_str(ptr, len)
+}
+
+fn install(file: FileRef) -> SysResult {
+    process::with_files(|files| files.insert(file)).map(|fd| fd as u64).map_err(errno_of)
+}
+
+/// Looks `name` up in `table`, creating it with `create` if `flags` ask for it.
+fn open_named<T: Clone>(
+    table: &mut BTreeMap<String, T>,
+    name: String,
+    flags: u64,
+    create: impl FnOnce() -> Result<T, u64>,
+) -> Result<T, u64> {
+    match table.get(&name) {
+        Some(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => Err(EEXIST),
+        Some(object) => Ok(object.clone()),
+        None if flags & O_CREAT != 0 => {
+            let object = create()?;
+            table.insert(name, object.clone());
+            Ok(object)
+        }
+        None => Err(ENOENT),
+    }
+}
+
+/// Retries `attempt` until it produces a value. A negative `timeout_ms`
+/// waits forever, 0 gives up right away with `EAGAIN`.
+fn block<T>(timeout_ms: i64, mut attempt: impl FnMut() -> Result<Option<T>, u64>) -> Result<T, u64> {
+    let deadline = TICKS.load(Ordering::Relaxed) + interrupts::ms_to_ticks(timeout_ms.max(0) as u64);
+    loop {
+        if let Some(value) = attempt()? {
+            return Ok(value);
+        }
+        if timeout_ms == 0 {
+            return Err(EAGAIN);
+        }
+        if timeout_ms > 0 && TICKS.load(Ordering::Relaxed) >= deadline {
+            return Err(ETIMEDOUT);
+        }
+        if signal::has_pending() {
+            return Err(EINTR);
+        }
+        process::yield_now();
+    }
+}
+
+fn queue_of(fd: u64) -> Result<QueueRef, u64> {
+    let file = process::get_file(fd as usize).ok_or(EBADF)?;
+    let open = file.borrow();
+    match &open.kind {
+        FileKind::Queue(queue) => Ok(queue.clone()),
+        _ => Err(EBADF),
+    }
+}
+
+pub fn sys_mq_open(name: u64, name_len: usize, flags: u64) -> SysResult {
+    let name = read_name(name, name_len)?;
+    let queue = open_named(&mut QUEUES.borrow_mut(), name, flags, || {
+        Ok(Rc::new(RefCell::new(MessageQueue { messages: VecDeque::new() })))
+    })?;
+    install(OpenFile::object(FileKind::Queue(queue)))
+}
+
+pub fn sys_mq_send(fd: u64, buf: u64, len: usize, timeout_ms: i64) -> SysResult {
+    let queue = queue_of(fd)?;
+    if len > MQ_MAX_SIZE {
+        return Err(EMSGSIZE);
+    }
+    let mut message = Some(usercopy::read_bytes(buf, len)?);
+    block(timeout_ms, || {
+        let mut queue = queue.borrow_mut();
+        if queue.messages.len() >= MQ_MAX_MESSAGES {
+            return Ok(None);
+        }
+        queue.messages.extend(message.take());
+        Ok(Some(0))
+    })
+}
+
+/// Takes the oldest message; fails with `EMSGSIZE` if it does not fit in `len`.
+pub fn sys_mq_receive(fd: u64, buf: u64, len: usize, timeout_ms: i64) -> SysResult {
+    let queue = queue_of(fd)?;
+    usercopy::check_range(buf, len, true)?;
+    let message = block(timeout_ms, || {
+        let mut queue = queue.borrow_mut();
+        match queue.messages.front() {
+            Some(message) if message.len() > len => Err(EMSGSIZE),
+            Some(_) => Ok(queue.messages.pop_front()),
+            None => Ok(None),
+        }
+    })?;
+    usercopy::copy_to_user(buf, &message)?;
+    Ok(message.len() as u64)
+}
+
+pub fn sys_mq_unlink(name: u64, name_len: usize) -> SysResult {
+    let name = read_name(name, name_len)?;
+    QUEUES.borrow_mut().remove(&name).map(|_| 0).ok_or(ENOENT)
+}
+
+/// Opens a shared memory object; a new one is created with `size` bytes,
+/// rounded up to whole pages.
+pub fn sys_shm_open(name: u64, name_len: usize, flags: u64, size: u64) -> SysResult {
+    let name = read_name(name, name_len)?;
+    let object = open_named(&mut SHARED.borrow_mut(), name, flags, || {
+        if size == 0 || size > SHM_MAX_SIZE {
+            return Err(EINVAL);
+        }
+        SharedMemory::new(size).map(Rc::new).map_err(|_| ENOMEM)
+    })?;
+    install(OpenFile::object(FileKind::SharedMemory(object)))
+}
+
+/// Maps the object behind `fd` into the caller and returns its address.
+pub fn sys_shm_map(fd: u64) -> SysResult {
+    let file = process::get_file(fd as usize).ok_or(EBADF)?;
+    let object = match &file.borrow().kind {
+        FileKind::SharedMemory(object) => object.clone(),
+        _ => return Err(EBADF),
+    };
+    process::map_shared(object).map_err(errno_of)
+}
+
+pub fn sys_shm_unmap(addr: u64) -> SysResult {
+    process::unmap_shared(addr).map(|_| 0).map_err(|_| EINVAL)
+}
+
+pub fn sys_shm_unlink(name: u64, name_len: usize) -> SysResult {
+    let name = read_name(name, name_len)?;
+    SHARED.borrow_mut().remove(&name).map(|_| 0).ok_or(ENOENT)
+}
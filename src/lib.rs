@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(abi_x86_interrupt)]
 #![feature(naked_functions)]
 #![feature(asm_sym)]
@@ -8,9 +8,33 @@ pub mod vga_buffer;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+mod global;
 pub mod syscalls;
 pub mod shell;
+pub mod vfs;
 pub mod ramfs;
+pub mod process;
+pub mod elf;
+pub mod file;
+pub mod usercopy;
+pub mod serial;
+pub mod strace;
+pub mod signal;
+pub mod pipe;
+pub mod ipc;
+pub mod rtc;
+pub mod block;
+pub mod cache;
+pub mod devfs;
+pub mod procfs;
+pub mod partition;
+pub mod ata;
+pub mod pci;
+pub mod virtio;
+pub mod snapshot;
+pub mod fat;
+pub mod ext2;
+pub mod initrd;
 extern crate alloc;
 
 pub fn init() {
@@ -24,8 +48,14 @@ pub fn init() {
 
     interrupts::init_mouse();
 
+    interrupts::init_timer();
+
+    rtc::init();
+
     syscalls::init_syscall();
 
+    strace::calibrate();
+
     x86_64::instructions::interrupts::enable();
 }
 
@@ -3,6 +3,8 @@
 
 extern crate alloc;
 
+use alloc::string::String;
+
 use core::panic::PanicInfo;
 use test_os::{
     println, 
@@ -12,6 +14,7 @@ use test_os::{
     vga_buffer::Color, 
     shell::shell_loop, 
     ramfs::Node,
+    vfs,
 };
 use bootloader::{BootInfo, entry_point};
 
@@ -22,6 +25,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use test_os::memory::{self, BootInfoFrameAllocator};
     use x86_64::{VirtAddr};
     use test_os::allocator;
+    use test_os::process;
+    use test_os::{ata, devfs, partition, pci, procfs, virtio};
     
     test_os::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
@@ -29,8 +34,16 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(phys_mem_offset, frame_allocator);
 
+    ata::init();
+    virtio::init();
+    pci::init();
+    partition::init();
     Node::init_fs();
+    devfs::init();
+    procfs::init();
+    process::init();
 
     println_colored!(Color::LightCyan, Color::Black, "\n        Hello!");
     print!("    It's test OS on Rust by ");
@@ -39,6 +52,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     print!("    Write ");
     print_colored!(Color::Green, Color::Black,"help");
     println!(" to see available commands.");
+    if let Ok(motd) = vfs::lookup("/etc/motd").and_then(|motd| vfs::read_to_end(&motd)) {
+        print!("\n{}", String::from_utf8_lossy(&motd));
+    }
 
     shell_loop();
 }
@@ -1,16 +1,42 @@
 use x86_64::{
     PhysAddr, VirtAddr,
     structures::paging::{
-        FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB, Translate,
     },
 };
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// First address of the lower-half region handed out to user processes.
+pub const USER_SPACE_START: u64 = 0x0000_1000_0000_0000;
+/// End (exclusive) of the user region. It spans level 4 entries 32..64,
+/// which the bootloader leaves unused.
+pub const USER_SPACE_END: u64 = 0x0000_2000_0000_0000;
+
+/// Marks pages whose frames the address space does not own, such as shared
+/// memory; they are left alone when the address space is freed.
+const SHARED_PAGE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Start of the kernel region where device memory gets mapped.
+const MMIO_START: u64 = 0x0000_3000_0000_0000;
+
+const USER_L4_FIRST: usize = (USER_SPACE_START >> 39) as usize;
+const USER_L4_LAST: usize = ((USER_SPACE_END - 1) >> 39) as usize;
+
+static mut PHYSICAL_MEMORY_OFFSET: u64 = 0;
+static mut KERNEL_L4_FRAME: Option<PhysFrame> = None;
+static mut MMIO_NEXT: u64 = MMIO_START;
+
+pub static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
 
 pub struct EmptyFrameAllocator;
 
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    recycled: Vec<PhysFrame>,
 }
 
 unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
@@ -24,6 +50,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            recycled: Vec::new(),
         }
     }
 
@@ -34,16 +61,55 @@ impl BootInfoFrameAllocator {
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Returns `(total, free)` usable frames; frames skipped by
+    /// `allocate_contiguous` count as taken.
+    fn stats(&self) -> (usize, usize) {
+        let total = self
+            .memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| ((r.range.end_addr() - r.range.start_addr()) / 4096) as usize)
+            .sum::<usize>();
+        (total, total.saturating_sub(self.next) + self.recycled.len())
+    }
+
+    /// Takes `count` frames that follow each other in physical memory.
+    /// Frames skipped to find such a run are not handed out again.
+    fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        loop {
+            let mut frames = self.usable_frames().skip(self.next);
+            let first = frames.next()?;
+            let run = 1 + frames
+                .take(count - 1)
+                .enumerate()
+                .take_while(|&(i, frame)| frame == first + (i as u64 + 1))
+                .count();
+            self.next += run;
+            if run == count {
+                return Some(first);
+            }
+        }
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.recycled.pop() {
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.recycled.push(frame);
+    }
+}
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     unsafe {
         let level_4_table = active_level_4_table(physical_memory_offset);
@@ -51,6 +117,281 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
     }
 }
 
+/// Makes the frame allocator and the physical memory mapping available to
+/// the rest of the kernel. Must be called once the heap is up.
+pub fn init_global(physical_memory_offset: VirtAddr, frame_allocator: BootInfoFrameAllocator) {
+    use x86_64::registers::control::Cr3;
+
+    unsafe {
+        PHYSICAL_MEMORY_OFFSET = physical_memory_offset.as_u64();
+        KERNEL_L4_FRAME = Some(Cr3::read().0);
+    }
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(unsafe { PHYSICAL_MEMORY_OFFSET } + addr.as_u64())
+}
+
+pub fn kernel_l4_frame() -> PhysFrame {
+    unsafe { KERNEL_L4_FRAME.expect("memory not initialized") }
+}
+
+/// Flags of the page containing `addr` in the active address space.
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::mapper::TranslateResult;
+
+    let table = unsafe { &mut *table_ptr(Cr3::read().0) };
+    let mapper = unsafe { OffsetPageTable::new(table, phys_to_virt(PhysAddr::new(0))) };
+    match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None,
+    }
+}
+
+pub fn is_user_range(start: u64, len: u64) -> bool {
+    match start.checked_add(len) {
+        Some(end) => start >= USER_SPACE_START && end <= USER_SPACE_END,
+        None => false,
+    }
+}
+
+pub fn allocate_zeroed_frame() -> Option<PhysFrame> {
+    let frame = FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()?;
+    let ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe { core::ptr::write_bytes(ptr, 0, 4096) };
+    Some(frame)
+}
+
+/// Returns `(total, free)` frames of physical memory.
+pub fn frame_stats() -> (usize, usize) {
+    FRAME_ALLOCATOR.lock().as_ref().map_or((0, 0), BootInfoFrameAllocator::stats)
+}
+
+pub fn deallocate_frame(frame: PhysFrame) {
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+}
+
+/// Zeroed, physically contiguous memory for a device to read and write
+/// directly. It is never freed.
+pub fn allocate_dma(pages: usize) -> Option<PhysAddr> {
+    let frame = FRAME_ALLOCATOR.lock().as_mut()?.allocate_contiguous(pages)?;
+    let ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe { core::ptr::write_bytes(ptr, 0, pages * 4096) };
+    Some(frame.start_address())
+}
+
+/// Physical address behind a kernel virtual address, such as one on the heap.
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    let table = unsafe { &mut *table_ptr(kernel_l4_frame()) };
+    let mapper = unsafe { OffsetPageTable::new(table, phys_to_virt(PhysAddr::new(0))) };
+    mapper.translate_addr(addr)
+}
+
+/// Maps device registers at `phys` into the kernel half, uncached. Must be
+/// called before processes are created, since they copy the kernel half
+/// of the page table when they start.
+pub fn map_mmio(phys: PhysAddr, len: u64) -> Result<VirtAddr, &'static str> {
+    let offset = phys.as_u64() % 4096;
+    let pages = (offset + len).div_ceil(4096);
+    let start = unsafe { MMIO_NEXT };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let table = unsafe { &mut *table_ptr(kernel_l4_frame()) };
+    let mut mapper = unsafe { OffsetPageTable::new(table, phys_to_virt(PhysAddr::new(0))) };
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    for i in 0..pages {
+        unsafe {
+            mapper
+                .map_to(first_page + i, first_frame + i, flags, &mut GlobalFrameAllocator)
+                .map_err(|_| "Failed to map page")?
+                .flush();
+        }
+    }
+    unsafe { MMIO_NEXT += pages * 4096 };
+    Ok(VirtAddr::new(start + offset))
+}
+
+struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_zeroed_frame()
+    }
+}
+
+/// Page tables of a user process. The kernel half is shared with the boot
+/// page table, the user region is private and freed on drop.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, &'static str> {
+        let frame = allocate_zeroed_frame().ok_or("Out of memory")?;
+        let kernel_table = unsafe { &*table_ptr(kernel_l4_frame()) };
+        let table = unsafe { &mut *table_ptr(frame) };
+        for (i, entry) in kernel_table.iter().enumerate() {
+            if !(USER_L4_FIRST..=USER_L4_LAST).contains(&i) {
+                table[i] = entry.clone();
+            }
+        }
+        Ok(AddressSpace { level_4_frame: frame })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe {
+            OffsetPageTable::new(&mut *table_ptr(self.level_4_frame), phys_to_virt(PhysAddr::new(0)))
+        }
+    }
+
+    /// Maps zeroed pages over `[start, start + len)`. Pages that are already
+    /// mapped are left untouched.
+    pub fn map_user(&mut self, start: VirtAddr, len: u64, writable: bool) -> Result<(), &'static str> {
+        if len == 0 {
+            return Ok(());
+        }
+        if !is_user_range(start.as_u64(), len) {
+            return Err("Address outside of user space");
+        }
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + (len - 1));
+        let mut mapper = self.mapper();
+        for page in Page::range_inclusive(first, last) {
+            if mapper.translate_page(page).is_ok() {
+                continue;
+            }
+            let frame = allocate_zeroed_frame().ok_or("Out of memory")?;
+            unsafe {
+                mapper
+                    .map_to_with_table_flags(page, frame, flags, table_flags, &mut GlobalFrameAllocator)
+                    .map_err(|_| "Failed to map page")?
+                    .flush();
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps `frames` one after another starting at `start`. The frames stay
+    /// owned by the caller.
+    pub fn map_shared(&mut self, start: VirtAddr, frames: &[PhysFrame], writable: bool) -> Result<(), &'static str> {
+        if !is_user_range(start.as_u64(), frames.len() as u64 * 4096) {
+            return Err("Address outside of user space");
+        }
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | SHARED_PAGE;
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let first = Page::<Size4KiB>::containing_address(start);
+        let mut mapper = self.mapper();
+        for (i, frame) in frames.iter().enumerate() {
+            unsafe {
+                mapper
+                    .map_to_with_table_flags(first + i as u64, *frame, flags, table_flags, &mut GlobalFrameAllocator)
+                    .map_err(|_| "Failed to map page")?
+                    .flush();
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the mappings made by `map_shared` without freeing the frames.
+    pub fn unmap_shared(&mut self, start: VirtAddr, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + (len - 1));
+        let mut mapper = self.mapper();
+        for page in Page::range_inclusive(first, last) {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+    }
+
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(addr)
+    }
+
+    /// Copies `data` into this address space through the physical memory mapping.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), &'static str> {
+        let mut done = 0;
+        while done < data.len() {
+            let virt = addr + done as u64;
+            let phys = self.translate(virt).ok_or("Page not mapped")?;
+            let chunk = core::cmp::min(data.len() - done, 4096 - (virt.as_u64() % 4096) as usize);
+            unsafe {
+                let dst: *mut u8 = phys_to_virt(phys).as_mut_ptr();
+                core::ptr::copy_nonoverlapping(data[done..].as_ptr(), dst, chunk);
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    pub fn activate(&self) {
+        activate(self.level_4_frame);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let table = unsafe { &mut *table_ptr(self.level_4_frame) };
+        for i in USER_L4_FIRST..=USER_L4_LAST {
+            if !table[i].is_unused() {
+                free_table(table[i].frame().ok(), 3);
+                table[i].set_unused();
+            }
+        }
+        deallocate_frame(self.level_4_frame);
+    }
+}
+
+fn free_table(frame: Option<PhysFrame>, level: u8) {
+    let frame = match frame {
+        Some(frame) => frame,
+        None => return,
+    };
+    let table = unsafe { &*table_ptr(frame) };
+    for entry in table.iter().filter(|e| !e.is_unused()) {
+        if level > 1 {
+            free_table(entry.frame().ok(), level - 1);
+        } else if entry.flags().contains(SHARED_PAGE) {
+            continue;
+        } else if let Ok(page_frame) = entry.frame() {
+            deallocate_frame(page_frame);
+        }
+    }
+    deallocate_frame(frame);
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+pub fn activate(level_4_frame: PhysFrame) {
+    use x86_64::registers::control::Cr3;
+
+    let (current, flags) = Cr3::read();
+    if current != level_4_frame {
+        unsafe { Cr3::write(level_4_frame, flags) };
+    }
+}
+
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     -> &'static mut PageTable
 {
@@ -63,4 +404,4 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
 
     unsafe { &mut *page_table_ptr }
-}
\ No newline at end of file
+}
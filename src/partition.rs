@@ -0,0 +1,368 @@
+//! MBR and GPT partition tables. Every partition found is registered as a
+//! block device of its own, named after its disk (`hda1`, `vda2`), whose
+//! sector numbers are translated to the disk's.
+
+use alloc::{format, rc::Rc, string::String, vec, vec::Vec};
+use core::convert::TryInto;
+use spin::Mutex;
+
+use crate::block::{self, BlockDevice, DeviceRef};
+use crate::serial_println;
+
+const MBR_SIGNATURE: u16 = 0xAA55;
+const MBR_ENTRIES: usize = 446;
+
+const TYPE_EMPTY: u8 = 0x00;
+const TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_LBA: u64 = 1;
+
+/// Logical partitions followed in an extended partition before giving up.
+const MAX_LOGICAL: usize = 64;
+
+/// A slice of a disk.
+pub struct Partition {
+    name: String,
+    disk: DeviceRef,
+    start: u64,
+    sectors: u64,
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        self.disk.read_sectors(self.start + lba, buf)
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        self.disk.write_sectors(self.start + lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        self.disk.flush()
+    }
+}
+
+/// What the tables say about a partition.
+#[derive(Debug, Clone)]
+pub struct PartitionInfo {
+    pub name: String,
+    pub disk: String,
+    pub number: u32,
+    pub start: u64,
+    pub sectors: u64,
+    /// Type name, such as "Linux" or "EFI System".
+    pub kind: String,
+    /// Name stored in a GPT entry.
+    pub label: String,
+}
+
+static PARTITIONS: Mutex<Vec<PartitionInfo>> = Mutex::new(Vec::new());
+
+pub fn partitions() -> Vec<PartitionInfo> {
+    PARTITIONS.lock().clone()
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read(disk: &DeviceRef, lba: u64, sectors: usize) -> Result<Vec<u8>, &'static str> {
+    let mut buf = vec![0; sectors * disk.sector_size()];
+    disk.read_sectors(lba, &mut buf)?;
+    Ok(buf)
+}
+
+/// CRC-32 as used by GPT (IEEE 802.3, reflected).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn mbr_type_name(kind: u8) -> String {
+    let name = match kind {
+        0x01 => "FAT12",
+        0x04 | 0x06 | 0x0E => "FAT16",
+        0x05 | 0x0F | 0x85 => "Extended",
+        0x07 => "NTFS/exFAT",
+        0x0B | 0x0C => "FAT32",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x8E => "Linux LVM",
+        0xEF => "EFI System",
+        _ => return format!("Unknown ({:#04x})", kind),
+    };
+    String::from(name)
+}
+
+fn is_extended(kind: u8) -> bool {
+    matches!(kind, 0x05 | 0x0F | 0x85)
+}
+
+/// A GUID in its usual text form; the first three groups are stored
+/// little-endian.
+fn guid_string(guid: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+        u32_at(guid, 0), u16_at(guid, 4), u16_at(guid, 6),
+        guid[8], guid[9], guid[10], guid[11], guid[12], guid[13], guid[14], guid[15]
+    )
+}
+
+fn gpt_type_name(guid: &str) -> String {
+    let name = match guid {
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B" => "EFI System",
+        "21686148-6449-6E6F-744E-656564454649" => "BIOS boot",
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => "Linux filesystem",
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => "Linux swap",
+        "E6D6D379-F507-44C2-A23C-238F2A3DF928" => "Linux LVM",
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => "Microsoft basic data",
+        _ => return String::from(guid),
+    };
+    String::from(name)
+}
+
+/// (number, start, sectors, kind, label) of each partition on `disk`.
+type Entry = (u32, u64, u64, String, String);
+
+fn parse_mbr(disk: &DeviceRef, sector: &[u8]) -> Result<Vec<Entry>, &'static str> {
+    let mut found = Vec::new();
+    for i in 0..4 {
+        let entry = &sector[MBR_ENTRIES + i * 16..MBR_ENTRIES + (i + 1) * 16];
+        let kind = entry[4];
+        let start = u32_at(entry, 8) as u64;
+        let sectors = u32_at(entry, 12) as u64;
+        if kind == TYPE_EMPTY || sectors == 0 {
+            continue;
+        }
+        if is_extended(kind) {
+            parse_extended(disk, start, &mut found)?;
+        } else {
+            found.push((i as u32 + 1, start, sectors, mbr_type_name(kind), String::new()));
+        }
+    }
+    Ok(found)
+}
+
+/// Follows the chain of extended boot records; each holds one logical
+/// partition, relative to itself, and a link relative to the extended one.
+fn parse_extended(disk: &DeviceRef, extended: u64, found: &mut Vec<Entry>) -> Result<(), &'static str> {
+    let mut ebr = extended;
+    for number in 5..5 + MAX_LOGICAL as u32 {
+        let sector = read(disk, ebr, 1)?;
+        if u16_at(&sector, 510) != MBR_SIGNATURE {
+            return Err("Bad extended boot record");
+        }
+        let entry = &sector[MBR_ENTRIES..MBR_ENTRIES + 16];
+        if entry[4] != TYPE_EMPTY && u32_at(entry, 12) != 0 {
+            let start = ebr + u32_at(entry, 8) as u64;
+            found.push((number, start, u32_at(entry, 12) as u64, mbr_type_name(entry[4]), String::new()));
+        }
+        let link = &sector[MBR_ENTRIES + 16..MBR_ENTRIES + 32];
+        if !is_extended(link[4]) || u32_at(link, 8) == 0 {
+            break;
+        }
+        ebr = extended + u32_at(link, 8) as u64;
+    }
+    Ok(())
+}
+
+fn parse_gpt(disk: &DeviceRef) -> Result<Vec<Entry>, &'static str> {
+    let sector = read(disk, GPT_HEADER_LBA, 1)?;
+    if &sector[..8] != GPT_SIGNATURE {
+        return Err("Bad GPT signature");
+    }
+    let header_size = u32_at(&sector, 12) as usize;
+    if !(92..=sector.len()).contains(&header_size) {
+        return Err("Bad GPT header size");
+    }
+    let mut header = sector[..header_size].to_vec();
+    header[16..20].fill(0);
+    if crc32(&header) != u32_at(&sector, 16) {
+        return Err("Bad GPT header checksum");
+    }
+
+    let entries_lba = u64_at(&sector, 72);
+    let count = u32_at(&sector, 80) as usize;
+    let entry_size = u32_at(&sector, 84) as usize;
+    if !(128..=4096).contains(&entry_size) || count > 1024 {
+        return Err("Bad GPT entry table");
+    }
+    let table_sectors = (count * entry_size).div_ceil(disk.sector_size());
+    let table = read(disk, entries_lba, table_sectors)?;
+    if crc32(&table[..count * entry_size]) != u32_at(&sector, 88) {
+        return Err("Bad GPT entry table checksum");
+    }
+
+    let mut found = Vec::new();
+    for (i, entry) in table.chunks_exact(entry_size).take(count).enumerate() {
+        if entry[..16].iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let first = u64_at(entry, 32);
+        let last = u64_at(entry, 40);
+        if last < first {
+            continue;
+        }
+        let name: Vec<u16> = (0..36).map(|j| u16_at(entry, 56 + j * 2)).take_while(|&c| c != 0).collect();
+        let label = String::from_utf16_lossy(&name);
+        let kind = gpt_type_name(&guid_string(&entry[..16]));
+        found.push((i as u32 + 1, first, last - first + 1, kind, label));
+    }
+    Ok(found)
+}
+
+/// Partitions of `disk`, from its GPT if the MBR is a protective one.
+fn scan(disk: &DeviceRef) -> Result<Vec<Entry>, &'static str> {
+    let sector = read(disk, 0, 1)?;
+    if sector.len() < 512 || u16_at(&sector, 510) != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    let protective = (0..4).any(|i| sector[MBR_ENTRIES + i * 16 + 4] == TYPE_GPT_PROTECTIVE);
+    if protective {
+        parse_gpt(disk)
+    } else {
+        parse_mbr(disk, &sector)
+    }
+}
+
+/// Reads the partition tables of every disk registered so far and
+/// registers their partitions.
+pub fn init() {
+    for disk in block::devices() {
+        let entries = match scan(&disk) {
+            Ok(entries) => entries,
+            Err(e) => {
+                serial_println!("{}: {}", disk.name(), e);
+                continue;
+            }
+        };
+        // `nvme0n1` gets `nvme0n1p1`, `hda` gets `hda1`.
+        let separator = if disk.name().ends_with(|c: char| c.is_ascii_digit()) { "p" } else { "" };
+        for (number, start, sectors, kind, label) in entries {
+            let name = format!("{}{}{}", disk.name(), separator, number);
+            if start.checked_add(sectors).is_none_or(|end| end > disk.sectors()) {
+                serial_println!("{}: partition extends past the end of the disk", name);
+                continue;
+            }
+            block::register(Rc::new(Partition { name: name.clone(), disk: disk.clone(), start, sectors }));
+            let info = PartitionInfo { name, disk: String::from(disk.name()), number, start, sectors, kind, label };
+            PARTITIONS.lock().push(info);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    fn mbr_entry(disk: &RamDisk, sector: u64, slot: usize, kind: u8, start: u32, sectors: u32) {
+        let offset = sector as usize * 512 + MBR_ENTRIES + slot * 16;
+        disk.put(offset + 4, &[kind]);
+        disk.put(offset + 8, &start.to_le_bytes());
+        disk.put(offset + 12, &sectors.to_le_bytes());
+        disk.put(sector as usize * 512 + 510, &MBR_SIGNATURE.to_le_bytes());
+    }
+
+    fn entry(number: u32, start: u64, sectors: u64, kind: &str, label: &str) -> Entry {
+        (number, start, sectors, String::from(kind), String::from(label))
+    }
+
+    /// A disk with a protective MBR and a GPT of four entries, the first
+    /// one a Linux partition named "root".
+    fn gpt_disk() -> Rc<RamDisk> {
+        let disk = RamDisk::new(64, 512);
+        mbr_entry(&disk, 0, 0, TYPE_GPT_PROTECTIVE, 1, 63);
+        let mut table = [0; 4 * 128];
+        table[..16].copy_from_slice(&[
+            0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4,
+        ]);
+        table[32..40].copy_from_slice(&34u64.to_le_bytes());
+        table[40..48].copy_from_slice(&40u64.to_le_bytes());
+        for (i, c) in "root".encode_utf16().enumerate() {
+            table[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        disk.put(2 * 512, &table);
+
+        let mut header = [0; 92];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&table).to_le_bytes());
+        let crc = crc32(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk.put(512, &header);
+        disk
+    }
+
+    #[test]
+    fn mbr_primary_and_logical_partitions() {
+        let disk = RamDisk::new(64, 512);
+        mbr_entry(&disk, 0, 0, 0x83, 2, 10);
+        mbr_entry(&disk, 0, 1, 0x05, 20, 40);
+        // Logical partitions are relative to their EBR, links to the
+        // extended partition.
+        mbr_entry(&disk, 20, 0, 0x0B, 1, 5);
+        mbr_entry(&disk, 20, 1, 0x05, 10, 30);
+        mbr_entry(&disk, 30, 0, 0x83, 2, 4);
+        let disk: DeviceRef = disk;
+        assert_eq!(
+            scan(&disk).unwrap(),
+            [entry(1, 2, 10, "Linux", ""), entry(5, 21, 5, "FAT32", ""), entry(6, 32, 4, "Linux", "")]
+        );
+    }
+
+    #[test]
+    fn disk_without_signature_has_no_partitions() {
+        let disk: DeviceRef = RamDisk::new(8, 512);
+        assert!(scan(&disk).unwrap().is_empty());
+    }
+
+    #[test]
+    fn gpt_partitions() {
+        let disk: DeviceRef = gpt_disk();
+        assert_eq!(scan(&disk).unwrap(), [entry(1, 34, 7, "Linux filesystem", "root")]);
+    }
+
+    #[test]
+    fn gpt_checksums_are_checked() {
+        let disk = gpt_disk();
+        disk.put(2 * 512 + 100, &[1]);
+        let device: DeviceRef = disk.clone();
+        assert_eq!(scan(&device), Err("Bad GPT entry table checksum"));
+
+        disk.put(512 + 80, &5u32.to_le_bytes());
+        assert_eq!(scan(&device), Err("Bad GPT header checksum"));
+    }
+}
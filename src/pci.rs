@@ -0,0 +1,397 @@
+//! PCI bus: configuration space through the legacy 0xCF8/0xCFC ports,
+//! enumeration of every bus behind the host and PCI-to-PCI bridges, and a
+//! registry of drivers matched by vendor/device or class ID.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::serial_println;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const REG_VENDOR: u8 = 0x00;
+const REG_DEVICE: u8 = 0x02;
+const REG_COMMAND: u8 = 0x04;
+const REG_STATUS: u8 = 0x06;
+const REG_REVISION: u8 = 0x08;
+const REG_PROG_IF: u8 = 0x09;
+const REG_SUBCLASS: u8 = 0x0A;
+const REG_CLASS: u8 = 0x0B;
+const REG_HEADER_TYPE: u8 = 0x0E;
+const REG_BAR0: u8 = 0x10;
+const REG_SECONDARY_BUS: u8 = 0x19;
+const REG_CAPABILITIES: u8 = 0x34;
+const REG_INTERRUPT_LINE: u8 = 0x3C;
+
+const COMMAND_IO: u16 = 0x0001;
+const COMMAND_MEMORY: u16 = 0x0002;
+const COMMAND_BUS_MASTER: u16 = 0x0004;
+const COMMAND_INTX_DISABLE: u16 = 0x0400;
+
+const STATUS_CAPABILITIES: u16 = 0x0010;
+
+const HEADER_BRIDGE: u8 = 0x01;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+/// Where a base address register points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io(u16),
+    Memory(u64),
+}
+
+/// A decoded base address register with the size of its range.
+#[derive(Debug, Clone, Copy)]
+pub struct BarInfo {
+    pub index: u8,
+    pub bar: Bar,
+    pub size: u64,
+    pub is_64: bool,
+    pub prefetchable: bool,
+}
+
+/// A function found on the bus, with what enumeration learned about it.
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub bars: Vec<BarInfo>,
+    /// (id, offset) of each capability.
+    pub capabilities: Vec<(u8, u8)>,
+    pub interrupt_line: u8,
+    /// Name of the driver that took the device.
+    pub driver: Option<&'static str>,
+}
+
+/// How a driver recognises its devices.
+#[derive(Debug, Clone, Copy)]
+pub enum Match {
+    Device(u16, u16),
+    Class(u8, u8),
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    pub probe: fn(&PciDevice) -> Result<(), &'static str>,
+}
+
+static DRIVERS: Mutex<Vec<&'static Driver>> = Mutex::new(Vec::new());
+static DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+
+impl PciAddress {
+    fn config_address(&self, offset: u8) -> u32 {
+        0x8000_0000
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn vendor(&self) -> u16 {
+        self.read_u16(REG_VENDOR)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(REG_DEVICE)
+    }
+
+    pub fn interrupt_line(&self) -> u8 {
+        self.read_u8(REG_INTERRUPT_LINE)
+    }
+
+    /// Decodes base address register `index`, joining the two halves of a
+    /// 64-bit one.
+    pub fn bar(&self, index: u8) -> Bar {
+        let offset = REG_BAR0 + index * 4;
+        let low = self.read_u32(offset);
+        if low & 1 != 0 {
+            return Bar::Io((low & !0x3) as u16);
+        }
+        let mut address = (low & !0xF) as u64;
+        if (low >> 1) & 0x3 == 0x2 {
+            address |= (self.read_u32(offset + 4) as u64) << 32;
+        }
+        Bar::Memory(address)
+    }
+
+    /// Decodes base address register `index` and finds the size of its
+    /// range by writing all ones and reading back which bits stick.
+    /// Decoding is off meanwhile, so the device does not answer at the
+    /// probe address.
+    pub fn probe_bar(&self, index: u8) -> Option<BarInfo> {
+        let offset = REG_BAR0 + index * 4;
+        let low = self.read_u32(offset);
+        let is_io = low & 1 != 0;
+        let is_64 = !is_io && (low >> 1) & 0x3 == 0x2;
+        let command = self.read_u16(REG_COMMAND);
+        self.write_u32(REG_COMMAND, (command & !(COMMAND_IO | COMMAND_MEMORY)) as u32);
+
+        self.write_u32(offset, 0xFFFF_FFFF);
+        let mut mask = self.read_u32(offset) as u64;
+        self.write_u32(offset, low);
+        if is_64 {
+            let high = self.read_u32(offset + 4);
+            self.write_u32(offset + 4, 0xFFFF_FFFF);
+            mask |= (self.read_u32(offset + 4) as u64) << 32;
+            self.write_u32(offset + 4, high);
+        } else {
+            mask |= 0xFFFF_FFFF_0000_0000;
+        }
+        self.write_u32(REG_COMMAND, command as u32);
+
+        let mask = if is_io { mask & !0x3 } else { mask & !0xF };
+        if mask & 0xFFFF_FFFF == 0 {
+            return None;
+        }
+        Some(BarInfo {
+            index,
+            bar: self.bar(index),
+            size: (!mask).wrapping_add(1) & if is_io { 0xFFFF } else { u64::MAX },
+            is_64,
+            prefetchable: !is_io && low & 0x8 != 0,
+        })
+    }
+
+    /// Offsets of the capabilities in configuration space, as (id, offset).
+    pub fn capabilities(&self) -> Vec<(u8, u8)> {
+        let mut found = Vec::new();
+        if self.read_u16(REG_STATUS) & STATUS_CAPABILITIES == 0 {
+            return found;
+        }
+        let mut offset = self.read_u8(REG_CAPABILITIES) & 0xFC;
+        // A broken list could loop; there is room for at most 48 entries.
+        while offset != 0 && found.len() < 48 {
+            found.push((self.read_u8(offset), offset));
+            offset = self.read_u8(offset + 1) & 0xFC;
+        }
+        found
+    }
+
+    /// Lets the device decode its address ranges, master the bus and raise
+    /// its legacy interrupt.
+    pub fn enable(&self) {
+        let command = self.read_u16(REG_COMMAND);
+        let command = (command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER) & !COMMAND_INTX_DISABLE;
+        // The status half is written as zeros, which leaves its
+        // write-one-to-clear bits alone.
+        self.write_u32(REG_COMMAND, command as u32);
+    }
+}
+
+fn read_device(address: PciAddress) -> PciDevice {
+    let header_type = address.read_u8(REG_HEADER_TYPE) & 0x7F;
+    let bar_count = match header_type {
+        0 => 6,
+        HEADER_BRIDGE => 2,
+        _ => 0,
+    };
+    let mut bars = Vec::new();
+    let mut index = 0;
+    while index < bar_count {
+        match address.probe_bar(index) {
+            Some(bar) => {
+                // The upper half of a 64-bit register is not a register of its own.
+                index += if bar.is_64 { 2 } else { 1 };
+                bars.push(bar);
+            }
+            None => index += 1,
+        }
+    }
+    PciDevice {
+        address,
+        vendor: address.vendor(),
+        device: address.device_id(),
+        class: address.read_u8(REG_CLASS),
+        subclass: address.read_u8(REG_SUBCLASS),
+        prog_if: address.read_u8(REG_PROG_IF),
+        revision: address.read_u8(REG_REVISION),
+        bars,
+        capabilities: address.capabilities(),
+        interrupt_line: address.interrupt_line(),
+        driver: None,
+    }
+}
+
+fn scan_bus(bus: u8, found: &mut Vec<PciDevice>, scanned: &mut [bool; 256]) {
+    if scanned[bus as usize] {
+        return;
+    }
+    scanned[bus as usize] = true;
+    for device in 0..32 {
+        let first = PciAddress { bus, device, function: 0 };
+        if first.vendor() == 0xFFFF {
+            continue;
+        }
+        let functions = if first.read_u8(REG_HEADER_TYPE) & HEADER_MULTIFUNCTION != 0 { 8 } else { 1 };
+        for function in 0..functions {
+            let address = PciAddress { bus, device, function };
+            if address.vendor() == 0xFFFF {
+                continue;
+            }
+            let device = read_device(address);
+            let is_bridge = device.class == CLASS_BRIDGE && device.subclass == SUBCLASS_PCI_BRIDGE;
+            found.push(device);
+            if is_bridge {
+                scan_bus(address.read_u8(REG_SECONDARY_BUS), found, scanned);
+            }
+        }
+    }
+}
+
+/// Walks every bus reachable from the host bridges. A multi-function host
+/// bridge has one function per host controller, each with its own bus.
+fn enumerate() -> Vec<PciDevice> {
+    let mut found = Vec::new();
+    let mut scanned = [false; 256];
+    let host = PciAddress { bus: 0, device: 0, function: 0 };
+    if host.read_u8(REG_HEADER_TYPE) & HEADER_MULTIFUNCTION == 0 {
+        scan_bus(0, &mut found, &mut scanned);
+    } else {
+        for function in 0..8 {
+            if (PciAddress { function, ..host }).vendor() != 0xFFFF {
+                scan_bus(function, &mut found, &mut scanned);
+            }
+        }
+    }
+    found
+}
+
+/// Makes `driver` a candidate for the devices `init` finds.
+pub fn register_driver(driver: &'static Driver) {
+    DRIVERS.lock().push(driver);
+}
+
+fn matches(device: &PciDevice, pattern: &Match) -> bool {
+    match *pattern {
+        Match::Device(vendor, id) => device.vendor == vendor && device.device == id,
+        Match::Class(class, subclass) => device.class == class && device.subclass == subclass,
+    }
+}
+
+/// Enumerates the bus and hands each device to the first registered driver
+/// that matches it and accepts it.
+pub fn init() {
+    let mut devices = enumerate();
+    let drivers = DRIVERS.lock().clone();
+    for device in devices.iter_mut() {
+        for driver in &drivers {
+            if !driver.matches.iter().any(|pattern| matches(device, pattern)) {
+                continue;
+            }
+            match (driver.probe)(device) {
+                Ok(()) => {
+                    device.driver = Some(driver.name);
+                    break;
+                }
+                Err(e) => serial_println!("{}: {}: {}", device.address, driver.name, e),
+            }
+        }
+    }
+    *DEVICES.lock() = devices;
+}
+
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+impl core::fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+pub fn vendor_name(vendor: u16) -> Option<&'static str> {
+    match vendor {
+        0x8086 => Some("Intel"),
+        0x1234 => Some("QEMU"),
+        0x1AF4 | 0x1B36 => Some("Red Hat"),
+        0x10EC => Some("Realtek"),
+        0x1022 => Some("AMD"),
+        _ => None,
+    }
+}
+
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x05) => "ATA controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x01) => "Audio device",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus",
+        (0x0C, _) => "Serial bus controller",
+        (0xFF, _) => "Unassigned class",
+        _ => "Unknown class",
+    }
+}
+
+pub fn capability_name(id: u8) -> &'static str {
+    match id {
+        0x01 => "Power Management",
+        0x05 => "MSI",
+        0x09 => "Vendor Specific",
+        0x0D => "Bridge subsystem vendor",
+        0x10 => "PCI Express",
+        0x11 => "MSI-X",
+        0x12 => "SATA",
+        _ => "Unknown",
+    }
+}
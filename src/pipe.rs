@@ -0,0 +1,108 @@
+use alloc::{collections::VecDeque, rc::Rc};
+use core::cell::RefCell;
+
+use crate::{process, signal};
+
+/// Bytes a pipe buffers before writers block.
+pub const PIPE_CAPACITY: usize = 4096;
+
+pub struct Pipe {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+pub type PipeRef = Rc<RefCell<Pipe>>;
+
+/// Read end of a pipe. Ends are counted so that readers see end of file
+/// once every writer is gone, and writers fail once every reader is gone.
+pub struct PipeReader(PipeRef);
+
+pub struct PipeWriter(PipeRef);
+
+pub fn new() -> (PipeReader, PipeWriter) {
+    let pipe = Rc::new(RefCell::new(Pipe {
+        buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+        readers: 1,
+        writers: 1,
+    }));
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+impl PipeReader {
+    pub fn pipe(&self) -> PipeRef {
+        self.0.clone()
+    }
+}
+
+impl PipeWriter {
+    pub fn pipe(&self) -> PipeRef {
+        self.0.clone()
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.borrow_mut().readers -= 1;
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.borrow_mut().writers -= 1;
+    }
+}
+
+/// Blocks until data is available and returns as much as fits in `buf`;
+/// returns 0 once the pipe is empty and has no writers left.
+pub fn read(pipe: &PipeRef, buf: &mut [u8]) -> Result<usize, &'static str> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    loop {
+        {
+            let mut pipe = pipe.borrow_mut();
+            if !pipe.buffer.is_empty() {
+                let len = core::cmp::min(buf.len(), pipe.buffer.len());
+                for (dst, byte) in buf.iter_mut().zip(pipe.buffer.drain(..len)) {
+                    *dst = byte;
+                }
+                return Ok(len);
+            }
+            if pipe.writers == 0 {
+                return Ok(0);
+            }
+        }
+        if signal::has_pending() {
+            return Err("Interrupted");
+        }
+        process::yield_now();
+    }
+}
+
+/// Blocks until all of `data` is buffered. Writing with no readers left
+/// raises SIGPIPE and fails.
+pub fn write(pipe: &PipeRef, data: &[u8]) -> Result<usize, &'static str> {
+    let mut written = 0;
+    while written < data.len() {
+        {
+            let mut pipe = pipe.borrow_mut();
+            if pipe.readers == 0 {
+                let _ = signal::send(process::current_pid(), signal::SIGPIPE);
+                return if written > 0 { Ok(written) } else { Err("Broken pipe") };
+            }
+            let space = PIPE_CAPACITY - pipe.buffer.len();
+            if space > 0 {
+                let len = core::cmp::min(space, data.len() - written);
+                pipe.buffer.extend(&data[written..written + len]);
+                written += len;
+                continue;
+            }
+        }
+        if signal::has_pending() {
+            return if written > 0 { Ok(written) } else { Err("Interrupted") };
+        }
+        process::yield_now();
+    }
+    Ok(written)
+}
@@ -0,0 +1,572 @@
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec, vec::Vec};
+use core::arch::naked_asm;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{VirtAddr, instructions::interrupts};
+
+use crate::{elf, gdt, signal, syscalls};
+use crate::file::{FileRef, FileTable};
+use crate::global::Global;
+use crate::ipc::ShmRef;
+use crate::memory::{self, AddressSpace, USER_SPACE_END, USER_SPACE_START};
+use crate::vfs;
+use crate::signal::SignalState;
+use crate::syscalls::TrapFrame;
+
+pub type Pid = u64;
+
+/// The kernel itself (the shell) is process 0; it never leaves ring 0.
+pub const KERNEL_PID: Pid = 0;
+
+/// Exit status of a process killed by a CPU exception.
+pub const EXIT_FAULT: i32 = 139;
+
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+const USER_STACK_SIZE: u64 = 4096 * 16;
+/// One unmapped guard page is left between the stack and the end of user space.
+const USER_STACK_TOP: u64 = USER_SPACE_END - 4096;
+const MAX_ARGS_SIZE: usize = 4096 * 4;
+/// Shared memory is mapped into the upper half of user space, below the
+/// stack and its guard page; the heap grown by `brk` stays under it.
+const SHM_BASE: u64 = USER_SPACE_START + (USER_SPACE_END - USER_SPACE_START) / 2;
+const SHM_END: u64 = USER_STACK_TOP - USER_STACK_SIZE - 4096;
+const HEAP_LIMIT: u64 = SHM_BASE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    Zombie(i32),
+}
+
+pub struct Process {
+    pub pid: Pid,
+    pub ppid: Pid,
+    pub name: String,
+    pub state: State,
+    pub files: FileTable,
+    /// Log every syscall of this process to the serial port.
+    pub trace: bool,
+    pub signals: SignalState,
+    /// Start and current end of the heap managed by `brk`.
+    heap_start: u64,
+    heap_end: u64,
+    /// Shared memory mapped into this process, by start address, and where
+    /// the next object goes.
+    shared: Vec<(u64, ShmRef)>,
+    shm_next: u64,
+    address_space: Option<AddressSpace>,
+    kernel_stack: Option<Box<[u8]>>,
+    saved_rsp: u64,
+}
+
+impl Process {
+    fn kernel_stack_top(&self) -> Option<u64> {
+        self.kernel_stack
+            .as_ref()
+            .map(|stack| (stack.as_ptr() as u64 + stack.len() as u64) & !0xF)
+    }
+}
+
+/// The process table. It is only borrowed with interrupts disabled, since
+/// the timer interrupt switches processes.
+static PROCESSES: Global<BTreeMap<Pid, Box<Process>>> = Global::new(BTreeMap::new());
+static CURRENT_PID: AtomicU64 = AtomicU64::new(KERNEL_PID);
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        PROCESSES.borrow_mut().insert(KERNEL_PID, Box::new(Process {
+            pid: KERNEL_PID,
+            ppid: KERNEL_PID,
+            name: String::from("kernel"),
+            state: State::Running,
+            files: FileTable::with_console(),
+            trace: false,
+            signals: SignalState::new(),
+            heap_start: 0,
+            heap_end: 0,
+            shared: Vec::new(),
+            shm_next: SHM_BASE,
+            address_space: None,
+            kernel_stack: None,
+            saved_rsp: 0,
+        }));
+    });
+}
+
+pub fn current_pid() -> Pid {
+    CURRENT_PID.load(Ordering::Relaxed)
+}
+
+pub fn parent_pid() -> Pid {
+    interrupts::without_interrupts(|| {
+        PROCESSES.borrow().get(&current_pid()).map(|p| p.ppid).unwrap_or(KERNEL_PID)
+    })
+}
+
+/// Runs `f` on the descriptor table of the current process. The table is
+/// taken out of the process meanwhile, so `f` may block without holding the
+/// process table.
+pub fn with_files<R>(f: impl FnOnce(&mut FileTable) -> R) -> R {
+    let pid = current_pid();
+    let mut files = interrupts::without_interrupts(|| {
+        let mut processes = PROCESSES.borrow_mut();
+        let process = processes.get_mut(&pid).expect("no current process");
+        mem::replace(&mut process.files, FileTable::empty())
+    });
+    let result = f(&mut files);
+    interrupts::without_interrupts(|| {
+        if let Some(process) = PROCESSES.borrow_mut().get_mut(&pid) {
+            process.files = files;
+        }
+    });
+    result
+}
+
+/// Runs `f` on the signal state of `pid`; `None` if there is no such process.
+pub fn with_signals<R>(pid: Pid, f: impl FnOnce(&mut SignalState) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        PROCESSES.borrow_mut().get_mut(&pid).map(|process| f(&mut process.signals))
+    })
+}
+
+pub fn get_file(fd: usize) -> Option<FileRef> {
+    with_files(|files| files.get(fd))
+}
+
+pub fn is_traced(pid: Pid) -> bool {
+    interrupts::without_interrupts(|| PROCESSES.borrow().get(&pid).is_some_and(|p| p.trace))
+}
+
+/// Turns syscall tracing on or off; children spawned later inherit it.
+pub fn set_trace(pid: Pid, trace: bool) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        match PROCESSES.borrow_mut().get_mut(&pid) {
+            Some(process) if pid != KERNEL_PID => {
+                process.trace = trace;
+                Ok(())
+            }
+            Some(_) => Err("Kernel does not make syscalls"),
+            None => Err("No such process"),
+        }
+    })
+}
+
+/// Returns `(pid, ppid, name, state)` for every process in the table.
+pub fn list() -> Vec<(Pid, Pid, String, State)> {
+    interrupts::without_interrupts(|| {
+        PROCESSES.borrow().values().map(|p| (p.pid, p.ppid, p.name.clone(), p.state)).collect()
+    })
+}
+
+/// What `/proc/<pid>/status` shows about a process.
+pub struct Info {
+    pub pid: Pid,
+    pub ppid: Pid,
+    pub name: String,
+    pub state: State,
+    /// Bytes of heap grown with `brk`.
+    pub heap: u64,
+    /// Shared memory objects mapped.
+    pub shared: usize,
+    pub files: usize,
+    pub pending: u64,
+    pub blocked: u64,
+    pub trace: bool,
+}
+
+pub fn info(pid: Pid) -> Option<Info> {
+    interrupts::without_interrupts(|| {
+        PROCESSES.borrow().get(&pid).map(|p| Info {
+            pid: p.pid,
+            ppid: p.ppid,
+            name: p.name.clone(),
+            state: p.state,
+            heap: p.heap_end - p.heap_start,
+            shared: p.shared.len(),
+            files: p.files.count(),
+            pending: p.signals.pending,
+            blocked: p.signals.blocked,
+            trace: p.trace,
+        })
+    })
+}
+
+/// Reads an executable from the file system.
+pub fn read_program(path: &str) -> Result<Vec<u8>, &'static str> {
+    let inode = vfs::lookup(path)?;
+    vfs::read_to_end(&inode)
+}
+
+/// Starts `path` as a child of the current process.
+pub fn spawn(path: &str, args: &[String]) -> Result<Pid, &'static str> {
+    let image = read_program(path)?;
+    spawn_image(path, &image, args)
+}
+
+pub fn spawn_image(name: &str, image: &[u8], args: &[String]) -> Result<Pid, &'static str> {
+    let mut space = AddressSpace::new()?;
+    let loaded = elf::load(image, &mut space)?;
+    let user_rsp = setup_user_stack(&mut space, args)?;
+    let heap_start = page_align_up(loaded.end.as_u64());
+
+    let mut kernel_stack = vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice();
+    let top = (kernel_stack.as_mut_ptr() as u64 + KERNEL_STACK_SIZE as u64) & !0xF;
+    let selectors = gdt::selectors();
+    // `switch_context` pops six callee-saved registers and returns into
+    // `enter_user`, which `iretq`s through the frame above.
+    let initial: [u64; 12] = [
+        0, 0, 0, 0, 0, 0,
+        enter_user as *const () as u64,
+        loaded.entry.as_u64(),
+        selectors.user_code_selector.0 as u64,
+        0x202,
+        user_rsp,
+        selectors.user_data_selector.0 as u64,
+    ];
+    let saved_rsp = top - (initial.len() * 8) as u64;
+    unsafe {
+        core::ptr::copy_nonoverlapping(initial.as_ptr(), saved_rsp as *mut u64, initial.len());
+    }
+
+    Ok(interrupts::without_interrupts(|| {
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+        let mut processes = PROCESSES.borrow_mut();
+        let parent = &processes[&current_pid()];
+        let files = parent.files.clone();
+        let trace = parent.trace;
+        let signals = parent.signals.inherit();
+        processes.insert(pid, Box::new(Process {
+            pid,
+            ppid: current_pid(),
+            name: String::from(name),
+            state: State::Ready,
+            files,
+            trace,
+            signals,
+            heap_start,
+            heap_end: heap_start,
+            shared: Vec::new(),
+            shm_next: SHM_BASE,
+            address_space: Some(space),
+            kernel_stack: Some(kernel_stack),
+            saved_rsp,
+        }));
+        pid
+    }))
+}
+
+/// Replaces the image of the current process; `frame` is rewritten so that
+/// the `iretq` returning from the syscall lands on the new entry point.
+pub fn exec(frame: &mut TrapFrame, name: &str, image: &[u8], args: &[String]) -> Result<(), &'static str> {
+    let mut space = AddressSpace::new()?;
+    let loaded = elf::load(image, &mut space)?;
+    let user_rsp = setup_user_stack(&mut space, args)?;
+
+    interrupts::without_interrupts(|| {
+        let mut processes = PROCESSES.borrow_mut();
+        let process = processes.get_mut(&current_pid()).ok_or("No current process")?;
+        space.activate();
+        let old = process.address_space.replace(space);
+        drop(old);
+        process.shared.clear();
+        process.shm_next = SHM_BASE;
+        process.name = String::from(name);
+        process.heap_start = page_align_up(loaded.end.as_u64());
+        process.heap_end = process.heap_start;
+        process.signals = process.signals.inherit();
+        Ok::<(), &'static str>(())
+    })?;
+
+    *frame = TrapFrame {
+        rip: loaded.entry.as_u64(),
+        cs: frame.cs,
+        rflags: 0x202,
+        rsp: user_rsp,
+        ss: frame.ss,
+        ..TrapFrame::default()
+    };
+    Ok(())
+}
+
+/// Moves the end of the heap of the current process to `new_end` and
+/// returns the resulting end; on failure the old end is returned unchanged.
+pub fn brk(new_end: u64) -> u64 {
+    interrupts::without_interrupts(|| {
+        let mut processes = PROCESSES.borrow_mut();
+        let process = match processes.get_mut(&current_pid()) {
+            Some(process) => process,
+            None => return 0,
+        };
+        let space = match process.address_space.as_mut() {
+            Some(space) => space,
+            None => return 0,
+        };
+        if new_end < process.heap_start || new_end > HEAP_LIMIT {
+            return process.heap_end;
+        }
+        if new_end > process.heap_end {
+            let mapped_end = page_align_up(process.heap_end);
+            if new_end > mapped_end
+                && space.map_user(VirtAddr::new(mapped_end), new_end - mapped_end, true).is_err()
+            {
+                return process.heap_end;
+            }
+        }
+        process.heap_end = new_end;
+        new_end
+    })
+}
+
+/// Maps a shared memory object into the current process and returns its
+/// address. An unmapped guard page is left after each object.
+pub fn map_shared(object: ShmRef) -> Result<u64, &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut processes = PROCESSES.borrow_mut();
+        let process = processes.get_mut(&current_pid()).ok_or("No current process")?;
+        let space = process.address_space.as_mut().ok_or("No user address space")?;
+        let start = process.shm_next;
+        let end = start + object.size();
+        if end > SHM_END {
+            return Err("Out of memory");
+        }
+        space.map_shared(VirtAddr::new(start), object.frames(), true)?;
+        process.shm_next = end + 4096;
+        process.shared.push((start, object));
+        Ok(start)
+    })
+}
+
+/// Undoes `map_shared` for the object mapped at `addr`.
+pub fn unmap_shared(addr: u64) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut processes = PROCESSES.borrow_mut();
+        let process = processes.get_mut(&current_pid()).ok_or("No current process")?;
+        let index = process.shared.iter().position(|(start, _)| *start == addr).ok_or("Not mapped")?;
+        let (start, object) = process.shared.remove(index);
+        if let Some(space) = process.address_space.as_mut() {
+            space.unmap_shared(VirtAddr::new(start), object.size());
+        }
+        Ok(())
+    })
+}
+
+fn page_align_up(addr: u64) -> u64 {
+    (addr + 4095) & !4095
+}
+
+/// Builds `argc`, the `argv` pointer array and the argument strings at the
+/// top of the user stack and returns the initial stack pointer.
+fn setup_user_stack(space: &mut AddressSpace, args: &[String]) -> Result<u64, &'static str> {
+    let strings_size: usize = args.iter().map(|a| a.len() + 1).sum();
+    if strings_size > MAX_ARGS_SIZE {
+        return Err("Argument list too long");
+    }
+    space.map_user(VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE), USER_STACK_SIZE, true)?;
+
+    let strings_start = (USER_STACK_TOP - strings_size as u64) & !0xF;
+    let mut pointers = Vec::with_capacity(args.len() + 2);
+    pointers.push(args.len() as u64);
+    let mut cursor = strings_start;
+    for arg in args {
+        space.write(VirtAddr::new(cursor), arg.as_bytes())?;
+        space.write(VirtAddr::new(cursor + arg.len() as u64), &[0])?;
+        pointers.push(cursor);
+        cursor += arg.len() as u64 + 1;
+    }
+    pointers.push(0);
+
+    let rsp = (strings_start - (pointers.len() * 8) as u64) & !0xF;
+    for (i, value) in pointers.iter().enumerate() {
+        space.write(VirtAddr::new(rsp + (i * 8) as u64), &value.to_le_bytes())?;
+    }
+    Ok(rsp)
+}
+
+/// Terminates the current process. Its resources are released right away;
+/// the table entry stays as a zombie until the parent collects the status.
+pub fn exit(code: i32) -> ! {
+    interrupts::disable();
+    let pid = current_pid();
+    assert!(pid != KERNEL_PID, "kernel tried to exit");
+
+    // The files are closed with the table not borrowed.
+    let files = PROCESSES
+        .borrow_mut()
+        .get_mut(&pid)
+        .map(|process| mem::replace(&mut process.files, FileTable::empty()));
+    drop(files);
+    {
+        let mut processes = PROCESSES.borrow_mut();
+        memory::activate(memory::kernel_l4_frame());
+        let mut orphan_zombies = Vec::new();
+        for child in processes.values_mut().filter(|p| p.ppid == pid) {
+            child.ppid = KERNEL_PID;
+            if let State::Zombie(_) = child.state {
+                orphan_zombies.push(child.pid);
+            }
+        }
+        for child in orphan_zombies {
+            processes.remove(&child);
+        }
+
+        let mut ppid = KERNEL_PID;
+        if let Some(process) = processes.get_mut(&pid) {
+            process.address_space = None;
+            process.shared.clear();
+            process.state = State::Zombie(code);
+            ppid = process.ppid;
+        }
+        if let Some(parent) = processes.get_mut(&ppid) {
+            parent.signals.post(signal::SIGCHLD);
+        }
+    }
+    switch_to_next();
+    unreachable!("zombie process was scheduled");
+}
+
+/// Collects an exited child of the current process (any child if `pid` is
+/// `None`). Returns `Ok(None)` if `nohang` is set and no child has exited yet.
+pub fn wait(pid: Option<Pid>, nohang: bool) -> Result<Option<(Pid, i32)>, &'static str> {
+    loop {
+        let found = interrupts::without_interrupts(|| {
+            let parent = current_pid();
+            let mut processes = PROCESSES.borrow_mut();
+            let mut children = processes
+                .values()
+                .filter(|p| p.pid != parent && p.ppid == parent && pid.is_none_or(|want| p.pid == want))
+                .peekable();
+            if children.peek().is_none() {
+                return Err("No child processes");
+            }
+            let zombie = children.find_map(|p| match p.state {
+                State::Zombie(code) => Some((p.pid, code)),
+                _ => None,
+            });
+            if let Some((child, _)) = zombie {
+                processes.remove(&child);
+            }
+            Ok(zombie)
+        })?;
+
+        if found.is_some() || nohang {
+            return Ok(found);
+        }
+        if signal::has_pending() {
+            return Err("Interrupted");
+        }
+        yield_now();
+    }
+}
+
+/// Removes zombies that were re-parented to the kernel and are not waited for.
+pub fn reap_orphans() {
+    interrupts::without_interrupts(|| {
+        PROCESSES.borrow_mut().retain(|&pid, p| {
+            pid == KERNEL_PID || p.ppid != KERNEL_PID || !matches!(p.state, State::Zombie(_))
+        });
+    });
+}
+
+/// Called from the timer interrupt when it arrived in user mode.
+pub fn schedule() {
+    switch_to_next();
+}
+
+/// Gives the CPU to another process; if nobody else can run, waits for the
+/// next interrupt instead.
+pub fn yield_now() {
+    let switched = interrupts::without_interrupts(switch_to_next);
+    if !switched {
+        if interrupts::are_enabled() {
+            x86_64::instructions::hlt();
+        } else {
+            interrupts::enable_and_hlt();
+            interrupts::disable();
+        }
+    }
+}
+
+/// Called with interrupts disabled. The processes are boxed, so pointers to
+/// them stay valid once the table is released for the switch.
+fn switch_to_next() -> bool {
+    let (prev, next) = {
+        let mut processes = PROCESSES.borrow_mut();
+        let current = current_pid();
+        let next = processes
+            .range(current + 1..)
+            .chain(processes.range(..current))
+            .find(|(_, p)| p.state == State::Ready)
+            .map(|(&pid, _)| pid);
+
+        let next = match next {
+            Some(next) => next,
+            None => return false,
+        };
+
+        let prev: *mut Process = &mut **processes.get_mut(&current).unwrap();
+        let next: *mut Process = &mut **processes.get_mut(&next).unwrap();
+        (prev, next)
+    };
+
+    unsafe {
+        if (*prev).state == State::Running {
+            (*prev).state = State::Ready;
+        }
+        (*next).state = State::Running;
+        CURRENT_PID.store((*next).pid, Ordering::Relaxed);
+
+        match &(*next).address_space {
+            Some(space) => space.activate(),
+            None => memory::activate(memory::kernel_l4_frame()),
+        }
+        if let Some(top) = (*next).kernel_stack_top() {
+            gdt::set_kernel_stack(VirtAddr::new(top));
+            syscalls::KERNEL_STACK_TOP = top;
+        }
+
+        switch_context(&mut (*prev).saved_rsp, (*next).saved_rsp);
+    }
+    true
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(prev_rsp: *mut u64, next_rsp: u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    );
+}
+
+/// First code a new process runs in the kernel: drops to ring 3 through the
+/// `iretq` frame prepared by `spawn_image`.
+#[unsafe(naked)]
+unsafe extern "C" fn enter_user() -> ! {
+    naked_asm!(
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "iretq",
+    );
+}
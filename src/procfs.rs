@@ -0,0 +1,344 @@
+//! Process file system, mounted on `/proc`. Its files hold no data: they
+//! are generated from live kernel state every time they are read, so
+//! `open /proc/meminfo` always shows the current numbers.
+
+use alloc::{collections::BTreeMap, format, rc::{Rc, Weak}, string::{String, ToString}, vec::Vec};
+use core::arch::x86_64::__cpuid;
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
+
+use crate::interrupts::{self, TICKS, TIMER_HZ};
+use crate::process::{self, Pid, State};
+use crate::vfs::{self, FileSystem, FileType, Inode, InodeRef, Metadata};
+use crate::{allocator, memory, println, rtc};
+
+type Generator = fn() -> String;
+type ProcessGenerator = fn(&process::Info) -> String;
+
+/// Files at the top of `/proc`.
+const FILES: &[(&str, Generator)] = &[
+    ("cpuinfo", cpuinfo),
+    ("interrupts", interrupts),
+    ("meminfo", meminfo),
+    ("mounts", mounts),
+    ("uptime", uptime),
+];
+
+/// Files in the directory of each process.
+const PROCESS_FILES: &[(&str, ProcessGenerator)] = &[("status", status)];
+
+fn meminfo() -> String {
+    let (frames, free_frames) = memory::frame_stats();
+    let (heap, heap_used) = allocator::heap_stats();
+    let line = |name: &str, bytes: usize| format!("{:<11}{:>10} kB\n", format!("{}:", name), bytes / 1024);
+    [
+        line("MemTotal", frames * 4096),
+        line("MemFree", free_frames * 4096),
+        line("HeapTotal", heap),
+        line("HeapUsed", heap_used),
+        line("HeapFree", heap - heap_used),
+    ]
+    .concat()
+}
+
+fn interrupts() -> String {
+    let mut text = format!("{:>3} {:>12}  DEVICE\n", "IRQ", "COUNT");
+    for (irq, count, device) in interrupts::irq_counts() {
+        text += &format!("{:>3} {:>12}  {}\n", irq, count, device);
+    }
+    text
+}
+
+/// Seconds since boot, to hundredths.
+fn uptime() -> String {
+    let ticks = TICKS.load(Ordering::Relaxed);
+    format!("{}.{:02}\n", ticks / TIMER_HZ, ticks % TIMER_HZ * 100 / TIMER_HZ)
+}
+
+fn mounts() -> String {
+    vfs::mounts().into_iter().map(|(path, fs)| format!("{} on {}\n", fs, path)).collect()
+}
+
+/// Names of the CPUID leaf 1 feature bits shown in `flags`, from EDX and
+/// then ECX.
+const EDX_FLAGS: &[(u32, &str)] = &[
+    (0, "fpu"), (4, "tsc"), (5, "msr"), (6, "pae"), (9, "apic"), (11, "sep"), (13, "pge"),
+    (15, "cmov"), (16, "pat"), (19, "clflush"), (23, "mmx"), (24, "fxsr"), (25, "sse"),
+    (26, "sse2"), (28, "ht"),
+];
+const ECX_FLAGS: &[(u32, &str)] = &[
+    (0, "pni"), (1, "pclmulqdq"), (9, "ssse3"), (12, "fma"), (13, "cx16"), (19, "sse4_1"),
+    (20, "sse4_2"), (21, "x2apic"), (22, "movbe"), (23, "popcnt"), (25, "aes"), (26, "xsave"),
+    (28, "avx"), (30, "rdrand"), (31, "hypervisor"),
+];
+
+fn cpuinfo() -> String {
+    let registers = |leaf: u32| {
+        let result = __cpuid(leaf);
+        [result.eax, result.ebx, result.ecx, result.edx]
+    };
+    let [max_leaf, ebx, ecx, edx] = registers(0);
+    let vendor: Vec<u8> = [ebx, edx, ecx].iter().flat_map(|register| register.to_le_bytes()).collect();
+
+    let [signature, _, features_ecx, features_edx] = if max_leaf >= 1 { registers(1) } else { [0; 4] };
+    let mut family = (signature >> 8) & 0xF;
+    let mut model = (signature >> 4) & 0xF;
+    if family == 0xF {
+        family += (signature >> 20) & 0xFF;
+    }
+    if family == 0x6 || family >= 0xF {
+        model |= ((signature >> 16) & 0xF) << 4;
+    }
+
+    let brand = if registers(0x8000_0000)[0] >= 0x8000_0004 {
+        let bytes: Vec<u8> = (0x8000_0002..=0x8000_0004)
+            .flat_map(registers)
+            .flat_map(u32::to_le_bytes)
+            .take_while(|&byte| byte != 0)
+            .collect();
+        String::from_utf8_lossy(&bytes).trim().to_string()
+    } else {
+        String::from("unknown")
+    };
+
+    let flags: Vec<&str> = EDX_FLAGS
+        .iter()
+        .filter(|&&(bit, _)| features_edx & (1 << bit) != 0)
+        .chain(ECX_FLAGS.iter().filter(|&&(bit, _)| features_ecx & (1 << bit) != 0))
+        .map(|&(_, name)| name)
+        .collect();
+
+    format!(
+        "vendor_id\t: {}\ncpu family\t: {}\nmodel\t\t: {}\nmodel name\t: {}\nstepping\t: {}\nflags\t\t: {}\n",
+        String::from_utf8_lossy(&vendor), family, model, brand, signature & 0xF, flags.join(" ")
+    )
+}
+
+fn status(info: &process::Info) -> String {
+    let state = match info.state {
+        State::Ready => "ready".to_string(),
+        State::Running => "running".to_string(),
+        State::Zombie(code) => format!("zombie({})", code),
+    };
+    format!(
+        "Name:\t{}\nState:\t{}\nPid:\t{}\nPPid:\t{}\nHeap:\t{} kB\nShared:\t{}\nFiles:\t{}\n\
+         SigPnd:\t{:016x}\nSigBlk:\t{:016x}\nTraced:\t{}\n",
+        info.name, state, info.pid, info.ppid, info.heap / 1024, info.shared, info.files,
+        info.pending, info.blocked, if info.trace { "yes" } else { "no" }
+    )
+}
+
+enum Kind {
+    Root,
+    /// `/proc/<pid>`.
+    Process(Pid),
+    File(Generator),
+    ProcessFile(Pid, ProcessGenerator),
+    /// `/proc/self`, a link to the directory of whoever follows it.
+    SelfLink,
+}
+
+/// A file or directory of `/proc`. Nodes are kept by their directory once
+/// looked up, so that they are the same inode every time.
+pub struct ProcNode {
+    kind: Kind,
+    ino: u64,
+    parent: Option<Rc<ProcNode>>,
+    children: RefCell<BTreeMap<String, Rc<ProcNode>>>,
+    me: Weak<ProcNode>,
+}
+
+impl ProcNode {
+    fn new(kind: Kind, ino: u64, parent: Option<Rc<ProcNode>>) -> Rc<Self> {
+        Rc::new_cyclic(|me| ProcNode { kind, ino, parent, children: RefCell::new(BTreeMap::new()), me: me.clone() })
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, Kind::Root | Kind::Process(_))
+    }
+
+    fn content(&self) -> Result<String, &'static str> {
+        match self.kind {
+            Kind::File(generate) => Ok(generate()),
+            Kind::ProcessFile(pid, generate) => process::info(pid).map(|info| generate(&info)).ok_or("Entry not found"),
+            _ => Err("Is a directory"),
+        }
+    }
+
+    /// Forgets the directories of processes that are gone.
+    fn prune(&self) {
+        let pids: Vec<Pid> = process::list().into_iter().map(|(pid, ..)| pid).collect();
+        self.children.borrow_mut().retain(|_, node| match node.kind {
+            Kind::Process(pid) => pids.contains(&pid),
+            _ => true,
+        });
+    }
+
+    /// Makes the child `name` of this directory, if it has one. Only
+    /// directories point back to their parent, so dropping a process
+    /// directory frees everything under it.
+    fn make_child(&self, name: &str) -> Option<Rc<ProcNode>> {
+        let me = self.me.upgrade()?;
+        match self.kind {
+            Kind::Root => {
+                if let Some(i) = FILES.iter().position(|&(file, _)| file == name) {
+                    return Some(ProcNode::new(Kind::File(FILES[i].1), 2 + i as u64, None));
+                }
+                if name == "self" {
+                    return Some(ProcNode::new(Kind::SelfLink, 2 + FILES.len() as u64, None));
+                }
+                // Only the plain spelling, so that `007` is not another `7`.
+                let pid: Pid = name.parse().ok().filter(|pid: &Pid| pid.to_string() == name)?;
+                process::info(pid)?;
+                Some(ProcNode::new(Kind::Process(pid), (pid + 1) << 8, Some(me)))
+            }
+            Kind::Process(pid) => {
+                let i = PROCESS_FILES.iter().position(|&(file, _)| file == name)?;
+                Some(ProcNode::new(Kind::ProcessFile(pid, PROCESS_FILES[i].1), self.ino + 1 + i as u64, None))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Inode for ProcNode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, mode) = match self.kind {
+            Kind::Root | Kind::Process(_) => (FileType::Directory, 0o555),
+            Kind::File(_) | Kind::ProcessFile(..) => (FileType::File, 0o444),
+            Kind::SelfLink => (FileType::Symlink, 0o777),
+        };
+        let size = match self.kind {
+            Kind::SelfLink => self.read_link().map_or(0, |target| target.len()),
+            _ => self.size(),
+        };
+        let now = rtc::now();
+        Metadata {
+            ino: self.ino,
+            nlink: if self.is_dir() { 2 } else { 1 },
+            file_type,
+            size: size as u64,
+            mode,
+            uid: 0,
+            created: now,
+            modified: now,
+            accessed: now,
+        }
+    }
+
+    fn size(&self) -> usize {
+        if self.is_dir() {
+            self.entries().map_or(0, |entries| entries.len())
+        } else {
+            self.content().map_or(0, |content| content.len())
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let content = self.content()?;
+        let Some(rest) = content.as_bytes().get(offset..) else { return Ok(0) };
+        let len = core::cmp::min(buf.len(), rest.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _data: &[u8]) -> Result<usize, &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, &'static str> {
+        if !self.is_dir() {
+            return Err("Not a directory");
+        }
+        if matches!(self.kind, Kind::Root) {
+            self.prune();
+        }
+        if let Some(node) = self.children.borrow().get(name) {
+            return Ok(node.clone());
+        }
+        let node = self.make_child(name).ok_or("Entry not found")?;
+        self.children.borrow_mut().insert(name.to_string(), node.clone());
+        Ok(node)
+    }
+
+    fn parent(&self) -> Option<InodeRef> {
+        if !self.is_dir() {
+            return None;
+        }
+        let parent: InodeRef = self.parent.clone()?;
+        Some(parent)
+    }
+
+    fn create(self: Rc<Self>, _name: &str, _file_type: FileType) -> Result<InodeRef, &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn link(self: Rc<Self>, _name: &str, _target: InodeRef) -> Result<(), &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn symlink(self: Rc<Self>, _name: &str, _target: &str) -> Result<(), &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn read_link(&self) -> Result<String, &'static str> {
+        match self.kind {
+            Kind::SelfLink => Ok(process::current_pid().to_string()),
+            _ => Err("Invalid argument"),
+        }
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn rename(self: Rc<Self>, _name: &str, _new_dir: InodeRef, _new_name: &str) -> Result<(), &'static str> {
+        Err("Operation not permitted")
+    }
+
+    fn entries(&self) -> Result<Vec<String>, &'static str> {
+        match self.kind {
+            Kind::Root => {
+                let files = FILES.iter().map(|&(name, _)| name.to_string());
+                let pids = process::list().into_iter().map(|(pid, ..)| pid.to_string());
+                Ok(files.chain(core::iter::once("self".to_string())).chain(pids).collect())
+            }
+            Kind::Process(pid) => {
+                process::info(pid).ok_or("Entry not found")?;
+                Ok(PROCESS_FILES.iter().map(|&(name, _)| name.to_string()).collect())
+            }
+            _ => Err("Not a directory"),
+        }
+    }
+}
+
+pub struct ProcFs {
+    root: Rc<ProcNode>,
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+/// Mounts procfs on `/proc`, which is made if the root file system lacks
+/// it.
+pub fn init() {
+    let fs = ProcFs { root: ProcNode::new(Kind::Root, 1, None) };
+    let result = match vfs::create("/proc", FileType::Directory) {
+        Ok(_) | Err("Entry already exists") => vfs::mount("/proc", Rc::new(fs)),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        println!("Could not mount /proc: {}", e);
+    }
+}
@@ -1,97 +1,499 @@
 extern crate alloc;
 
-use alloc::{rc::Rc, string::String, collections::BTreeMap, vec::Vec};
+use alloc::{boxed::Box, rc::{Rc, Weak}, string::String, collections::BTreeMap, vec::Vec};
+use core::any::Any;
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{initrd, println, rtc, snapshot};
+use crate::vfs::{self, FileSystem, FileType, Inode, InodeRef, Metadata};
 
 pub type NodeRef = Rc<RefCell<Node>>;
 
-pub static mut ROOT_DIR: Option<NodeRef> = None;
-pub static mut CURRENT_DIR: Option<NodeRef> = None;
+pub const DIR_MODE: u16 = 0o755;
+pub const FILE_MODE: u16 = 0o644;
+pub const SYMLINK_MODE: u16 = 0o777;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Inode numbers are never reused; the root gets 1.
+static NEXT_INO: AtomicU64 = AtomicU64::new(1);
+
+/// Keeps new inode numbers above `ino`, which a restored node already has.
+pub fn reserve_ino(ino: u64) {
+    NEXT_INO.fetch_max(ino + 1, Ordering::Relaxed);
+}
+
+/// Metadata kept with every node.
+#[derive(Debug, Clone, Copy)]
+pub struct Attributes {
+    pub ino: u64,
+    /// Number of names the node has; directories cannot have more than one.
+    pub nlink: u32,
+    pub mode: u16,
+    pub uid: u32,
+    pub created: u64,
+    pub modified: u64,
+    pub accessed: u64,
+}
+
+impl Attributes {
+    fn new(mode: u16) -> Self {
+        let now = rtc::now();
+        Attributes {
+            ino: NEXT_INO.fetch_add(1, Ordering::Relaxed),
+            nlink: 0,
+            mode,
+            uid: 0,
+            created: now,
+            modified: now,
+            accessed: now,
+        }
+    }
+}
+
+/// Content of a file, kept in pages. Pages that were never written are not
+/// allocated and read as zeros, so writing past the end or growing a file
+/// with `truncate` leaves a hole instead of filling memory.
+#[derive(Debug, Default)]
+pub struct FileData {
+    pages: BTreeMap<usize, Box<[u8]>>,
+    len: usize,
+}
+
+impl FileData {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Allocated pages in order, as (page index, bytes).
+    pub fn pages(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.pages.iter().map(|(&index, page)| (index, &page[..]))
+    }
+
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        if offset >= self.len {
+            return 0;
+        }
+        let len = core::cmp::min(buf.len(), self.len - offset);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let start = position % PAGE_SIZE;
+            let count = core::cmp::min(PAGE_SIZE - start, len - done);
+            let dst = &mut buf[done..done + count];
+            match self.pages.get(&(position / PAGE_SIZE)) {
+                Some(page) => dst.copy_from_slice(&page[start..start + count]),
+                None => dst.fill(0),
+            }
+            done += count;
+        }
+        len
+    }
+
+    pub fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        let end = offset.checked_add(data.len()).ok_or("File too large")?;
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done;
+            let start = position % PAGE_SIZE;
+            let count = core::cmp::min(PAGE_SIZE - start, data.len() - done);
+            let page = self
+                .pages
+                .entry(position / PAGE_SIZE)
+                .or_insert_with(|| alloc::vec![0; PAGE_SIZE].into_boxed_slice());
+            page[start..start + count].copy_from_slice(&data[done..done + count]);
+            done += count;
+        }
+        self.len = core::cmp::max(self.len, end);
+        Ok(data.len())
+    }
+
+    /// Cuts the file at `len` or extends it with a hole.
+    pub fn set_len(&mut self, len: usize) {
+        if len < self.len {
+            self.pages.split_off(&len.div_ceil(PAGE_SIZE));
+            if let Some(page) = self.pages.get_mut(&(len / PAGE_SIZE)) {
+                page[len % PAGE_SIZE..].fill(0);
+            }
+        }
+        self.len = len;
+    }
+}
 
 #[derive(Debug)]
 pub enum Node {
     Directory {
         entries: BTreeMap<String, NodeRef>,
+        /// Dangling for the root, so `..` stays there.
+        parent: Weak<RefCell<Node>>,
+        attrs: Attributes,
     },
     File {
-        content: Vec<u8>,
+        content: FileData,
+        attrs: Attributes,
     },
+    /// Followed during path resolution; `target` may be relative to the
+    /// directory holding the link and need not exist.
+    Symlink {
+        target: String,
+        attrs: Attributes,
+    },
+}
+
+/// File system kept entirely in memory.
+pub struct RamFs {
+    root: NodeRef,
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        let root = Node::new_dir();
+        root.borrow_mut().attrs_mut().nlink = 1;
+        RamFs { root }
+    }
+
+    fn with_initrd() -> Self {
+        let fs = RamFs::new();
+        if let Err(e) = initrd::unpack(&fs.root) {
+            println!("Could not unpack initrd: {}", e);
+        }
+        fs
+    }
+
+    /// Wraps a tree built elsewhere, such as one loaded from disk.
+    pub fn from_root(root: NodeRef) -> Self {
+        root.borrow_mut().attrs_mut().nlink = 1;
+        RamFs { root }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
 }
 
 impl Node {
     pub fn new_dir() -> NodeRef {
         Rc::new(RefCell::new(Node::Directory {
             entries: BTreeMap::new(),
+            parent: Weak::new(),
+            attrs: Attributes::new(DIR_MODE),
         }))
     }
 
     pub fn new_file() -> NodeRef {
         Rc::new(RefCell::new(Node::File {
-            content: Vec::new(),
+            content: FileData::default(),
+            attrs: Attributes::new(FILE_MODE),
+        }))
+    }
+
+    pub fn new_symlink(target: &str) -> NodeRef {
+        Rc::new(RefCell::new(Node::Symlink {
+            target: String::from(target),
+            attrs: Attributes::new(SYMLINK_MODE),
         }))
     }
 
+    pub fn attrs(&self) -> &Attributes {
+        match self {
+            Node::Directory { attrs, .. } | Node::File { attrs, .. } | Node::Symlink { attrs, .. } => attrs,
+        }
+    }
+
+    pub fn attrs_mut(&mut self) -> &mut Attributes {
+        match self {
+            Node::Directory { attrs, .. } | Node::File { attrs, .. } | Node::Symlink { attrs, .. } => attrs,
+        }
+    }
+
+    /// Links `node` into `dir` and counts the new name; a directory gets
+    /// `dir` as its parent.
     pub fn add_entry(dir: &NodeRef, name: String, node: NodeRef) -> Result<(), &'static str> {
         let mut dir_borrow = dir.borrow_mut();
         match &mut *dir_borrow {
-            Node::Directory { entries } => {
+            Node::Directory { entries, attrs, .. } => {
                 if entries.contains_key(&name) {
                     return Err("Entry already exists");
                 }
+                let mut node_borrow = node.borrow_mut();
+                if let Node::Directory { parent, .. } = &mut *node_borrow {
+                    *parent = Rc::downgrade(dir);
+                }
+                node_borrow.attrs_mut().nlink += 1;
+                drop(node_borrow);
                 entries.insert(name, node);
+                attrs.modified = rtc::now();
                 Ok(())
             }
             _ => Err("Not a directory"),
         }
     }
 
-    pub fn get_entry(dir: &NodeRef, name: &str) -> Option<NodeRef> {
+    pub fn get_entry(dir: &RefCell<Node>, name: &str) -> Option<NodeRef> {
         let dir_borrow = dir.borrow();
         match &*dir_borrow {
-            Node::Directory { entries } => entries.get(name).cloned(),
+            Node::Directory { entries, .. } => entries.get(name).cloned(),
             _ => None,
         }
     }
 
-    pub fn write_file(file: &NodeRef, data: &[u8]) -> Result<(), &'static str> {
-        let mut file_borrow = file.borrow_mut();
-        match &mut *file_borrow {
-            Node::File { content } => {
-                content.clear();
-                content.extend_from_slice(data);
+    /// Mounts the root file system: the tree saved by `sync` if the disk has
+    /// one, otherwise a new ramfs with the initial ramdisk unpacked into it.
+    pub fn init_fs() {
+        let fs = match snapshot::load() {
+            Ok(Some(root)) => {
+                println!("Restored ramfs from disk");
+                RamFs::from_root(root)
+            }
+            Ok(None) => RamFs::with_initrd(),
+            Err(e) => {
+                println!("Could not restore ramfs: {}", e);
+                RamFs::with_initrd()
+            }
+        };
+        vfs::mount("/", Rc::new(fs)).expect("mounting the root file system failed");
+    }
+
+    /// Unlinks `name` from `dir`; the node lives on while it has other names
+    /// or is still open.
+    pub fn remove_entry(dir: &RefCell<Node>, name: &str) -> Result<(), &'static str> {
+        let mut dir_borrow = dir.borrow_mut();
+        match &mut *dir_borrow {
+            Node::Directory { entries, attrs, .. } => {
+                let node = entries.remove(name).ok_or("Entry not found")?;
+                node.borrow_mut().attrs_mut().nlink -= 1;
+                attrs.modified = rtc::now();
                 Ok(())
             }
-            _ => Err("Not a file"),
+            _ => Err("Not a directory"),
         }
     }
+}
 
-    pub fn read_file(file: &NodeRef) -> Result<Vec<u8>, &'static str> {
-        let file_borrow = file.borrow();
-        match &*file_borrow {
-           Node::File { content } => Ok(content.clone()),
-           _ => Err("Not a file"),
+impl Inode for RefCell<Node> {
+    fn metadata(&self) -> Metadata {
+        let node = self.borrow();
+        let (file_type, size) = match &*node {
+            Node::Directory { entries, .. } => (FileType::Directory, entries.len()),
+            Node::File { content, .. } => (FileType::File, content.len()),
+            Node::Symlink { target, .. } => (FileType::Symlink, target.len()),
+        };
+        let attrs = node.attrs();
+        Metadata {
+            ino: attrs.ino,
+            nlink: attrs.nlink,
+            file_type,
+            size: size as u64,
+            mode: attrs.mode,
+            uid: attrs.uid,
+            created: attrs.created,
+            modified: attrs.modified,
+            accessed: attrs.accessed,
         }
     }
 
-    pub fn init_fs() {
-        unsafe {
-            let root = Node::new_dir();
-            ROOT_DIR = Some(Node::new_dir());
-            CURRENT_DIR = Some(root);
+    fn file_type(&self) -> FileType {
+        match &*self.borrow() {
+            Node::Directory { .. } => FileType::Directory,
+            Node::File { .. } => FileType::File,
+            Node::Symlink { .. } => FileType::Symlink,
         }
     }
 
-    pub fn remove_entry(dir: &NodeRef, name: &str) -> Result<(), &'static str> {
-        let mut dir_borrow = dir.borrow_mut();
-        match &mut *dir_borrow {
-            Node::Directory { entries } => {
-                if entries.remove(name).is_some() {
-                    Ok(())
-                } else {
-                    Err("Entry not found")
-                }
+    fn size(&self) -> usize {
+        match &*self.borrow() {
+            Node::Directory { entries, .. } => entries.len(),
+            Node::File { content, .. } => content.len(),
+            Node::Symlink { target, .. } => target.len(),
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        match &mut *self.borrow_mut() {
+            Node::File { content, attrs } => {
+                attrs.accessed = rtc::now();
+                Ok(content.read_at(offset, buf))
+            }
+            Node::Directory { .. } => Err("Is a directory"),
+            Node::Symlink { .. } => Err("Invalid argument"),
+        }
+    }
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        match &mut *self.borrow_mut() {
+            Node::File { content, attrs } => {
+                attrs.modified = rtc::now();
+                content.write_at(offset, data)
+            }
+            Node::Directory { .. } => Err("Is a directory"),
+            Node::Symlink { .. } => Err("Invalid argument"),
+        }
+    }
+
+    fn truncate(&self, size: usize) -> Result<(), &'static str> {
+        match &mut *self.borrow_mut() {
+            Node::File { content, attrs } => {
+                content.set_len(size);
+                attrs.modified = rtc::now();
+                Ok(())
+            }
+            Node::Directory { .. } => Err("Is a directory"),
+            Node::Symlink { .. } => Err("Invalid argument"),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, &'static str> {
+        match &*self.borrow() {
+            Node::Directory { entries, .. } => {
+                let node: InodeRef = entries.get(name).ok_or("Entry not found")?.clone();
+                Ok(node)
             }
             _ => Err("Not a directory"),
         }
     }
-}
\ No newline at end of file
+
+    fn parent(&self) -> Option<InodeRef> {
+        match &*self.borrow() {
+            Node::Directory { parent, .. } => {
+                let parent: InodeRef = parent.upgrade()?;
+                Some(parent)
+            }
+            _ => None,
+        }
+    }
+
+    fn create(self: Rc<Self>, name: &str, file_type: FileType) -> Result<InodeRef, &'static str> {
+        let node = match file_type {
+            FileType::Directory => Node::new_dir(),
+            FileType::File => Node::new_file(),
+            FileType::Symlink | FileType::CharDevice | FileType::BlockDevice => return Err("Invalid argument"),
+        };
+        Node::add_entry(&self, String::from(name), node.clone())?;
+        Ok(node)
+    }
+
+    fn link(self: Rc<Self>, name: &str, target: InodeRef) -> Result<(), &'static str> {
+        let node: NodeRef = (target as Rc<dyn Any>).downcast().map_err(|_| "Cross-device link")?;
+        if matches!(&*node.borrow(), Node::Directory { .. }) {
+            return Err("Operation not permitted");
+        }
+        Node::add_entry(&self, String::from(name), node)
+    }
+
+    fn symlink(self: Rc<Self>, name: &str, target: &str) -> Result<(), &'static str> {
+        Node::add_entry(&self, String::from(name), Node::new_symlink(target))
+    }
+
+    fn read_link(&self) -> Result<String, &'static str> {
+        match &*self.borrow() {
+            Node::Symlink { target, .. } => Ok(target.clone()),
+            _ => Err("Invalid argument"),
+        }
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), &'static str> {
+        let node = Node::get_entry(self, name).ok_or("Entry not found")?;
+        if node.file_type() == FileType::Directory && node.size() > 0 {
+            return Err("Directory not empty");
+        }
+        Node::remove_entry(self, name)
+    }
+
+    fn rename(self: Rc<Self>, name: &str, new_dir: InodeRef, new_name: &str) -> Result<(), &'static str> {
+        let new_dir: NodeRef = (new_dir as Rc<dyn Any>).downcast().map_err(|_| "Cross-device link")?;
+        let node = Node::get_entry(&self, name).ok_or("Entry not found")?;
+        Node::remove_entry(&self, name)?;
+        let _ = Node::remove_entry(&new_dir, new_name);
+        Node::add_entry(&new_dir, String::from(new_name), node)
+    }
+
+    fn entries(&self) -> Result<Vec<String>, &'static str> {
+        match &*self.borrow() {
+            Node::Directory { entries, .. } => Ok(entries.keys().cloned().collect()),
+            _ => Err("Not a directory"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holes_read_as_zeros_without_pages() {
+        let mut data = FileData::default();
+        data.write_at(3 * PAGE_SIZE + 10, b"end").unwrap();
+        assert_eq!(data.len(), 3 * PAGE_SIZE + 13);
+        assert_eq!(data.pages.len(), 1);
+        let mut buf = [0xff; 16];
+        assert_eq!(data.read_at(PAGE_SIZE, &mut buf), 16);
+        assert_eq!(buf, [0; 16]);
+        data.set_len(10 * PAGE_SIZE);
+        assert_eq!(data.pages.len(), 1);
+        assert_eq!(data.read_at(9 * PAGE_SIZE, &mut buf), 16);
+        assert_eq!(buf, [0; 16]);
+    }
+
+    #[test]
+    fn writes_span_pages() {
+        let mut data = FileData::default();
+        let pattern: Vec<u8> = (0..2 * PAGE_SIZE + 100).map(|i| i as u8).collect();
+        assert_eq!(data.write_at(PAGE_SIZE - 50, &pattern).unwrap(), pattern.len());
+        assert_eq!(data.pages.len(), 4);
+        let mut buf = alloc::vec![0; pattern.len()];
+        assert_eq!(data.read_at(PAGE_SIZE - 50, &mut buf), pattern.len());
+        assert_eq!(buf, pattern);
+    }
+
+    #[test]
+    fn reads_stop_at_the_end() {
+        let mut data = FileData::default();
+        assert!(data.is_empty());
+        data.write_at(0, b"hello").unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(data.read_at(3, &mut buf), 2);
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(data.read_at(5, &mut buf), 0);
+        assert_eq!(data.read_at(100, &mut buf), 0);
+    }
+
+    #[test]
+    fn shrinking_drops_pages_and_clears_the_tail() {
+        let mut data = FileData::default();
+        data.write_at(0, &[0xaa; 3 * PAGE_SIZE]).unwrap();
+        data.set_len(10);
+        assert_eq!(data.pages.len(), 1);
+        data.set_len(PAGE_SIZE);
+        let mut buf = [0xff; 20];
+        assert_eq!(data.read_at(0, &mut buf), 20);
+        assert_eq!(&buf[..10], &[0xaa; 10]);
+        assert_eq!(&buf[10..], &[0; 10]);
+        data.set_len(0);
+        assert!(data.is_empty());
+        assert!(data.pages.is_empty());
+    }
+
+    #[test]
+    fn offsets_that_overflow_fail() {
+        let mut data = FileData::default();
+        assert_eq!(data.write_at(usize::MAX, b"x"), Err("File too large"));
+        assert!(data.is_empty());
+    }
+}
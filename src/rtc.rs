@@ -0,0 +1,153 @@
+//! CMOS real-time clock. It is read once at boot; after that the time is
+//! advanced by the timer ticks, so file timestamps need no port I/O.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::{TICKS, TIMER_HZ};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Seconds since 1970-01-01 00:00:00 when `BOOT_TICKS` was taken.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+static BOOT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address = Port::new(CMOS_ADDRESS);
+    let mut data = Port::new(CMOS_DATA);
+    unsafe {
+        address.write(register);
+        data.read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & 0x80 != 0
+}
+
+fn read_raw() -> [u8; 6] {
+    while update_in_progress() {}
+    [REG_SECONDS, REG_MINUTES, REG_HOURS, REG_DAY, REG_MONTH, REG_YEAR].map(read_register)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+impl DateTime {
+    /// Reads the CMOS clock, retrying until two reads agree so that an
+    /// update in the middle does not give a torn value.
+    pub fn read() -> Self {
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        let [mut second, mut minute, mut hour, mut day, mut month, mut year] = raw;
+        let status = read_register(REG_STATUS_B);
+        let pm = hour & 0x80 != 0;
+        hour &= 0x7F;
+        if status & 0x04 == 0 {
+            second = from_bcd(second);
+            minute = from_bcd(minute);
+            hour = from_bcd(hour);
+            day = from_bcd(day);
+            month = from_bcd(month);
+            year = from_bcd(year);
+        }
+        if status & 0x02 == 0 {
+            // 12-hour clock: 12 AM is midnight, 12 PM is noon.
+            hour = (hour % 12) + if pm { 12 } else { 0 };
+        }
+        DateTime { year: 2000 + year as u16, month, day, hour, minute, second }
+    }
+
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let seconds = days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        seconds.max(0) as u64
+    }
+
+    pub fn from_timestamp(timestamp: u64) -> Self {
+        let (year, month, day) = civil_from_days((timestamp / 86400) as i64);
+        let seconds = timestamp % 86400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub fn init() {
+    BOOT_TIME.store(DateTime::read().timestamp(), Ordering::Relaxed);
+    BOOT_TICKS.store(TICKS.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Current time in seconds since 1970-01-01 00:00:00.
+pub fn now() -> u64 {
+    let elapsed = TICKS.load(Ordering::Relaxed) - BOOT_TICKS.load(Ordering::Relaxed);
+    BOOT_TIME.load(Ordering::Relaxed) + elapsed / TIMER_HZ
+}
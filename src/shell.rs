@@ -1,27 +1,26 @@
 use crate::{println, print, println_colored, print_colored};
-use crate::vga_buffer::{WRITER, buffer_copy, buffer_clear, Color, HistoryBuffer};
-use crate::ramfs::{Node, CURRENT_DIR, ROOT_DIR, NodeRef};
-use alloc::{string::{String, ToString}, vec::Vec, rc::Rc, format};
+use crate::vga_buffer::{self, WRITER, buffer_copy, buffer_clear, Color, HistoryBuffer};
+use crate::file::{self, FileRef, OpenFile};
+use crate::vfs::{self, File, FileHandle, FileType, FsRef, InodeRef, Metadata};
+use crate::ext2::Ext2Fs;
+use crate::fat::FatFs;
+use crate::rtc::DateTime;
+use crate::process::{self, State};
+use crate::{block, cache, ipc, partition, pci, signal, snapshot, strace};
+use alloc::{rc::Rc, string::{String, ToString}, vec::Vec, format};
 use core::arch::asm;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
 
 use crate::interrupts::{ENTER_PRESSED, UP_PRESSED, DOWN_PRESSED};
 
-static mut DIR_STACK: Vec<NodeRef> = Vec::new();
+/// Output of the previous stage of a pipeline, for builtins that read it.
+static PIPE_INPUT: Mutex<Option<Vec<u8>>> = Mutex::new(None);
 
 pub fn shell_loop() -> ! {
-    unsafe {
-        if ROOT_DIR.is_none() {
-            ROOT_DIR = Some(Node::new_dir());
-        }
-        if let Some(root) = &ROOT_DIR {
-            CURRENT_DIR = Some(root.clone());
-            DIR_STACK.clear();
-            DIR_STACK.push(root.clone());
-        }
+    if let Ok(root) = vfs::root() {
+        let _ = vfs::set_current_dir(root);
     }
-    let current_dir = unsafe {
-        CURRENT_DIR.as_ref().expect("File system not initialized").clone()
-    };
     let mut counter = 0;
     let mut max_history = 0;
     let mut history_command = HistoryBuffer::new(10);
@@ -40,9 +39,10 @@ pub fn shell_loop() -> ! {
                     counter = max_history;
                 }
 
-                execute_command(s);
                 ENTER_PRESSED = false;
-                let path = get_path(&DIR_STACK);
+                execute_command(s);
+                process::reap_orphans();
+                let path = current_path();
                 print!("\n");
                 print_colored!(Color::Magenta, Color::Black, "{}", path);
                 print_colored!(Color::Magenta, Color::Black, " > ");
@@ -62,7 +62,7 @@ pub fn shell_loop() -> ! {
                 writer.write_col_null();
                 drop(writer);
 
-                let path = get_path(&DIR_STACK);
+                let path = current_path();
                 print_colored!(Color::Magenta, Color::Black, "{}", path);
                 print_colored!(Color::Magenta, Color::Black, " > ");
                 buffer_clear();
@@ -84,7 +84,7 @@ pub fn shell_loop() -> ! {
                 writer.write_col_null();
                 drop(writer);
                 
-                let path = get_path(&DIR_STACK);
+                let path = current_path();
                 print_colored!(Color::Magenta, Color::Black, "{}", path);
                 print_colored!(Color::Magenta, Color::Black, " > ");
                 buffer_clear();
@@ -102,16 +102,18 @@ pub fn shell_loop() -> ! {
 }
 
 pub fn execute_command(command: &str) {
+    if command.contains('|') {
+        run_pipeline(command);
+        return;
+    }
     let mut parts = command.trim().split_whitespace();
     let cmd = parts.next().unwrap_or("");
     
-    let current_dir = unsafe {
-        match &CURRENT_DIR {
-            Some(dir) => dir.clone(),
-            None => {
-                println!("File system not initialized");
-                return;
-            }
+    let current_dir = match vfs::current_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            println!("{}", e);
+            return;
         }
     };
 
@@ -132,19 +134,57 @@ pub fn execute_command(command: &str) {
             print_colored!(Color::Green, Color::Black,"  touch");
             println!(" - create file");
             print_colored!(Color::Green, Color::Black,"  ls");
-            println!(" - show directory and files");
+            println!(" - show directory and files (-l for details)");
+            print_colored!(Color::Green, Color::Black,"  stat");
+            println!(" - show file metadata");
             print_colored!(Color::Green, Color::Black,"  cd");
             println!(" - change directory");
             print_colored!(Color::Green, Color::Black,"  rm");
             println!(" - remove file");
             print_colored!(Color::Green, Color::Black,"  rmdir");
             println!(" - remove directory");
+            print_colored!(Color::Green, Color::Black,"  mv");
+            println!(" - move or rename file or directory");
+            print_colored!(Color::Green, Color::Black,"  cp");
+            println!(" - copy file (-r for directories)");
+            print_colored!(Color::Green, Color::Black,"  ln");
+            println!(" - create hard link (-s for symbolic link)");
+            print_colored!(Color::Green, Color::Black,"  readlink");
+            println!(" - show target of symbolic link");
             print_colored!(Color::Green, Color::Black,"  write");
             println!(" - write data in file");
+            print_colored!(Color::Green, Color::Black,"  append");
+            println!(" - append data to file");
+            print_colored!(Color::Green, Color::Black,"  truncate");
+            println!(" - cut or extend file to a size");
             print_colored!(Color::Green, Color::Black,"  open");
             println!(" - take data from file");
+            print_colored!(Color::Green, Color::Black,"  mount");
+            println!(" - show mounted file systems, or mount a device: mount <device> <path> [vfat|ext2]");
+            print_colored!(Color::Green, Color::Black,"  lsblk");
+            println!(" - show disks and partitions");
+            print_colored!(Color::Green, Color::Black,"  lspci");
+            println!(" - show PCI devices (-v for details)");
+            print_colored!(Color::Green, Color::Black,"  bcache");
+            println!(" - show buffer cache statistics");
+            print_colored!(Color::Green, Color::Black,"  sync");
+            println!(" - write out mounted file systems and save ramfs to disk");
             print_colored!(Color::Green, Color::Black,"  time");
             println!(" - show time and date");
+            print_colored!(Color::Green, Color::Black,"  run");
+            println!(" - run program and wait for it");
+            print_colored!(Color::Green, Color::Black,"  ps");
+            println!(" - show processes");
+            print_colored!(Color::Green, Color::Black,"  ipcs");
+            println!(" - show message queues and shared memory");
+            print_colored!(Color::Green, Color::Black,"  kill");
+            println!(" - send a signal to a process");
+            print_colored!(Color::Green, Color::Black,"  strace");
+            println!(" - run program and trace its syscalls");
+            print_colored!(Color::Green, Color::Black,"  syscalls");
+            println!(" - show syscall statistics");
+            print_colored!(Color::Green, Color::Black,"  cmd1 | cmd2");
+            println!(" - pass output of cmd1 to cmd2 (programs via run)");
         },
         "clear" => WRITER.lock().clear_screen(),
         "off" => unsafe{ outw(0x604, 0x2000); },
@@ -156,26 +196,14 @@ pub fn execute_command(command: &str) {
                 }
             };
         },
-        "time" => unsafe {
-            outb(0x70, 0x02);
-            let min = inb(0x71);
-            outb(0x70, 0x04);
-            let h = inb(0x71);
-
-            outb(0x70, 0x07);
-            let d = inb(0x71);
-            outb(0x70, 0x08);
-            let m = inb(0x71);
-            outb(0x70, 0x09);
-            let y = inb(0x71);
-
-            println!("    {:02x}:{:02x}", h, min);
-            println!("    {:02x}.{:02x}.{:02x}", d, m, y);
-        },
+        "time" => {
+            let now = DateTime::read();
+            println!("    {:02}:{:02}", now.hour, now.minute);
+            println!("    {:02}.{:02}.{:04}", now.day, now.month, now.year);
+        }
         "mkdir" => {
             if let Some(name) = parts.next() {
-                let dir = Node::new_dir();
-                if let Err(e) = Node::add_entry(&current_dir, name.to_string(), dir) {
+                if let Err(e) = vfs::create(name, FileType::Directory) {
                     println_colored!(Color::Red, Color::Black, "Error creating directory: {}", e);
                 }
             } else {
@@ -184,8 +212,7 @@ pub fn execute_command(command: &str) {
         }
         "touch" => {
             if let Some(name) = parts.next() {
-                let file = Node::new_file();
-                if let Err(e) = Node::add_entry(&current_dir, name.to_string(), file) {
+                if let Err(e) = vfs::create(name, FileType::File) {
                     println_colored!(Color::Red, Color::Black, "Error creating file: {}", e);
                 }
             } else {
@@ -193,47 +220,92 @@ pub fn execute_command(command: &str) {
             }
         }
         "ls" => {
-            let current_dir_borrow = current_dir.borrow();
-            match &*current_dir_borrow {
-                Node::Directory { entries } => {
-                    for (name, node) in entries {
-                        let n = node.borrow();
-                        match &*n {
-                            Node::Directory { .. } => println!("{}  [dir]", name),
-                            Node::File { .. } => println!("{}  [file]", name),
-                        }
-                    }
+            let mut long = false;
+            let mut path = None;
+            for arg in parts {
+                match arg {
+                    "-l" => long = true,
+                    _ => path = Some(arg),
                 }
-                _ => println_colored!(Color::Red, Color::Black, "Current directory is not a directory"),
             }
-        }
-        "cd" => {
-            if let Some(arg) = parts.next() {
-                if arg == ".." {
-                    unsafe {
-                        if DIR_STACK.len() > 1 {
-                            DIR_STACK.pop();
-                            CURRENT_DIR = DIR_STACK.last().cloned();
+            let dir = match path {
+                Some(path) => match vfs::lookup(path) {
+                    Ok(dir) => dir,
+                    Err(e) => {
+                        println_colored!(Color::Red, Color::Black, "{}: {}", path, e);
+                        return;
+                    }
+                },
+                None => current_dir,
+            };
+            match dir.entries() {
+                Ok(names) => {
+                    for name in names {
+                        let node = match dir.lookup(&name) {
+                            Ok(node) => node,
+                            Err(_) => {
+                                println!("{}  [?]", name);
+                                continue;
+                            }
+                        };
+                        let meta = node.metadata();
+                        if long {
+                            let target = match node.read_link() {
+                                Ok(target) => format!(" -> {}", target),
+                                Err(_) => String::new(),
+                            };
+                            println!(
+                                "{} {:>2} {} {:>8} {} {}{}",
+                                mode_string(&meta), meta.nlink, owner_name(meta.uid), meta.size,
+                                DateTime::from_timestamp(meta.modified), name, target
+                            );
                         } else {
-                            println_colored!(Color::Red, Color::Black, "Already at root directory");
+                            match meta.file_type {
+                                FileType::Directory => println!("{}  [dir]", name),
+                                FileType::File => println!("{}  [file]", name),
+                                FileType::Symlink => println!("{}  [link]", name),
+                                FileType::CharDevice | FileType::BlockDevice => println!("{}  [dev]", name),
+                            }
                         }
                     }
-                } else {
-                    match Node::get_entry(&current_dir, arg) {
-                        Some(node) => {
-                            let n = node.borrow();
-                            match &*n {
-                                Node::Directory { .. } => {
-                                    unsafe {
-                                        DIR_STACK.push(node.clone());
-                                        CURRENT_DIR = Some(node.clone());
-                                    }
-                                }
-                                _ => println_colored!(Color::Red, Color::Black, "{} is not a directory", arg),
-                            }
+                }
+                Err(_) => println_colored!(Color::Red, Color::Black, "Not a directory"),
+            }
+        }
+        "stat" => {
+            if let Some(path) = parts.next() {
+                match vfs::lookup_link(path) {
+                    Ok(node) => {
+                        let meta = node.metadata();
+                        let kind = match meta.file_type {
+                            FileType::Directory => "directory",
+                            FileType::File => "regular file",
+                            FileType::Symlink => "symbolic link",
+                            FileType::CharDevice => "character device",
+                            FileType::BlockDevice => "block device",
+                        };
+                        match node.read_link() {
+                            Ok(target) => println!("  File: {} -> {}", path, target),
+                            Err(_) => println!("  File: {}", path),
                         }
-                        None => println_colored!(Color::Red, Color::Black, "No such directory: {}", arg),
+                        println!("  Type: {}  Inode: {}  Links: {}  Size: {}", kind, meta.ino, meta.nlink, meta.size);
+                        println!("  Mode: {:04o} ({})  Owner: {} ({})", meta.mode, mode_string(&meta), meta.uid, owner_name(meta.uid));
+                        println!("Access: {}", DateTime::from_timestamp(meta.accessed));
+                        println!("Modify: {}", DateTime::from_timestamp(meta.modified));
+                        println!("Create: {}", DateTime::from_timestamp(meta.created));
                     }
+                    Err(e) => println_colored!(Color::Red, Color::Black, "{}: {}", path, e),
+                }
+            } else {
+                println_colored!(Color::Green, Color::Black, "Usage: stat <path>");
+            }
+        }
+        "cd" => {
+            if let Some(path) = parts.next() {
+                match vfs::lookup(path).map(vfs::set_current_dir) {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => println_colored!(Color::Red, Color::Black, "{} is not a directory", path),
+                    Err(_) => println_colored!(Color::Red, Color::Black, "No such directory: {}", path),
                 }
             } else {
                 println_colored!(Color::Green, Color::Black, "Usage: cd <dir>");
@@ -241,24 +313,19 @@ pub fn execute_command(command: &str) {
         }
         "rm" => {
             if let Some(name) = parts.next() {
-                match Node::get_entry(&current_dir, name) {
-                    Some(node) => {
-                        let n = node.borrow();
-                        match &*n {
-                            Node::File { .. } => {
-                                if let Err(e) = Node::remove_entry(&current_dir, name) {
-                                    println_colored!(Color::Red, Color::Black, "Error removing file: {}", e);
-                                }
-                            }
-                            Node::Directory { .. } => {
-                                print_colored!(Color::Red, Color::Black, "{} is a directory,", name);
-                                print!("use ");
-                                print_colored!(Color::Green, Color::Black, "rmdir ");
-                                println!("to remove directories");
-                            }
+                match vfs::lookup_link(name).map(|node| node.file_type()) {
+                    Ok(FileType::File | FileType::Symlink | FileType::CharDevice | FileType::BlockDevice) => {
+                        if let Err(e) = vfs::remove(name) {
+                            println_colored!(Color::Red, Color::Black, "Error removing file: {}", e);
                         }
                     }
-                    None => println_colored!(Color::Red, Color::Black, "No such file: {}", name),
+                    Ok(FileType::Directory) => {
+                        print_colored!(Color::Red, Color::Black, "{} is a directory,", name);
+                        print!("use ");
+                        print_colored!(Color::Green, Color::Black, "rmdir ");
+                        println!("to remove directories");
+                    }
+                    Err(_) => println_colored!(Color::Red, Color::Black, "No such file: {}", name),
                 }
             } else {
                 println_colored!(Color::Green, Color::Black, "Usage: rm <file>");
@@ -266,79 +333,312 @@ pub fn execute_command(command: &str) {
         }
         "rmdir" => {
             if let Some(name) = parts.next() {
-                match Node::get_entry(&current_dir, name) {
-                    Some(node) => {
-                        let n = node.borrow();
-                        match &*n {
-                            Node::Directory { entries } => {
-                                if entries.is_empty() {
-                                    if let Err(e) = Node::remove_entry(&current_dir, name) {
-                                        println_colored!(Color::Red, Color::Black, "Error removing directory: {}", e);
-                                    }
-                                } else {
-                                    println_colored!(Color::Red, Color::Black, "Directory is not empty");
-                                }
-                            }
-                            Node::File { .. } => {
-                                print_colored!(Color::Red, Color::Black, "{} is a file,", name);
-                                print!("use ");
-                                print_colored!(Color::Green, Color::Black, "rm ");
-                                println!("to remove files");
-                            }
-                        }
+                match vfs::lookup_link(name).map(|node| node.file_type()) {
+                    Ok(FileType::Directory) => match vfs::remove(name) {
+                        Ok(()) => {}
+                        Err("Directory not empty") => println_colored!(Color::Red, Color::Black, "Directory is not empty"),
+                        Err(e) => println_colored!(Color::Red, Color::Black, "Error removing directory: {}", e),
+                    },
+                    Ok(FileType::File | FileType::Symlink | FileType::CharDevice | FileType::BlockDevice) => {
+                        print_colored!(Color::Red, Color::Black, "{} is a file,", name);
+                        print!("use ");
+                        print_colored!(Color::Green, Color::Black, "rm ");
+                        println!("to remove files");
                     }
-                    None => println_colored!(Color::Red, Color::Black, "No such directory: {}", name),
+                    Err(_) => println_colored!(Color::Red, Color::Black, "No such directory: {}", name),
                 }
             } else {
                 println_colored!(Color::Green, Color::Black, "Usage: rmdir <dir>");
             }
         }
-        "write" => {
+        "write" | "append" => {
             if let Some(name) = parts.next() {
-                let data: String = parts.collect::<Vec<_>>().join(" ");
+                let mut data: String = parts.collect::<Vec<_>>().join(" ");
                 if data.is_empty() {
-                    println_colored!(Color::Green, Color::Black, "Usage: write <file> <text>");
+                    if let Some(input) = PIPE_INPUT.lock().take() {
+                        data = String::from_utf8_lossy(&input).into_owned();
+                    }
+                }
+                if data.is_empty() {
+                    println_colored!(Color::Green, Color::Black, "Usage: {} <file> <text>", cmd);
                     return;
                 }
-                match Node::get_entry(&current_dir, name) {
-                    Some(node) => {
-                        if let Err(e) = Node::write_file(&node, data.as_bytes()) {
-                            println_colored!(Color::Red, Color::Black, "Error writing to file: {}", e);
-                        }
+                let result = match vfs::lookup(name) {
+                    Ok(node) if cmd == "append" => vfs::write_fully(&mut FileHandle::new(node, true), data.as_bytes()),
+                    Ok(node) => vfs::write_all(&node, data.as_bytes()),
+                    Err(_) => {
+                        println_colored!(Color::Red, Color::Black, "No such file: {}", name);
+                        return;
                     }
-                    None => println_colored!(Color::Red, Color::Black, "No such file: {}", name),
+                };
+                if let Err(e) = result {
+                    println_colored!(Color::Red, Color::Black, "Error writing to file: {}", e);
                 }
             } else {
-                println_colored!(Color::Green, Color::Black, "Usage: write <file> <text>");
+                println_colored!(Color::Green, Color::Black, "Usage: {} <file> <text>", cmd);
+            }
+        }
+        "truncate" => {
+            match (parts.next(), parts.next().map(str::parse::<usize>)) {
+                (Some(name), Some(Ok(size))) => {
+                    if let Err(e) = vfs::lookup(name).and_then(|node| node.truncate(size)) {
+                        println_colored!(Color::Red, Color::Black, "{}: {}", name, e);
+                    }
+                }
+                _ => println_colored!(Color::Green, Color::Black, "Usage: truncate <file> <size>"),
             }
         }
         "open" => {
             if let Some(name) = parts.next() {
-                match Node::get_entry(&current_dir, name) {
-                    Some(node) => {
-                        let n = node.borrow();
-                        match &*n {
-                            Node::File { .. } => {
-                                match Node::read_file(&node) {
-                                    Ok(content) => {
-                                        if let Ok(text) = core::str::from_utf8(&content) {
-                                            println!("{}", text);
-                                        } else {
-                                            println!("<binary data>");
-                                        }
-                                    }
-                                    Err(e) => println_colored!(Color::Red, Color::Black, "Error reading file: {}", e),
-                                }
+                match vfs::lookup(name) {
+                    Ok(node) => match node.file_type() {
+                        FileType::File | FileType::Symlink | FileType::CharDevice | FileType::BlockDevice => {
+                            if let Err(e) = print_file(node) {
+                                println_colored!(Color::Red, Color::Black, "Error reading file: {}", e);
                             }
-                            Node::Directory { .. } => println_colored!(Color::Red, Color::Black, "{} is a directory", name),
                         }
-                    }
-                    None => println_colored!(Color::Red, Color::Black, "No such file: {}", name),
+                        FileType::Directory => println_colored!(Color::Red, Color::Black, "{} is a directory", name),
+                    },
+                    Err(_) => println_colored!(Color::Red, Color::Black, "No such file: {}", name),
                 }
             } else {
                 println_colored!(Color::Green, Color::Black, "Usage: open <file>");
             }
         }
+        "mv" => {
+            match (parts.next(), parts.next()) {
+                (Some(src), Some(dst)) => {
+                    if let Err(e) = vfs::rename(src, &destination(src, dst)) {
+                        println_colored!(Color::Red, Color::Black, "Error moving {}: {}", src, e);
+                    }
+                }
+                _ => println_colored!(Color::Green, Color::Black, "Usage: mv <source> <destination>"),
+            }
+        }
+        "cp" => {
+            let mut src = parts.next();
+            let recursive = src == Some("-r");
+            if recursive {
+                src = parts.next();
+            }
+            let (src, dst) = match (src, parts.next()) {
+                (Some(src), Some(dst)) => (src, dst),
+                _ => {
+                    println_colored!(Color::Green, Color::Black, "Usage: cp [-r] <source> <destination>");
+                    return;
+                }
+            };
+            let node = match vfs::lookup(src) {
+                Ok(node) => node,
+                Err(e) => {
+                    println_colored!(Color::Red, Color::Black, "{}: {}", src, e);
+                    return;
+                }
+            };
+            if node.file_type() == FileType::Directory && !recursive {
+                print_colored!(Color::Red, Color::Black, "{} is a directory,", src);
+                print!("use ");
+                print_colored!(Color::Green, Color::Black, "cp -r ");
+                println!("to copy directories");
+                return;
+            }
+            let dst = destination(src, dst);
+            if let Err(e) = vfs::lookup_parent(&dst).and_then(|(dir, name)| vfs::copy(&node, &dir, name)) {
+                println_colored!(Color::Red, Color::Black, "Error copying {}: {}", src, e);
+            }
+        }
+        "ln" => {
+            let mut target = parts.next();
+            let symbolic = target == Some("-s");
+            if symbolic {
+                target = parts.next();
+            }
+            match (target, parts.next()) {
+                (Some(target), Some(link)) => {
+                    let link = destination(target, link);
+                    let result = if symbolic { vfs::symlink(target, &link) } else { vfs::link(target, &link) };
+                    if let Err(e) = result {
+                        println_colored!(Color::Red, Color::Black, "Error linking {}: {}", link, e);
+                    }
+                }
+                _ => println_colored!(Color::Green, Color::Black, "Usage: ln [-s] <target> <link>"),
+            }
+        }
+        "readlink" => {
+            if let Some(path) = parts.next() {
+                match vfs::read_link(path) {
+                    Ok(target) => println!("{}", target),
+                    Err(e) => println_colored!(Color::Red, Color::Black, "{}: {}", path, e),
+                }
+            } else {
+                println_colored!(Color::Green, Color::Black, "Usage: readlink <link>");
+            }
+        }
+        "lspci" => {
+            let verbose = parts.next() == Some("-v");
+            for device in pci::devices() {
+                print!("{} {}: ", device.address, pci::class_name(device.class, device.subclass));
+                if let Some(vendor) = pci::vendor_name(device.vendor) {
+                    print!("{} ", vendor);
+                }
+                println!("[{:04x}:{:04x}] (rev {:02x})", device.vendor, device.device, device.revision);
+                if !verbose {
+                    continue;
+                }
+                println!("        Class {:02x}{:02x}, prog-if {:02x}, IRQ {}",
+                    device.class, device.subclass, device.prog_if, device.interrupt_line);
+                for bar in &device.bars {
+                    match bar.bar {
+                        pci::Bar::Io(port) => print!("        BAR{}: I/O ports at {:#x}", bar.index, port),
+                        pci::Bar::Memory(address) => print!("        BAR{}: Memory at {:#x} ({}-bit{})",
+                            bar.index, address, if bar.is_64 { 64 } else { 32 },
+                            if bar.prefetchable { ", prefetchable" } else { "" }),
+                    }
+                    println!(" [size={}]", size_string(bar.size));
+                }
+                if !device.capabilities.is_empty() {
+                    let names: Vec<&str> = device.capabilities.iter().map(|&(id, _)| pci::capability_name(id)).collect();
+                    println!("        Capabilities: {}", names.join(", "));
+                }
+                if let Some(driver) = device.driver {
+                    println!("        Kernel driver in use: {}", driver);
+                }
+            }
+        }
+        "lsblk" => {
+            let partitions = partition::partitions();
+            println!("{:<10} {:>7} {:<5} {:>10}  TYPE", "NAME", "SIZE", "", "START");
+            for disk in block::devices() {
+                if partitions.iter().any(|part| part.name == disk.name()) {
+                    continue;
+                }
+                println!("{:<10} {:>7} {:<5}", disk.name(), rounded_size(disk.capacity()), "disk");
+                let parts: Vec<_> = partitions.iter().filter(|part| part.disk == disk.name()).collect();
+                for (i, part) in parts.iter().enumerate() {
+                    let branch = if i + 1 == parts.len() { "`-" } else { "|-" };
+                    let size = part.sectors * disk.sector_size() as u64;
+                    print!("{:<10} {:>7} {:<5} {:>10}  {}", format!("{}{}", branch, part.name),
+                        rounded_size(size), "part", part.start, part.kind);
+                    if part.label.is_empty() {
+                        println!();
+                    } else {
+                        println!(" \"{}\"", part.label);
+                    }
+                }
+            }
+        }
+        "bcache" => {
+            let stats = cache::stats();
+            let lookups = stats.hits + stats.misses;
+            println!("Blocks:     {} of {} ({} bytes each), {} dirty", stats.cached, cache::CAPACITY,
+                cache::BLOCK_SIZE, stats.dirty);
+            println!("Hits:       {} of {} ({}%)", stats.hits, lookups,
+                (stats.hits * 100).checked_div(lookups).unwrap_or(0));
+            println!("Misses:     {} ({} blocks read ahead)", stats.misses, stats.read_ahead);
+            println!("Evictions:  {}", stats.evictions);
+            println!("Writebacks: {}", stats.writebacks);
+        }
+        "sync" => {
+            if let Err(e) = vfs::sync().and_then(|_| cache::sync()) {
+                println_colored!(Color::Red, Color::Black, "Error: {}", e);
+            }
+            if snapshot::configured() {
+                match snapshot::save() {
+                    Ok(summary) => println!("Saved {} nodes ({} bytes)", summary.nodes, summary.bytes),
+                    Err(e) => println_colored!(Color::Red, Color::Black, "Error: {}", e),
+                }
+            }
+        }
+        "mount" => match (parts.next(), parts.next()) {
+            (None, _) => {
+                for (path, fs) in vfs::mounts() {
+                    println!("{} on {}", fs, path);
+                }
+            }
+            (Some(device), Some(path)) => {
+                if let Err(e) = mount_device(device, path, parts.next()) {
+                    println_colored!(Color::Red, Color::Black, "Error: {}", e);
+                }
+            }
+            _ => println_colored!(Color::Green, Color::Black, "Usage: mount [<device> <path> [fstype]]"),
+        },
+        "run" => {
+            if let Some(path) = parts.next() {
+                run_program(path, parts, false);
+            } else {
+                println_colored!(Color::Green, Color::Black, "Usage: run <program> [args]");
+            }
+        }
+        "strace" => {
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("-p"), Some(pid), Some(mode @ ("on" | "off"))) => match pid.parse() {
+                    Ok(pid) => {
+                        if let Err(e) = process::set_trace(pid, mode == "on") {
+                            println_colored!(Color::Red, Color::Black, "Error: {}", e);
+                        }
+                    }
+                    Err(_) => println_colored!(Color::Red, Color::Black, "Bad pid: {}", pid),
+                },
+                (Some("-p"), _, _) => println_colored!(Color::Green, Color::Black, "Usage: strace -p <pid> <on|off>"),
+                (Some(path), arg1, arg2) => {
+                    let args = arg1.into_iter().chain(arg2).chain(parts);
+                    run_program(path, args, true);
+                    println!("(trace written to the serial port)");
+                }
+                (None, _, _) => println_colored!(Color::Green, Color::Black, "Usage: strace <program> [args]"),
+            }
+        }
+        "syscalls" => {
+            if parts.next() == Some("reset") {
+                strace::reset();
+                return;
+            }
+            println!("  NR  NAME        CALLS   ERRORS      AVG NS");
+            for stat in strace::stats() {
+                println!("{:>4}  {:<10} {:>6} {:>8} {:>11}",
+                    stat.number, strace::name(stat.number), stat.calls, stat.errors, strace::nanos(stat.cycles / stat.calls));
+            }
+        }
+        "ipcs" => {
+            println!("Message queues:");
+            for (name, messages) in ipc::queues() {
+                println!("  {:<20} {} messages", name, messages);
+            }
+            println!("Shared memory:");
+            for (name, size, users) in ipc::shared_objects() {
+                println!("  {:<20} {} bytes, {} users", name, size, users);
+            }
+        }
+        "kill" => {
+            let (pid, sig) = match (parts.next(), parts.next()) {
+                (Some(pid), None) => (pid, Some(signal::SIGTERM)),
+                (Some(pid), Some(sig)) => (pid, sig.parse().ok()),
+                _ => {
+                    println_colored!(Color::Green, Color::Black, "Usage: kill <pid> [signal]");
+                    return;
+                }
+            };
+            match (pid.parse(), sig) {
+                (Ok(pid), Some(sig)) => {
+                    if let Err(e) = signal::send(pid, sig) {
+                        println_colored!(Color::Red, Color::Black, "Error: {}", e);
+                    }
+                }
+                (Err(_), _) => println_colored!(Color::Red, Color::Black, "Bad pid: {}", pid),
+                (_, None) => println_colored!(Color::Red, Color::Black, "Bad signal number"),
+            }
+        }
+        "ps" => {
+            println!("  PID  PPID  STATE     NAME");
+            for (pid, ppid, name, state) in process::list() {
+                let state = match state {
+                    State::Ready => "ready".to_string(),
+                    State::Running => "running".to_string(),
+                    State::Zombie(code) => format!("zombie({})", code),
+                };
+                println!("{:>5} {:>5}  {:<9} {}", pid, ppid, state, name);
+            }
+        }
         "hi" | "hello" | "hi!" | "hello!" => {
             println_colored!(Color::Yellow, Color::Black, "Hi broooooooo!");
             println_colored!(Color::Yellow, Color::Black, "You nice, good luck!!!");
@@ -352,6 +652,166 @@ pub fn execute_command(command: &str) {
     }
 }
 
+/// Runs `stage1 | stage2 | ...`. Stages written as `run <program> [args]`
+/// are processes connected by pipes; the output of builtins is captured and
+/// handed to the next stage.
+fn run_pipeline(command: &str) {
+    let stages: Vec<&str> = command.split('|').map(str::trim).collect();
+    if stages.iter().any(|stage| stage.is_empty()) {
+        println_colored!(Color::Red, Color::Black, "Empty command in pipeline");
+        return;
+    }
+    let is_program = |i: usize| stages[i].split_whitespace().next() == Some("run");
+
+    // Pipe `i` connects stage `i` to stage `i + 1` when either is a program;
+    // two builtins in a row pass the captured text directly.
+    let mut readers: Vec<Option<FileRef>> = Vec::new();
+    let mut writers: Vec<Option<FileRef>> = Vec::new();
+    for i in 0..stages.len() - 1 {
+        let (read_end, write_end) = if is_program(i) || is_program(i + 1) {
+            let (read_end, write_end) = OpenFile::pipe();
+            (Some(read_end), Some(write_end))
+        } else {
+            (None, None)
+        };
+        readers.push(read_end);
+        writers.push(write_end);
+    }
+
+    // Programs start first, so that builtins have someone to write to.
+    let mut pids = Vec::new();
+    for (i, stage) in stages.iter().enumerate() {
+        if !is_program(i) {
+            continue;
+        }
+        let mut words = stage.split_whitespace().skip(1);
+        let path = match words.next() {
+            Some(path) => path,
+            None => {
+                println_colored!(Color::Green, Color::Black, "Usage: run <program> [args]");
+                continue;
+            }
+        };
+        let args: Vec<String> = core::iter::once(path).chain(words).map(|arg| arg.to_string()).collect();
+        let stdin = i.checked_sub(1).and_then(|prev| readers[prev].clone());
+        let stdout = writers.get(i).cloned().flatten();
+        match spawn_redirected(path, &args, stdin, stdout) {
+            Ok(pid) => pids.push((i, pid)),
+            Err(e) => println_colored!(Color::Red, Color::Black, "Error running {}: {}", path, e),
+        }
+    }
+    // Drop the ends that belong to programs, so that they see end of file
+    // and broken pipes once the other side is gone.
+    for i in 0..stages.len() - 1 {
+        if is_program(i) {
+            writers[i] = None;
+        }
+        if is_program(i + 1) {
+            readers[i] = None;
+        }
+    }
+
+    // Ctrl+C goes to the programs from here on, while builtins wait for
+    // their output or feed them, until every one has been reaped.
+    if let Some(&(_, pid)) = pids.first() {
+        signal::FOREGROUND.store(pid, Ordering::Relaxed);
+    }
+    let mut output: Option<Vec<u8>> = None;
+    for (i, stage) in stages.iter().enumerate() {
+        if is_program(i) {
+            continue;
+        }
+        let input = match i.checked_sub(1) {
+            Some(prev) if is_program(prev) => {
+                if let Some(&(_, pid)) = pids.iter().find(|&&(stage, _)| stage == prev) {
+                    signal::FOREGROUND.store(pid, Ordering::Relaxed);
+                }
+                readers[prev].take().map(|pipe| read_all(&pipe))
+            }
+            Some(_) => output.take(),
+            None => None,
+        };
+        *PIPE_INPUT.lock() = input;
+        if i == stages.len() - 1 {
+            execute_command(stage);
+        } else {
+            let captured = vga_buffer::capture(|| execute_command(stage));
+            match writers[i].take() {
+                Some(pipe) => {
+                    let _ = OpenFile::write(&pipe, captured.as_bytes());
+                }
+                None => output = Some(captured.into_bytes()),
+            }
+        }
+        *PIPE_INPUT.lock() = None;
+    }
+    drop(readers);
+    drop(writers);
+
+    for (_, pid) in pids {
+        signal::FOREGROUND.store(pid, Ordering::Relaxed);
+        if let Ok(Some((_, code))) = process::wait(Some(pid), false) {
+            if code != 0 {
+                println!("Process {} exited with status {}", pid, code);
+            }
+        }
+    }
+    signal::FOREGROUND.store(process::KERNEL_PID, Ordering::Relaxed);
+}
+
+/// Starts a program with stdin and stdout replaced by the given files.
+fn spawn_redirected(path: &str, args: &[String], stdin: Option<FileRef>, stdout: Option<FileRef>) -> Result<process::Pid, &'static str> {
+    let saved = process::with_files(|files| files.clone());
+    process::with_files(|files| {
+        if let Some(stdin) = stdin {
+            let _ = files.set(file::STDIN, stdin);
+        }
+        if let Some(stdout) = stdout {
+            let _ = files.set(file::STDOUT, stdout);
+        }
+    });
+    let result = process::spawn(path, args);
+    process::with_files(|files| *files = saved);
+    result
+}
+
+/// Reads a pipe until every writer is gone.
+fn read_all(pipe: &FileRef) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 512];
+    while let Ok(len) = OpenFile::read(pipe, &mut buf) {
+        if len == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..len]);
+    }
+    data
+}
+
+fn run_program<'a>(path: &'a str, args: impl Iterator<Item = &'a str>, trace: bool) {
+    let args: Vec<String> = core::iter::once(path)
+        .chain(args)
+        .map(|arg| arg.to_string())
+        .collect();
+    let pid = match process::spawn(path, &args) {
+        Ok(pid) => pid,
+        Err(e) => {
+            println_colored!(Color::Red, Color::Black, "Error running {}: {}", path, e);
+            return;
+        }
+    };
+    if trace {
+        let _ = process::set_trace(pid, true);
+    }
+    signal::FOREGROUND.store(pid, Ordering::Relaxed);
+    let status = process::wait(Some(pid), false);
+    signal::FOREGROUND.store(process::KERNEL_PID, Ordering::Relaxed);
+    match status {
+        Ok(Some((_, code))) => println!("Process {} exited with status {}", pid, code),
+        _ => println_colored!(Color::Red, Color::Black, "Lost process {}", pid),
+    }
+}
+
 unsafe fn outw(port: u16, value: u16) {
     asm!(
         "out dx, ax",
@@ -370,49 +830,120 @@ unsafe fn outb(port: u16, value: u8) {
     );
 }
 
-unsafe fn inb(port: u16) -> u8 {
-    let result: u8;
-    asm!(
-        "in al, dx",
-        in("dx") port,
-        out("al") result,
-        options(nomem, nostack, preserves_flags),
-    );
-    result
+/// Where `src` ends up for `mv` and `cp`: inside `dst` if that is an
+/// existing directory, at `dst` itself otherwise.
+fn destination(src: &str, dst: &str) -> String {
+    match vfs::lookup(dst) {
+        Ok(node) if node.file_type() == FileType::Directory => {
+            let name = src.trim_end_matches('/').rsplit('/').next().unwrap_or(src);
+            format!("{}/{}", dst.trim_end_matches('/'), name)
+        }
+        _ => dst.to_string(),
+    }
 }
 
-fn get_path(dir_stack: &Vec<NodeRef>) -> String {
-    if dir_stack.is_empty() {
-        return "/".to_string();
+/// Prints a text file a chunk at a time, so it never has to fit in memory.
+fn print_file(node: InodeRef) -> Result<(), &'static str> {
+    let mut file = FileHandle::new(node, false);
+    let mut buf = [0u8; 512];
+    let mut pending = Vec::new();
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        pending.extend_from_slice(&buf[..read]);
+        // A character may be split between two chunks.
+        let valid = match core::str::from_utf8(&pending) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => break,
+        };
+        if let Ok(text) = core::str::from_utf8(&pending[..valid]) {
+            print!("{}", text);
+        }
+        pending.drain(..valid);
     }
+    if pending.is_empty() {
+        println!();
+    } else {
+        println!("<binary data>");
+    }
+    Ok(())
+}
 
-    let mut parts = Vec::new();
+/// `drwxr-xr-x` style rendering of the type and permission bits.
+fn mode_string(meta: &Metadata) -> String {
+    let mut text = String::with_capacity(10);
+    text.push(match meta.file_type {
+        FileType::Directory => 'd',
+        FileType::File => '-',
+        FileType::Symlink => 'l',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+    });
+    for shift in [6, 3, 0] {
+        let bits = meta.mode >> shift;
+        text.push(if bits & 4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 2 != 0 { 'w' } else { '-' });
+        text.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    text
+}
 
-    for i in 1..dir_stack.len() {
-        let current = &dir_stack[i];
-        let parent = &dir_stack[i - 1];
+/// Size with the largest binary unit that divides it, like `16K`.
+fn size_string(size: u64) -> String {
+    for (shift, unit) in [(30, "G"), (20, "M"), (10, "K")] {
+        if size >= 1 << shift && size.is_multiple_of(1 << shift) {
+            return format!("{}{}", size >> shift, unit);
+        }
+    }
+    size.to_string()
+}
 
-        let parent_borrow = parent.borrow();
-        match &*parent_borrow {
-            Node::Directory { entries } => {
-                let name = entries.iter()
-                    .find_map(|(name, node_ref)| {
-                        if Rc::ptr_eq(node_ref, current) {
-                            Some(name.clone())
-                        } else {
-                            None
-                        }
-                    });
-                if let Some(name) = name {
-                    parts.push(name);
-                } else {
-                    parts.push("<unknown>".to_string());
-                }
-            }
-            _ => {
-                parts.push("<not a directory>".to_string());
-            }
+/// Size in the largest binary unit it reaches, rounded to a tenth, like `15.9M`.
+fn rounded_size(size: u64) -> String {
+    for (shift, unit) in [(40, "T"), (30, "G"), (20, "M"), (10, "K")] {
+        if size >= 1 << shift {
+            let tenths = (size * 10 + (1 << shift) / 2) >> shift;
+            return if tenths.is_multiple_of(10) {
+                format!("{}{}", tenths / 10, unit)
+            } else {
+                format!("{}.{}{}", tenths / 10, tenths % 10, unit)
+            };
         }
     }
-    format!("/{}", parts.join("/"))
+    format!("{}B", size)
+}
+
+/// There are no user accounts yet; everything belongs to root.
+fn owner_name(uid: u32) -> String {
+    match uid {
+        0 => "root".to_string(),
+        _ => uid.to_string(),
+    }
+}
+
+/// Mounts the file system on a block device, of the given type or whatever
+/// type is found on it.
+fn mount_device(device: &str, path: &str, fstype: Option<&str>) -> Result<(), &'static str> {
+    let device = block::find(device).ok_or("No such device")?;
+    let fs: FsRef = match fstype {
+        Some("vfat" | "fat" | "msdos") => Rc::new(FatFs::new(device)?),
+        Some("ext2") => Rc::new(Ext2Fs::new(device)?),
+        Some(_) => return Err("Unknown file system type"),
+        None => match Ext2Fs::new(device.clone()) {
+            Ok(fs) => Rc::new(fs),
+            Err("Not an ext2 file system") => Rc::new(FatFs::new(device)?),
+            Err(e) => return Err(e),
+        },
+    };
+    vfs::mount(path, fs)
+}
+
+/// Path of the current directory for the prompt.
+fn current_path() -> String {
+    vfs::current_dir()
+        .and_then(|dir| vfs::path_of(&dir))
+        .unwrap_or_else(|_| "?".to_string())
 }
\ No newline at end of file
@@ -0,0 +1,304 @@
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::memory;
+use crate::process::{self, Pid, KERNEL_PID};
+use crate::syscalls::{SysResult, TrapFrame, EINVAL, EPERM, ESRCH};
+use crate::usercopy::UserPtr;
+
+pub const SIGHUP: u64 = 1;
+pub const SIGINT: u64 = 2;
+pub const SIGQUIT: u64 = 3;
+pub const SIGILL: u64 = 4;
+pub const SIGABRT: u64 = 6;
+pub const SIGKILL: u64 = 9;
+pub const SIGUSR1: u64 = 10;
+pub const SIGSEGV: u64 = 11;
+pub const SIGUSR2: u64 = 12;
+pub const SIGPIPE: u64 = 13;
+pub const SIGALRM: u64 = 14;
+pub const SIGTERM: u64 = 15;
+pub const SIGCHLD: u64 = 17;
+
+/// Signals are numbered `1..NSIG`; bit `n` of a mask stands for signal `n`.
+pub const NSIG: usize = 32;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// `how` values of `sigprocmask`.
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+/// Signals that can be neither caught, ignored nor blocked.
+const UNBLOCKABLE: u64 = 1 << SIGKILL;
+
+/// Area below the interrupted stack pointer a handler frame must not touch.
+const RED_ZONE: u64 = 128;
+
+/// Flags user code may change through `sigreturn`: CF, PF, AF, ZF, SF, TF, DF, OF.
+const USER_RFLAGS: u64 = 0xdd5;
+const RFLAGS_IF: u64 = 0x200;
+const RFLAGS_DF: u64 = 0x400;
+
+/// Process that receives SIGINT when Ctrl+C is pressed; the kernel pid while
+/// the shell itself is in the foreground.
+pub static FOREGROUND: AtomicU64 = AtomicU64::new(KERNEL_PID);
+
+/// What happens to a signal, as laid out in user memory for `sigaction`.
+/// `restorer` is where the handler returns to; it must call `sigreturn`.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct SigAction {
+    pub handler: u64,
+    pub mask: u64,
+    pub restorer: u64,
+}
+
+/// Pushed on the user stack when a handler is entered. The handler's return
+/// address is `restorer`, so `sigreturn` finds the rest 8 bytes below its
+/// stack pointer.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct SignalFrame {
+    restorer: u64,
+    signal: u64,
+    blocked: u64,
+    context: TrapFrame,
+}
+
+#[derive(Clone)]
+pub struct SignalState {
+    pub pending: u64,
+    pub blocked: u64,
+    actions: [SigAction; NSIG],
+}
+
+impl Default for SignalState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SignalState {
+    pub fn new() -> Self {
+        SignalState { pending: 0, blocked: 0, actions: [SigAction::default(); NSIG] }
+    }
+
+    /// State for a new image: handlers go back to the default action since
+    /// their code is gone, while ignored signals and the mask are kept.
+    pub fn inherit(&self) -> Self {
+        let mut state = SignalState { pending: 0, blocked: self.blocked, actions: self.actions };
+        for action in state.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+        state
+    }
+
+    /// Marks `signal` pending, unless it would be ignored anyway.
+    pub fn post(&mut self, signal: u64) {
+        if signal == SIGKILL || !self.ignores(signal) {
+            self.pending |= 1 << signal;
+        }
+    }
+
+    fn ignores(&self, signal: u64) -> bool {
+        match self.actions[signal as usize].handler {
+            SIG_IGN => true,
+            SIG_DFL => !terminates_by_default(signal),
+            _ => false,
+        }
+    }
+
+    fn deliverable(&self) -> u64 {
+        self.pending & !self.blocked
+    }
+
+    /// Takes the lowest deliverable signal off the pending set.
+    fn take_next(&mut self) -> Option<u64> {
+        let ready = self.deliverable();
+        if ready == 0 {
+            return None;
+        }
+        let signal = ready.trailing_zeros() as u64;
+        self.pending &= !(1 << signal);
+        Some(signal)
+    }
+}
+
+fn terminates_by_default(signal: u64) -> bool {
+    signal != SIGCHLD
+}
+
+pub fn is_valid(signal: u64) -> bool {
+    signal >= 1 && signal < NSIG as u64
+}
+
+/// Exit status of a process terminated by `signal`.
+pub fn exit_status(signal: u64) -> i32 {
+    128 + signal as i32
+}
+
+pub fn send(pid: Pid, signal: u64) -> Result<(), &'static str> {
+    if pid == KERNEL_PID {
+        return Err("Kernel does not take signals");
+    }
+    if !is_valid(signal) {
+        return Err("Bad signal number");
+    }
+    process::with_signals(pid, |state| state.post(signal)).ok_or("No such process")
+}
+
+/// Called on Ctrl+C.
+pub fn interrupt_foreground() {
+    let pid = FOREGROUND.load(Ordering::Relaxed);
+    if pid != KERNEL_PID {
+        let _ = send(pid, SIGINT);
+    }
+}
+
+/// Whether a blocking call of the current process should give up so the
+/// pending signal can be delivered.
+pub fn has_pending() -> bool {
+    process::with_signals(process::current_pid(), |state| state.deliverable() != 0).unwrap_or(false)
+}
+
+/// Acts on pending signals right before returning to user mode: default
+/// actions are carried out here, a handler is entered by rewriting `frame`.
+pub fn deliver(frame: &mut TrapFrame) {
+    let pid = process::current_pid();
+    if pid == KERNEL_PID || !frame.from_user_mode() {
+        return;
+    }
+    loop {
+        let next = process::with_signals(pid, |state| {
+            state.take_next().map(|signal| (signal, state.actions[signal as usize], state.blocked))
+        });
+        let (signal, action, blocked) = match next.flatten() {
+            Some(next) => next,
+            None => return,
+        };
+        match action.handler {
+            SIG_IGN => continue,
+            SIG_DFL if !terminates_by_default(signal) => continue,
+            SIG_DFL => process::exit(exit_status(signal)),
+            _ => {
+                if enter_handler(frame, signal, &action, blocked).is_err() {
+                    process::exit(exit_status(SIGSEGV));
+                }
+                process::with_signals(pid, |state| {
+                    state.blocked |= (action.mask | 1 << signal) & !UNBLOCKABLE;
+                });
+                return;
+            }
+        }
+    }
+}
+
+fn enter_handler(frame: &mut TrapFrame, signal: u64, action: &SigAction, blocked: u64) -> Result<(), u64> {
+    let size = mem::size_of::<SignalFrame>() as u64;
+    // Aligned like a call: the stack is 16-byte aligned before the return
+    // address is pushed.
+    let sp = (frame.rsp.wrapping_sub(RED_ZONE + size) & !0xF).wrapping_sub(8);
+    UserPtr::new(sp).write(SignalFrame {
+        restorer: action.restorer,
+        signal,
+        blocked,
+        context: *frame,
+    })?;
+    frame.rip = action.handler;
+    frame.rsp = sp;
+    frame.rdi = signal;
+    frame.rflags &= !RFLAGS_DF;
+    Ok(())
+}
+
+pub fn sys_kill(pid: u64, signal: u64) -> SysResult {
+    if pid == KERNEL_PID {
+        return Err(EPERM);
+    }
+    if signal == 0 {
+        // Only checks that the process exists.
+        return process::with_signals(pid, |_| 0).ok_or(ESRCH);
+    }
+    match send(pid, signal) {
+        Ok(()) => Ok(0),
+        Err("No such process") => Err(ESRCH),
+        Err(_) => Err(EINVAL),
+    }
+}
+
+pub fn sys_sigaction(signal: u64, action: UserPtr<SigAction>, old: UserPtr<SigAction>) -> SysResult {
+    if !is_valid(signal) {
+        return Err(EINVAL);
+    }
+    let new = if action.is_null() { None } else { Some(action.read()?) };
+    if let Some(new) = new {
+        let handled = new.handler > SIG_IGN;
+        if signal == SIGKILL
+            || (handled && !(memory::is_user_range(new.handler, 1) && memory::is_user_range(new.restorer, 1)))
+        {
+            return Err(EINVAL);
+        }
+    }
+    let previous = process::with_signals(process::current_pid(), |state| {
+        let previous = state.actions[signal as usize];
+        if let Some(new) = new {
+            state.actions[signal as usize] = new;
+            if state.ignores(signal) {
+                state.pending &= !(1 << signal);
+            }
+        }
+        previous
+    })
+    .ok_or(ESRCH)?;
+    if !old.is_null() {
+        old.write(previous)?;
+    }
+    Ok(0)
+}
+
+pub fn sys_sigprocmask(how: u64, set: UserPtr<u64>, old: UserPtr<u64>) -> SysResult {
+    let set = if set.is_null() { None } else { Some(set.read()?) };
+    let previous = process::with_signals(process::current_pid(), |state| {
+        let previous = state.blocked;
+        if let Some(set) = set {
+            state.blocked = match how {
+                SIG_BLOCK => state.blocked | set,
+                SIG_UNBLOCK => state.blocked & !set,
+                SIG_SETMASK => set,
+                _ => return Err(EINVAL),
+            } & !UNBLOCKABLE;
+        }
+        Ok(previous)
+    })
+    .ok_or(ESRCH)??;
+    if !old.is_null() {
+        old.write(previous)?;
+    }
+    Ok(0)
+}
+
+/// Returns from a handler: restores the registers and mask saved by
+/// `enter_handler`. The result is the restored `rax`, so the syscall return
+/// path leaves it untouched.
+pub fn sys_sigreturn(frame: &mut TrapFrame) -> SysResult {
+    let saved = UserPtr::<SignalFrame>::new(frame.rsp.wrapping_sub(8)).read();
+    let saved = match saved {
+        Ok(saved) if memory::is_user_range(saved.context.rip, 1) => saved,
+        // The frame was clobbered; there is nothing sane to return to.
+        _ => process::exit(exit_status(SIGSEGV)),
+    };
+    let mut context = saved.context;
+    context.cs = frame.cs;
+    context.ss = frame.ss;
+    context.rflags = (context.rflags & USER_RFLAGS) | RFLAGS_IF;
+    process::with_signals(process::current_pid(), |state| {
+        state.blocked = saved.blocked & !UNBLOCKABLE;
+    });
+    *frame = context;
+    Ok(frame.rax)
+}
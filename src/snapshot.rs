@@ -0,0 +1,455 @@
+//! Saving the root ramfs to a raw disk and loading it back at boot.
+//!
+//! Sector 0 holds a header and the tree is stored as a stream of node
+//! records, the root first. A node is written once however many names it
+//! has, so hard links survive: a directory record lists its entries as
+//! names with the index of the record they point to.
+//!
+//! The rest of the disk is split into two slots. `save` writes the stream
+//! into the slot the current snapshot is not in and only then replaces the
+//! header, which says where the stream starts and carries its checksum, so
+//! an interrupted `sync` leaves the previous snapshot intact.
+//!
+//! Only a disk that is blank or already holds a snapshot is written to.
+//!
+//! The disk is read and written through the buffer cache, like any other.
+
+use alloc::{collections::BTreeMap, rc::Rc, string::String, vec, vec::Vec};
+use core::any::Any;
+
+use crate::block::{self, DeviceRef};
+use crate::{cache, partition};
+use crate::ramfs::{self, Attributes, Node, NodeRef, PAGE_SIZE};
+use crate::vfs::{self, FileType, Inode};
+
+/// The second drive of the primary channel; the first one is the boot image.
+const DISK: &str = "hdb";
+
+/// The format is laid out in sectors of this size.
+const SECTOR_SIZE: usize = 512;
+
+const MAGIC: &[u8; 8] = b"RAMFSIMG";
+const VERSION: u32 = 1;
+
+const KIND_DIRECTORY: u8 = 0;
+const KIND_FILE: u8 = 1;
+const KIND_SYMLINK: u8 = 2;
+
+const CORRUPT: &str = "Snapshot is corrupt";
+
+/// Bytes at the start of a disk that must be zero for it to count as blank.
+/// Partition tables and the superblocks of common file systems all lie
+/// within them.
+const BLANK_SIZE: usize = 4096;
+
+/// What `save` wrote.
+pub struct Summary {
+    pub nodes: usize,
+    pub bytes: u64,
+}
+
+/// FNV-1a, continued over every byte of the stream.
+fn checksum(mut hash: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        hash = (hash ^ byte as u32).wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+const CHECKSUM_START: u32 = 0x811C_9DC5;
+
+struct Writer {
+    /// `None` to only measure the stream.
+    disk: Option<DeviceRef>,
+    lba: u64,
+    /// First sector past the slot.
+    end: u64,
+    sector: [u8; SECTOR_SIZE],
+    used: usize,
+    length: u64,
+    checksum: u32,
+}
+
+impl Writer {
+    fn new(disk: Option<DeviceRef>, lba: u64, end: u64) -> Self {
+        Writer { disk, lba, end, sector: [0; SECTOR_SIZE], used: 0, length: 0, checksum: CHECKSUM_START }
+    }
+
+    fn bytes(&mut self, mut data: &[u8]) -> Result<(), &'static str> {
+        self.checksum = checksum(self.checksum, data);
+        self.length += data.len() as u64;
+        while !data.is_empty() {
+            let count = core::cmp::min(SECTOR_SIZE - self.used, data.len());
+            self.sector[self.used..self.used + count].copy_from_slice(&data[..count]);
+            self.used += count;
+            data = &data[count..];
+            if self.used == SECTOR_SIZE {
+                self.write_sector()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sector(&mut self) -> Result<(), &'static str> {
+        if self.lba >= self.end {
+            return Err("No space left on disk");
+        }
+        if let Some(disk) = &self.disk {
+            cache::write(disk, self.lba * SECTOR_SIZE as u64, &self.sector)?;
+        }
+        self.lba += 1;
+        self.used = 0;
+        self.sector.fill(0);
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), &'static str> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), &'static str> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Result<(), &'static str> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> Result<(), &'static str> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn string(&mut self, value: &str) -> Result<(), &'static str> {
+        self.u32(value.len() as u32)?;
+        self.bytes(value.as_bytes())
+    }
+
+    fn attributes(&mut self, kind: u8, attrs: &Attributes) -> Result<(), &'static str> {
+        self.u8(kind)?;
+        self.u16(attrs.mode)?;
+        self.u32(attrs.uid)?;
+        self.u64(attrs.ino)?;
+        self.u64(attrs.created)?;
+        self.u64(attrs.modified)?;
+        self.u64(attrs.accessed)
+    }
+
+    /// Writes out the last partial sector.
+    fn finish(&mut self) -> Result<(), &'static str> {
+        if self.used > 0 {
+            self.write_sector()?;
+        }
+        Ok(())
+    }
+}
+
+struct Reader {
+    disk: DeviceRef,
+    lba: u64,
+    sector: [u8; SECTOR_SIZE],
+    used: usize,
+    /// Bytes of the stream not read yet.
+    remaining: u64,
+    checksum: u32,
+}
+
+impl Reader {
+    fn new(disk: DeviceRef, lba: u64, length: u64) -> Self {
+        Reader {
+            disk,
+            lba,
+            sector: [0; SECTOR_SIZE],
+            used: SECTOR_SIZE,
+            remaining: length,
+            checksum: CHECKSUM_START,
+        }
+    }
+
+    fn bytes(&mut self, buf: &mut [u8]) -> Result<(), &'static str> {
+        if buf.len() as u64 > self.remaining {
+            return Err(CORRUPT);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            if self.used == SECTOR_SIZE {
+                cache::read(&self.disk, self.lba * SECTOR_SIZE as u64, &mut self.sector)?;
+                self.lba += 1;
+                self.used = 0;
+            }
+            let count = core::cmp::min(SECTOR_SIZE - self.used, buf.len() - done);
+            buf[done..done + count].copy_from_slice(&self.sector[self.used..self.used + count]);
+            self.used += count;
+            done += count;
+        }
+        self.remaining -= buf.len() as u64;
+        self.checksum = checksum(self.checksum, buf);
+        Ok(())
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], &'static str> {
+        let mut buf = [0; N];
+        self.bytes(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, &'static str> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        let len = self.u32()? as u64;
+        if len > self.remaining {
+            return Err(CORRUPT);
+        }
+        let mut buf = vec![0; len as usize];
+        self.bytes(&mut buf)?;
+        String::from_utf8(buf).map_err(|_| CORRUPT)
+    }
+}
+
+fn disk() -> Result<Option<DeviceRef>, &'static str> {
+    match block::find(DISK) {
+        Some(disk) if disk.sector_size() != SECTOR_SIZE => Err("Unsupported sector size"),
+        disk => Ok(disk),
+    }
+}
+
+/// Sectors in each of the two slots.
+fn slot_size(disk: &DeviceRef) -> u64 {
+    disk.sectors().saturating_sub(1) / 2
+}
+
+/// Whether `disk` may be overwritten: it holds a snapshot already, or its
+/// start is blank.
+fn overwritable(disk: &DeviceRef) -> Result<bool, &'static str> {
+    let mut sector = [0; SECTOR_SIZE];
+    for lba in 0..core::cmp::min((BLANK_SIZE / SECTOR_SIZE) as u64, disk.sectors()) {
+        cache::read(disk, lba * SECTOR_SIZE as u64, &mut sector)?;
+        if lba == 0 && sector.starts_with(MAGIC) {
+            return Ok(true);
+        }
+        if sector.iter().any(|&byte| byte != 0) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Writes the records of every node reachable from `root` and returns how
+/// many there are.
+fn write_tree(root: &NodeRef, writer: &mut Writer) -> Result<usize, &'static str> {
+    // Nodes are numbered in the order they are first reached.
+    let mut nodes = vec![root.clone()];
+    let mut indices = BTreeMap::new();
+    indices.insert(Rc::as_ptr(root), 0u32);
+    let mut next = 0;
+    while next < nodes.len() {
+        let node = nodes[next].clone();
+        next += 1;
+        let node = node.borrow();
+        match &*node {
+            Node::Directory { entries, attrs, .. } => {
+                writer.attributes(KIND_DIRECTORY, attrs)?;
+                writer.u32(entries.len() as u32)?;
+                for (name, child) in entries {
+                    let index = *indices.entry(Rc::as_ptr(child)).or_insert_with(|| {
+                        nodes.push(child.clone());
+                        (nodes.len() - 1) as u32
+                    });
+                    writer.string(name)?;
+                    writer.u32(index)?;
+                }
+            }
+            Node::File { content, attrs } => {
+                writer.attributes(KIND_FILE, attrs)?;
+                writer.u64(content.len() as u64)?;
+                writer.u32(content.pages().count() as u32)?;
+                for (index, page) in content.pages() {
+                    writer.u64(index as u64)?;
+                    writer.bytes(page)?;
+                }
+            }
+            Node::Symlink { target, attrs } => {
+                writer.attributes(KIND_SYMLINK, attrs)?;
+                writer.string(target)?;
+            }
+        }
+    }
+    writer.finish()?;
+    Ok(nodes.len())
+}
+
+/// Whether `disk` or one of its partitions is mounted.
+fn mounted(disk: &DeviceRef) -> bool {
+    let partitions = partition::partitions();
+    vfs::mounted_devices().iter().any(|device| {
+        device.name() == disk.name()
+            || partitions.iter().any(|part| part.disk == disk.name() && part.name == device.name())
+    })
+}
+
+/// Whether a disk for snapshots is attached: one that holds a snapshot or
+/// is blank.
+pub fn configured() -> bool {
+    match disk() {
+        Ok(Some(disk)) => overwritable(&disk).unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Writes the root ramfs to the snapshot disk. Only a disk that holds a
+/// snapshot or is blank is written to, and not while it is mounted.
+pub fn save() -> Result<Summary, &'static str> {
+    let root: NodeRef = (vfs::root()? as Rc<dyn Any>)
+        .downcast()
+        .map_err(|_| "Root file system is not ramfs")?;
+    let disk = disk()?.ok_or("No disk attached")?;
+    if mounted(&disk) {
+        return Err("Disk is mounted");
+    }
+    if !overwritable(&disk)? {
+        return Err("Disk holds other data");
+    }
+
+    // Nothing is written unless the whole stream fits in a slot.
+    let slot_size = slot_size(&disk);
+    let mut measure = Writer::new(None, 0, u64::MAX);
+    write_tree(&root, &mut measure)?;
+    if measure.length.div_ceil(SECTOR_SIZE as u64) > slot_size {
+        return Err("No space left on disk");
+    }
+
+    // The new stream goes to the slot the current snapshot is not in.
+    let mut header = [0; SECTOR_SIZE];
+    cache::read(&disk, 0, &mut header)?;
+    let start = if header.starts_with(MAGIC) && header[28..36] == 1u64.to_le_bytes() {
+        1 + slot_size
+    } else {
+        1
+    };
+    let mut writer = Writer::new(Some(disk.clone()), start, start + slot_size);
+    let count = write_tree(&root, &mut writer)?;
+    let (length, checksum) = (writer.length, writer.checksum);
+    // The stream has to be on the disk before the header that vouches for it.
+    cache::sync_device(&disk)?;
+
+    header.fill(0);
+    header[..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&(count as u32).to_le_bytes());
+    header[16..24].copy_from_slice(&length.to_le_bytes());
+    header[24..28].copy_from_slice(&checksum.to_le_bytes());
+    header[28..36].copy_from_slice(&start.to_le_bytes());
+    cache::write(&disk, 0, &header)?;
+    cache::sync_device(&disk)?;
+    Ok(Summary { nodes: count, bytes: length })
+}
+
+/// Reads the tree saved by `save`. Gives `None` when there is no disk or
+/// the disk holds no snapshot.
+pub fn load() -> Result<Option<NodeRef>, &'static str> {
+    let disk = match disk()? {
+        Some(disk) => disk,
+        None => return Ok(None),
+    };
+    let mut header = [0; SECTOR_SIZE];
+    cache::read(&disk, 0, &mut header)?;
+    if &header[..8] != MAGIC {
+        return Ok(None);
+    }
+    let field = |range: core::ops::Range<usize>| {
+        let mut bytes = [0; 8];
+        bytes[..range.len()].copy_from_slice(&header[range]);
+        u64::from_le_bytes(bytes)
+    };
+    if field(8..12) != VERSION as u64 {
+        return Err("Unsupported snapshot version");
+    }
+    let count = field(12..16) as u32;
+    let length = field(16..24);
+    let start = field(28..36);
+    let slot_size = slot_size(&disk);
+    if (start != 1 && start != 1 + slot_size) || length.div_ceil(SECTOR_SIZE as u64) > slot_size {
+        return Err(CORRUPT);
+    }
+    let mut reader = Reader::new(disk, start, length);
+
+    let mut nodes = Vec::new();
+    let mut saved = Vec::new();
+    // (directory, name, node) for every entry, linked once all nodes exist.
+    let mut links = Vec::new();
+    for index in 0..count {
+        let kind = reader.u8()?;
+        let mode = reader.u16()?;
+        let uid = reader.u32()?;
+        let ino = reader.u64()?;
+        let created = reader.u64()?;
+        let modified = reader.u64()?;
+        let accessed = reader.u64()?;
+        let node = match kind {
+            KIND_DIRECTORY => {
+                for _ in 0..reader.u32()? {
+                    let name = reader.string()?;
+                    links.push((index, name, reader.u32()?));
+                }
+                Node::new_dir()
+            }
+            KIND_FILE => {
+                let node = Node::new_file();
+                let len = reader.u64()?;
+                let mut page = vec![0; PAGE_SIZE];
+                for _ in 0..reader.u32()? {
+                    let offset = (reader.u64()? as usize).checked_mul(PAGE_SIZE).ok_or(CORRUPT)?;
+                    reader.bytes(&mut page)?;
+                    node.write_at(offset, &page)?;
+                }
+                node.truncate(len as usize)?;
+                node
+            }
+            KIND_SYMLINK => Node::new_symlink(&reader.string()?),
+            _ => return Err(CORRUPT),
+        };
+        nodes.push(node);
+        saved.push(Attributes { ino, nlink: 0, mode, uid, created, modified, accessed });
+    }
+    if reader.remaining != 0 || reader.checksum != field(24..28) as u32 {
+        return Err(CORRUPT);
+    }
+    match nodes.first() {
+        Some(root) if root.file_type() == FileType::Directory => {}
+        _ => return Err(CORRUPT),
+    }
+
+    for (dir, name, index) in links {
+        // The root has no name and a directory has only one.
+        let node = match nodes.get(index as usize) {
+            Some(node) if index != 0 => node.clone(),
+            _ => return Err(CORRUPT),
+        };
+        if node.file_type() == FileType::Directory && node.borrow().attrs().nlink > 0 {
+            return Err(CORRUPT);
+        }
+        Node::add_entry(&nodes[dir as usize], name, node).map_err(|_| CORRUPT)?;
+    }
+    // Linking counted the names and touched the directories; everything
+    // else comes from the disk.
+    for (node, attrs) in nodes.iter().zip(saved) {
+        let mut node = node.borrow_mut();
+        let nlink = node.attrs().nlink;
+        *node.attrs_mut() = Attributes { nlink, ..attrs };
+        ramfs::reserve_ino(attrs.ino);
+    }
+    Ok(Some(nodes[0].clone()))
+}
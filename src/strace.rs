@@ -0,0 +1,228 @@
+use alloc::{format, string::String, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+use crate::serial_println;
+use crate::syscalls::*;
+use crate::usercopy;
+
+/// Syscall numbers below this are counted individually.
+const MAX_SYSCALLS: usize = 64;
+
+static CALLS: [AtomicU64; MAX_SYSCALLS] = [const { AtomicU64::new(0) }; MAX_SYSCALLS];
+static ERRORS: [AtomicU64; MAX_SYSCALLS] = [const { AtomicU64::new(0) }; MAX_SYSCALLS];
+static CYCLES: [AtomicU64; MAX_SYSCALLS] = [const { AtomicU64::new(0) }; MAX_SYSCALLS];
+
+/// Input clock of the PIT, in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// How long `calibrate` measures the TSC for.
+const CALIBRATION_MS: u64 = 10;
+
+/// TSC cycles per microsecond, measured by `calibrate`.
+static CYCLES_PER_US: AtomicU64 = AtomicU64::new(0);
+
+pub struct SyscallStats {
+    pub number: u64,
+    pub calls: u64,
+    pub errors: u64,
+    pub cycles: u64,
+}
+
+pub fn name(number: u64) -> &'static str {
+    match number {
+        SYSCALL_WRITE => "write",
+        SYSCALL_EXIT => "exit",
+        SYSCALL_WAITPID => "waitpid",
+        SYSCALL_SPAWN => "spawn",
+        SYSCALL_EXEC => "exec",
+        SYSCALL_GETPID => "getpid",
+        SYSCALL_GETPPID => "getppid",
+        SYSCALL_READ => "read",
+        SYSCALL_OPEN => "open",
+        SYSCALL_CLOSE => "close",
+        SYSCALL_LSEEK => "lseek",
+        SYSCALL_STAT => "stat",
+        SYSCALL_MKDIR => "mkdir",
+        SYSCALL_UNLINK => "unlink",
+        SYSCALL_READDIR => "readdir",
+        SYSCALL_BRK => "brk",
+        SYSCALL_KILL => "kill",
+        SYSCALL_SIGACTION => "sigaction",
+        SYSCALL_SIGPROCMASK => "sigprocmask",
+        SYSCALL_SIGRETURN => "sigreturn",
+        SYSCALL_PIPE => "pipe",
+        SYSCALL_DUP2 => "dup2",
+        SYSCALL_MQ_OPEN => "mq_open",
+        SYSCALL_MQ_SEND => "mq_send",
+        SYSCALL_MQ_RECEIVE => "mq_receive",
+        SYSCALL_MQ_UNLINK => "mq_unlink",
+        SYSCALL_SHM_OPEN => "shm_open",
+        SYSCALL_SHM_MAP => "shm_map",
+        SYSCALL_SHM_UNMAP => "shm_unmap",
+        SYSCALL_SHM_UNLINK => "shm_unlink",
+        SYSCALL_FTRUNCATE => "ftruncate",
+        SYSCALL_RENAME => "rename",
+        SYSCALL_LINK => "link",
+        SYSCALL_SYMLINK => "symlink",
+        SYSCALL_READLINK => "readlink",
+        _ => "unknown",
+    }
+}
+
+pub fn timestamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Measures the TSC frequency by counting cycles while PIT channel 2, the
+/// speaker timer, counts down `CALIBRATION_MS` milliseconds.
+pub fn calibrate() {
+    let count = (PIT_FREQUENCY * CALIBRATION_MS / 1000) as u16;
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+    unsafe {
+        // Gate channel 2 on with the speaker off.
+        let saved = gate.read();
+        gate.write((saved & !0x02) | 0x01);
+        // Channel 2, low then high byte, mode 0: the output goes high once
+        // the count reaches zero.
+        command.write(0b1011_0000);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+        let start = timestamp();
+        while gate.read() & 0x20 == 0 {}
+        let cycles = timestamp() - start;
+        gate.write(saved);
+        CYCLES_PER_US.store((cycles / (CALIBRATION_MS * 1000)).max(1), Ordering::Relaxed);
+    }
+}
+
+/// Converts a TSC cycle count to nanoseconds.
+pub fn nanos(cycles: u64) -> u64 {
+    let per_us = CYCLES_PER_US.load(Ordering::Relaxed).max(1);
+    (cycles as u128 * 1000 / per_us as u128) as u64
+}
+
+/// Adds one call to the per-syscall counters.
+pub fn record(number: u64, result: &SysResult, cycles: u64) {
+    if let Some(calls) = CALLS.get(number as usize) {
+        calls.fetch_add(1, Ordering::Relaxed);
+        CYCLES[number as usize].fetch_add(cycles, Ordering::Relaxed);
+        if result.is_err() {
+            ERRORS[number as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn stats() -> Vec<SyscallStats> {
+    (0..MAX_SYSCALLS)
+        .filter(|&i| CALLS[i].load(Ordering::Relaxed) > 0)
+        .map(|i| SyscallStats {
+            number: i as u64,
+            calls: CALLS[i].load(Ordering::Relaxed),
+            errors: ERRORS[i].load(Ordering::Relaxed),
+            cycles: CYCLES[i].load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+pub fn reset() {
+    for i in 0..MAX_SYSCALLS {
+        CALLS[i].store(0, Ordering::Relaxed);
+        ERRORS[i].store(0, Ordering::Relaxed);
+        CYCLES[i].store(0, Ordering::Relaxed);
+    }
+}
+
+/// Logs a call that does not return, such as `exit`.
+pub fn log_entry(pid: u64, number: u64, args: &[u64; 6]) {
+    serial_println!("[pid {}] {}({}) = ?", pid, name(number), format_args(number, args));
+}
+
+pub fn log_call(pid: u64, number: u64, args: &[u64; 6], result: &SysResult, cycles: u64) {
+    let result = match result {
+        Ok(value) => format!("{}", value),
+        Err(errno) => format!("-{} ({})", errno, errno_name(*errno)),
+    };
+    serial_println!(
+        "[pid {}] {}({}) = {} <{} ns>",
+        pid, name(number), format_args(number, args), result, nanos(cycles)
+    );
+}
+
+fn format_args(number: u64, args: &[u64; 6]) -> String {
+    let [a0, a1, a2, a3, _, _] = *args;
+    match number {
+        SYSCALL_WRITE | SYSCALL_READ | SYSCALL_READDIR => format!("{}, {:#x}, {}", a0, a1, a2),
+        SYSCALL_EXIT => format!("{}", a0 as i32),
+        SYSCALL_WAITPID => format!("{}, {:#x}, {}", a0 as i64, a1, a2),
+        SYSCALL_SPAWN | SYSCALL_EXEC => format!("{}, {:#x}, {}", user_string(a0, a1), a2, a3),
+        SYSCALL_GETPID | SYSCALL_GETPPID => String::new(),
+        SYSCALL_OPEN => format!("{}, {:#x}", user_string(a0, a1), a2),
+        SYSCALL_CLOSE => format!("{}", a0),
+        SYSCALL_BRK => format!("{:#x}", a0),
+        SYSCALL_LSEEK => format!("{}, {}, {}", a0, a1 as i64, a2),
+        SYSCALL_STAT => format!("{}, {:#x}", user_string(a0, a1), a2),
+        SYSCALL_MKDIR | SYSCALL_UNLINK => user_string(a0, a1),
+        SYSCALL_RENAME | SYSCALL_LINK | SYSCALL_SYMLINK => {
+            format!("{}, {}", user_string(a0, a1), user_string(a2, a3))
+        }
+        SYSCALL_READLINK => format!("{}, {:#x}, {}", user_string(a0, a1), a2, a3),
+        SYSCALL_KILL | SYSCALL_DUP2 | SYSCALL_FTRUNCATE => format!("{}, {}", a0, a1),
+        SYSCALL_PIPE | SYSCALL_SHM_UNMAP => format!("{:#x}", a0),
+        SYSCALL_MQ_OPEN => format!("{}, {:#x}", user_string(a0, a1), a2),
+        SYSCALL_MQ_SEND | SYSCALL_MQ_RECEIVE => format!("{}, {:#x}, {}, {}", a0, a1, a2, a3 as i64),
+        SYSCALL_MQ_UNLINK | SYSCALL_SHM_UNLINK => user_string(a0, a1),
+        SYSCALL_SHM_OPEN => format!("{}, {:#x}, {}", user_string(a0, a1), a2, a3),
+        SYSCALL_SHM_MAP => format!("{}", a0),
+        SYSCALL_SIGACTION | SYSCALL_SIGPROCMASK => format!("{}, {:#x}, {:#x}", a0, a1, a2),
+        SYSCALL_SIGRETURN => String::new(),
+        _ => format!("{:#x}, {:#x}, {:#x}, {:#x}, {:#x}, {:#x}", a0, a1, a2, a3, args[4], args[5]),
+    }
+}
+
+/// Quotes a string argument, or shows the raw pointer if it can't be read.
+fn user_string(ptr: u64, len: u64) -> String {
+    let shown = core::cmp::min(len as usize, 64);
+    match usercopy::read_str(ptr, shown) {
+        Ok(s) if shown < len as usize => format!("{:?}...", s),
+        Ok(s) => format!("{:?}", s),
+        Err(_) => format!("{:#x}", ptr),
+    }
+}
+
+fn errno_name(errno: u64) -> &'static str {
+    match errno {
+        EPERM => "EPERM",
+        ENOENT => "ENOENT",
+        ESRCH => "ESRCH",
+        EINTR => "EINTR",
+        EIO => "EIO",
+        E2BIG => "E2BIG",
+        ENOEXEC => "ENOEXEC",
+        EBADF => "EBADF",
+        ECHILD => "ECHILD",
+        EAGAIN => "EAGAIN",
+        ENOMEM => "ENOMEM",
+        EFAULT => "EFAULT",
+        EBUSY => "EBUSY",
+        EEXIST => "EEXIST",
+        EXDEV => "EXDEV",
+        ENOTDIR => "ENOTDIR",
+        EISDIR => "EISDIR",
+        EINVAL => "EINVAL",
+        EMFILE => "EMFILE",
+        EFBIG => "EFBIG",
+        ENOSPC => "ENOSPC",
+        ESPIPE => "ESPIPE",
+        EROFS => "EROFS",
+        EPIPE => "EPIPE",
+        ENAMETOOLONG => "ENAMETOOLONG",
+        ENOSYS => "ENOSYS",
+        ENOTEMPTY => "ENOTEMPTY",
+        ELOOP => "ELOOP",
+        EMSGSIZE => "EMSGSIZE",
+        ETIMEDOUT => "ETIMEDOUT",
+        _ => "?",
+    }
+}
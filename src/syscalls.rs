@@ -1,13 +1,209 @@
 use core::arch::{asm, naked_asm};
-use x86_64::registers::model_specific::Msr;
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
 
-use crate::vga_buffer::{WRITER};
+use crate::{devfs, ipc, process, signal, strace};
+use crate::usercopy::{self, UserPtr};
+use crate::file::{FileKind, OpenFile};
+use crate::vfs::{self, FileHandle, FileType};
 
 const IA32_LSTAR: u32 = 0xC000_0082;
 const IA32_STAR: u32 = 0xC000_0081;
 const IA32_FMASK: u32 = 0xC000_0084;
 
+/// Flags cleared on `syscall`: IF, so the stack switch cannot be interrupted, and DF.
+const SYSCALL_RFLAGS_MASK: u64 = 0x600;
+
 pub const SYSCALL_WRITE: u64 = 1;
+pub const SYSCALL_EXIT: u64 = 2;
+pub const SYSCALL_WAITPID: u64 = 3;
+pub const SYSCALL_SPAWN: u64 = 4;
+pub const SYSCALL_EXEC: u64 = 5;
+pub const SYSCALL_GETPID: u64 = 6;
+pub const SYSCALL_GETPPID: u64 = 7;
+pub const SYSCALL_READ: u64 = 8;
+pub const SYSCALL_OPEN: u64 = 9;
+pub const SYSCALL_CLOSE: u64 = 10;
+pub const SYSCALL_LSEEK: u64 = 11;
+pub const SYSCALL_STAT: u64 = 12;
+pub const SYSCALL_MKDIR: u64 = 13;
+pub const SYSCALL_UNLINK: u64 = 14;
+pub const SYSCALL_READDIR: u64 = 15;
+pub const SYSCALL_BRK: u64 = 16;
+pub const SYSCALL_KILL: u64 = 17;
+pub const SYSCALL_SIGACTION: u64 = 18;
+pub const SYSCALL_SIGPROCMASK: u64 = 19;
+pub const SYSCALL_SIGRETURN: u64 = 20;
+pub const SYSCALL_PIPE: u64 = 21;
+pub const SYSCALL_DUP2: u64 = 22;
+pub const SYSCALL_MQ_OPEN: u64 = 23;
+pub const SYSCALL_MQ_SEND: u64 = 24;
+pub const SYSCALL_MQ_RECEIVE: u64 = 25;
+pub const SYSCALL_MQ_UNLINK: u64 = 26;
+pub const SYSCALL_SHM_OPEN: u64 = 27;
+pub const SYSCALL_SHM_MAP: u64 = 28;
+pub const SYSCALL_SHM_UNMAP: u64 = 29;
+pub const SYSCALL_SHM_UNLINK: u64 = 30;
+pub const SYSCALL_FTRUNCATE: u64 = 31;
+pub const SYSCALL_RENAME: u64 = 32;
+pub const SYSCALL_LINK: u64 = 33;
+pub const SYSCALL_SYMLINK: u64 = 34;
+pub const SYSCALL_READLINK: u64 = 35;
+
+pub const EPERM: u64 = 1;
+pub const ENOENT: u64 = 2;
+pub const ESRCH: u64 = 3;
+pub const EINTR: u64 = 4;
+pub const EIO: u64 = 5;
+pub const E2BIG: u64 = 7;
+pub const ENOEXEC: u64 = 8;
+pub const EBADF: u64 = 9;
+pub const ECHILD: u64 = 10;
+pub const EAGAIN: u64 = 11;
+pub const ENOMEM: u64 = 12;
+pub const EFAULT: u64 = 14;
+pub const EBUSY: u64 = 16;
+pub const EEXIST: u64 = 17;
+pub const EXDEV: u64 = 18;
+pub const ENOTDIR: u64 = 20;
+pub const EISDIR: u64 = 21;
+pub const EINVAL: u64 = 22;
+pub const EMFILE: u64 = 24;
+pub const EFBIG: u64 = 27;
+pub const ENOSPC: u64 = 28;
+pub const ESPIPE: u64 = 29;
+pub const EROFS: u64 = 30;
+pub const EPIPE: u64 = 32;
+pub const ENAMETOOLONG: u64 = 36;
+pub const ENOSYS: u64 = 38;
+pub const ENOTEMPTY: u64 = 39;
+pub const ELOOP: u64 = 40;
+pub const EMSGSIZE: u64 = 90;
+pub const ETIMEDOUT: u64 = 110;
+
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_ACCMODE: u64 = 3;
+pub const O_CREAT: u64 = 0x40;
+pub const O_EXCL: u64 = 0x80;
+pub const O_TRUNC: u64 = 0x200;
+pub const O_APPEND: u64 = 0x400;
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFBLK: u32 = 0o060000;
+
+/// Most arguments accepted by `spawn` and `exec`.
+pub const MAX_ARGS: usize = 64;
+
+/// `waitpid` option: return 0 instead of blocking if no child has exited.
+pub const WNOHANG: u64 = 1;
+
+/// Kernel stack of the current process, loaded by `syscall_entry`.
+pub static mut KERNEL_STACK_TOP: u64 = 0;
+static mut USER_RSP: u64 = 0;
+
+/// Selectors loaded by `sysret` given the STAR value in `init_syscall`.
+pub const USER_CODE_SELECTOR: u64 = 0x20 | 3;
+pub const USER_DATA_SELECTOR: u64 = 0x18 | 3;
+
+/// User registers saved by `syscall_entry` and the timer interrupt, lowest
+/// address first. The last five fields form an `iretq` frame.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    pub fn from_user_mode(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+/// Result of `stat`, as laid out in user memory. `mode` holds the file type
+/// bits and the permission bits; times are seconds since 1970-01-01.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Stat {
+    pub mode: u32,
+    pub uid: u32,
+    pub ino: u64,
+    pub nlink: u64,
+    pub size: u64,
+    pub accessed: u64,
+    pub modified: u64,
+    pub created: u64,
+}
+
+pub fn error(errno: u64) -> u64 {
+    (-(errno as i64)) as u64
+}
+
+/// Maps the error messages used inside the kernel to errno values.
+pub fn errno_of(message: &'static str) -> u64 {
+    match message {
+        "Entry not found" => ENOENT,
+        "Entry already exists" => EEXIST,
+        "Not a directory" => ENOTDIR,
+        "Is a directory" | "Not a file" => EISDIR,
+        "Bad file descriptor" | "File not open for reading" | "File not open for writing" => EBADF,
+        "Too many open files" => EMFILE,
+        "Argument list too long" => E2BIG,
+        "Not an ELF file"
+        | "Not a 64-bit little-endian ELF file"
+        | "Not an x86_64 executable"
+        | "Bad program header size"
+        | "Bad program header table"
+        | "Truncated program header table"
+        | "Entry point is not inside a loaded segment"
+        | "Segment file size exceeds memory size"
+        | "Bad segment offset"
+        | "Truncated segment"
+        | "Segment outside of user space" => ENOEXEC,
+        "Directory not empty" => ENOTEMPTY,
+        "Mount point busy" => EBUSY,
+        "Cross-device link" => EXDEV,
+        "Too many levels of symbolic links" => ELOOP,
+        "Operation not permitted" => EPERM,
+        "Interrupted" => EINTR,
+        "Broken pipe" => EPIPE,
+        "Out of memory" => ENOMEM,
+        "File too large" => EFBIG,
+        "No space left on device" => ENOSPC,
+        "Read-only file system" | "Read-only device" => EROFS,
+        "File name too long" => ENAMETOOLONG,
+        "I/O error" | "Bad sector" | "Sector not found" | "Command aborted" | "Drive fault" | "Device timeout"
+        | "Corrupt file system" => EIO,
+        _ => EINVAL,
+    }
+}
 
 pub unsafe fn syscall(syscall_number: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
     let ret: u64;
@@ -18,23 +214,77 @@ pub unsafe fn syscall(syscall_number: u64, arg1: u64, arg2: u64, arg3: u64) -> u
         in("rsi") arg2,
         in("rdx") arg3,
         lateout("rax") ret,
+        out("rcx") _,
+        out("r11") _,
     );
     ret
 }
 
 #[no_mangle]
-pub extern "C" fn syscall_dispatcher(
-    syscall_number: u64,
-    arg1: u64,
-    arg2: u64,
-    arg3: u64,
-    _arg4: u64,
-    _arg5: u64,
-    _arg6: u64,
-) -> u64 {
+pub extern "C" fn syscall_dispatcher(frame: &mut TrapFrame) {
+    let syscall_number = frame.rax;
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    let pid = process::current_pid();
+    let traced = process::is_traced(pid);
+    if traced && syscall_number == SYSCALL_EXIT {
+        strace::log_entry(pid, syscall_number, &args);
+    }
+
+    let start = strace::timestamp();
+    let result = dispatch(frame, syscall_number, &args);
+    let cycles = strace::timestamp() - start;
+
+    strace::record(syscall_number, &result, cycles);
+    if traced {
+        strace::log_call(pid, syscall_number, &args, &result, cycles);
+    }
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => error(errno),
+    };
+    signal::deliver(frame);
+}
+
+fn dispatch(frame: &mut TrapFrame, syscall_number: u64, args: &[u64; 6]) -> SysResult {
+    let [arg1, arg2, arg3, arg4, _, _] = *args;
+
     match syscall_number {
-        SYSCALL_WRITE => sys_write(arg1, arg2 as *const u8, arg3 as usize),
-        _ => 0,
+        SYSCALL_WRITE => sys_write(arg1, arg2, arg3 as usize),
+        SYSCALL_EXIT => process::exit(arg1 as i32),
+        SYSCALL_WAITPID => sys_waitpid(arg1 as i64, UserPtr::new(arg2), arg3),
+        SYSCALL_SPAWN => sys_spawn(arg1, arg2 as usize, UserPtr::new(arg3), arg4 as usize),
+        SYSCALL_EXEC => sys_exec(frame, arg1, arg2 as usize, UserPtr::new(arg3), arg4 as usize),
+        SYSCALL_GETPID => Ok(process::current_pid()),
+        SYSCALL_GETPPID => Ok(process::parent_pid()),
+        SYSCALL_READ => sys_read(arg1, arg2, arg3 as usize),
+        SYSCALL_OPEN => sys_open(arg1, arg2 as usize, arg3),
+        SYSCALL_CLOSE => sys_close(arg1),
+        SYSCALL_LSEEK => sys_lseek(arg1, arg2 as i64, arg3),
+        SYSCALL_STAT => sys_stat(arg1, arg2 as usize, UserPtr::new(arg3)),
+        SYSCALL_MKDIR => sys_mkdir(arg1, arg2 as usize),
+        SYSCALL_UNLINK => sys_unlink(arg1, arg2 as usize),
+        SYSCALL_READDIR => sys_readdir(arg1, arg2, arg3 as usize),
+        SYSCALL_BRK => Ok(process::brk(arg1)),
+        SYSCALL_KILL => signal::sys_kill(arg1, arg2),
+        SYSCALL_SIGACTION => signal::sys_sigaction(arg1, UserPtr::new(arg2), UserPtr::new(arg3)),
+        SYSCALL_SIGPROCMASK => signal::sys_sigprocmask(arg1, UserPtr::new(arg2), UserPtr::new(arg3)),
+        SYSCALL_SIGRETURN => signal::sys_sigreturn(frame),
+        SYSCALL_PIPE => sys_pipe(UserPtr::new(arg1)),
+        SYSCALL_DUP2 => sys_dup2(arg1, arg2),
+        SYSCALL_MQ_OPEN => ipc::sys_mq_open(arg1, arg2 as usize, arg3),
+        SYSCALL_MQ_SEND => ipc::sys_mq_send(arg1, arg2, arg3 as usize, arg4 as i64),
+        SYSCALL_MQ_RECEIVE => ipc::sys_mq_receive(arg1, arg2, arg3 as usize, arg4 as i64),
+        SYSCALL_MQ_UNLINK => ipc::sys_mq_unlink(arg1, arg2 as usize),
+        SYSCALL_SHM_OPEN => ipc::sys_shm_open(arg1, arg2 as usize, arg3, arg4),
+        SYSCALL_SHM_MAP => ipc::sys_shm_map(arg1),
+        SYSCALL_SHM_UNMAP => ipc::sys_shm_unmap(arg1),
+        SYSCALL_SHM_UNLINK => ipc::sys_shm_unlink(arg1, arg2 as usize),
+        SYSCALL_FTRUNCATE => sys_ftruncate(arg1, arg2),
+        SYSCALL_RENAME => sys_rename(arg1, arg2 as usize, arg3, arg4 as usize),
+        SYSCALL_LINK => sys_link(arg1, arg2 as usize, arg3, arg4 as usize),
+        SYSCALL_SYMLINK => sys_symlink(arg1, arg2 as usize, arg3, arg4 as usize),
+        SYSCALL_READLINK => sys_readlink(arg1, arg2 as usize, arg3, arg4 as usize),
+        _ => Err(ENOSYS),
     }
 }
 
@@ -42,38 +292,333 @@ pub extern "C" fn syscall_dispatcher(
 #[no_mangle]
 pub extern "C" fn syscall_entry() -> ! {
         naked_asm!(
-            "mov rcx, r10",
+            "mov [rip + {user_rsp}], rsp",
+            "mov rsp, [rip + {kernel_stack}]",
+            // Build the same frame an interrupt would, so that the return
+            // path can restore every register with `iretq`; this matters
+            // when `sigreturn` resumes code that was interrupted by the timer.
+            "push {user_ss}",
+            "push qword ptr [rip + {user_rsp}]",
+            "push r11",
+            "push {user_cs}",
+            "push rcx",
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push rbp",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "mov rdi, rsp",
             "call {dispatcher}",
-            "sysretq",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            "iretq",
+            user_rsp = sym USER_RSP,
+            kernel_stack = sym KERNEL_STACK_TOP,
+            user_ss = const USER_DATA_SELECTOR,
+            user_cs = const USER_CODE_SELECTOR,
             dispatcher = sym syscall_dispatcher,
         );
-    
+
+}
+
+/// Syscall result: a return value or an errno.
+pub type SysResult = Result<u64, u64>;
+
+/// Largest chunk copied between user space and a file in one step.
+const IO_CHUNK: usize = 4096;
+
+pub fn sys_write(fd: u64, buf: u64, len: usize) -> SysResult {
+    let file = process::get_file(fd as usize).ok_or(EBADF)?;
+    let mut written = 0;
+    while written < len {
+        let chunk = core::cmp::min(IO_CHUNK, len - written);
+        let data = usercopy::read_bytes(buf + written as u64, chunk)?;
+        written += OpenFile::write(&file, &data).map_err(errno_of)?;
+    }
+    Ok(written as u64)
+}
+
+pub fn sys_read(fd: u64, buf: u64, len: usize) -> SysResult {
+    let file = process::get_file(fd as usize).ok_or(EBADF)?;
+    usercopy::check_range(buf, len, true)?;
+    let mut data = vec![0u8; core::cmp::min(len, IO_CHUNK)];
+    let read = OpenFile::read(&file, &mut data).map_err(errno_of)?;
+    usercopy::copy_to_user(buf, &data[..read])?;
+    Ok(read as u64)
+}
+
+fn sys_open(path: u64, path_len: usize, flags: u64) -> SysResult {
+    let path = usercopy::read_str(path, path_len)?;
+    let inode = match vfs::lookup(&path) {
+        Ok(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => return Err(EEXIST),
+        Ok(inode) => inode,
+        Err("Entry not found") if flags & O_CREAT != 0 => {
+            vfs::create(&path, FileType::File).map_err(errno_of)?
+        }
+        Err(e) => return Err(errno_of(e)),
+    };
+
+    let (readable, writable) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(EINVAL),
+    };
+    if inode.file_type() == FileType::Directory && writable {
+        return Err(EISDIR);
+    }
+    if flags & O_TRUNC != 0 && writable {
+        inode.truncate(0).map_err(errno_of)?;
+    }
+
+    let file = match devfs::char_device(&inode) {
+        Some(device) => OpenFile::device(device, readable, writable),
+        None => OpenFile::file(Box::new(FileHandle::new(inode, flags & O_APPEND != 0)), readable, writable),
+    };
+    let fd = process::with_files(|files| files.insert(file)).map_err(errno_of)?;
+    Ok(fd as u64)
 }
 
-pub fn sys_write(_fd: u64, buf: *const u8, len: usize) -> u64 {
-    use core::slice;
+fn sys_close(fd: u64) -> SysResult {
+    process::with_files(|files| files.close(fd as usize)).map_err(errno_of)?;
+    Ok(0)
+}
+
+/// Creates a pipe and stores its read and write descriptors at `fds`.
+fn sys_pipe(fds: UserPtr<[u64; 2]>) -> SysResult {
+    usercopy::check_range(fds.addr(), core::mem::size_of::<[u64; 2]>(), true)?;
+    let (read_end, write_end) = OpenFile::pipe();
+    let (read_fd, write_fd) = process::with_files(|files| {
+        let read_fd = files.insert(read_end)?;
+        match files.insert(write_end) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(e) => {
+                let _ = files.close(read_fd);
+                Err(e)
+            }
+        }
+    })
+    .map_err(errno_of)?;
+    if let Err(errno) = fds.write([read_fd as u64, write_fd as u64]) {
+        process::with_files(|files| {
+            let _ = files.close(read_fd);
+            let _ = files.close(write_fd);
+        });
+        return Err(errno);
+    }
+    Ok(0)
+}
+
+fn sys_dup2(old_fd: u64, new_fd: u64) -> SysResult {
+    let file = process::get_file(old_fd as usize).ok_or(EBADF)?;
+    if old_fd != new_fd {
+        process::with_files(|files| files.set(new_fd as usize, file)).map_err(errno_of)?;
+    }
+    Ok(new_fd)
+}
+
+fn sys_lseek(fd: u64, offset: i64, whence: u64) -> SysResult {
+    let file = process::get_file(fd as usize).ok_or(EBADF)?;
+    let mut open = file.borrow_mut();
+    let file = match &mut open.kind {
+        FileKind::File(file) => file,
+        _ => return Err(ESPIPE),
+    };
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => file.position() as i64,
+        SEEK_END => file.inode().size() as i64,
+        _ => return Err(EINVAL),
+    };
+    match base.checked_add(offset) {
+        Some(position) if position >= 0 => {
+            file.seek(position as usize);
+            Ok(position as u64)
+        }
+        _ => Err(EINVAL),
+    }
+}
+
+/// Cuts or extends the file behind `fd` to `len` bytes.
+fn sys_ftruncate(fd: u64, len: u64) -> SysResult {
+    let file = process::get_file(fd as usize).ok_or(EBADF)?;
+    let mut open = file.borrow_mut();
+    if !open.writable {
+        return Err(EBADF);
+    }
+    match &mut open.kind {
+        FileKind::File(file) => file.truncate(len as usize).map_err(errno_of)?,
+        _ => return Err(EINVAL),
+    }
+    Ok(0)
+}
 
-    let slice = unsafe { slice::from_raw_parts(buf, len) };
+fn sys_stat(path: u64, path_len: usize, stat: UserPtr<Stat>) -> SysResult {
+    let path = usercopy::read_str(path, path_len)?;
+    let meta = vfs::lookup(&path).map_err(errno_of)?.metadata();
+    let kind = match meta.file_type {
+        FileType::Directory => S_IFDIR,
+        FileType::File => S_IFREG,
+        FileType::Symlink => S_IFLNK,
+        FileType::CharDevice => S_IFCHR,
+        FileType::BlockDevice => S_IFBLK,
+    };
+    stat.write(Stat {
+        mode: kind | meta.mode as u32,
+        uid: meta.uid,
+        ino: meta.ino,
+        nlink: meta.nlink as u64,
+        size: meta.size,
+        accessed: meta.accessed,
+        modified: meta.modified,
+        created: meta.created,
+    })?;
+    Ok(0)
+}
+
+fn sys_mkdir(path: u64, path_len: usize) -> SysResult {
+    let path = usercopy::read_str(path, path_len)?;
+    vfs::create(&path, FileType::Directory).map_err(errno_of)?;
+    Ok(0)
+}
+
+/// Removes a file or an empty directory.
+fn sys_unlink(path: u64, path_len: usize) -> SysResult {
+    let path = usercopy::read_str(path, path_len)?;
+    vfs::remove(&path).map_err(errno_of)?;
+    Ok(0)
+}
 
-    for &byte in slice {
-        match byte {
-            0x20..=0x7e | b'\n' => {
-                if byte == b'\n' {
-                    WRITER.lock().new_line();
-                } else {
-                    WRITER.lock().write_byte(byte);
-                }
+fn sys_rename(from: u64, from_len: usize, to: u64, to_len: usize) -> SysResult {
+    let from = usercopy::read_str(from, from_len)?;
+    let to = usercopy::read_str(to, to_len)?;
+    vfs::rename(&from, &to).map_err(errno_of)?;
+    Ok(0)
+}
+
+fn sys_link(existing: u64, existing_len: usize, new: u64, new_len: usize) -> SysResult {
+    let existing = usercopy::read_str(existing, existing_len)?;
+    let new = usercopy::read_str(new, new_len)?;
+    vfs::link(&existing, &new).map_err(errno_of)?;
+    Ok(0)
+}
+
+fn sys_symlink(target: u64, target_len: usize, path: u64, path_len: usize) -> SysResult {
+    let target = usercopy::read_str(target, target_len)?;
+    let path = usercopy::read_str(path, path_len)?;
+    vfs::symlink(&target, &path).map_err(errno_of)?;
+    Ok(0)
+}
+
+/// Copies the target of a symbolic link into `buf`, cut to `len` bytes,
+/// and returns the number of bytes copied.
+fn sys_readlink(path: u64, path_len: usize, buf: u64, len: usize) -> SysResult {
+    let path = usercopy::read_str(path, path_len)?;
+    let target = vfs::read_link(&path).map_err(errno_of)?;
+    let len = core::cmp::min(len, target.len());
+    usercopy::copy_to_user(buf, &target.as_bytes()[..len])?;
+    Ok(len as u64)
+}
+
+/// Copies the name of the next entry of an open directory into `buf` and
+/// returns its length, or 0 once all entries have been returned.
+fn sys_readdir(fd: u64, buf: u64, len: usize) -> SysResult {
+    let file = process::get_file(fd as usize).ok_or(EBADF)?;
+    let mut open = file.borrow_mut();
+    let file = match &mut open.kind {
+        FileKind::File(file) => file,
+        _ => return Err(ENOTDIR),
+    };
+    let position = file.position();
+    let name = match file.read_dir().map_err(errno_of)? {
+        Some(name) => name,
+        None => return Ok(0),
+    };
+    if name.len() > len {
+        file.seek(position);
+        return Err(EINVAL);
+    }
+    if let Err(errno) = usercopy::copy_to_user(buf, name.as_bytes()) {
+        file.seek(position);
+        return Err(errno);
+    }
+    Ok(name.len() as u64)
+}
+
+fn sys_waitpid(pid: i64, status: UserPtr<i32>, options: u64) -> SysResult {
+    let pid = if pid > 0 { Some(pid as u64) } else { None };
+    if !status.is_null() {
+        usercopy::check_range(status.addr(), core::mem::size_of::<i32>(), true)?;
+    }
+    match process::wait(pid, options & WNOHANG != 0) {
+        Ok(Some((child, code))) => {
+            if !status.is_null() {
+                status.write(code)?;
             }
-            _ => WRITER.lock().write_byte(0xfe),
+            Ok(child)
         }
+        Ok(None) => Ok(0),
+        Err(_) => Err(ECHILD),
+    }
+}
+
+/// Reads an argument vector passed as an array of pointer/length pairs.
+fn user_args(argv: UserPtr<[u64; 2]>, argc: usize) -> Result<Vec<String>, u64> {
+    if argc > MAX_ARGS {
+        return Err(E2BIG);
     }
-    len as u64
+    (0..argc)
+        .map(|i| {
+            let [ptr, len] = argv.offset(i).read()?;
+            usercopy::read_str(ptr, len as usize)
+        })
+        .collect()
+}
+
+fn sys_spawn(path: u64, path_len: usize, argv: UserPtr<[u64; 2]>, argc: usize) -> SysResult {
+    let path = usercopy::read_str(path, path_len)?;
+    let args = user_args(argv, argc)?;
+    let image = process::read_program(&path).map_err(errno_of)?;
+    process::spawn_image(&path, &image, &args).map_err(errno_of)
+}
+
+fn sys_exec(frame: &mut TrapFrame, path: u64, path_len: usize, argv: UserPtr<[u64; 2]>, argc: usize) -> SysResult {
+    let path = usercopy::read_str(path, path_len)?;
+    let args = user_args(argv, argc)?;
+    let image = process::read_program(&path).map_err(errno_of)?;
+    process::exec(frame, &path, &image, &args).map_err(errno_of)?;
+    Ok(0)
 }
 
 pub fn init_syscall() {
     unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
         Msr::new(IA32_LSTAR).write(syscall_entry as u64);
+        // `syscall` loads CS = 0x08, SS = 0x10; `sysret` loads SS = 0x18 | 3, CS = 0x20 | 3.
         Msr::new(IA32_STAR).write(((0x08u64) << 32) | ((0x10u64) << 48));
-        Msr::new(IA32_FMASK).write(0);
+        Msr::new(IA32_FMASK).write(SYSCALL_RFLAGS_MASK);
     }
-}
\ No newline at end of file
+}
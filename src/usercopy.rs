@@ -0,0 +1,146 @@
+use alloc::{string::String, vec, vec::Vec};
+use core::{arch::global_asm, marker::PhantomData, mem};
+use x86_64::{VirtAddr, structures::paging::PageTableFlags};
+
+use crate::memory;
+use crate::syscalls::{EFAULT, EINVAL};
+
+/// Longest path or argument string accepted from user space.
+pub const MAX_USER_STRING: usize = 4096;
+
+// `usercopy_bytes(dst, src, len)` returns the number of bytes that were NOT
+// copied. If the copy faults, the page fault handler resumes execution at the
+// matching fixup address from `USERCOPY_FIXUPS` instead of panicking.
+global_asm!(
+    ".global usercopy_bytes",
+    "usercopy_bytes:",
+    "    mov rcx, rdx",
+    "1:  rep movsb",
+    "    xor eax, eax",
+    "    ret",
+    "2:  mov rax, rcx",
+    "    ret",
+    ".pushsection .data.rel.ro",
+    ".balign 8",
+    ".global USERCOPY_FIXUPS",
+    "USERCOPY_FIXUPS:",
+    "    .quad 1b, 2b",
+    ".popsection",
+);
+
+#[repr(C)]
+struct Fixup {
+    fault_ip: u64,
+    fixup_ip: u64,
+}
+
+unsafe extern "C" {
+    fn usercopy_bytes(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    static USERCOPY_FIXUPS: [Fixup; 1];
+}
+
+/// Called by the page fault handler for faults in kernel mode: returns where
+/// to resume if the faulting instruction belongs to a user copy.
+pub fn fixup(instruction_pointer: VirtAddr) -> Option<VirtAddr> {
+    let ip = instruction_pointer.as_u64();
+    unsafe {
+        USERCOPY_FIXUPS
+            .iter()
+            .find(|entry| entry.fault_ip == ip)
+            .map(|entry| VirtAddr::new(entry.fixup_ip))
+    }
+}
+
+/// Checks that `[addr, addr + len)` lies in user space and is mapped
+/// user-accessible (and writable if `write` is set) in the current process.
+pub fn check_range(addr: u64, len: usize, write: bool) -> Result<(), u64> {
+    if len == 0 {
+        return Ok(());
+    }
+    if !memory::is_user_range(addr, len as u64) {
+        return Err(EFAULT);
+    }
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let first = addr & !0xfff;
+    let last = (addr + len as u64 - 1) & !0xfff;
+    let mut page = first;
+    while page <= last {
+        match memory::page_flags(VirtAddr::new(page)) {
+            Some(flags) if flags.contains(required) => {}
+            _ => return Err(EFAULT),
+        }
+        page += 4096;
+    }
+    Ok(())
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), u64> {
+    check_range(src, dst.len(), false)?;
+    let left = unsafe { usercopy_bytes(dst.as_mut_ptr(), src as *const u8, dst.len()) };
+    if left == 0 { Ok(()) } else { Err(EFAULT) }
+}
+
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), u64> {
+    check_range(dst, src.len(), true)?;
+    let left = unsafe { usercopy_bytes(dst as *mut u8, src.as_ptr(), src.len()) };
+    if left == 0 { Ok(()) } else { Err(EFAULT) }
+}
+
+/// Copies `len` bytes from user space into a new buffer.
+pub fn read_bytes(src: u64, len: usize) -> Result<Vec<u8>, u64> {
+    let mut buf = vec![0u8; len];
+    copy_from_user(&mut buf, src)?;
+    Ok(buf)
+}
+
+/// Copies a UTF-8 string passed as a pointer/length pair.
+pub fn read_str(src: u64, len: usize) -> Result<String, u64> {
+    if len > MAX_USER_STRING {
+        return Err(EINVAL);
+    }
+    String::from_utf8(read_bytes(src, len)?).map_err(|_| EINVAL)
+}
+
+/// A typed pointer into the address space of the current process.
+pub struct UserPtr<T> {
+    addr: u64,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T: Copy> UserPtr<T> {
+    pub fn new(addr: u64) -> Self {
+        UserPtr { addr, _marker: PhantomData }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Pointer to the `index`-th element of an array starting here.
+    pub fn offset(&self, index: usize) -> Self {
+        UserPtr::new(self.addr.wrapping_add((index * mem::size_of::<T>()) as u64))
+    }
+
+    pub fn read(&self) -> Result<T, u64> {
+        let mut value = mem::MaybeUninit::<T>::uninit();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, mem::size_of::<T>())
+        };
+        copy_from_user(bytes, self.addr)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write(&self, value: T) -> Result<(), u64> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&value as *const T as *const u8, mem::size_of::<T>())
+        };
+        copy_to_user(self.addr, bytes)
+    }
+}
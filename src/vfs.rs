@@ -0,0 +1,713 @@
+//! Virtual file system. Backends implement [`FileSystem`] and [`Inode`] and
+//! are mounted on directories of a single tree; paths are resolved here, so
+//! the shell and syscalls never deal with a particular file system.
+
+use alloc::{format, rc::Rc, string::{String, ToString}, vec, vec::Vec};
+use core::any::Any;
+
+use crate::block::DeviceRef;
+use crate::global::Global;
+
+pub type InodeRef = Rc<dyn Inode>;
+pub type FsRef = Rc<dyn FileSystem>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+/// What `stat` reports about an inode. Times are seconds since 1970-01-01.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub ino: u64,
+    /// Number of names the inode has.
+    pub nlink: u32,
+    pub file_type: FileType,
+    pub size: u64,
+    /// Permission bits, `0o755` style.
+    pub mode: u16,
+    pub uid: u32,
+    pub created: u64,
+    pub modified: u64,
+    pub accessed: u64,
+}
+
+pub trait FileSystem {
+    fn name(&self) -> &'static str;
+    fn root(&self) -> InodeRef;
+
+    /// Writes out whatever is kept in memory, for file systems on a disk.
+    fn sync(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// The block device the file system lives on, if any.
+    fn device(&self) -> Option<DeviceRef> {
+        None
+    }
+}
+
+/// A file or directory of some file system. Operations that make no sense
+/// for the kind of inode fail by default. `Any` lets a backend get its own
+/// type back from an inode it is handed, e.g. the target of `rename`.
+pub trait Inode: Any {
+    fn metadata(&self) -> Metadata;
+
+    fn file_type(&self) -> FileType {
+        self.metadata().file_type
+    }
+
+    /// Length in bytes for files, number of entries for directories.
+    fn size(&self) -> usize {
+        self.metadata().size as usize
+    }
+
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize, &'static str> {
+        Err("Is a directory")
+    }
+
+    fn write_at(&self, _offset: usize, _data: &[u8]) -> Result<usize, &'static str> {
+        Err("Is a directory")
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), &'static str> {
+        Err("Is a directory")
+    }
+
+    fn lookup(&self, _name: &str) -> Result<InodeRef, &'static str> {
+        Err("Not a directory")
+    }
+
+    /// Directory containing this one, `None` for the root of the file system.
+    fn parent(&self) -> Option<InodeRef> {
+        None
+    }
+
+    fn create(self: Rc<Self>, _name: &str, _file_type: FileType) -> Result<InodeRef, &'static str> {
+        Err("Not a directory")
+    }
+
+    /// Adds `name` as another name for `target`, a file of the same file
+    /// system.
+    fn link(self: Rc<Self>, _name: &str, _target: InodeRef) -> Result<(), &'static str> {
+        Err("Not a directory")
+    }
+
+    fn symlink(self: Rc<Self>, _name: &str, _target: &str) -> Result<(), &'static str> {
+        Err("Not a directory")
+    }
+
+    /// Target of a symbolic link.
+    fn read_link(&self) -> Result<String, &'static str> {
+        Err("Invalid argument")
+    }
+
+    /// Removes the entry `name`. Directories must be empty.
+    fn unlink(&self, _name: &str) -> Result<(), &'static str> {
+        Err("Not a directory")
+    }
+
+    /// Moves the entry `name` to `new_name` in `new_dir`, a directory of the
+    /// same file system, replacing what is there. The inode stays the same.
+    fn rename(self: Rc<Self>, _name: &str, _new_dir: InodeRef, _new_name: &str) -> Result<(), &'static str> {
+        Err("Not a directory")
+    }
+
+    /// Names of all entries, in the order `readdir` returns them.
+    fn entries(&self) -> Result<Vec<String>, &'static str> {
+        Err("Not a directory")
+    }
+}
+
+/// An open inode with its own position, as used by file descriptors.
+pub trait File {
+    fn inode(&self) -> InodeRef;
+    fn position(&self) -> usize;
+    fn seek(&mut self, position: usize);
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str>;
+
+    /// Writes at the position, or at the end in append mode. Writing past
+    /// the end fills the gap with zeros.
+    fn write(&mut self, data: &[u8]) -> Result<usize, &'static str>;
+
+    /// Cuts or extends the file to `size` bytes; the position is kept.
+    fn truncate(&mut self, size: usize) -> Result<(), &'static str>;
+
+    /// Returns the next entry of a directory, or `None` after the last one.
+    fn read_dir(&mut self) -> Result<Option<String>, &'static str>;
+}
+
+/// [`File`] for any inode, going through `read_at` and `write_at`.
+pub struct FileHandle {
+    inode: InodeRef,
+    position: usize,
+    append: bool,
+}
+
+impl FileHandle {
+    pub fn new(inode: InodeRef, append: bool) -> Self {
+        FileHandle { inode, position: 0, append }
+    }
+}
+
+impl File for FileHandle {
+    fn inode(&self) -> InodeRef {
+        self.inode.clone()
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn seek(&mut self, position: usize) {
+        self.position = position;
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let read = self.inode.read_at(self.position, buf)?;
+        self.position += read;
+        Ok(read)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, &'static str> {
+        if self.append {
+            self.position = self.inode.size();
+        }
+        let written = self.inode.write_at(self.position, data)?;
+        self.position += written;
+        Ok(written)
+    }
+
+    fn truncate(&mut self, size: usize) -> Result<(), &'static str> {
+        self.inode.truncate(size)
+    }
+
+    fn read_dir(&mut self) -> Result<Option<String>, &'static str> {
+        let name = self.inode.entries()?.into_iter().nth(self.position);
+        if name.is_some() {
+            self.position += 1;
+        }
+        Ok(name)
+    }
+}
+
+#[derive(Clone)]
+struct Mount {
+    path: String,
+    fs: FsRef,
+    root: InodeRef,
+    /// Directory hidden by the mount; `None` for the root file system.
+    covered: Option<InodeRef>,
+}
+
+/// Symbolic links followed while resolving one path before giving up.
+const MAX_SYMLINKS: usize = 8;
+
+static MOUNTS: Global<Vec<Mount>> = Global::new(Vec::new());
+static CURRENT_DIR: Global<Option<InodeRef>> = Global::new(None);
+
+/// Whether `a` and `b` are the same inode.
+pub fn same(a: &InodeRef, b: &InodeRef) -> bool {
+    Rc::as_ptr(a) as *const () == Rc::as_ptr(b) as *const ()
+}
+
+/// Mounts `fs` on the directory `path`. The first mount must be `/`.
+pub fn mount(path: &str, fs: FsRef) -> Result<(), &'static str> {
+    let covered = if MOUNTS.borrow().is_empty() {
+        if path != "/" {
+            return Err("No root file system");
+        }
+        None
+    } else {
+        let dir = lookup(path)?;
+        if dir.file_type() != FileType::Directory {
+            return Err("Not a directory");
+        }
+        Some(dir)
+    };
+    let root = fs.root();
+    if covered.is_none() {
+        *CURRENT_DIR.borrow_mut() = Some(root.clone());
+    }
+    MOUNTS.borrow_mut().push(Mount { path: path.to_string(), fs, root, covered });
+    Ok(())
+}
+
+/// Returns `(path, file system name)` for every mount, in mount order.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS.borrow().iter().map(|m| (m.path.clone(), m.fs.name())).collect()
+}
+
+/// Block devices that mounted file systems live on.
+pub fn mounted_devices() -> Vec<DeviceRef> {
+    MOUNTS.borrow().iter().filter_map(|m| m.fs.device()).collect()
+}
+
+/// Syncs every mounted file system.
+pub fn sync() -> Result<(), &'static str> {
+    // Syncing does I/O, which may yield, so the table is not held.
+    let filesystems: Vec<FsRef> = MOUNTS.borrow().iter().map(|m| m.fs.clone()).collect();
+    for fs in filesystems {
+        fs.sync()?;
+    }
+    Ok(())
+}
+
+fn is_mount_point(inode: &InodeRef) -> bool {
+    MOUNTS.borrow().iter().any(|m| m.covered.as_ref().is_some_and(|c| same(c, inode)))
+}
+
+/// Replaces a covered directory with the root of what is mounted on it.
+fn cross_mounts(mut inode: InodeRef) -> InodeRef {
+    loop {
+        let mounted = MOUNTS
+            .borrow()
+            .iter()
+            .rev()
+            .find(|m| m.covered.as_ref().is_some_and(|c| same(c, &inode)))
+            .map(|m| m.root.clone());
+        match mounted {
+            Some(root) => inode = root,
+            None => return inode,
+        }
+    }
+}
+
+/// The mount whose file system has `inode` as its root.
+fn mount_of_root(inode: &InodeRef) -> Option<Mount> {
+    MOUNTS.borrow().iter().rev().find(|m| same(&m.root, inode)).cloned()
+}
+
+/// Goes one level up from `dir`, leaving a mounted file system through the
+/// directory it covers. The root's parent is the root itself.
+fn parent_of(dir: InodeRef) -> InodeRef {
+    let mut dir = dir;
+    while let Some(Mount { covered: Some(covered), .. }) = mount_of_root(&dir) {
+        dir = covered;
+    }
+    match dir.parent() {
+        Some(parent) => cross_mounts(parent),
+        None => dir,
+    }
+}
+
+pub fn root() -> Result<InodeRef, &'static str> {
+    MOUNTS.borrow().first().map(|m| m.root.clone()).ok_or("No root file system")
+}
+
+pub fn current_dir() -> Result<InodeRef, &'static str> {
+    CURRENT_DIR.borrow().clone().ok_or("No root file system")
+}
+
+pub fn set_current_dir(dir: InodeRef) -> Result<(), &'static str> {
+    if dir.file_type() != FileType::Directory {
+        return Err("Not a directory");
+    }
+    // The old directory goes after the borrow, in case dropping it does I/O.
+    let _old = CURRENT_DIR.borrow_mut().replace(dir);
+    Ok(())
+}
+
+/// Absolute path of a directory, found by walking up its parents.
+pub fn path_of(dir: &InodeRef) -> Result<String, &'static str> {
+    let mut names = Vec::new();
+    let mut dir = dir.clone();
+    loop {
+        if let Some(mount) = mount_of_root(&dir) {
+            if names.is_empty() {
+                return Ok(mount.path.clone());
+            }
+            names.reverse();
+            return Ok(format!("{}/{}", mount.path.trim_end_matches('/'), names.join("/")));
+        }
+        let parent = dir.parent().ok_or("Entry not found")?;
+        let name = parent
+            .entries()?
+            .into_iter()
+            .find(|name| parent.lookup(name).is_ok_and(|child| same(&child, &dir)))
+            .ok_or("Entry not found")?;
+        names.push(name);
+        dir = parent;
+    }
+}
+
+/// Resolves a `/`-separated path, from the root if it starts with `/` and
+/// from the current directory otherwise. `.` and `..` are understood,
+/// symbolic links are followed, and a trailing slash requires the result to
+/// be a directory.
+pub fn lookup(path: &str) -> Result<InodeRef, &'static str> {
+    resolve(current_dir()?, path, true, &mut 0)
+}
+
+/// Like [`lookup`], but a symbolic link in the last component is returned
+/// itself rather than followed.
+pub fn lookup_link(path: &str) -> Result<InodeRef, &'static str> {
+    resolve(current_dir()?, path, false, &mut 0)
+}
+
+/// Walks `path` from `start`. `followed` counts the links followed so far,
+/// including those of enclosing resolutions, to catch loops.
+fn resolve(start: InodeRef, path: &str, follow_last: bool, followed: &mut usize) -> Result<InodeRef, &'static str> {
+    let mut inode = if path.starts_with('/') { root()? } else { start };
+    let mut names = path.split('/').filter(|name| !name.is_empty()).peekable();
+    while let Some(name) = names.next() {
+        if inode.file_type() != FileType::Directory {
+            return Err("Not a directory");
+        }
+        let mut next = match name {
+            "." => inode.clone(),
+            ".." => parent_of(inode.clone()),
+            _ => cross_mounts(inode.lookup(name)?),
+        };
+        let last = names.peek().is_none();
+        if next.file_type() == FileType::Symlink && (!last || follow_last || path.ends_with('/')) {
+            *followed += 1;
+            if *followed > MAX_SYMLINKS {
+                return Err("Too many levels of symbolic links");
+            }
+            next = resolve(inode, &next.read_link()?, true, followed)?;
+        }
+        inode = next;
+    }
+    if path.ends_with('/') && inode.file_type() != FileType::Directory {
+        return Err("Not a directory");
+    }
+    Ok(inode)
+}
+
+/// Splits `path` into its parent directory and the last component, which
+/// must be a real name rather than `.` or `..`.
+pub fn lookup_parent(path: &str) -> Result<(InodeRef, &str), &'static str> {
+    let path = path.trim_end_matches('/');
+    let (dir, name) = match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err("Invalid path");
+    }
+    let dir = lookup(dir)?;
+    if dir.file_type() != FileType::Directory {
+        return Err("Not a directory");
+    }
+    Ok((dir, name))
+}
+
+/// Whether `dir` is `ancestor` or lies somewhere below it.
+fn is_within(dir: &InodeRef, ancestor: &InodeRef) -> bool {
+    let mut dir = dir.clone();
+    loop {
+        if same(&dir, ancestor) {
+            return true;
+        }
+        let parent = parent_of(dir.clone());
+        if same(&parent, &dir) {
+            return false;
+        }
+        dir = parent;
+    }
+}
+
+/// Root of the file system `dir` belongs to, without crossing mounts.
+fn fs_root(dir: &InodeRef) -> InodeRef {
+    let mut dir = dir.clone();
+    while let Some(parent) = dir.parent() {
+        dir = parent;
+    }
+    dir
+}
+
+pub fn create(path: &str, file_type: FileType) -> Result<InodeRef, &'static str> {
+    let (dir, name) = lookup_parent(path)?;
+    dir.create(name, file_type)
+}
+
+/// Removes a file or an empty directory.
+pub fn remove(path: &str) -> Result<(), &'static str> {
+    let (dir, name) = lookup_parent(path)?;
+    let inode = dir.lookup(name)?;
+    if is_mount_point(&inode) {
+        return Err("Mount point busy");
+    }
+    if inode.file_type() == FileType::Directory && inode.size() > 0 {
+        return Err("Directory not empty");
+    }
+    dir.unlink(name)
+}
+
+/// Reads a whole file into memory. Prefer going through a [`File`] in
+/// chunks for anything that is not needed in one piece.
+pub fn read_to_end(inode: &InodeRef) -> Result<Vec<u8>, &'static str> {
+    let mut data = Vec::new();
+    data.try_reserve_exact(inode.size()).map_err(|_| "Out of memory")?;
+    data.resize(inode.size(), 0);
+    let mut filled = 0;
+    while filled < data.len() {
+        match inode.read_at(filled, &mut data[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    data.truncate(filled);
+    Ok(data)
+}
+
+/// Makes `new` another name for the file `existing`.
+pub fn link(existing: &str, new: &str) -> Result<(), &'static str> {
+    let target = lookup_link(existing)?;
+    let (src_dir, _) = lookup_parent(existing)?;
+    let (dir, name) = lookup_parent(new)?;
+    if !same(&fs_root(&src_dir), &fs_root(&dir)) {
+        return Err("Cross-device link");
+    }
+    dir.link(name, target)
+}
+
+/// Creates a symbolic link at `path` pointing to `target`.
+pub fn symlink(target: &str, path: &str) -> Result<(), &'static str> {
+    if target.is_empty() {
+        return Err("Entry not found");
+    }
+    let (dir, name) = lookup_parent(path)?;
+    dir.symlink(name, target)
+}
+
+pub fn read_link(path: &str) -> Result<String, &'static str> {
+    lookup_link(path)?.read_link()
+}
+
+/// Moves `from` to `to` within one file system, keeping the inode. An
+/// existing file at `to` is replaced, an existing directory only if empty.
+pub fn rename(from: &str, to: &str) -> Result<(), &'static str> {
+    let (src_dir, src_name) = lookup_parent(from)?;
+    let (dst_dir, dst_name) = lookup_parent(to)?;
+    let node = src_dir.lookup(src_name)?;
+    if is_mount_point(&node) {
+        return Err("Mount point busy");
+    }
+    if !same(&fs_root(&src_dir), &fs_root(&dst_dir)) {
+        return Err("Cross-device link");
+    }
+    if node.file_type() == FileType::Directory && is_within(&dst_dir, &node) {
+        return Err("Cannot move a directory into itself");
+    }
+    match dst_dir.lookup(dst_name) {
+        Ok(existing) if same(&existing, &node) => return Ok(()),
+        Ok(existing) => {
+            if is_mount_point(&existing) {
+                return Err("Mount point busy");
+            }
+            let is_dir = |inode: &InodeRef| inode.file_type() == FileType::Directory;
+            match (is_dir(&node), is_dir(&existing)) {
+                (false, true) => return Err("Is a directory"),
+                (true, false) => return Err("Not a directory"),
+                (true, true) if existing.size() > 0 => return Err("Directory not empty"),
+                _ => {}
+            }
+        }
+        Err("Entry not found") => {}
+        Err(e) => return Err(e),
+    }
+    src_dir.rename(src_name, dst_dir, dst_name)
+}
+
+/// Copies `src` to the entry `name` of `dir`, directories with everything
+/// in them. Existing files are overwritten and existing directories merged.
+pub fn copy(src: &InodeRef, dir: &InodeRef, name: &str) -> Result<(), &'static str> {
+    if src.file_type() == FileType::Directory && is_within(dir, src) {
+        return Err("Cannot copy a directory into itself");
+    }
+    copy_inode(src, dir, name)
+}
+
+fn copy_inode(src: &InodeRef, dir: &InodeRef, name: &str) -> Result<(), &'static str> {
+    if src.file_type() == FileType::Symlink {
+        return dir.clone().symlink(name, &src.read_link()?);
+    }
+    let dst = match dir.lookup(name) {
+        Ok(existing) => cross_mounts(existing),
+        Err("Entry not found") => dir.clone().create(name, src.file_type())?,
+        Err(e) => return Err(e),
+    };
+    match (src.file_type(), dst.file_type()) {
+        (FileType::File, FileType::File) => {
+            if same(src, &dst) {
+                return Err("Source and destination are the same file");
+            }
+            dst.truncate(0)?;
+            let mut buf = vec![0u8; 4096];
+            let mut offset = 0;
+            loop {
+                let read = src.read_at(offset, &mut buf)?;
+                if read == 0 {
+                    return Ok(());
+                }
+                let mut written = 0;
+                while written < read {
+                    written += dst.write_at(offset + written, &buf[written..read])?;
+                }
+                offset += read;
+            }
+        }
+        (FileType::Directory, FileType::Directory) => {
+            for name in src.entries()? {
+                copy_inode(&cross_mounts(src.lookup(&name)?), &dst, &name)?;
+            }
+            Ok(())
+        }
+        (FileType::File, FileType::Directory) => Err("Is a directory"),
+        (FileType::Directory, _) => Err("Not a directory"),
+        _ => Err("Entry already exists"),
+    }
+}
+
+/// Writes all of `data` through `file`.
+pub fn write_fully(file: &mut dyn File, data: &[u8]) -> Result<(), &'static str> {
+    let mut written = 0;
+    while written < data.len() {
+        written += file.write(&data[written..])?;
+    }
+    Ok(())
+}
+
+/// Replaces the content of a file with `data`.
+pub fn write_all(inode: &InodeRef, data: &[u8]) -> Result<(), &'static str> {
+    inode.truncate(0)?;
+    write_fully(&mut FileHandle::new(inode.clone(), false), data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global::TEST_LOCK;
+    use crate::ramfs::RamFs;
+
+    /// Makes a fresh ramfs the root, holding `/a/b/file`.
+    fn fresh_root() {
+        MOUNTS.borrow_mut().clear();
+        *CURRENT_DIR.borrow_mut() = None;
+        mount("/", Rc::new(RamFs::new())).unwrap();
+        create("/a", FileType::Directory).unwrap();
+        create("/a/b", FileType::Directory).unwrap();
+        create("/a/b/file", FileType::File).unwrap();
+    }
+
+    fn is(path: &str, expected: &str) -> bool {
+        same(&lookup(path).unwrap(), &lookup(expected).unwrap())
+    }
+
+    #[test]
+    fn relative_paths_start_at_current_dir() {
+        let _lock = TEST_LOCK.lock();
+        fresh_root();
+        set_current_dir(lookup("/a").unwrap()).unwrap();
+        assert!(is("b/file", "/a/b/file"));
+        assert!(is("./b/./file", "/a/b/file"));
+        assert!(is("", "/a"));
+        assert_eq!(lookup("file").err(), Some("Entry not found"));
+    }
+
+    #[test]
+    fn dot_dot_goes_up_and_stops_at_root() {
+        let _lock = TEST_LOCK.lock();
+        fresh_root();
+        assert!(is("/a/b/..", "/a"));
+        assert!(is("/a/b/../../a/b/file", "/a/b/file"));
+        assert!(is("/..", "/"));
+        assert!(is("/../../a", "/a"));
+        set_current_dir(lookup("/a/b").unwrap()).unwrap();
+        assert!(is("../..", "/"));
+    }
+
+    #[test]
+    fn files_are_not_directories() {
+        let _lock = TEST_LOCK.lock();
+        fresh_root();
+        assert_eq!(lookup("/a/b/file/").err(), Some("Not a directory"));
+        assert_eq!(lookup("/a/b/file/x").err(), Some("Not a directory"));
+        assert_eq!(lookup("/a/b/file/..").err(), Some("Not a directory"));
+        assert!(lookup("/a/b/").is_ok());
+    }
+
+    #[test]
+    fn lookup_parent_needs_a_real_name() {
+        let _lock = TEST_LOCK.lock();
+        fresh_root();
+        let (dir, name) = lookup_parent("/a/b/file/").unwrap();
+        assert!(same(&dir, &lookup("/a/b").unwrap()));
+        assert_eq!(name, "file");
+        assert_eq!(lookup_parent("/a/.").err(), Some("Invalid path"));
+        assert_eq!(lookup_parent("/a/..").err(), Some("Invalid path"));
+        assert_eq!(lookup_parent("/").err(), Some("Invalid path"));
+        assert_eq!(lookup_parent("/a/b/file/x").err(), Some("Not a directory"));
+    }
+
+    #[test]
+    fn dot_dot_leaves_mounts_through_the_covered_directory() {
+        let _lock = TEST_LOCK.lock();
+        fresh_root();
+        mount("/a/b", Rc::new(RamFs::new())).unwrap();
+        create("/a/b/inner", FileType::Directory).unwrap();
+        assert_eq!(lookup("/a/b/file").err(), Some("Entry not found"));
+        assert!(is("/a/b/inner/../..", "/a"));
+        assert_eq!(path_of(&lookup("/a/b/inner").unwrap()).unwrap(), "/a/b/inner");
+        assert_eq!(path_of(&lookup("/a/b").unwrap()).unwrap(), "/a/b");
+        assert_eq!(path_of(&root().unwrap()).unwrap(), "/");
+    }
+
+    #[test]
+    fn symlinks_resolve_from_their_directory() {
+        let _lock = TEST_LOCK.lock();
+        fresh_root();
+        symlink("b/file", "/a/rel").unwrap();
+        symlink("/a/b", "/abs").unwrap();
+        assert!(is("/a/rel", "/a/b/file"));
+        assert!(is("/abs/file", "/a/b/file"));
+        assert!(is("/abs/..", "/a"));
+        assert_eq!(lookup_link("/a/rel").unwrap().file_type(), FileType::Symlink);
+        assert_eq!(lookup_link("/abs/").unwrap().file_type(), FileType::Directory);
+        assert_eq!(read_link("/a/rel").unwrap(), "b/file");
+    }
+
+    #[test]
+    fn dangling_symlinks_can_still_be_read() {
+        let _lock = TEST_LOCK.lock();
+        fresh_root();
+        symlink("/missing", "/dangling").unwrap();
+        assert_eq!(lookup("/dangling").err(), Some("Entry not found"));
+        assert_eq!(read_link("/dangling").unwrap(), "/missing");
+        assert_eq!(symlink("", "/empty").err(), Some("Entry not found"));
+    }
+
+    #[test]
+    fn symlink_chains_are_limited() {
+        let _lock = TEST_LOCK.lock();
+        fresh_root();
+        symlink("/a/b/file", "/l0").unwrap();
+        for i in 1..=MAX_SYMLINKS {
+            symlink(&format!("/l{}", i - 1), &format!("/l{}", i)).unwrap();
+        }
+        assert!(is(&format!("/l{}", MAX_SYMLINKS - 1), "/a/b/file"));
+        assert_eq!(
+            lookup(&format!("/l{}", MAX_SYMLINKS)).err(),
+            Some("Too many levels of symbolic links")
+        );
+    }
+
+    #[test]
+    fn symlink_loops_fail() {
+        let _lock = TEST_LOCK.lock();
+        fresh_root();
+        symlink("/loop", "/loop").unwrap();
+        symlink("/pong", "/ping").unwrap();
+        symlink("/ping", "/pong").unwrap();
+        assert_eq!(lookup("/loop").err(), Some("Too many levels of symbolic links"));
+        assert_eq!(lookup("/ping/x").err(), Some("Too many levels of symbolic links"));
+        assert_eq!(lookup_link("/loop").unwrap().file_type(), FileType::Symlink);
+    }
+}
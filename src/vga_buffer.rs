@@ -434,11 +434,27 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// While set, `print!` output is collected here instead of shown.
+static CAPTURE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Runs `f` and returns what it printed with `print!`; colored output such
+/// as error messages still goes to the screen.
+pub fn capture(f: impl FnOnce()) -> String {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| *CAPTURE.lock() = Some(String::new()));
+    f();
+    interrupts::without_interrupts(|| CAPTURE.lock().take()).unwrap_or_default()
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
     interrupts::without_interrupts(|| {
+        if let Some(output) = CAPTURE.lock().as_mut() {
+            let _ = output.write_fmt(args);
+            return;
+        }
         let mut writer = WRITER.lock();
         writer.check_write_row();
         writer.write_fmt(args).unwrap();
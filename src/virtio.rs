@@ -0,0 +1,579 @@
+//! virtio-blk over PCI with a split virtqueue. Both transports are
+//! supported: the legacy one, with its registers in an I/O BAR, and the
+//! modern one, whose structures are found through vendor capabilities and
+//! live in memory BARs. Data goes through per-request bounce buffers; a
+//! waiting request yields until the used ring shows it done, and the
+//! device interrupt ends that wait early.
+
+use alloc::{format, rc::Rc, string::String, vec::Vec};
+use core::cell::RefCell;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::block::{self, BlockDevice};
+use crate::pci::{self, Bar, Match, PciAddress, PciDevice};
+use crate::{interrupts, memory, process, serial_println};
+
+const VENDOR_VIRTIO: u16 = 0x1AF4;
+const DEVICE_BLOCK_TRANSITIONAL: u16 = 0x1001;
+const DEVICE_BLOCK_MODERN: u16 = 0x1042;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+const FEATURE_BLK_RO: u64 = 1 << 5;
+const FEATURE_BLK_FLUSH: u64 = 1 << 9;
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// Legacy registers, relative to the I/O BAR.
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+const LEGACY_CONFIG: u16 = 0x14;
+
+// Modern capabilities and the common configuration they point to.
+const CAP_VENDOR: u8 = 0x09;
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_ISR: u8 = 3;
+const CFG_DEVICE: u8 = 4;
+
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1A;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// Tells the device to use the legacy interrupt instead of MSI-X.
+const NO_VECTOR: u16 = 0xFFFF;
+
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const RESULT_OK: u8 = 0;
+const RESULT_UNSUPPORTED: u8 = 2;
+
+const SECTOR_SIZE: usize = 512;
+const PAGE_SIZE: usize = 4096;
+
+/// Queue size asked for on the modern transport; legacy devices dictate it.
+const QUEUE_SIZE: u16 = 16;
+
+/// Requests in flight at once. Each takes three descriptors and a buffer:
+/// one page for the header and status, then the data.
+const SLOTS: usize = 4;
+const SLOT_DATA_PAGES: usize = 8;
+const SLOT_DATA: usize = SLOT_DATA_PAGES * PAGE_SIZE;
+const STATUS_OFFSET: usize = 16;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Reading the ISR status acknowledges the interrupt.
+#[derive(Clone, Copy)]
+enum IsrRegister {
+    Port(u16),
+    Mmio(u64),
+}
+
+impl IsrRegister {
+    fn read(&self) -> u8 {
+        match *self {
+            IsrRegister::Port(port) => unsafe { Port::new(port).read() },
+            IsrRegister::Mmio(addr) => unsafe { read_volatile(addr as *const u8) },
+        }
+    }
+}
+
+/// ISR registers of every device, with the line it interrupts on.
+static ISR_REGISTERS: Mutex<Vec<(u8, IsrRegister)>> = Mutex::new(Vec::new());
+
+fn handle_interrupt() {
+    for (_, register) in ISR_REGISTERS.lock().iter() {
+        register.read();
+    }
+}
+
+fn mmio_read<T>(addr: u64) -> T {
+    unsafe { read_volatile(addr as *const T) }
+}
+
+fn mmio_write<T>(addr: u64, value: T) {
+    unsafe { write_volatile(addr as *mut T, value) }
+}
+
+enum Transport {
+    Legacy { port: u16 },
+    /// Virtual addresses of the mapped structures.
+    Modern { common: u64, notify: u64, isr: u64, device: u64 },
+}
+
+impl Transport {
+    /// Uses the modern structures when the device lists them all.
+    fn probe(address: &PciAddress) -> Result<Transport, &'static str> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+        for (id, offset) in address.capabilities() {
+            if id != CAP_VENDOR {
+                continue;
+            }
+            let bar = match address.bar(address.read_u8(offset + 4)) {
+                Bar::Memory(bar) => bar,
+                Bar::Io(_) => continue,
+            };
+            let start = PhysAddr::new(bar + address.read_u32(offset + 8) as u64);
+            let len = address.read_u32(offset + 12) as u64;
+            match address.read_u8(offset + 3) {
+                CFG_COMMON => common = Some(memory::map_mmio(start, len)?.as_u64()),
+                CFG_NOTIFY => {
+                    let multiplier = address.read_u32(offset + 16) as u64;
+                    notify = Some((memory::map_mmio(start, len)?.as_u64(), multiplier));
+                }
+                CFG_ISR => isr = Some(memory::map_mmio(start, len)?.as_u64()),
+                CFG_DEVICE => device = Some(memory::map_mmio(start, len)?.as_u64()),
+                _ => {}
+            }
+        }
+        if let (Some(common), Some((notify, multiplier)), Some(isr), Some(device)) = (common, notify, isr, device) {
+            // Queue 0 is the only one used; its doorbell is found once.
+            mmio_write::<u16>(common + COMMON_QUEUE_SELECT, 0);
+            let notify_off = mmio_read::<u16>(common + COMMON_QUEUE_NOTIFY_OFF) as u64;
+            return Ok(Transport::Modern { common, notify: notify + notify_off * multiplier, isr, device });
+        }
+        match address.bar(0) {
+            Bar::Io(port) => Ok(Transport::Legacy { port }),
+            Bar::Memory(_) => Err("No usable transport"),
+        }
+    }
+
+    fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { port } => unsafe { Port::new(port + LEGACY_STATUS).read() },
+            Transport::Modern { common, .. } => mmio_read(common + COMMON_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { port } => unsafe { Port::new(port + LEGACY_STATUS).write(status) },
+            Transport::Modern { common, .. } => mmio_write(common + COMMON_STATUS, status),
+        }
+    }
+
+    /// Accepts the features of `wanted` the device offers and returns them.
+    fn negotiate(&self, wanted: u64) -> Result<u64, &'static str> {
+        match *self {
+            Transport::Legacy { port } => {
+                let offered: u32 = unsafe { Port::new(port + LEGACY_DEVICE_FEATURES).read() };
+                let accepted = offered & wanted as u32;
+                unsafe { Port::new(port + LEGACY_DRIVER_FEATURES).write(accepted) };
+                Ok(accepted as u64)
+            }
+            Transport::Modern { common, .. } => {
+                let mut offered = 0;
+                for half in 0..2u32 {
+                    mmio_write(common + COMMON_DEVICE_FEATURE_SELECT, half);
+                    offered |= (mmio_read::<u32>(common + COMMON_DEVICE_FEATURE) as u64) << (32 * half);
+                }
+                let accepted = offered & (wanted | FEATURE_VERSION_1);
+                if accepted & FEATURE_VERSION_1 == 0 {
+                    return Err("Device does not support virtio 1.0");
+                }
+                for half in 0..2u32 {
+                    mmio_write(common + COMMON_DRIVER_FEATURE_SELECT, half);
+                    mmio_write(common + COMMON_DRIVER_FEATURE, (accepted >> (32 * half)) as u32);
+                }
+                self.set_status(self.status() | STATUS_FEATURES_OK);
+                if self.status() & STATUS_FEATURES_OK == 0 {
+                    return Err("Device rejected the features");
+                }
+                Ok(accepted)
+            }
+        }
+    }
+
+    /// Picks the size of queue 0.
+    fn queue_size(&self) -> u16 {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                Port::<u16>::new(port + LEGACY_QUEUE_SELECT).write(0);
+                Port::new(port + LEGACY_QUEUE_SIZE).read()
+            },
+            Transport::Modern { common, .. } => {
+                mmio_write::<u16>(common + COMMON_QUEUE_SELECT, 0);
+                let max = mmio_read::<u16>(common + COMMON_QUEUE_SIZE);
+                core::cmp::min(max, QUEUE_SIZE)
+            }
+        }
+    }
+
+    /// Hands queue 0 to the device; the legacy transport only takes the
+    /// start of the queue and finds the rings from the layout.
+    fn set_queue(&self, queue: &Queue, phys: PhysAddr) {
+        let desc = phys.as_u64();
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                Port::<u16>::new(port + LEGACY_QUEUE_SELECT).write(0);
+                Port::new(port + LEGACY_QUEUE_ADDRESS).write((desc / PAGE_SIZE as u64) as u32);
+            },
+            Transport::Modern { common, .. } => {
+                mmio_write::<u16>(common + COMMON_QUEUE_SELECT, 0);
+                mmio_write(common + COMMON_QUEUE_SIZE, queue.size);
+                mmio_write(common + COMMON_QUEUE_MSIX_VECTOR, NO_VECTOR);
+                mmio_write(common + COMMON_QUEUE_DESC, desc);
+                mmio_write(common + COMMON_QUEUE_DRIVER, desc + queue.avail_offset as u64);
+                mmio_write(common + COMMON_QUEUE_DEVICE, desc + queue.used_offset as u64);
+                mmio_write::<u16>(common + COMMON_QUEUE_ENABLE, 1);
+            }
+        }
+    }
+
+    fn notify(&self) {
+        match *self {
+            Transport::Legacy { port } => unsafe { Port::<u16>::new(port + LEGACY_QUEUE_NOTIFY).write(0) },
+            Transport::Modern { notify, .. } => mmio_write::<u16>(notify, 0),
+        }
+    }
+
+    /// Capacity from the block device configuration, in 512-byte sectors.
+    fn capacity(&self) -> u64 {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                let low: u32 = Port::new(port + LEGACY_CONFIG).read();
+                let high: u32 = Port::new(port + LEGACY_CONFIG + 4).read();
+                low as u64 | (high as u64) << 32
+            },
+            Transport::Modern { device, .. } => {
+                let low = mmio_read::<u32>(device);
+                let high = mmio_read::<u32>(device + 4);
+                low as u64 | (high as u64) << 32
+            }
+        }
+    }
+
+    fn isr(&self) -> IsrRegister {
+        match *self {
+            Transport::Legacy { port } => IsrRegister::Port(port + LEGACY_ISR),
+            Transport::Modern { isr, .. } => IsrRegister::Mmio(isr),
+        }
+    }
+}
+
+/// A split virtqueue in the legacy layout: descriptors, then the available
+/// ring, then the used ring on the next page boundary.
+struct Queue {
+    size: u16,
+    base: *mut u8,
+    avail_offset: usize,
+    used_offset: usize,
+    next_avail: u16,
+    last_used: u16,
+}
+
+impl Queue {
+    /// Pages for a queue of `size` and where its rings start.
+    fn layout(size: u16) -> (usize, usize, usize) {
+        let size = size as usize;
+        let avail_offset = size * core::mem::size_of::<Descriptor>();
+        let used_offset = (avail_offset + 6 + 2 * size).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let pages = (used_offset + 6 + 8 * size).div_ceil(PAGE_SIZE);
+        (pages, avail_offset, used_offset)
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        unsafe { (self.base as *mut Descriptor).add(index as usize) }
+    }
+
+    fn set_descriptor(&self, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        unsafe { write_volatile(self.descriptor(index), Descriptor { addr, len, flags, next }) };
+    }
+
+    /// Puts a descriptor chain on the available ring.
+    fn push(&mut self, head: u16) {
+        unsafe {
+            let avail = self.base.add(self.avail_offset) as *mut u16;
+            write_volatile(avail.add(2 + (self.next_avail % self.size) as usize), head);
+            self.next_avail = self.next_avail.wrapping_add(1);
+            fence(Ordering::SeqCst);
+            write_volatile(avail.add(1), self.next_avail);
+            fence(Ordering::SeqCst);
+        }
+    }
+
+    /// Takes the next chain the device finished with, if any.
+    fn pop(&mut self) -> Option<u16> {
+        unsafe {
+            let used = self.base.add(self.used_offset);
+            fence(Ordering::SeqCst);
+            if read_volatile(used.add(2) as *const u16) == self.last_used {
+                return None;
+            }
+            let element = used.add(4 + 8 * (self.last_used % self.size) as usize) as *const u32;
+            self.last_used = self.last_used.wrapping_add(1);
+            Some(read_volatile(element) as u16)
+        }
+    }
+}
+
+struct Slot {
+    buffer: PhysAddr,
+    busy: bool,
+    done: bool,
+}
+
+struct State {
+    queue: Queue,
+    slots: Vec<Slot>,
+}
+
+pub struct VirtioBlk {
+    name: String,
+    transport: Transport,
+    features: u64,
+    sectors: u64,
+    state: RefCell<State>,
+}
+
+impl VirtioBlk {
+    fn new(address: &PciAddress, name: String) -> Result<VirtioBlk, &'static str> {
+        address.enable();
+        let transport = Transport::probe(address)?;
+        transport.set_status(0);
+        transport.set_status(STATUS_ACKNOWLEDGE);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let features = match transport.negotiate(FEATURE_BLK_RO | FEATURE_BLK_FLUSH) {
+            Ok(features) => features,
+            Err(e) => {
+                transport.set_status(STATUS_FAILED);
+                return Err(e);
+            }
+        };
+
+        let size = transport.queue_size();
+        if (size as usize) < SLOTS * 3 {
+            transport.set_status(STATUS_FAILED);
+            return Err("Virtqueue too small");
+        }
+        let (pages, avail_offset, used_offset) = Queue::layout(size);
+        let phys = memory::allocate_dma(pages).ok_or("Out of memory")?;
+        let queue = Queue {
+            size,
+            base: memory::phys_to_virt(phys).as_mut_ptr(),
+            avail_offset,
+            used_offset,
+            next_avail: 0,
+            last_used: 0,
+        };
+        transport.set_queue(&queue, phys);
+        let mut slots = Vec::new();
+        for _ in 0..SLOTS {
+            let buffer = memory::allocate_dma(1 + SLOT_DATA_PAGES).ok_or("Out of memory")?;
+            slots.push(Slot { buffer, busy: false, done: false });
+        }
+        transport.set_status(transport.status() | STATUS_DRIVER_OK);
+
+        let line = address.interrupt_line();
+        let first_on_line = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut registers = ISR_REGISTERS.lock();
+            let first = registers.iter().all(|(other, _)| *other != line);
+            registers.push((line, transport.isr()));
+            first
+        });
+        if first_on_line {
+            // Without the interrupt, waits still end on the next timer tick.
+            if let Err(e) = interrupts::add_irq_handler(line, handle_interrupt) {
+                serial_println!("{}: IRQ {}: {}", name, line, e);
+            }
+        }
+
+        Ok(VirtioBlk {
+            name,
+            sectors: transport.capacity(),
+            transport,
+            features,
+            state: RefCell::new(State { queue, slots }),
+        })
+    }
+
+    /// The data part of a slot's buffer.
+    fn data(&self, slot: usize) -> *mut u8 {
+        let buffer = self.state.borrow().slots[slot].buffer + PAGE_SIZE as u64;
+        memory::phys_to_virt(buffer).as_mut_ptr()
+    }
+
+    fn acquire_slot(&self) -> usize {
+        loop {
+            let mut state = self.state.borrow_mut();
+            if let Some(slot) = state.slots.iter().position(|slot| !slot.busy) {
+                state.slots[slot].busy = true;
+                return slot;
+            }
+            drop(state);
+            process::yield_now();
+        }
+    }
+
+    /// Sends one request through `slot` and waits for it; `len` bytes of
+    /// the slot's data buffer go along.
+    fn run(&self, slot: usize, kind: u32, sector: u64, len: usize) -> Result<(), &'static str> {
+        let buffer = self.state.borrow().slots[slot].buffer;
+        let header = memory::phys_to_virt(buffer).as_mut_ptr::<u8>();
+        unsafe {
+            write_volatile(header as *mut u32, kind);
+            write_volatile(header.add(4) as *mut u32, 0);
+            write_volatile(header.add(8) as *mut u64, sector);
+            write_volatile(header.add(STATUS_OFFSET), 0xFF);
+        }
+
+        let mut state = self.state.borrow_mut();
+        let head = (slot * 3) as u16;
+        let buffer = buffer.as_u64();
+        let status = buffer + STATUS_OFFSET as u64;
+        if len > 0 {
+            let flags = if kind == REQUEST_IN { DESC_NEXT | DESC_WRITE } else { DESC_NEXT };
+            state.queue.set_descriptor(head, buffer, 16, DESC_NEXT, head + 1);
+            state.queue.set_descriptor(head + 1, buffer + PAGE_SIZE as u64, len as u32, flags, head + 2);
+        } else {
+            state.queue.set_descriptor(head, buffer, 16, DESC_NEXT, head + 2);
+        }
+        state.queue.set_descriptor(head + 2, status, 1, DESC_WRITE, 0);
+        state.queue.push(head);
+        drop(state);
+        self.transport.notify();
+
+        loop {
+            let mut state = self.state.borrow_mut();
+            while let Some(done) = state.queue.pop() {
+                state.slots[done as usize / 3].done = true;
+            }
+            if state.slots[slot].done {
+                state.slots[slot].done = false;
+                break;
+            }
+            drop(state);
+            process::yield_now();
+        }
+        match unsafe { read_volatile(header.add(STATUS_OFFSET)) } {
+            RESULT_OK => Ok(()),
+            RESULT_UNSUPPORTED => Err("Operation not supported"),
+            _ => Err("I/O error"),
+        }
+    }
+
+    fn release_slot(&self, slot: usize) {
+        self.state.borrow_mut().slots[slot].busy = false;
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(SLOT_DATA).enumerate() {
+            let slot = self.acquire_slot();
+            let sector = lba + (i * SLOT_DATA / SECTOR_SIZE) as u64;
+            let result = self.run(slot, REQUEST_IN, sector, chunk.len());
+            if result.is_ok() {
+                unsafe { core::ptr::copy_nonoverlapping(self.data(slot), chunk.as_mut_ptr(), chunk.len()) };
+            }
+            self.release_slot(slot);
+            result?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        if self.features & FEATURE_BLK_RO != 0 {
+            return Err("Read-only device");
+        }
+        for (i, chunk) in buf.chunks(SLOT_DATA).enumerate() {
+            let slot = self.acquire_slot();
+            let sector = lba + (i * SLOT_DATA / SECTOR_SIZE) as u64;
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), self.data(slot), chunk.len()) };
+            let result = self.run(slot, REQUEST_OUT, sector, chunk.len());
+            self.release_slot(slot);
+            result?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        // Without the feature the device writes through.
+        if self.features & FEATURE_BLK_FLUSH == 0 {
+            return Ok(());
+        }
+        let slot = self.acquire_slot();
+        let result = self.run(slot, REQUEST_FLUSH, 0, 0);
+        self.release_slot(slot);
+        result
+    }
+}
+
+static DRIVER: pci::Driver = pci::Driver {
+    name: "virtio-blk",
+    matches: &[
+        Match::Device(VENDOR_VIRTIO, DEVICE_BLOCK_TRANSITIONAL),
+        Match::Device(VENDOR_VIRTIO, DEVICE_BLOCK_MODERN),
+    ],
+    probe,
+};
+
+/// Block devices set up so far, for naming the next one.
+static COUNT: AtomicU8 = AtomicU8::new(0);
+
+/// Registers a virtio block device as `vda`, `vdb` and so on.
+fn probe(device: &PciDevice) -> Result<(), &'static str> {
+    let name = format!("vd{}", (b'a' + COUNT.load(Ordering::Relaxed)) as char);
+    let device = VirtioBlk::new(&device.address, name)?;
+    serial_println!("{}: virtio-blk, {} sectors", device.name, device.sectors);
+    block::register(Rc::new(device));
+    COUNT.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Makes the driver known to the PCI bus; devices are set up by `pci::init`.
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
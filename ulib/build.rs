@@ -0,0 +1,7 @@
+use std::env;
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rustc-link-arg-examples=-T{}/link.ld", dir);
+    println!("cargo:rerun-if-changed=link.ld");
+}
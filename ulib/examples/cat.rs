@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+use ulib::{eprintln, sys};
+
+ulib::entry!(main);
+
+fn cat(fd: u64) -> sys::Result<()> {
+    let mut buf = [0u8; 512];
+    loop {
+        let len = sys::read(fd, &mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        sys::write(sys::STDOUT, &buf[..len])?;
+    }
+}
+
+fn main(args: &[&str]) -> i32 {
+    if args.len() < 2 {
+        eprintln!("Usage: cat <file>...");
+        return 1;
+    }
+    let mut status = 0;
+    for path in &args[1..] {
+        let result = sys::open(path, sys::O_RDONLY).and_then(|fd| {
+            let result = cat(fd);
+            let _ = sys::close(fd);
+            result
+        });
+        if let Err(errno) = result {
+            eprintln!("cat: {}: error {}", path, errno.0);
+            status = 1;
+        }
+    }
+    status
+}
@@ -0,0 +1,14 @@
+#![no_std]
+#![no_main]
+
+use ulib::println;
+
+ulib::entry!(main);
+
+fn main(args: &[&str]) -> i32 {
+    println!("Hello from pid {}!", ulib::sys::getpid());
+    for (i, arg) in args.iter().enumerate() {
+        println!("argv[{}] = {}", i, arg);
+    }
+    0
+}
@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use ulib::{eprintln, println, sys};
+
+ulib::entry!(main);
+
+const REQUESTS: &str = "ipcdemo.req";
+const REPLIES: &str = "ipcdemo.rep";
+const SHARED: &str = "ipcdemo";
+const ROUNDS: u64 = 5;
+
+/// Parent and child pass a token through two message queues and count the
+/// round trips in shared memory. Run without arguments.
+fn main(args: &[&str]) -> i32 {
+    let child = args.get(1) == Some(&"child");
+    let result = if child { run_child() } else { run_parent(args[0]) };
+    match result {
+        Ok(()) => 0,
+        Err(errno) => {
+            eprintln!("ipcdemo: error {}", errno.0);
+            1
+        }
+    }
+}
+
+fn counter() -> sys::Result<&'static AtomicU64> {
+    let fd = sys::shm_open(SHARED, sys::O_CREAT, 4096)?;
+    let addr = sys::shm_map(fd)?;
+    sys::close(fd)?;
+    Ok(unsafe { &*(addr as *const AtomicU64) })
+}
+
+fn run_parent(path: &str) -> sys::Result<()> {
+    let requests = sys::mq_open(REQUESTS, sys::O_CREAT)?;
+    let replies = sys::mq_open(REPLIES, sys::O_CREAT)?;
+    let count = counter()?;
+    let pid = sys::spawn(path, &[path, "child"])?;
+    let mut buf = [0u8; 64];
+    for round in 0..ROUNDS {
+        sys::mq_send(requests, b"ping", None)?;
+        let len = sys::mq_receive(replies, &mut buf, Some(1000))?;
+        println!("round {}: got {:?}, counter = {}", round, core::str::from_utf8(&buf[..len]), count.load(Ordering::SeqCst));
+    }
+    sys::mq_send(requests, b"quit", None)?;
+    sys::waitpid(pid as i64, 0)?;
+    sys::mq_unlink(REQUESTS)?;
+    sys::mq_unlink(REPLIES)?;
+    sys::shm_unlink(SHARED)?;
+    Ok(())
+}
+
+fn run_child() -> sys::Result<()> {
+    let requests = sys::mq_open(REQUESTS, 0)?;
+    let replies = sys::mq_open(REPLIES, 0)?;
+    let count = counter()?;
+    let mut buf = [0u8; 64];
+    loop {
+        let len = sys::mq_receive(requests, &mut buf, None)?;
+        if &buf[..len] == b"quit" {
+            return Ok(());
+        }
+        count.fetch_add(1, Ordering::SeqCst);
+        sys::mq_send(replies, b"pong", None)?;
+    }
+}
@@ -0,0 +1,32 @@
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use ulib::{println, signal, sys};
+
+ulib::entry!(main);
+
+static CAUGHT: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn on_signal(signal: u64) {
+    CAUGHT.fetch_add(1, Ordering::Relaxed);
+    println!("caught signal {}", signal);
+}
+
+fn main(_args: &[&str]) -> i32 {
+    signal::signal(sys::SIGUSR1, on_signal).expect("sigaction failed");
+    signal::signal(sys::SIGINT, on_signal).expect("sigaction failed");
+
+    sys::kill(sys::getpid(), sys::SIGUSR1).expect("kill failed");
+    println!("after kill: {} caught", CAUGHT.load(Ordering::Relaxed));
+
+    println!("press Ctrl+C twice");
+    while CAUGHT.load(Ordering::Relaxed) < 3 {
+        core::hint::spin_loop();
+    }
+    signal::reset(sys::SIGINT).expect("sigaction failed");
+    println!("press Ctrl+C once more to quit");
+    loop {
+        core::hint::spin_loop();
+    }
+}
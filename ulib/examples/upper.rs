@@ -0,0 +1,22 @@
+#![no_std]
+#![no_main]
+
+use ulib::sys;
+
+ulib::entry!(main);
+
+/// Copies stdin to stdout in upper case, e.g. `ls | run upper`.
+fn main(_args: &[&str]) -> i32 {
+    let mut buf = [0u8; 512];
+    loop {
+        let len = match sys::read(sys::STDIN, &mut buf) {
+            Ok(0) => return 0,
+            Ok(len) => len,
+            Err(_) => return 1,
+        };
+        buf[..len].make_ascii_uppercase();
+        if sys::write(sys::STDOUT, &buf[..len]).is_err() {
+            return 1;
+        }
+    }
+}
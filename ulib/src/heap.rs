@@ -0,0 +1,64 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+
+use crate::sys;
+
+/// The break is moved in steps of this size to keep `brk` calls rare.
+const GROW_STEP: u64 = 64 * 1024;
+
+/// A bump allocator on top of `brk`. Only the most recent allocation is
+/// given back on `dealloc`; user programs are short-lived.
+pub struct BrkAllocator {
+    state: UnsafeCell<State>,
+}
+
+struct State {
+    next: u64,
+    end: u64,
+    last: u64,
+}
+
+// User programs are single-threaded.
+unsafe impl Sync for BrkAllocator {}
+
+#[global_allocator]
+static ALLOCATOR: BrkAllocator = BrkAllocator {
+    state: UnsafeCell::new(State { next: 0, end: 0, last: 0 }),
+};
+
+unsafe impl GlobalAlloc for BrkAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let state = unsafe { &mut *self.state.get() };
+        if state.end == 0 {
+            state.next = sys::brk(0);
+            state.end = state.next;
+        }
+
+        let align = layout.align() as u64;
+        let start = match state.next.checked_add(align - 1) {
+            Some(addr) => addr & !(align - 1),
+            None => return core::ptr::null_mut(),
+        };
+        let end = match start.checked_add(layout.size() as u64) {
+            Some(end) => end,
+            None => return core::ptr::null_mut(),
+        };
+        if end > state.end {
+            let new_end = (end + GROW_STEP - 1) & !(GROW_STEP - 1);
+            if sys::brk(new_end) != new_end {
+                return core::ptr::null_mut();
+            }
+            state.end = new_end;
+        }
+        state.last = start;
+        state.next = end;
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        let state = unsafe { &mut *self.state.get() };
+        if ptr as u64 == state.last {
+            state.next = state.last;
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use core::fmt::{self, Write};
+
+use crate::sys;
+
+/// Writes formatted output to a file descriptor, ignoring errors.
+pub struct Fd(pub u64);
+
+impl Write for Fd {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            match sys::write(self.0, bytes) {
+                Ok(0) | Err(_) => return Err(fmt::Error),
+                Ok(written) => bytes = &bytes[written..],
+            }
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: u64, args: fmt::Arguments) {
+    let _ = Fd(fd).write_fmt(args);
+}
+
+/// Reads one line from standard input, without the trailing newline.
+pub fn read_line(buf: &mut [u8]) -> sys::Result<&str> {
+    let len = sys::read(sys::STDIN, buf)?;
+    let line = &buf[..len];
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    core::str::from_utf8(line).map_err(|_| sys::Errno::EINVAL)
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print($crate::sys::STDOUT, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_print($crate::sys::STDERR, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
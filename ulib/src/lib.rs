@@ -0,0 +1,86 @@
+//! Runtime for user programs: entry point, syscall wrappers, heap and
+//! printing.
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! ulib::entry!(main);
+//!
+//! fn main(args: &[&str]) -> i32 {
+//!     ulib::println!("hello from {}", args[0]);
+//!     0
+//! }
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod heap;
+pub mod io;
+pub mod signal;
+pub mod sys;
+
+use alloc::vec::Vec;
+use core::arch::naked_asm;
+
+pub use alloc::{boxed::Box, format, string::String, vec};
+
+unsafe extern "Rust" {
+    fn __ulib_main(args: &[&str]) -> i32;
+}
+
+/// Defines the program's main function, which receives the arguments and
+/// returns the exit status.
+#[macro_export]
+macro_rules! entry {
+    ($path:path) => {
+        #[unsafe(export_name = "__ulib_main")]
+        pub fn __ulib_main(args: &[&str]) -> i32 {
+            let f: fn(&[&str]) -> i32 = $path;
+            f(args)
+        }
+    };
+}
+
+/// The kernel enters here with `rsp` pointing at `argc`, followed by the
+/// `argv` pointers.
+///
+/// # Safety
+///
+/// Must only be used as the program entry point.
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn _start() -> ! {
+    naked_asm!(
+        "mov rdi, rsp",
+        "and rsp, -16",
+        "call {}",
+        "ud2",
+        sym start_rust,
+    );
+}
+
+unsafe extern "C" fn start_rust(stack: *const u64) -> ! {
+    let argc = unsafe { *stack } as usize;
+    let argv = unsafe { stack.add(1) } as *const *const u8;
+    let mut args: Vec<&'static str> = Vec::with_capacity(argc);
+    for i in 0..argc {
+        let ptr = unsafe { *argv.add(i) };
+        let mut len = 0;
+        while unsafe { *ptr.add(len) } != 0 {
+            len += 1;
+        }
+        let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+        args.push(core::str::from_utf8(bytes).unwrap_or(""));
+    }
+    let code = unsafe { __ulib_main(&args) };
+    sys::exit(code)
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    eprintln!("{}", info);
+    sys::exit(101)
+}
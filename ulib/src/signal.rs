@@ -0,0 +1,39 @@
+use core::arch::naked_asm;
+
+use crate::sys::{self, SigAction};
+
+pub type Handler = extern "C" fn(signal: u64);
+
+/// Runs `handler` whenever `signal` is delivered.
+pub fn signal(signal: u64, handler: Handler) -> sys::Result<()> {
+    install(signal, handler as *const () as u64)
+}
+
+pub fn ignore(signal: u64) -> sys::Result<()> {
+    install(signal, sys::SIG_IGN)
+}
+
+/// Restores the default action (terminate, or ignore for SIGCHLD).
+pub fn reset(signal: u64) -> sys::Result<()> {
+    install(signal, sys::SIG_DFL)
+}
+
+fn install(signal: u64, handler: u64) -> sys::Result<()> {
+    let action = SigAction {
+        handler,
+        mask: 0,
+        restorer: restorer as *const () as u64,
+    };
+    sys::sigaction(signal, &action).map(|_| ())
+}
+
+/// Handlers return here; the kernel restores the interrupted registers.
+#[unsafe(naked)]
+unsafe extern "C" fn restorer() -> ! {
+    naked_asm!(
+        "mov eax, {sigreturn}",
+        "syscall",
+        "ud2",
+        sigreturn = const sys::SYSCALL_SIGRETURN,
+    );
+}
@@ -0,0 +1,448 @@
+//! Raw and typed wrappers around the kernel's syscalls.
+//!
+//! The numbers and flag values mirror `src/syscalls.rs` in the kernel. The raw
+//! `syscallN` functions are unsafe because the kernel trusts that pointer
+//! arguments are valid for the requested call.
+
+#![allow(clippy::missing_safety_doc)]
+
+use core::arch::asm;
+
+pub const SYSCALL_WRITE: u64 = 1;
+pub const SYSCALL_EXIT: u64 = 2;
+pub const SYSCALL_WAITPID: u64 = 3;
+pub const SYSCALL_SPAWN: u64 = 4;
+pub const SYSCALL_EXEC: u64 = 5;
+pub const SYSCALL_GETPID: u64 = 6;
+pub const SYSCALL_GETPPID: u64 = 7;
+pub const SYSCALL_READ: u64 = 8;
+pub const SYSCALL_OPEN: u64 = 9;
+pub const SYSCALL_CLOSE: u64 = 10;
+pub const SYSCALL_LSEEK: u64 = 11;
+pub const SYSCALL_STAT: u64 = 12;
+pub const SYSCALL_MKDIR: u64 = 13;
+pub const SYSCALL_UNLINK: u64 = 14;
+pub const SYSCALL_READDIR: u64 = 15;
+pub const SYSCALL_BRK: u64 = 16;
+pub const SYSCALL_KILL: u64 = 17;
+pub const SYSCALL_SIGACTION: u64 = 18;
+pub const SYSCALL_SIGPROCMASK: u64 = 19;
+pub const SYSCALL_SIGRETURN: u64 = 20;
+pub const SYSCALL_PIPE: u64 = 21;
+pub const SYSCALL_DUP2: u64 = 22;
+pub const SYSCALL_MQ_OPEN: u64 = 23;
+pub const SYSCALL_MQ_SEND: u64 = 24;
+pub const SYSCALL_MQ_RECEIVE: u64 = 25;
+pub const SYSCALL_MQ_UNLINK: u64 = 26;
+pub const SYSCALL_SHM_OPEN: u64 = 27;
+pub const SYSCALL_SHM_MAP: u64 = 28;
+pub const SYSCALL_SHM_UNMAP: u64 = 29;
+pub const SYSCALL_SHM_UNLINK: u64 = 30;
+pub const SYSCALL_FTRUNCATE: u64 = 31;
+pub const SYSCALL_RENAME: u64 = 32;
+pub const SYSCALL_LINK: u64 = 33;
+pub const SYSCALL_SYMLINK: u64 = 34;
+pub const SYSCALL_READLINK: u64 = 35;
+
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_CREAT: u64 = 0x40;
+pub const O_EXCL: u64 = 0x80;
+pub const O_TRUNC: u64 = 0x200;
+pub const O_APPEND: u64 = 0x400;
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFBLK: u32 = 0o060000;
+/// Bits of `mode` that hold the file type.
+pub const S_IFMT: u32 = 0o170000;
+
+pub const WNOHANG: u64 = 1;
+
+pub const SIGHUP: u64 = 1;
+pub const SIGINT: u64 = 2;
+pub const SIGQUIT: u64 = 3;
+pub const SIGILL: u64 = 4;
+pub const SIGABRT: u64 = 6;
+pub const SIGKILL: u64 = 9;
+pub const SIGUSR1: u64 = 10;
+pub const SIGSEGV: u64 = 11;
+pub const SIGUSR2: u64 = 12;
+pub const SIGPIPE: u64 = 13;
+pub const SIGALRM: u64 = 14;
+pub const SIGTERM: u64 = 15;
+pub const SIGCHLD: u64 = 17;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// An error number returned by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub u64);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ENOENT: Errno = Errno(2);
+    pub const ESRCH: Errno = Errno(3);
+    pub const EINTR: Errno = Errno(4);
+    pub const E2BIG: Errno = Errno(7);
+    pub const ENOEXEC: Errno = Errno(8);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const EAGAIN: Errno = Errno(11);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EBUSY: Errno = Errno(16);
+    pub const EEXIST: Errno = Errno(17);
+    pub const EXDEV: Errno = Errno(18);
+    pub const ENOTDIR: Errno = Errno(20);
+    pub const EISDIR: Errno = Errno(21);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EMFILE: Errno = Errno(24);
+    pub const EFBIG: Errno = Errno(27);
+    pub const ESPIPE: Errno = Errno(29);
+    pub const EPIPE: Errno = Errno(32);
+    pub const ENOSYS: Errno = Errno(38);
+    pub const ENOTEMPTY: Errno = Errno(39);
+    pub const ELOOP: Errno = Errno(40);
+    pub const EMSGSIZE: Errno = Errno(90);
+    pub const ETIMEDOUT: Errno = Errno(110);
+}
+
+pub type Result<T> = core::result::Result<T, Errno>;
+
+/// Result of `stat`; times are seconds since 1970-01-01.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Stat {
+    pub mode: u32,
+    pub uid: u32,
+    pub ino: u64,
+    pub nlink: u64,
+    pub size: u64,
+    pub accessed: u64,
+    pub modified: u64,
+    pub created: u64,
+}
+
+impl Stat {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+/// Argument of `sigaction`; `restorer` is called when the handler returns
+/// and must invoke `sigreturn`.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct SigAction {
+    pub handler: u64,
+    pub mask: u64,
+    pub restorer: u64,
+}
+
+#[inline(always)]
+pub unsafe fn syscall0(number: u64) -> u64 {
+    let ret: u64;
+    asm!("syscall", inlateout("rax") number => ret, out("rcx") _, out("r11") _, options(nostack));
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall1(number: u64, arg1: u64) -> u64 {
+    let ret: u64;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg1,
+        out("rcx") _, out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall2(number: u64, arg1: u64, arg2: u64) -> u64 {
+    let ret: u64;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg1, in("rsi") arg2,
+        out("rcx") _, out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall3(number: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
+    let ret: u64;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg1, in("rsi") arg2, in("rdx") arg3,
+        out("rcx") _, out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall4(number: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> u64 {
+    let ret: u64;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg1, in("rsi") arg2, in("rdx") arg3, in("r10") arg4,
+        out("rcx") _, out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+/// Splits a raw return value into a result: values in `-4095..0` are errors.
+fn check(ret: u64) -> Result<u64> {
+    let signed = ret as i64;
+    if (-4095..0).contains(&signed) {
+        Err(Errno((-signed) as u64))
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Arguments are passed to the kernel as an array of pointer/length pairs.
+fn arg_pairs<'a>(args: &[&str], buf: &'a mut [[u64; 2]; MAX_ARGS]) -> Result<&'a [[u64; 2]]> {
+    if args.len() > MAX_ARGS {
+        return Err(Errno::E2BIG);
+    }
+    for (pair, arg) in buf.iter_mut().zip(args) {
+        *pair = [arg.as_ptr() as u64, arg.len() as u64];
+    }
+    Ok(&buf[..args.len()])
+}
+
+/// Mirrors the kernel's `MAX_ARGS`.
+pub const MAX_ARGS: usize = 64;
+
+pub fn write(fd: u64, data: &[u8]) -> Result<usize> {
+    check(unsafe { syscall3(SYSCALL_WRITE, fd, data.as_ptr() as u64, data.len() as u64) }).map(|n| n as usize)
+}
+
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize> {
+    check(unsafe { syscall3(SYSCALL_READ, fd, buf.as_mut_ptr() as u64, buf.len() as u64) }).map(|n| n as usize)
+}
+
+pub fn exit(code: i32) -> ! {
+    unsafe { syscall1(SYSCALL_EXIT, code as u64) };
+    unreachable!("exit returned");
+}
+
+/// Waits for a child (any child if `pid` is -1) and returns its pid and
+/// exit status; `Ok(None)` with `WNOHANG` if no child has exited yet.
+pub fn waitpid(pid: i64, options: u64) -> Result<Option<(u64, i32)>> {
+    let mut status: i32 = 0;
+    let child = check(unsafe {
+        syscall3(SYSCALL_WAITPID, pid as u64, &mut status as *mut i32 as u64, options)
+    })?;
+    Ok(if child == 0 { None } else { Some((child, status)) })
+}
+
+/// Starts `path` as a new child process; `args[0]` is its name by convention.
+pub fn spawn(path: &str, args: &[&str]) -> Result<u64> {
+    let mut buf = [[0u64; 2]; MAX_ARGS];
+    let pairs = arg_pairs(args, &mut buf)?;
+    check(unsafe {
+        syscall4(SYSCALL_SPAWN, path.as_ptr() as u64, path.len() as u64, pairs.as_ptr() as u64, pairs.len() as u64)
+    })
+}
+
+/// Replaces the current program; only returns on error.
+pub fn exec(path: &str, args: &[&str]) -> Result<()> {
+    let mut buf = [[0u64; 2]; MAX_ARGS];
+    let pairs = arg_pairs(args, &mut buf)?;
+    check(unsafe {
+        syscall4(SYSCALL_EXEC, path.as_ptr() as u64, path.len() as u64, pairs.as_ptr() as u64, pairs.len() as u64)
+    })?;
+    Ok(())
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall0(SYSCALL_GETPID) }
+}
+
+pub fn getppid() -> u64 {
+    unsafe { syscall0(SYSCALL_GETPPID) }
+}
+
+pub fn open(path: &str, flags: u64) -> Result<u64> {
+    check(unsafe { syscall3(SYSCALL_OPEN, path.as_ptr() as u64, path.len() as u64, flags) })
+}
+
+pub fn close(fd: u64) -> Result<()> {
+    check(unsafe { syscall1(SYSCALL_CLOSE, fd) }).map(|_| ())
+}
+
+pub fn lseek(fd: u64, offset: i64, whence: u64) -> Result<u64> {
+    check(unsafe { syscall3(SYSCALL_LSEEK, fd, offset as u64, whence) })
+}
+
+pub fn ftruncate(fd: u64, len: u64) -> Result<()> {
+    check(unsafe { syscall2(SYSCALL_FTRUNCATE, fd, len) }).map(|_| ())
+}
+
+pub fn stat(path: &str) -> Result<Stat> {
+    let mut stat = Stat::default();
+    check(unsafe {
+        syscall3(SYSCALL_STAT, path.as_ptr() as u64, path.len() as u64, &mut stat as *mut Stat as u64)
+    })?;
+    Ok(stat)
+}
+
+pub fn mkdir(path: &str) -> Result<()> {
+    check(unsafe { syscall2(SYSCALL_MKDIR, path.as_ptr() as u64, path.len() as u64) }).map(|_| ())
+}
+
+pub fn unlink(path: &str) -> Result<()> {
+    check(unsafe { syscall2(SYSCALL_UNLINK, path.as_ptr() as u64, path.len() as u64) }).map(|_| ())
+}
+
+pub fn rename(from: &str, to: &str) -> Result<()> {
+    check(unsafe {
+        syscall4(SYSCALL_RENAME, from.as_ptr() as u64, from.len() as u64, to.as_ptr() as u64, to.len() as u64)
+    })
+    .map(|_| ())
+}
+
+pub fn link(existing: &str, new: &str) -> Result<()> {
+    check(unsafe {
+        syscall4(SYSCALL_LINK, existing.as_ptr() as u64, existing.len() as u64, new.as_ptr() as u64, new.len() as u64)
+    })
+    .map(|_| ())
+}
+
+pub fn symlink(target: &str, path: &str) -> Result<()> {
+    check(unsafe {
+        syscall4(SYSCALL_SYMLINK, target.as_ptr() as u64, target.len() as u64, path.as_ptr() as u64, path.len() as u64)
+    })
+    .map(|_| ())
+}
+
+/// Copies the target of the link at `path` into `buf` and returns its length.
+pub fn readlink(path: &str, buf: &mut [u8]) -> Result<usize> {
+    check(unsafe {
+        syscall4(SYSCALL_READLINK, path.as_ptr() as u64, path.len() as u64, buf.as_mut_ptr() as u64, buf.len() as u64)
+    })
+    .map(|len| len as usize)
+}
+
+/// Reads the next entry name of an open directory into `buf`.
+pub fn readdir(fd: u64, buf: &mut [u8]) -> Result<Option<&str>> {
+    let len = check(unsafe { syscall3(SYSCALL_READDIR, fd, buf.as_mut_ptr() as u64, buf.len() as u64) })?;
+    if len == 0 {
+        return Ok(None);
+    }
+    core::str::from_utf8(&buf[..len as usize]).map(Some).map_err(|_| Errno::EINVAL)
+}
+
+/// Moves the program break; `brk(0)` returns the current one.
+pub fn brk(addr: u64) -> u64 {
+    unsafe { syscall1(SYSCALL_BRK, addr) }
+}
+
+pub fn kill(pid: u64, signal: u64) -> Result<()> {
+    check(unsafe { syscall2(SYSCALL_KILL, pid, signal) }).map(|_| ())
+}
+
+/// Installs `action` for `signal` and returns the previous one.
+pub fn sigaction(signal: u64, action: &SigAction) -> Result<SigAction> {
+    let mut old = SigAction::default();
+    check(unsafe {
+        syscall3(SYSCALL_SIGACTION, signal, action as *const SigAction as u64, &mut old as *mut SigAction as u64)
+    })?;
+    Ok(old)
+}
+
+/// Changes the blocked mask as selected by `how` and returns the old mask.
+pub fn sigprocmask(how: u64, set: u64) -> Result<u64> {
+    let mut old = 0u64;
+    check(unsafe { syscall3(SYSCALL_SIGPROCMASK, how, &set as *const u64 as u64, &mut old as *mut u64 as u64) })?;
+    Ok(old)
+}
+
+/// Creates a pipe and returns its read and write descriptors.
+pub fn pipe() -> Result<(u64, u64)> {
+    let mut fds = [0u64; 2];
+    check(unsafe { syscall1(SYSCALL_PIPE, fds.as_mut_ptr() as u64) })?;
+    Ok((fds[0], fds[1]))
+}
+
+/// Makes `new_fd` refer to the same open file as `old_fd`.
+pub fn dup2(old_fd: u64, new_fd: u64) -> Result<u64> {
+    check(unsafe { syscall2(SYSCALL_DUP2, old_fd, new_fd) })
+}
+
+/// Timeouts are in milliseconds; `None` blocks until the call can complete.
+fn timeout_arg(timeout_ms: Option<u64>) -> u64 {
+    match timeout_ms {
+        Some(ms) => ms,
+        None => -1i64 as u64,
+    }
+}
+
+/// Opens a named message queue, creating it with `O_CREAT`.
+pub fn mq_open(name: &str, flags: u64) -> Result<u64> {
+    check(unsafe { syscall3(SYSCALL_MQ_OPEN, name.as_ptr() as u64, name.len() as u64, flags) })
+}
+
+pub fn mq_send(fd: u64, message: &[u8], timeout_ms: Option<u64>) -> Result<()> {
+    check(unsafe {
+        syscall4(SYSCALL_MQ_SEND, fd, message.as_ptr() as u64, message.len() as u64, timeout_arg(timeout_ms))
+    })
+    .map(|_| ())
+}
+
+/// Receives the oldest message into `buf` and returns its length.
+pub fn mq_receive(fd: u64, buf: &mut [u8], timeout_ms: Option<u64>) -> Result<usize> {
+    check(unsafe {
+        syscall4(SYSCALL_MQ_RECEIVE, fd, buf.as_mut_ptr() as u64, buf.len() as u64, timeout_arg(timeout_ms))
+    })
+    .map(|n| n as usize)
+}
+
+pub fn mq_unlink(name: &str) -> Result<()> {
+    check(unsafe { syscall2(SYSCALL_MQ_UNLINK, name.as_ptr() as u64, name.len() as u64) }).map(|_| ())
+}
+
+/// Opens a named shared memory object; with `O_CREAT` a missing one is
+/// created with `size` bytes.
+pub fn shm_open(name: &str, flags: u64, size: u64) -> Result<u64> {
+    check(unsafe { syscall4(SYSCALL_SHM_OPEN, name.as_ptr() as u64, name.len() as u64, flags, size) })
+}
+
+/// Maps the object into this process and returns its address.
+pub fn shm_map(fd: u64) -> Result<*mut u8> {
+    check(unsafe { syscall1(SYSCALL_SHM_MAP, fd) }).map(|addr| addr as *mut u8)
+}
+
+pub fn shm_unmap(addr: *mut u8) -> Result<()> {
+    check(unsafe { syscall1(SYSCALL_SHM_UNMAP, addr as u64) }).map(|_| ())
+}
+
+pub fn shm_unlink(name: &str) -> Result<()> {
+    check(unsafe { syscall2(SYSCALL_SHM_UNLINK, name.as_ptr() as u64, name.len() as u64) }).map(|_| ())
+}